@@ -0,0 +1,406 @@
+use blake2::digest::{FixedOutput, Mac};
+use bytes::{BufMut, Bytes, BytesMut};
+
+use crate::token::{self, to_array, KeyRing, Kind, Settings, Token, TokenError, VERSION};
+
+/// version.kind.action.key_id.issued.exp.issuer.audience.user.jti.group
+const BASE_LEN: usize = 103;
+
+/// a delegated token without any caveats
+pub const MIN_LEN: usize = BASE_LEN + 2 + 32;
+
+/// a restriction appended to a delegated token.
+///
+/// caveats can only ever narrow what a token allows, every one of
+/// them has to hold for the token to be accepted.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Caveat {
+    /// only this entity
+    Entity([u8; 16]),
+    /// only entities of this kind
+    Kind(u8),
+    /// can't be used to write
+    ReadOnly,
+    /// expires earlier than the token it was derived from
+    Expires(u64),
+    /// can only be used once, along with every token derived from it
+    SingleUse,
+}
+
+impl Caveat {
+    fn encode(&self, buf: &mut BytesMut) {
+        match self {
+            Caveat::Entity(entity_uuid) => {
+                buf.put_u8(0);
+                buf.put(&entity_uuid[..]);
+            }
+            Caveat::Kind(kind) => {
+                buf.put_u8(1);
+                buf.put_u8(*kind);
+            }
+            Caveat::ReadOnly => buf.put_u8(2),
+            Caveat::Expires(exp) => {
+                buf.put_u8(3);
+                buf.put_u64(*exp);
+            }
+            Caveat::SingleUse => buf.put_u8(4),
+        }
+    }
+
+    /// (caveat, encoded length)
+    fn decode(bytes: &[u8]) -> Result<(Caveat, usize), TokenError> {
+        let len = match bytes.first() {
+            Some(0) => 17,
+            Some(1) => 2,
+            Some(2) | Some(4) => 1,
+            Some(3) => 9,
+            _ => return Err(TokenError::Caveat),
+        };
+
+        if bytes.len() < len {
+            return Err(TokenError::Caveat);
+        }
+
+        let caveat = match bytes[0] {
+            0 => Caveat::Entity(to_array(&bytes[1..17])),
+            1 => Caveat::Kind(bytes[1]),
+            2 => Caveat::ReadOnly,
+            3 => Caveat::Expires(u64::from_be_bytes(to_array(&bytes[1..9]))),
+            _ => Caveat::SingleUse,
+        };
+
+        Ok((caveat, len))
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Delegated {
+    /// `hmac` is the one at the end of the caveat chain
+    pub token: Token,
+    pub caveats: Vec<Caveat>,
+    /// the hmac right after the first `SingleUse` caveat, which every
+    /// token derived from it shares.
+    pub single_use: Option<[u8; 32]>,
+}
+
+impl Delegated {
+    /// the caveats allow `entity_uuid` and `kind`
+    pub fn allows(&self, entity_uuid: &[u8; 16], kind: u8) -> bool {
+        self.caveats.iter().all(|caveat| match caveat {
+            Caveat::Entity(allowed) => allowed == entity_uuid,
+            Caveat::Kind(allowed) => *allowed == kind,
+            _ => true,
+        })
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.caveats.contains(&Caveat::ReadOnly)
+    }
+}
+
+/// a delegated token without caveats, for `action` in `group_uuid`.
+///
+/// version.kind.action.key_id.issued.exp.issuer.audience.user.jti.group.caveats_len.caveats.hmac
+///
+/// the hmac is chained, the first link is keyed with one of our keys
+/// over everything up to the group, and each caveat is keyed with the
+/// hmac before it. anyone holding the token can append a caveat, but
+/// no one can take one away.
+pub fn gen(
+    keys: &KeyRing,
+    settings: &Settings,
+    action: u8,
+    user_uuid: &[u8; 16],
+    group_uuid: &[u8; 16],
+) -> Result<Bytes, TokenError> {
+    let base = token::gen(
+        keys,
+        settings,
+        Kind::Delegated,
+        action,
+        user_uuid,
+        Some(group_uuid),
+    )?;
+
+    let mut buf = BytesMut::with_capacity(MIN_LEN);
+
+    buf.put(&base[..BASE_LEN]);
+    buf.put_u16(0);
+    buf.put(&base[BASE_LEN..]);
+
+    Ok(buf.into())
+}
+
+/// derives a narrower token, without needing any of our keys
+pub fn attenuate(token: &[u8], caveat: Caveat) -> Result<Bytes, TokenError> {
+    if token.len() < MIN_LEN || len(token)? != token.len() {
+        return Err(TokenError::Length);
+    }
+
+    let hmac_start = token.len() - 32;
+
+    let mut encoded = BytesMut::new();
+    caveat.encode(&mut encoded);
+
+    let caveats_len = u16::try_from(hmac_start - (BASE_LEN + 2) + encoded.len())
+        .map_err(|_| TokenError::Length)?;
+
+    let hmac = chain(&token[hmac_start..], &encoded)?;
+
+    let mut buf = BytesMut::with_capacity(token.len() + encoded.len());
+
+    buf.put(&token[..BASE_LEN]);
+    buf.put_u16(caveats_len);
+    buf.put(&token[BASE_LEN + 2..hmac_start]);
+    buf.put(&encoded[..]);
+    buf.put(&hmac[..]);
+
+    Ok(buf.into())
+}
+
+/// the length of the delegated token at the start of `bytes`, so
+/// ops can tell where it ends and the rest of the request begins.
+pub fn len(bytes: &[u8]) -> Result<usize, TokenError> {
+    if bytes.len() < MIN_LEN {
+        return Err(TokenError::Length);
+    }
+
+    let caveats_len = u16::from_be_bytes(to_array(&bytes[BASE_LEN..BASE_LEN + 2])) as usize;
+
+    if bytes.len() < MIN_LEN + caveats_len {
+        return Err(TokenError::Length);
+    }
+
+    Ok(MIN_LEN + caveats_len)
+}
+
+/// reads the token without checking it, see `verify` for that
+pub fn parse(token: &[u8]) -> Result<Delegated, TokenError> {
+    if len(token)? != token.len() {
+        return Err(TokenError::Length);
+    }
+
+    if token[0] != VERSION {
+        return Err(TokenError::Version(token[0]));
+    }
+
+    if Kind::try_from(token[1])? != Kind::Delegated {
+        return Err(TokenError::Kind);
+    }
+
+    let hmac_start = token.len() - 32;
+
+    let mut caveats = vec![];
+    let mut cursor = BASE_LEN + 2;
+
+    while cursor < hmac_start {
+        let (caveat, len) = Caveat::decode(&token[cursor..hmac_start])?;
+
+        caveats.push(caveat);
+        cursor += len;
+    }
+
+    Ok(Delegated {
+        token: Token {
+            kind: Kind::Delegated,
+            action: token[2],
+            key_id: u32::from_be_bytes(to_array(&token[3..7])),
+            issued: u64::from_be_bytes(to_array(&token[7..15])),
+            exp: u64::from_be_bytes(to_array(&token[15..23])),
+            issuer: to_array(&token[23..39]),
+            audience: to_array(&token[39..55]),
+            user_uuid: to_array(&token[55..71]),
+            jti: to_array(&token[71..87]),
+            group_uuid: Some(to_array(&token[87..103])),
+            hmac: to_array(&token[hmac_start..]),
+        },
+        caveats,
+        single_use: None,
+    })
+}
+
+/// checks the token is for this issuer and audience, was signed with
+/// one of our keys, and that neither it nor any `Expires` caveat has
+/// passed.
+///
+/// the action, and every other caveat, are left to the op using it.
+pub fn verify(keys: &KeyRing, settings: &Settings, token: &[u8]) -> Result<Delegated, TokenError> {
+    let mut parsed = parse(token)?;
+
+    if parsed.token.issuer != settings.issuer {
+        return Err(TokenError::Issuer);
+    }
+
+    if parsed.token.audience != settings.audience {
+        return Err(TokenError::Audience);
+    }
+
+    let now = token::now()?;
+
+    if parsed.token.exp < now {
+        return Err(TokenError::Expired);
+    }
+
+    for caveat in &parsed.caveats {
+        if let Caveat::Expires(exp) = caveat {
+            if *exp < now {
+                return Err(TokenError::Expired);
+            }
+        }
+    }
+
+    let key = keys.verification_key(parsed.token.key_id)?;
+
+    let mut hmac: [u8; 32] = blake2::Blake2sMac256::new_from_slice(key)
+        .map_err(|_| TokenError::Key)?
+        .chain_update(&token[..BASE_LEN])
+        .finalize_fixed()
+        .into();
+
+    let mut single_use = None;
+
+    for caveat in &parsed.caveats {
+        let mut encoded = BytesMut::new();
+        caveat.encode(&mut encoded);
+
+        hmac = chain(&hmac, &encoded)?;
+
+        if *caveat == Caveat::SingleUse && single_use.is_none() {
+            single_use = Some(hmac);
+        }
+    }
+
+    if hmac != parsed.token.hmac {
+        return Err(TokenError::Signature);
+    }
+
+    parsed.single_use = single_use;
+
+    Ok(parsed)
+}
+
+fn chain(hmac: &[u8], caveat: &[u8]) -> Result<[u8; 32], TokenError> {
+    Ok(blake2::Blake2sMac256::new_from_slice(hmac)
+        .map_err(|_| TokenError::Key)?
+        .chain_update(caveat)
+        .finalize_fixed()
+        .into())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use uuid::Uuid;
+
+    #[test]
+    fn caveats() -> Result<(), Box<dyn std::error::Error>> {
+        let user_uuid = *Uuid::now_v7().as_bytes();
+        let group_uuid = *Uuid::now_v7().as_bytes();
+        let entity_uuid = *Uuid::now_v7().as_bytes();
+
+        let keys = KeyRing::generate();
+        let settings = Settings::default();
+
+        let token = gen(&keys, &settings, 13, &user_uuid, &group_uuid)?;
+
+        let verified = verify(&keys, &settings, &token)?;
+
+        assert_eq!(verified.token.user_uuid, user_uuid);
+        assert_eq!(verified.token.group_uuid, Some(group_uuid));
+        assert!(verified.caveats.is_empty());
+        assert!(verified.allows(&entity_uuid, 7));
+
+        let exp = verified.token.exp - 60;
+
+        let narrowed = attenuate(&token, Caveat::Entity(entity_uuid))?;
+        let narrowed = attenuate(&narrowed, Caveat::Kind(7))?;
+        let narrowed = attenuate(&narrowed, Caveat::SingleUse)?;
+        let narrowed = attenuate(&narrowed, Caveat::Expires(exp))?;
+        let narrowed = attenuate(&narrowed, Caveat::ReadOnly)?;
+
+        assert_eq!(len(&[&narrowed[..], &[1, 2, 3]].concat())?, narrowed.len());
+
+        let verified = verify(&keys, &settings, &narrowed)?;
+
+        assert_eq!(
+            verified.caveats,
+            vec![
+                Caveat::Entity(entity_uuid),
+                Caveat::Kind(7),
+                Caveat::SingleUse,
+                Caveat::Expires(exp),
+                Caveat::ReadOnly,
+            ]
+        );
+        assert!(verified.allows(&entity_uuid, 7));
+        assert!(!verified.allows(&entity_uuid, 8));
+        assert!(!verified.allows(&user_uuid, 7));
+        assert!(verified.read_only());
+
+        // tokens derived from a single use token share its use
+        let further = verify(&keys, &settings, &attenuate(&narrowed, Caveat::Kind(7))?)?;
+
+        assert!(verified.single_use.is_some());
+        assert_eq!(further.single_use, verified.single_use);
+
+        Ok(())
+    }
+
+    #[test]
+    fn rejects() -> Result<(), Box<dyn std::error::Error>> {
+        let user_uuid = *Uuid::now_v7().as_bytes();
+        let group_uuid = *Uuid::now_v7().as_bytes();
+
+        let keys = KeyRing::generate();
+        let settings = Settings::default();
+
+        let token = gen(&keys, &settings, 13, &user_uuid, &group_uuid)?;
+        let narrowed = attenuate(&token, Caveat::ReadOnly)?;
+
+        // dropping a caveat breaks the chain
+        let mut stripped = narrowed[..BASE_LEN].to_vec();
+        stripped.extend_from_slice(&0u16.to_be_bytes());
+        stripped.extend_from_slice(&narrowed[narrowed.len() - 32..]);
+
+        assert_eq!(
+            verify(&keys, &settings, &stripped),
+            Err(TokenError::Signature)
+        );
+
+        // and so does swapping it for another
+        let mut swapped = narrowed.to_vec();
+        swapped[BASE_LEN + 2] = 4;
+
+        assert_eq!(
+            verify(&keys, &settings, &swapped),
+            Err(TokenError::Signature)
+        );
+
+        let expired = attenuate(&token, Caveat::Expires(1))?;
+
+        assert_eq!(verify(&keys, &settings, &expired), Err(TokenError::Expired));
+
+        let mut unknown = narrowed.to_vec();
+        unknown[BASE_LEN + 2] = 255;
+
+        assert_eq!(parse(&unknown), Err(TokenError::Caveat));
+
+        // an access token isn't a delegated token
+        let access = token::gen(
+            &keys,
+            &settings,
+            Kind::Access,
+            13,
+            &user_uuid,
+            Some(&group_uuid),
+        )?;
+
+        assert!(verify(&keys, &settings, &access).is_err());
+        assert_eq!(parse(&token[..40]), Err(TokenError::Length));
+
+        // nor can another deployment verify it
+        assert!(verify(&KeyRing::generate(), &settings, &narrowed).is_err());
+
+        Ok(())
+    }
+}
@@ -3,9 +3,11 @@ use opaque_ke::ciphersuite::CipherSuite;
 
 // pub use opaque_ke::ServerSetup;
 
+pub mod delegated;
 pub mod login;
 pub mod registration;
 pub mod token;
+pub mod totp;
 
 pub struct DefaultCipherSuite;
 
@@ -13,27 +13,37 @@ use opaque_ke::{
 };
 use rand::rngs::OsRng;
 
-use crate::{token, DefaultCipherSuite};
+use crate::DefaultCipherSuite;
 
 /// (state, message)
+///
+/// without a password file the response is made up, the same way every
+/// time for the username, so a client can't tell an unknown username
+/// from a wrong password.
 pub fn server_start(
     setup: &ServerSetup<DefaultCipherSuite>,
     username: &[u8],
-    password_file: &[u8],
+    password_file: Option<&[u8]>,
     client_start: &[u8],
 ) -> Result<(Vec<u8>, Bytes), Box<dyn std::error::Error>> {
-    let password_file =
-        ServerRegistration::<DefaultCipherSuite>::deserialize(&password_file).unwrap();
+    let password_file = match password_file {
+        Some(password_file) => Some(
+            ServerRegistration::<DefaultCipherSuite>::deserialize(password_file)
+                .map_err(|err| err.to_string())?,
+        ),
+        None => None,
+    };
+
     let mut rng = OsRng;
     let login_start_result = ServerLogin::start(
         &mut rng,
         setup,
-        Some(password_file),
-        CredentialRequest::deserialize(client_start).unwrap(),
+        password_file,
+        CredentialRequest::deserialize(client_start).map_err(|err| err.to_string())?,
         username,
         ServerLoginStartParameters::default(),
     )
-    .unwrap();
+    .map_err(|err| err.to_string())?;
 
     Ok((
         login_start_result.state.serialize().to_vec(),
@@ -43,27 +53,45 @@ pub fn server_start(
 
 /// encrypted token
 pub fn server_finish(
-    user_id: &[u8; 16],
+    refresh_token: &[u8],
     client_finish: &[u8],
     server_start: &[u8],
 ) -> Result<Bytes, Box<dyn std::error::Error>> {
-    let start_state = ServerLogin::<DefaultCipherSuite>::deserialize(server_start).unwrap();
+    let session_key = server_session_key(client_finish, server_start)?;
+    encrypt_token(refresh_token, &session_key)
+}
+
+/// session_key
+///
+/// fails if the client didn't know the password.
+pub fn server_session_key(
+    client_finish: &[u8],
+    server_start: &[u8],
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let start_state = ServerLogin::<DefaultCipherSuite>::deserialize(server_start)
+        .map_err(|err| err.to_string())?;
 
     let finish_result = start_state
-        .finish(CredentialFinalization::deserialize(client_finish).unwrap())
-        .unwrap();
+        .finish(CredentialFinalization::deserialize(client_finish).map_err(|err| err.to_string())?)
+        .map_err(|err| err.to_string())?;
 
-    let token = token::gen_without_group(0, user_id).unwrap();
+    Ok(finish_result.session_key.to_vec())
+}
 
+/// encrypted token
+pub fn encrypt_token(
+    refresh_token: &[u8],
+    session_key: &[u8],
+) -> Result<Bytes, Box<dyn std::error::Error>> {
     let mut key = [0u8; 32];
 
     let mut hasher = Blake2bVar::new(32).unwrap();
-    hasher.update(&finish_result.session_key);
+    hasher.update(session_key);
     hasher.finalize_variable(&mut key).unwrap();
 
     let cipher = XChaCha20Poly1305::new(&key.into());
     let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
-    let mut ciphertext = cipher.encrypt(&nonce, token.as_ref()).unwrap();
+    let mut ciphertext = cipher.encrypt(&nonce, refresh_token).unwrap();
 
     ciphertext.extend_from_slice(&nonce);
 
@@ -123,14 +151,18 @@ pub fn decrypt_token(
     hasher.update(session_key);
     hasher.finalize_variable(&mut key).unwrap();
 
-    let nonce_start = encrypted_token.len() - 25;
+    // the nonce is appended by encrypt_token
+    let nonce_start = encrypted_token
+        .len()
+        .checked_sub(24)
+        .ok_or("invalid format")?;
 
     let cipher = XChaCha20Poly1305::new(&key.into());
     let nonce = XNonce::from_slice(&encrypted_token[nonce_start..]);
 
     let plaintext = cipher
         .decrypt(nonce, encrypted_token[..nonce_start].as_ref())
-        .unwrap();
+        .map_err(|_| "invalid token")?;
 
     Ok(Bytes::copy_from_slice(&plaintext))
 }
@@ -1,163 +1,601 @@
-use std::time::{Duration, SystemTime};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 
-use blake2::digest::{
-    generic_array::{typenum, GenericArray},
-    FixedOutput, Mac,
-};
+use blake2::digest::{FixedOutput, Mac};
 use bytes::{BufMut, Bytes, BytesMut};
+use rand::{rngs::OsRng, RngCore};
+
+/// the longest any token can be valid for, and so how long a
+/// retired key has to stick around to verify the tokens it signed.
+pub const MAX_TOKEN_LIFETIME: u64 = 60 * 60 * 24;
+
+pub const VERSION: u8 = 2;
+
+pub const LEN_WITHOUT_GROUP: usize = 119;
+pub const LEN_WITH_GROUP: usize = 135;
+
+/// what a token can be used for
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Kind {
+    /// exchanged for access tokens, never accepted by any other op
+    Refresh = 0,
+    /// scoped to a single action, and optionally a group
+    Access = 1,
+    /// handed to a third party on behalf of a user
+    Delegated = 2,
+}
+
+impl TryFrom<u8> for Kind {
+    type Error = TokenError;
+
+    fn try_from(kind: u8) -> Result<Self, Self::Error> {
+        match kind {
+            0 => Ok(Kind::Refresh),
+            1 => Ok(Kind::Access),
+            2 => Ok(Kind::Delegated),
+            _ => Err(TokenError::Kind),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TokenError {
+    /// not the length of any token
+    Length,
+    /// written with a format version this build doesn't know
+    Version(u8),
+    /// unknown kind, or not the kind that was expected
+    Kind,
+    /// not for the action being performed
+    Action,
+    /// no group, when one was expected
+    Group,
+    /// signed with an unknown or retired key
+    Key,
+    Issuer,
+    Audience,
+    Expired,
+    /// hmac doesn't match
+    Signature,
+    /// lifetime is longer than `MAX_TOKEN_LIFETIME`
+    Lifetime,
+    /// system clock is out of range
+    Clock,
+    /// a caveat this build doesn't know, or that is malformed
+    Caveat,
+}
+
+impl std::fmt::Display for TokenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TokenError::Length => write!(f, "invalid token length"),
+            TokenError::Version(version) => write!(f, "unsupported token version {version}"),
+            TokenError::Kind => write!(f, "invalid token kind"),
+            TokenError::Action => write!(f, "token is not for this action"),
+            TokenError::Group => write!(f, "token is not for a group"),
+            TokenError::Key => write!(f, "token signing key is unknown or retired"),
+            TokenError::Issuer => write!(f, "invalid token issuer"),
+            TokenError::Audience => write!(f, "invalid token audience"),
+            TokenError::Expired => write!(f, "token is expired"),
+            TokenError::Signature => write!(f, "invalid token"),
+            TokenError::Lifetime => write!(f, "token lifetime is out of range"),
+            TokenError::Clock => write!(f, "date is out of range"),
+            TokenError::Caveat => write!(f, "invalid token caveat"),
+        }
+    }
+}
 
-// half needs to be pulled from one of the other 2+ threads,
-// processes, or servers (depending on the level)
-const HMAC_KEY: &'static [u8] = b"TODO: replace this asap";
+impl std::error::Error for TokenError {}
 
-/// 73 bytes
+/// who tokens are issued by and for, and how long each kind lasts.
 ///
-/// action: u8,
-/// exp: [u8; 8],
-/// hmac: [u8; 32],
-/// user_id: [u8; 16],
-/// group_id: [u8; 16]
-#[inline(always)]
-pub fn gen_with_group(
-    action: u8,
-    user_uuid: &[u8; 16],
-    group_uuid: &[u8; 16],
-) -> Result<Bytes, Box<dyn std::error::Error>> {
-    match SystemTime::now().checked_add(Duration::from_secs(60 * 60 * 24)) {
-        Some(tmrw) => {
-            let exp_as_bytes = tmrw
-                .duration_since(SystemTime::UNIX_EPOCH)?
-                .as_secs()
-                .to_be_bytes();
+/// lifetimes are in seconds.
+#[derive(Clone, Copy, Debug)]
+pub struct Settings {
+    pub issuer: [u8; 16],
+    pub audience: [u8; 16],
+
+    pub refresh_lifetime: u64,
+    /// access tokens aren't checked against anything but their signature
+    /// and exp, so one already issued keeps working until then even when
+    /// the api key, certificate, or group membership it was issued for is
+    /// revoked. keep it short.
+    pub access_lifetime: u64,
+    pub delegated_lifetime: u64,
+
+    /// how long after logging in refresh tokens can still be rotated,
+    /// no matter how often they have been
+    pub session_lifetime: u64,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            issuer: [0u8; 16],
+            audience: [0u8; 16],
 
-            let hmac = blake2::Blake2sMac256::new_from_slice(&HMAC_KEY)?
-                .chain_update(&[action])
-                .chain_update(&exp_as_bytes)
-                .chain_update(&user_uuid)
-                .chain_update(&group_uuid)
-                .finalize_fixed();
+            refresh_lifetime: MAX_TOKEN_LIFETIME,
+            access_lifetime: 60 * 15,
+            delegated_lifetime: 60 * 60,
 
-            let mut buf = BytesMut::with_capacity(73);
+            session_lifetime: 60 * 60 * 24 * 30,
+        }
+    }
+}
 
-            buf.put_u8(action);
+impl Settings {
+    pub fn lifetime(&self, kind: Kind) -> u64 {
+        match kind {
+            Kind::Refresh => self.refresh_lifetime,
+            Kind::Access => self.access_lifetime,
+            Kind::Delegated => self.delegated_lifetime,
+        }
+    }
 
-            buf.put(&exp_as_bytes[..]);
-            buf.put(&hmac[..]);
-            buf.put(&user_uuid[..]);
-            buf.put(&group_uuid[..]);
+    /// retired keys are only kept for `MAX_TOKEN_LIFETIME`, so no token
+    /// can be allowed to outlive that. a session is made up of many
+    /// refresh tokens so it can, but not be shorter than one of them.
+    pub fn validate(&self) -> Result<(), TokenError> {
+        for kind in [Kind::Refresh, Kind::Access, Kind::Delegated] {
+            if self.lifetime(kind) > MAX_TOKEN_LIFETIME {
+                return Err(TokenError::Lifetime);
+            }
+        }
 
-            Ok(buf.into())
+        if self.session_lifetime < self.refresh_lifetime {
+            return Err(TokenError::Lifetime);
         }
-        None => Err("date is out of range".into()),
+
+        Ok(())
     }
 }
 
-/// (user, group)
-#[inline(always)]
-pub fn verify_with_group(
-    action: u8,
-    token: &[u8],
-) -> Result<([u8; 16], [u8; 16]), Box<dyn std::error::Error>> {
-    let exp_as_bytes: [u8; 8] = token[1..9].try_into()?;
-    let exp = u64::from_be_bytes(exp_as_bytes);
+/// key_id: [u8; 4], retired: [u8; 8], key: [u8; 32]
+const KEY_LEN: usize = 4 + 8 + 32;
+
+struct Key {
+    /// 0 while the key is still the one being signed with
+    retired: u64,
+    key: [u8; 32],
+}
 
-    let now = SystemTime::now()
-        .duration_since(SystemTime::UNIX_EPOCH)?
-        .as_secs();
+/// HMAC keys tokens are signed and verified with.
+///
+/// only the newest key signs, but every key that was retired less than
+/// `MAX_TOKEN_LIFETIME` ago is still accepted for verification so that
+/// rotating doesn't log everyone out.
+pub struct KeyRing {
+    /// key_id -> key
+    keys: BTreeMap<u32, Key>,
+
+    /// file the ring was loaded from, and when it was last modified
+    source: Option<(PathBuf, SystemTime)>,
+}
 
-    if exp < now {
-        return Err("token is expired".into());
+impl KeyRing {
+    /// a new ring with a single freshly generated key
+    pub fn generate() -> Self {
+        let mut ring = KeyRing {
+            keys: BTreeMap::new(),
+            source: None,
+        };
+
+        ring.keys.insert(
+            0,
+            Key {
+                retired: 0,
+                key: random_key(),
+            },
+        );
+
+        ring
     }
 
-    let user_uuid: [u8; 16] = token[41..57].try_into()?;
-    let group_uuid: [u8; 16] = token[57..73].try_into()?;
+    /// [key_id.retired.key]..
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Box<dyn std::error::Error>> {
+        if bytes.is_empty() || !bytes.len().is_multiple_of(KEY_LEN) {
+            return Err("invalid key ring".into());
+        }
 
-    let comp = blake2::Blake2sMac256::new_from_slice(&HMAC_KEY)?
-        .chain_update(&[action])
-        .chain_update(&exp_as_bytes)
-        .chain_update(&user_uuid)
-        .chain_update(&group_uuid)
-        .finalize_fixed();
+        let mut keys = BTreeMap::new();
 
-    let hmac = GenericArray::<u8, typenum::U32>::from_slice(&token[9..41]);
+        for chunk in bytes.chunks_exact(KEY_LEN) {
+            let key_id = u32::from_be_bytes(chunk[0..4].try_into()?);
 
-    if &comp == hmac {
-        Ok((user_uuid, group_uuid))
-    } else {
-        return Err("invalid token".into());
+            keys.insert(
+                key_id,
+                Key {
+                    retired: u64::from_be_bytes(chunk[4..12].try_into()?),
+                    key: chunk[12..44].try_into()?,
+                },
+            );
+        }
+
+        Ok(KeyRing { keys, source: None })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.keys.len() * KEY_LEN);
+
+        for (key_id, key) in self.keys.iter() {
+            buf.extend_from_slice(&key_id.to_be_bytes());
+            buf.extend_from_slice(&key.retired.to_be_bytes());
+            buf.extend_from_slice(&key.key);
+        }
+
+        buf
+    }
+
+    /// the ring as hex, for the `keys` of the `[tokens]` table of
+    /// ordinary.toml
+    pub fn to_hex(&self) -> String {
+        self.to_bytes()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+
+    pub fn from_hex(hex: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+            return Err("invalid key ring".into());
+        }
+
+        let bytes = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Self::from_bytes(&bytes)
+    }
+
+    /// the key file the ring was loaded from, `None` if it wasn't
+    pub fn path(&self) -> Option<&Path> {
+        self.source.as_ref().map(|(path, _)| path.as_path())
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = path.as_ref();
+
+        let modified = std::fs::metadata(path)?.modified()?;
+        let mut ring = Self::from_bytes(&std::fs::read(path)?)?;
+
+        ring.source = Some((path.to_path_buf(), modified));
+
+        Ok(ring)
+    }
+
+    /// loads the key file, generating and saving a new ring the
+    /// first time the deployment is started.
+    pub fn load_or_generate(path: impl AsRef<Path>) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = path.as_ref();
+
+        if !path.exists() {
+            Self::generate().save(path)?;
+        }
+
+        Self::load(path)
+    }
+
+    /// writes the ring to a temp file and renames it into place, so a
+    /// process reloading mid-rotation never sees half a file.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), Box<dyn std::error::Error>> {
+        let path = path.as_ref();
+        let tmp = path.with_extension("tmp");
+
+        {
+            let mut options = std::fs::OpenOptions::new();
+            options.write(true).create(true).truncate(true);
+
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+            let mut file = options.open(&tmp)?;
+
+            std::io::Write::write_all(&mut file, &self.to_bytes())?;
+            file.sync_all()?;
+        }
+
+        std::fs::rename(&tmp, path)?;
+
+        Ok(())
+    }
+
+    /// true if the key file has been changed since the ring was loaded
+    pub fn is_stale(&self) -> Result<bool, Box<dyn std::error::Error>> {
+        match &self.source {
+            Some((path, modified)) => Ok(std::fs::metadata(path)?.modified()? != *modified),
+            None => Ok(false),
+        }
+    }
+
+    /// re-reads the key file the ring was loaded from
+    pub fn reload(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some((path, _)) = &self.source {
+            *self = Self::load(path.clone())?;
+        }
+
+        Ok(())
+    }
+
+    /// retires the current signing key and adds a new one.
+    ///
+    /// the retired key is still accepted for verification until
+    /// `MAX_TOKEN_LIFETIME` has passed, after which `prune` drops it.
+    pub fn rotate(&mut self) -> Result<u32, Box<dyn std::error::Error>> {
+        let now = now()?;
+
+        for key in self.keys.values_mut() {
+            if key.retired == 0 {
+                key.retired = now;
+            }
+        }
+
+        let key_id = match self.keys.last_key_value() {
+            Some((key_id, _)) => key_id.checked_add(1).ok_or("key_id is out of range")?,
+            None => 0,
+        };
+
+        self.keys.insert(
+            key_id,
+            Key {
+                retired: 0,
+                key: random_key(),
+            },
+        );
+
+        Ok(key_id)
+    }
+
+    /// drops retired keys that can no longer have valid tokens signed with them
+    pub fn prune(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let now = now()?;
+
+        self.keys
+            .retain(|_, key| key.retired == 0 || key.retired + MAX_TOKEN_LIFETIME >= now);
+
+        Ok(())
+    }
+
+    /// (key_id, key)
+    pub(crate) fn signing_key(&self) -> Result<(u32, &[u8; 32]), TokenError> {
+        match self.keys.iter().rev().find(|(_, key)| key.retired == 0) {
+            Some((key_id, key)) => Ok((*key_id, &key.key)),
+            None => Err(TokenError::Key),
+        }
+    }
+
+    pub(crate) fn verification_key(&self, key_id: u32) -> Result<&[u8; 32], TokenError> {
+        let key = match self.keys.get(&key_id) {
+            Some(key) => key,
+            None => return Err(TokenError::Key),
+        };
+
+        if key.retired != 0 && key.retired + MAX_TOKEN_LIFETIME < now()? {
+            return Err(TokenError::Key);
+        }
+
+        Ok(&key.key)
     }
 }
 
-/// 73 bytes
+#[inline(always)]
+pub(crate) fn now() -> Result<u64, TokenError> {
+    Ok(now_ms()? / 1000)
+}
+
+#[inline(always)]
+pub(crate) fn now_ms() -> Result<u64, TokenError> {
+    match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(now) => u64::try_from(now.as_millis()).map_err(|_| TokenError::Clock),
+        Err(_) => Err(TokenError::Clock),
+    }
+}
+
+fn random_key() -> [u8; 32] {
+    let mut key = [0u8; 32];
+    OsRng.fill_bytes(&mut key);
+    key
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Token {
+    pub kind: Kind,
+    pub action: u8,
+    pub key_id: u32,
+    /// milliseconds since the epoch
+    pub issued: u64,
+    /// seconds since the epoch
+    pub exp: u64,
+    pub issuer: [u8; 16],
+    pub audience: [u8; 16],
+    pub user_uuid: [u8; 16],
+    /// random, so no two tokens are the same even when issued to the
+    /// same user for the same action at the same time
+    pub jti: [u8; 16],
+    pub group_uuid: Option<[u8; 16]>,
+    pub hmac: [u8; 32],
+}
+
+/// 119 bytes, or 135 with a group
 ///
+/// version: u8,
+/// kind: u8,
 /// action: u8,
+/// key_id: [u8; 4],
+/// issued: [u8; 8],
 /// exp: [u8; 8],
-/// hmac: [u8; 32],
-/// user_id: [u8; 16]
-#[inline(always)]
-pub fn gen_without_group(
+/// issuer: [u8; 16],
+/// audience: [u8; 16],
+/// user_id: [u8; 16],
+/// jti: [u8; 16],
+/// group_id: [u8; 16] (optional)
+/// hmac: [u8; 32] (of everything before it)
+pub fn gen(
+    keys: &KeyRing,
+    settings: &Settings,
+    kind: Kind,
     action: u8,
     user_uuid: &[u8; 16],
-) -> Result<Bytes, Box<dyn std::error::Error>> {
-    match SystemTime::now().checked_add(Duration::from_secs(60 * 60 * 24)) {
-        Some(tmrw) => {
-            let exp_as_bytes = tmrw
-                .duration_since(SystemTime::UNIX_EPOCH)?
-                .as_secs()
-                .to_be_bytes();
+    group_uuid: Option<&[u8; 16]>,
+) -> Result<Bytes, TokenError> {
+    gen_until(
+        keys,
+        settings,
+        kind,
+        action,
+        user_uuid,
+        group_uuid,
+        u64::MAX,
+    )
+}
 
-            let hmac = blake2::Blake2sMac256::new_from_slice(&HMAC_KEY)?
-                .chain_update(&[action])
-                .chain_update(&exp_as_bytes)
-                .chain_update(&user_uuid)
-                .finalize_fixed();
+/// like `gen`, but the token expires at `not_after` if that is sooner
+/// than its lifetime
+pub fn gen_until(
+    keys: &KeyRing,
+    settings: &Settings,
+    kind: Kind,
+    action: u8,
+    user_uuid: &[u8; 16],
+    group_uuid: Option<&[u8; 16]>,
+    not_after: u64,
+) -> Result<Bytes, TokenError> {
+    let lifetime = settings.lifetime(kind);
 
-            let mut buf = BytesMut::with_capacity(73);
+    if lifetime > MAX_TOKEN_LIFETIME {
+        return Err(TokenError::Lifetime);
+    }
 
-            buf.put_u8(action);
+    let issued = now_ms()?;
+    let exp = (issued / 1000)
+        .checked_add(lifetime)
+        .ok_or(TokenError::Clock)?
+        .min(not_after);
 
-            buf.put(&exp_as_bytes[..]);
-            buf.put(&hmac[..]);
-            buf.put(&user_uuid[..]);
+    if exp <= issued / 1000 {
+        return Err(TokenError::Expired);
+    }
 
-            Ok(buf.into())
-        }
-        None => Err("date is out of range".into()),
+    let mut jti = [0u8; 16];
+    OsRng.fill_bytes(&mut jti);
+
+    let (key_id, key) = keys.signing_key()?;
+
+    let mut buf = BytesMut::with_capacity(if group_uuid.is_some() {
+        LEN_WITH_GROUP
+    } else {
+        LEN_WITHOUT_GROUP
+    });
+
+    buf.put_u8(VERSION);
+    buf.put_u8(kind as u8);
+    buf.put_u8(action);
+    buf.put_u32(key_id);
+    buf.put_u64(issued);
+    buf.put_u64(exp);
+    buf.put(&settings.issuer[..]);
+    buf.put(&settings.audience[..]);
+    buf.put(&user_uuid[..]);
+    buf.put(&jti[..]);
+
+    if let Some(group_uuid) = group_uuid {
+        buf.put(&group_uuid[..]);
     }
+
+    let hmac = blake2::Blake2sMac256::new_from_slice(key)
+        .map_err(|_| TokenError::Key)?
+        .chain_update(&buf)
+        .finalize_fixed();
+
+    buf.put(&hmac[..]);
+
+    Ok(buf.into())
 }
 
-/// (user)
-#[inline(always)]
-pub fn verify_without_group(
+/// reads the token without checking it, see `verify` for that
+pub fn parse(token: &[u8]) -> Result<Token, TokenError> {
+    let with_group = match token.len() {
+        LEN_WITHOUT_GROUP => false,
+        LEN_WITH_GROUP => true,
+        _ => return Err(TokenError::Length),
+    };
+
+    if token[0] != VERSION {
+        return Err(TokenError::Version(token[0]));
+    }
+
+    let hmac_start = token.len() - 32;
+
+    // lengths were checked above so none of these conversions can fail
+    Ok(Token {
+        kind: Kind::try_from(token[1])?,
+        action: token[2],
+        key_id: u32::from_be_bytes(to_array(&token[3..7])),
+        issued: u64::from_be_bytes(to_array(&token[7..15])),
+        exp: u64::from_be_bytes(to_array(&token[15..23])),
+        issuer: to_array(&token[23..39]),
+        audience: to_array(&token[39..55]),
+        user_uuid: to_array(&token[55..71]),
+        jti: to_array(&token[71..87]),
+        group_uuid: if with_group {
+            Some(to_array(&token[87..103]))
+        } else {
+            None
+        },
+        hmac: to_array(&token[hmac_start..]),
+    })
+}
+
+/// checks the token is a `kind` for `action`, is for this issuer
+/// and audience, hasn't expired, and was signed with one of our keys.
+pub fn verify(
+    keys: &KeyRing,
+    settings: &Settings,
+    kind: Kind,
     action: u8,
     token: &[u8],
-) -> Result<[u8; 16], Box<dyn std::error::Error>> {
-    let exp_as_bytes: [u8; 8] = token[1..9].try_into()?;
-    let exp = u64::from_be_bytes(exp_as_bytes);
-
-    let now = SystemTime::now()
-        .duration_since(SystemTime::UNIX_EPOCH)?
-        .as_secs();
+) -> Result<Token, TokenError> {
+    let parsed = parse(token)?;
 
-    if exp < now {
-        return Err("token is expired".into());
+    if parsed.kind != kind {
+        return Err(TokenError::Kind);
     }
 
-    let user: [u8; 16] = token[41..57].try_into()?;
+    if parsed.action != action {
+        return Err(TokenError::Action);
+    }
 
-    let comp = blake2::Blake2sMac256::new_from_slice(&HMAC_KEY)?
-        .chain_update(&[action])
-        .chain_update(&exp_as_bytes)
-        .chain_update(&user)
-        .finalize_fixed();
+    if parsed.issuer != settings.issuer {
+        return Err(TokenError::Issuer);
+    }
 
-    let hmac = GenericArray::<u8, typenum::U32>::from_slice(&token[9..41]);
+    if parsed.audience != settings.audience {
+        return Err(TokenError::Audience);
+    }
 
-    if &comp == hmac {
-        Ok(user)
-    } else {
-        return Err("invalid token".into());
+    if parsed.exp < now()? {
+        return Err(TokenError::Expired);
     }
+
+    let key = keys.verification_key(parsed.key_id)?;
+
+    let hmac_start = token.len() - 32;
+
+    blake2::Blake2sMac256::new_from_slice(key)
+        .map_err(|_| TokenError::Key)?
+        .chain_update(&token[..hmac_start])
+        .verify_slice(&token[hmac_start..])
+        .map_err(|_| TokenError::Signature)?;
+
+    Ok(parsed)
+}
+
+#[inline(always)]
+pub(crate) fn to_array<const N: usize>(slice: &[u8]) -> [u8; N] {
+    let mut array = [0u8; N];
+    array.copy_from_slice(slice);
+    array
 }
 
 #[cfg(test)]
@@ -171,12 +609,22 @@ mod test {
         let user_uuid = *Uuid::now_v7().as_bytes();
         let group = *Uuid::now_v7().as_bytes();
 
-        let token = gen_with_group(action, &user_uuid, &group)?;
+        let keys = KeyRing::generate();
+        let settings = Settings::default();
 
-        let (v_user_uuid, v_group) = verify_with_group(action, &token)?;
+        let token = gen(
+            &keys,
+            &settings,
+            Kind::Access,
+            action,
+            &user_uuid,
+            Some(&group),
+        )?;
 
-        assert_eq!(&user_uuid[..], &v_user_uuid[..]);
-        assert_eq!(&group[..], &v_group[..]);
+        let verified = verify(&keys, &settings, Kind::Access, action, &token)?;
+
+        assert_eq!(&user_uuid[..], &verified.user_uuid[..]);
+        assert_eq!(Some(group), verified.group_uuid);
 
         Ok(())
     }
@@ -186,11 +634,115 @@ mod test {
         let action: u8 = 0;
         let user_uuid = *Uuid::now_v7().as_bytes();
 
-        let token = gen_without_group(action, &user_uuid)?;
+        let keys = KeyRing::generate();
+        let settings = Settings::default();
+
+        let token = gen(&keys, &settings, Kind::Refresh, action, &user_uuid, None)?;
+
+        let verified = verify(&keys, &settings, Kind::Refresh, action, &token)?;
+
+        assert_eq!(&user_uuid[..], &verified.user_uuid[..]);
+        assert_eq!(None, verified.group_uuid);
+
+        // the same user, action and second still gets a different token
+        let again = gen(&keys, &settings, Kind::Refresh, action, &user_uuid, None)?;
+
+        assert_ne!(token, again);
+        assert_ne!(verified.jti, parse(&again)?.jti);
+
+        Ok(())
+    }
+
+    #[test]
+    fn rejects() -> Result<(), Box<dyn std::error::Error>> {
+        let user_uuid = *Uuid::now_v7().as_bytes();
+
+        let keys = KeyRing::generate();
+        let settings = Settings::default();
+
+        let token = gen(&keys, &settings, Kind::Refresh, 0, &user_uuid, None)?;
+
+        // a refresh token isn't an access token, even for the same action
+        assert_eq!(
+            verify(&keys, &settings, Kind::Access, 0, &token),
+            Err(TokenError::Kind)
+        );
+        assert_eq!(
+            verify(&keys, &settings, Kind::Refresh, 1, &token),
+            Err(TokenError::Action)
+        );
+
+        let other = Settings {
+            audience: *Uuid::now_v7().as_bytes(),
+            ..settings
+        };
+
+        assert_eq!(
+            verify(&keys, &other, Kind::Refresh, 0, &token),
+            Err(TokenError::Audience)
+        );
+
+        // short input is an error, not a panic
+        assert_eq!(parse(&token[..20]), Err(TokenError::Length));
+        assert_eq!(parse(&[]), Err(TokenError::Length));
+
+        // a bit of the user
+        let mut tampered = token.to_vec();
+        tampered[60] ^= 1;
+
+        assert_eq!(
+            verify(&keys, &settings, Kind::Refresh, 0, &tampered),
+            Err(TokenError::Signature)
+        );
+
+        tampered[0] = VERSION + 1;
+
+        assert_eq!(
+            verify(&keys, &settings, Kind::Refresh, 0, &tampered),
+            Err(TokenError::Version(VERSION + 1))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn rotation() -> Result<(), Box<dyn std::error::Error>> {
+        let action: u8 = 0;
+        let user_uuid = *Uuid::now_v7().as_bytes();
+
+        let mut keys = KeyRing::generate();
+        let settings = Settings::default();
+
+        let old_token = gen(&keys, &settings, Kind::Refresh, action, &user_uuid, None)?;
+
+        keys.rotate()?;
+        keys.prune()?;
+
+        let new_token = gen(&keys, &settings, Kind::Refresh, action, &user_uuid, None)?;
+
+        // retired keys keep verifying until the max token lifetime passes
+        assert_eq!(
+            verify(&keys, &settings, Kind::Refresh, action, &old_token)?.user_uuid,
+            user_uuid
+        );
+        assert_eq!(
+            verify(&keys, &settings, Kind::Refresh, action, &new_token)?.user_uuid,
+            user_uuid
+        );
+
+        // a ring from another deployment can't verify either
+        let other = KeyRing::generate();
+
+        assert!(verify(&other, &settings, Kind::Refresh, action, &old_token).is_err());
+        assert!(verify(&other, &settings, Kind::Refresh, action, &new_token).is_err());
 
-        let v_user_uuid = verify_without_group(action, &token)?;
+        // survives a round trip through the key file format
+        let keys = KeyRing::from_bytes(&keys.to_bytes())?;
 
-        assert_eq!(&user_uuid[..], &v_user_uuid[..]);
+        assert_eq!(
+            verify(&keys, &settings, Kind::Refresh, action, &old_token)?.user_uuid,
+            user_uuid
+        );
 
         Ok(())
     }
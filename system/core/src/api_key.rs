@@ -0,0 +1,118 @@
+// long-lived keys for services acting as a user without logging in
+//
+// a key is key_id.secret, only a hash of the secret is kept, and it can
+// only be exchanged for access tokens for the actions and groups it was
+// created with.
+//
+// key_id -> user_uuid.hash.expires.last_used.actions_len.actions.groups
+// user_uuid.key_id -> []
+
+use blake2::{Blake2s256, Digest};
+use saferlmdb::{put, ConstAccessor, LmdbResultExt, WriteAccessor};
+
+use crate::Core;
+
+/// key_id(16).secret(32)
+pub const API_KEY_LEN: usize = 48;
+
+/// the longest an api key can be valid for, in seconds
+pub const MAX_API_KEY_LIFETIME: u64 = 60 * 60 * 24 * 365;
+
+pub(crate) struct Record {
+    pub user_uuid: [u8; 16],
+    pub hash: [u8; 32],
+    pub expires: u64,
+    /// 0 until the key is first exchanged
+    pub last_used: u64,
+    pub actions: Vec<u8>,
+    pub groups: Vec<[u8; 16]>,
+}
+
+pub(crate) fn hash(secret: &[u8]) -> [u8; 32] {
+    Blake2s256::digest(secret).into()
+}
+
+pub(crate) fn load(
+    core: &Core,
+    access: &ConstAccessor,
+    key_id: &[u8; 16],
+) -> Result<Option<Record>, Box<dyn std::error::Error>> {
+    let value = match access
+        .get::<[u8; 16], [u8]>(&core.api_key_db, key_id)
+        .to_opt()?
+    {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+
+    if value.len() < 65 {
+        return Err("invalid format".into());
+    }
+
+    let actions_len = value[64] as usize;
+    let groups_start = 65 + actions_len;
+
+    if value.len() < groups_start || !(value.len() - groups_start).is_multiple_of(16) {
+        return Err("invalid format".into());
+    }
+
+    Ok(Some(Record {
+        user_uuid: value[0..16].try_into()?,
+        hash: value[16..48].try_into()?,
+        expires: u64::from_be_bytes(value[48..56].try_into()?),
+        last_used: u64::from_be_bytes(value[56..64].try_into()?),
+        actions: value[65..groups_start].to_vec(),
+        groups: value[groups_start..]
+            .chunks(16)
+            .map(|group| group.try_into())
+            .collect::<Result<_, _>>()?,
+    }))
+}
+
+pub(crate) fn store(
+    core: &Core,
+    access: &mut WriteAccessor,
+    key_id: &[u8; 16],
+    record: &Record,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut value = Vec::with_capacity(65 + record.actions.len() + record.groups.len() * 16);
+
+    value.extend_from_slice(&record.user_uuid);
+    value.extend_from_slice(&record.hash);
+    value.extend_from_slice(&record.expires.to_be_bytes());
+    value.extend_from_slice(&record.last_used.to_be_bytes());
+    value.push(record.actions.len() as u8);
+    value.extend_from_slice(&record.actions);
+
+    for group in &record.groups {
+        value.extend_from_slice(group);
+    }
+
+    access.put(&core.api_key_db, key_id, &value, put::Flags::empty())?;
+
+    let mut user_key = [0u8; 32];
+
+    user_key[0..16].copy_from_slice(&record.user_uuid);
+    user_key[16..32].copy_from_slice(key_id);
+
+    access.put::<[u8; 32], [u8]>(&core.api_key_db, &user_key, &[], put::Flags::empty())?;
+
+    Ok(())
+}
+
+pub(crate) fn delete(
+    core: &Core,
+    access: &mut WriteAccessor,
+    key_id: &[u8; 16],
+    user_uuid: &[u8; 16],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut user_key = [0u8; 32];
+
+    user_key[0..16].copy_from_slice(user_uuid);
+    user_key[16..32].copy_from_slice(key_id);
+
+    access.del_key(&core.api_key_db, key_id)?;
+    access.del_key(&core.api_key_db, &user_key)?;
+
+    Ok(())
+}
@@ -0,0 +1,135 @@
+// client certificates bound to users, so machine clients connecting
+// over mTLS can get access tokens without a password login.
+//
+// whatever terminates TLS has already checked the certificate chains
+// to a trusted CA, this only maps it to the user it was bound to.
+//
+// match.hash -> user_uuid
+
+use blake2::{Blake2s256, Digest};
+use saferlmdb::{put, ConstAccessor, LmdbResultExt, WriteAccessor};
+
+use crate::Core;
+
+/// what a client certificate is identified by, as read from it by
+/// whatever terminated the TLS connection.
+#[derive(Clone, Debug)]
+pub struct Certificate {
+    /// DER encoded subject name
+    pub subject: Vec<u8>,
+    /// SHA-256 of the DER encoded SubjectPublicKeyInfo
+    pub spki_hash: [u8; 32],
+}
+
+/// which part of a certificate a user is bound to
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CertificateMatch {
+    /// any certificate with the subject, so it survives the key being rotated
+    Subject = 0,
+    /// only certificates for one key pair
+    Spki = 1,
+}
+
+impl TryFrom<u8> for CertificateMatch {
+    type Error = Box<dyn std::error::Error>;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(CertificateMatch::Subject),
+            1 => Ok(CertificateMatch::Spki),
+            _ => Err("invalid certificate match".into()),
+        }
+    }
+}
+
+/// match.hash, where `value` is the subject or the spki hash
+pub(crate) fn key(
+    certificate_match: CertificateMatch,
+    value: &[u8],
+) -> Result<[u8; 33], Box<dyn std::error::Error>> {
+    let mut key = [0u8; 33];
+
+    key[0] = certificate_match as u8;
+
+    match certificate_match {
+        CertificateMatch::Subject if !value.is_empty() => {
+            key[1..33].copy_from_slice(&Blake2s256::digest(value))
+        }
+        CertificateMatch::Spki if value.len() == 32 => key[1..33].copy_from_slice(value),
+        _ => return Err("invalid certificate match".into()),
+    }
+
+    Ok(key)
+}
+
+/// match.hash of the part of the certificate `certificate_match` is for
+pub(crate) fn key_of(
+    certificate: &Certificate,
+    certificate_match: CertificateMatch,
+) -> Result<[u8; 33], Box<dyn std::error::Error>> {
+    match certificate_match {
+        CertificateMatch::Subject => key(certificate_match, &certificate.subject),
+        CertificateMatch::Spki => key(certificate_match, &certificate.spki_hash),
+    }
+}
+
+/// the user the certificate is bound to, by its key before its subject
+pub(crate) fn user(
+    core: &Core,
+    access: &ConstAccessor,
+    certificate: &Certificate,
+) -> Result<Option<[u8; 16]>, Box<dyn std::error::Error>> {
+    for key in [
+        key_of(certificate, CertificateMatch::Spki)?,
+        key_of(certificate, CertificateMatch::Subject)?,
+    ] {
+        if let Some(user_uuid) = access
+            .get::<[u8; 33], [u8]>(&core.certificate_db, &key)
+            .to_opt()?
+        {
+            return Ok(Some(user_uuid.try_into()?));
+        }
+    }
+
+    Ok(None)
+}
+
+/// fails if the certificate is already bound to a user
+pub(crate) fn bind(
+    core: &Core,
+    access: &mut WriteAccessor,
+    user_uuid: &[u8; 16],
+    key: &[u8; 33],
+) -> Result<(), Box<dyn std::error::Error>> {
+    if access
+        .get::<[u8; 33], [u8]>(&core.certificate_db, key)
+        .to_opt()?
+        .is_some()
+    {
+        return Err("certificate is already bound".into());
+    }
+
+    access.put(&core.certificate_db, key, user_uuid, put::Flags::empty())?;
+
+    Ok(())
+}
+
+/// fails if the certificate isn't bound to the user
+pub(crate) fn unbind(
+    core: &Core,
+    access: &mut WriteAccessor,
+    user_uuid: &[u8; 16],
+    key: &[u8; 33],
+) -> Result<(), Box<dyn std::error::Error>> {
+    match access
+        .get::<[u8; 33], [u8]>(&core.certificate_db, key)
+        .to_opt()?
+    {
+        Some(bound) if bound == &user_uuid[..] => {}
+        _ => return Err("certificate is not bound".into()),
+    }
+
+    access.del_key(&core.certificate_db, key)?;
+
+    Ok(())
+}
@@ -0,0 +1,147 @@
+// storage ops accept either an access token, or a delegated token
+// narrowed by its caveats.
+//
+// a delegated token for storage_put can also be used to query, and one
+// made read-only can only be used to query. the entity caveat is
+// checked against the entity being written under or queried, and the
+// kind caveat against the kind of its children.
+//
+// single use tokens are marked as used in revoked_db the same way a
+// logged out refresh token is, so logout cleans them up once expired.
+//
+// user_uuid.single_use -> exp
+
+use cbwaw::delegated::{self, Delegated};
+use cbwaw::token::{self, Kind, TokenError, LEN_WITH_GROUP};
+use saferlmdb::{self as lmdb, put, WriteTransaction};
+
+use crate::Core;
+
+const STORAGE_PUT: u8 = 12;
+const STORAGE_QUERY: u8 = 13;
+
+/// what the token at the start of a request lets the holder do
+pub(crate) struct Grant {
+    pub user_uuid: [u8; 16],
+    pub group_uuid: [u8; 16],
+    /// `None` for an access token
+    pub delegated: Option<Delegated>,
+}
+
+impl Grant {
+    pub fn allows(&self, entity_uuid: &[u8; 16], kind: u8) -> bool {
+        match &self.delegated {
+            Some(delegated) => delegated.allows(entity_uuid, kind),
+            None => true,
+        }
+    }
+}
+
+/// verifies the token at the start of `bytes` for `action`, and
+/// returns where the rest of the request starts.
+///
+/// a single use token is used up here, even if the op then fails.
+pub(crate) fn authorize(
+    core: &Core,
+    action: u8,
+    bytes: &[u8],
+) -> Result<(Grant, usize), Box<dyn std::error::Error>> {
+    if bytes.len() < 2 {
+        return Err("req does not include token".into());
+    }
+
+    if Kind::try_from(bytes[1])? != Kind::Delegated {
+        if bytes.len() < LEN_WITH_GROUP {
+            return Err("req does not include token".into());
+        }
+
+        let token_keys = core.token_keys()?;
+
+        let token = token::verify(
+            &token_keys,
+            &core.token_settings,
+            Kind::Access,
+            action,
+            &bytes[..LEN_WITH_GROUP],
+        )?;
+
+        return Ok((
+            Grant {
+                user_uuid: token.user_uuid,
+                group_uuid: token.group_uuid.ok_or(TokenError::Group)?,
+                delegated: None,
+            },
+            LEN_WITH_GROUP,
+        ));
+    }
+
+    let len = delegated::len(bytes)?;
+
+    let token_keys = core.token_keys()?;
+
+    let verified = delegated::verify(&token_keys, &core.token_settings, &bytes[..len])?;
+
+    // storage_put tokens can also query
+    let allowed = verified.token.action == action
+        || (action == STORAGE_QUERY && verified.token.action == STORAGE_PUT);
+
+    if !allowed {
+        return Err(TokenError::Action.into());
+    }
+
+    if action != STORAGE_QUERY && verified.read_only() {
+        return Err("token is read-only".into());
+    }
+
+    if let Some(single_use) = &verified.single_use {
+        use_up(
+            core,
+            &verified.token.user_uuid,
+            single_use,
+            verified.token.exp,
+        )?;
+    }
+
+    Ok((
+        Grant {
+            user_uuid: verified.token.user_uuid,
+            group_uuid: verified.token.group_uuid.ok_or(TokenError::Group)?,
+            delegated: Some(verified),
+        },
+        len,
+    ))
+}
+
+fn use_up(
+    core: &Core,
+    user_uuid: &[u8; 16],
+    single_use: &[u8; 32],
+    exp: u64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut key = Vec::with_capacity(16 + 32);
+
+    key.extend_from_slice(&user_uuid[..]);
+    key.extend_from_slice(&single_use[..]);
+
+    let txn = WriteTransaction::new(core.env.clone())?;
+
+    {
+        let mut access = txn.access();
+
+        match access.put(
+            &core.revoked_db,
+            &key[..],
+            &exp.to_be_bytes(),
+            put::Flags::NOOVERWRITE,
+        ) {
+            Err(lmdb::Error::Code(lmdb::error::KEYEXIST)) => {
+                return Err("token has already been used".into())
+            }
+            result => result?,
+        }
+    }
+
+    txn.commit()?;
+
+    Ok(())
+}
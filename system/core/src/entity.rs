@@ -0,0 +1,424 @@
+// where entities sit in the tree, and what groups can do with them.
+//
+// every entity is indexed by its uuid so its ancestors can be walked
+// without trusting the grandparent a client sends along. anything that
+// isn't indexed (a user, a group) is the root of its tree.
+//
+// entity_uuid -> parent_uuid.kind
+
+use saferlmdb::{
+    put, ConstAccessor, ConstTransaction, LmdbResultExt, WriteAccessor, WriteTransaction,
+};
+
+use crate::group;
+use crate::permissions::Permissions;
+use crate::Core;
+
+/// how far up the tree permissions are inherited from
+pub const MAX_ENTITY_DEPTH: usize = 64;
+
+/// (parent_uuid, kind)
+pub(crate) type ParentKind = ([u8; 16], u8);
+
+/// (permissions, entity_uuid they were allowed on, if anywhere)
+pub(crate) type Resolved = (Permissions, Option<[u8; 16]>);
+
+/// (kind, child_uuid)
+pub(crate) type Child = (u8, [u8; 16]);
+
+/// (parent_uuid, kind, value)
+pub(crate) type Located<'a> = ([u8; 16], u8, &'a [u8]);
+
+/// (parent_uuid, kind), `None` for the root of a tree
+pub(crate) fn parent(
+    core: &Core,
+    access: &ConstAccessor,
+    entity_uuid: &[u8; 16],
+) -> Result<Option<ParentKind>, Box<dyn std::error::Error>> {
+    match access
+        .get::<[u8; 16], [u8]>(&core.entity_db, entity_uuid)
+        .to_opt()?
+    {
+        Some(value) if value.len() == 17 => Ok(Some((value[0..16].try_into()?, value[16]))),
+        Some(_) => Err("invalid format".into()),
+        None => Ok(None),
+    }
+}
+
+/// (parent_uuid, kind, value) of the entity wherever it is, `None` for
+/// the root of a tree
+pub(crate) fn get<'a>(
+    core: &Core,
+    access: &'a ConstAccessor,
+    entity_uuid: &[u8; 16],
+) -> Result<Option<Located<'a>>, Box<dyn std::error::Error>> {
+    let Some((parent_uuid, kind)) = parent(core, access, entity_uuid)? else {
+        return Ok(None);
+    };
+
+    let mut key = [0u8; 33];
+
+    key[0..16].copy_from_slice(&parent_uuid[..]);
+    key[16] = kind;
+    key[17..33].copy_from_slice(&entity_uuid[..]);
+
+    match access
+        .get::<[u8; 33], [u8]>(&core.entity_db, &key)
+        .to_opt()?
+    {
+        Some(value) => Ok(Some((parent_uuid, kind, value))),
+        None => Ok(None),
+    }
+}
+
+pub(crate) fn index(
+    core: &Core,
+    access: &mut WriteAccessor,
+    entity_uuid: &[u8; 16],
+    parent_uuid: &[u8; 16],
+    kind: u8,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut value = [0u8; 17];
+
+    value[0..16].copy_from_slice(parent_uuid);
+    value[16] = kind;
+
+    access.put(&core.entity_db, entity_uuid, &value, put::Flags::empty())?;
+
+    Ok(())
+}
+
+/// what the group can do with the entity, and where that came from.
+///
+/// unless the core was built `with_inherited_permissions` that is only
+/// what the entity itself was allowed. otherwise it is walked up from,
+/// until something on the way was allowed anything in the group or
+/// doesn't inherit from its parent, taking out whatever was denied
+/// on the way.
+pub(crate) fn resolve(
+    core: &Core,
+    access: &ConstAccessor,
+    entity_uuid: &[u8; 16],
+    group_uuid: &[u8; 16],
+) -> Result<Resolved, Box<dyn std::error::Error>> {
+    if !core.inherit_permissions {
+        let allowed = group::allowed(core, access, entity_uuid, group_uuid)?;
+
+        if allowed.is_empty() {
+            return Ok((Permissions::NONE, None));
+        }
+
+        let denied = group::denied(core, access, entity_uuid, group_uuid)?;
+
+        return Ok((allowed.without(denied), Some(*entity_uuid)));
+    }
+
+    let mut denied = Permissions::NONE;
+    let mut uuid = *entity_uuid;
+
+    for _ in 0..MAX_ENTITY_DEPTH {
+        denied = denied | group::denied(core, access, &uuid, group_uuid)?;
+
+        let allowed = group::allowed(core, access, &uuid, group_uuid)?;
+
+        if !allowed.is_empty() {
+            return Ok((allowed.without(denied), Some(uuid)));
+        }
+
+        if !group::inherits(core, access, &uuid, group_uuid)? {
+            break;
+        }
+
+        match parent(core, access, &uuid)? {
+            Some((parent_uuid, _)) => uuid = parent_uuid,
+            None => break,
+        }
+    }
+
+    Ok((Permissions::NONE, None))
+}
+
+/// fails unless the group has every one of `required` on the entity
+pub(crate) fn require(
+    core: &Core,
+    access: &ConstAccessor,
+    entity_uuid: &[u8; 16],
+    group_uuid: &[u8; 16],
+    required: Permissions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (permissions, _) = resolve(core, access, entity_uuid, group_uuid)?;
+
+    if permissions.is_empty() || !permissions.contains(required) {
+        return Err("not permitted for this group".into());
+    }
+
+    Ok(())
+}
+
+/// fails unless the group can change the entity with `required`.
+///
+/// with inherited permissions that is what it can do with the entity.
+/// otherwise new entities only get a copy of read access, so it is what
+/// it can do with their parent.
+pub(crate) fn require_change(
+    core: &Core,
+    access: &ConstAccessor,
+    entity_uuid: &[u8; 16],
+    parent_uuid: &[u8; 16],
+    group_uuid: &[u8; 16],
+    required: Permissions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if core.inherit_permissions {
+        require(core, access, entity_uuid, group_uuid, required)
+    } else {
+        require(core, access, parent_uuid, group_uuid, required)
+    }
+}
+
+/// what the user can share the entity with, `None` if they can't share
+/// it at all.
+///
+/// that is whatever a group they are an admin of can do with it, or
+/// what they can do with it through a group, if that includes SHARE.
+pub(crate) fn shareable(
+    core: &Core,
+    txn: &ConstTransaction,
+    access: &ConstAccessor,
+    user_uuid: &[u8; 16],
+    entity_uuid: &[u8; 16],
+) -> Result<Option<Permissions>, Box<dyn std::error::Error>> {
+    let sources = if core.inherit_permissions {
+        ancestors(core, access, entity_uuid)?
+    } else {
+        vec![*entity_uuid]
+    };
+
+    let mut groups = vec![];
+
+    for uuid in &sources {
+        for group_uuid in group::groups(core, txn, access, uuid)? {
+            if !groups.contains(&group_uuid) {
+                groups.push(group_uuid);
+            }
+        }
+    }
+
+    let mut shareable = None;
+
+    for group_uuid in &groups {
+        let (entity_permissions, _) = resolve(core, access, entity_uuid, group_uuid)?;
+
+        if entity_permissions.is_empty() {
+            continue;
+        }
+
+        let (user_permissions, _) = group::resolve(core, txn, access, user_uuid, group_uuid)?;
+
+        let held = if user_permissions.contains(Permissions::ADMIN) {
+            entity_permissions
+        } else {
+            entity_permissions & user_permissions
+        };
+
+        if user_permissions.contains(Permissions::ADMIN) || held.contains(Permissions::SHARE) {
+            shareable = Some(shareable.unwrap_or(Permissions::NONE) | held);
+        }
+    }
+
+    Ok(shareable)
+}
+
+/// (kind, child_uuid) of every child of the entity
+pub(crate) fn children(
+    core: &Core,
+    txn: &ConstTransaction,
+    access: &ConstAccessor,
+    entity_uuid: &[u8; 16],
+) -> Result<Vec<Child>, Box<dyn std::error::Error>> {
+    let mut children = vec![];
+    let mut cursor = txn.cursor(core.entity_db.clone())?;
+
+    let mut next = cursor
+        .seek_range_k::<[u8], [u8]>(access, &entity_uuid[..])
+        .to_opt()?;
+
+    while let Some((key, _)) = next {
+        if !key.starts_with(&entity_uuid[..]) {
+            break;
+        }
+
+        // skips the index row of the entity itself
+        if key.len() == 33 {
+            children.push((key[16], key[17..33].try_into()?));
+        }
+
+        next = cursor.next::<[u8], [u8]>(access).to_opt()?;
+    }
+
+    Ok(children)
+}
+
+/// (kind, child_uuid) of a child of the entity, without listing the rest
+pub(crate) fn first_child(
+    core: &Core,
+    txn: &ConstTransaction,
+    access: &ConstAccessor,
+    entity_uuid: &[u8; 16],
+) -> Result<Option<Child>, Box<dyn std::error::Error>> {
+    let mut cursor = txn.cursor(core.entity_db.clone())?;
+
+    let mut next = cursor
+        .seek_range_k::<[u8], [u8]>(access, &entity_uuid[..])
+        .to_opt()?;
+
+    while let Some((key, _)) = next {
+        if !key.starts_with(&entity_uuid[..]) {
+            break;
+        }
+
+        // skips the index row of the entity itself
+        if key.len() == 33 {
+            return Ok(Some((key[16], key[17..33].try_into()?)));
+        }
+
+        next = cursor.next::<[u8], [u8]>(access).to_opt()?;
+    }
+
+    Ok(None)
+}
+
+/// removes the entity, along with its index, permission, expiry and
+/// reference rows, and its rows in the property indexes. fails if it
+/// has children, unless they are to be removed too.
+///
+/// a cascade for a group fails unless the group can delete every
+/// descendant, not just the entity. `None` for removals nobody asked
+/// for, like expired entities.
+///
+/// returns how many entities were removed.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn remove(
+    core: &Core,
+    txn: &ConstTransaction,
+    access: &mut WriteAccessor,
+    parent_uuid: &[u8; 16],
+    kind: u8,
+    entity_uuid: &[u8; 16],
+    cascade: bool,
+    group_uuid: Option<&[u8; 16]>,
+) -> Result<u32, Box<dyn std::error::Error>> {
+    if !cascade && !children(core, txn, access, entity_uuid)?.is_empty() {
+        return Err("entity has children".into());
+    }
+
+    let mut removed = 0;
+    let mut next = vec![(*parent_uuid, kind, *entity_uuid)];
+
+    while let Some((parent_uuid, kind, entity_uuid)) = next.pop() {
+        for (child_kind, child_uuid) in children(core, txn, access, &entity_uuid)? {
+            if let Some(group_uuid) = group_uuid {
+                require_change(
+                    core,
+                    access,
+                    &child_uuid,
+                    &entity_uuid,
+                    group_uuid,
+                    Permissions::DELETE,
+                )?;
+            }
+
+            next.push((entity_uuid, child_kind, child_uuid));
+        }
+
+        let mut key = [0u8; 33];
+
+        key[0..16].copy_from_slice(&parent_uuid);
+        key[16] = kind;
+        key[17..33].copy_from_slice(&entity_uuid);
+
+        let entity = access
+            .get::<[u8; 33], [u8]>(&core.entity_db, &key)?
+            .to_vec();
+
+        crate::index::remove(
+            core,
+            access,
+            &parent_uuid,
+            kind,
+            &entity_uuid,
+            &entity[33..],
+        )?;
+
+        access.del_key(&core.entity_db, &key)?;
+        access.del_key(&core.entity_db, &entity_uuid).to_opt()?;
+
+        group::forget(core, txn, access, &entity_uuid)?;
+        crate::expiry::forget(core, access, &entity_uuid)?;
+        crate::reference::forget(core, txn, access, &entity_uuid)?;
+
+        removed += 1;
+    }
+
+    Ok(removed)
+}
+
+/// the entity, and every entity above it
+pub(crate) fn ancestors(
+    core: &Core,
+    access: &ConstAccessor,
+    entity_uuid: &[u8; 16],
+) -> Result<Vec<[u8; 16]>, Box<dyn std::error::Error>> {
+    let mut ancestors = vec![*entity_uuid];
+
+    while let Some((parent_uuid, _)) = parent(core, access, &ancestors[ancestors.len() - 1])? {
+        if ancestors.len() > MAX_ENTITY_DEPTH || ancestors.contains(&parent_uuid) {
+            break;
+        }
+
+        ancestors.push(parent_uuid);
+    }
+
+    Ok(ancestors)
+}
+
+/// indexes entities put before they were indexed by uuid. does nothing
+/// once every entity has been.
+pub(crate) fn migrate(core: &Core) -> Result<(), Box<dyn std::error::Error>> {
+    let txn = WriteTransaction::new(core.env.clone())?;
+
+    {
+        let mut access = txn.access();
+
+        let mut unindexed = vec![];
+
+        {
+            let mut cursor = txn.cursor(core.entity_db.clone())?;
+            let mut next = cursor.first::<[u8], [u8]>(&access).to_opt()?;
+
+            while let Some((key, _)) = next {
+                if key.len() == 33 {
+                    let parent_uuid: [u8; 16] = key[0..16].try_into()?;
+                    let entity_uuid: [u8; 16] = key[17..33].try_into()?;
+
+                    if parent(core, &access, &entity_uuid)?.is_none() {
+                        unindexed.push((entity_uuid, parent_uuid, key[16]));
+                    }
+                }
+
+                next = cursor.next::<[u8], [u8]>(&access).to_opt()?;
+            }
+        }
+
+        if unindexed.is_empty() {
+            return Ok(());
+        }
+
+        for (entity_uuid, parent_uuid, kind) in &unindexed {
+            index(core, &mut access, entity_uuid, parent_uuid, *kind)?;
+        }
+    }
+
+    txn.commit()?;
+
+    Ok(())
+}
@@ -0,0 +1,249 @@
+// when entities expire.
+//
+// entities put without a ttl never do. once expired they are left out
+// of reads right away, and removed along with their children by `reap`.
+//
+// time is read from the core's clock, the system clock unless the core
+// was built `with_clock`.
+//
+// entity_uuid -> exp
+// EXPIRES.exp.entity_uuid -> []
+
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use saferlmdb::{
+    put, ConstAccessor, ConstTransaction, LmdbResultExt, WriteAccessor, WriteTransaction,
+};
+
+use crate::Core;
+
+const EXPIRES: u8 = 0;
+
+/// most entities removed in a single write transaction, counting
+/// everything under the expired ones
+pub const MAX_REAPED: usize = 1000;
+
+/// how often `Core::spawn_reaper` reaps by default
+pub const REAP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// seconds since the unix epoch
+pub(crate) type Clock = Arc<dyn Fn() -> Result<u64, Box<dyn std::error::Error>> + Send + Sync>;
+
+pub(crate) fn system_clock() -> Result<u64, Box<dyn std::error::Error>> {
+    Ok(SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_secs())
+}
+
+pub(crate) fn now(core: &Core) -> Result<u64, Box<dyn std::error::Error>> {
+    (core.clock)()
+}
+
+fn time_key(exp: u64, entity_uuid: &[u8; 16]) -> [u8; 25] {
+    let mut key = [0u8; 25];
+
+    key[0] = EXPIRES;
+    key[1..9].copy_from_slice(&exp.to_be_bytes());
+    key[9..25].copy_from_slice(entity_uuid);
+
+    key
+}
+
+/// when the entity expires, `None` if it never does
+pub(crate) fn get(
+    core: &Core,
+    access: &ConstAccessor,
+    entity_uuid: &[u8; 16],
+) -> Result<Option<u64>, Box<dyn std::error::Error>> {
+    match access
+        .get::<[u8; 16], [u8]>(&core.expiry_db, entity_uuid)
+        .to_opt()?
+    {
+        Some(exp) => Ok(Some(u64::from_be_bytes(exp.try_into()?))),
+        None => Ok(None),
+    }
+}
+
+pub(crate) fn is_expired(
+    core: &Core,
+    access: &ConstAccessor,
+    entity_uuid: &[u8; 16],
+    now: u64,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    Ok(get(core, access, entity_uuid)?.is_some_and(|exp| exp <= now))
+}
+
+/// expires the entity `ttl` seconds from now, or never if it is 0.
+///
+/// returns when it expires, 0 for never.
+pub(crate) fn set(
+    core: &Core,
+    access: &mut WriteAccessor,
+    entity_uuid: &[u8; 16],
+    ttl: u64,
+) -> Result<u64, Box<dyn std::error::Error>> {
+    forget(core, access, entity_uuid)?;
+
+    if ttl == 0 {
+        return Ok(0);
+    }
+
+    let exp = now(core)?.checked_add(ttl).ok_or("ttl is out of range")?;
+
+    access.put(
+        &core.expiry_db,
+        entity_uuid,
+        &exp.to_be_bytes(),
+        put::Flags::empty(),
+    )?;
+    access.put::<[u8; 25], [u8]>(
+        &core.expiry_db,
+        &time_key(exp, entity_uuid),
+        &[],
+        put::Flags::empty(),
+    )?;
+
+    Ok(exp)
+}
+
+/// the entity no longer expires
+pub(crate) fn forget(
+    core: &Core,
+    access: &mut WriteAccessor,
+    entity_uuid: &[u8; 16],
+) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(exp) = get(core, access, entity_uuid)? {
+        access.del_key(&core.expiry_db, entity_uuid)?;
+        access
+            .del_key(&core.expiry_db, &time_key(exp, entity_uuid))
+            .to_opt()?;
+    }
+
+    Ok(())
+}
+
+/// removes expired entities, and everything under them, `MAX_REAPED`
+/// at a time so writers aren't held up for long. a large subtree is
+/// removed over several write transactions, from the bottom up.
+///
+/// returns how many entities were removed.
+pub(crate) fn reap(core: &Core) -> Result<u32, Box<dyn std::error::Error>> {
+    let now = now(core)?;
+    let mut removed = 0;
+
+    loop {
+        let txn = WriteTransaction::new(core.env.clone())?;
+
+        let budget = {
+            let mut access = txn.access();
+            let mut expired = vec![];
+
+            {
+                let mut cursor = txn.cursor(core.expiry_db.clone())?;
+
+                let mut next = cursor
+                    .seek_range_k::<[u8], [u8]>(&access, &[EXPIRES][..])
+                    .to_opt()?;
+
+                while let Some((key, _)) = next {
+                    if key[0] != EXPIRES || expired.len() == MAX_REAPED {
+                        break;
+                    }
+
+                    // skips entity_uuid keys that happen to start with EXPIRES
+                    if key.len() == 25 {
+                        if u64::from_be_bytes(key[1..9].try_into()?) > now {
+                            break;
+                        }
+
+                        expired.push(<[u8; 16]>::try_from(&key[9..25])?);
+                    }
+
+                    next = cursor.next::<[u8], [u8]>(&access).to_opt()?;
+                }
+            }
+
+            let mut budget = MAX_REAPED;
+
+            for entity_uuid in &expired {
+                if budget == 0 {
+                    break;
+                }
+
+                match crate::entity::parent(core, &access, entity_uuid)? {
+                    Some((parent_uuid, kind)) => {
+                        let before = budget;
+
+                        remove(
+                            core,
+                            &txn,
+                            &mut access,
+                            &parent_uuid,
+                            kind,
+                            entity_uuid,
+                            &mut budget,
+                        )?;
+
+                        removed += (before - budget) as u32;
+                    }
+                    // went with an expired parent
+                    None => {
+                        forget(core, &mut access, entity_uuid)?;
+                        budget -= 1;
+                    }
+                }
+            }
+
+            budget
+        };
+
+        txn.commit()?;
+
+        if budget > 0 {
+            return Ok(removed);
+        }
+    }
+}
+
+/// removes the entity and everything under it, deepest first, until
+/// `budget` runs out. whatever is left stays under the expired entity
+/// for the next transaction.
+fn remove(
+    core: &Core,
+    txn: &ConstTransaction,
+    access: &mut WriteAccessor,
+    parent_uuid: &[u8; 16],
+    kind: u8,
+    entity_uuid: &[u8; 16],
+    budget: &mut usize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut path = vec![(*parent_uuid, kind, *entity_uuid)];
+
+    while let Some(&(parent_uuid, kind, entity_uuid)) = path.last() {
+        if *budget == 0 {
+            break;
+        }
+
+        match crate::entity::first_child(core, txn, access, &entity_uuid)? {
+            Some((child_kind, child_uuid)) => path.push((entity_uuid, child_kind, child_uuid)),
+            None => {
+                crate::entity::remove(
+                    core,
+                    txn,
+                    access,
+                    &parent_uuid,
+                    kind,
+                    &entity_uuid,
+                    false,
+                    None,
+                )?;
+
+                *budget -= 1;
+                path.pop();
+            }
+        }
+    }
+
+    Ok(())
+}
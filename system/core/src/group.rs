@@ -0,0 +1,659 @@
+// group membership
+//
+// a subject is a user, a group nested in the group, or an entity (or
+// secret) made available to the group. each has a single set of
+// permissions per group, and can have some of them denied whatever it
+// is allowed. members of a nested group get what it is allowed in the
+// group it is nested in, unless it is denied them there.
+//
+// entities can also stop inheriting what their parent can do in a
+// group, see entity.rs.
+//
+// members and nested groups are also listed under the group so it can
+// be queried without scanning every subject.
+//
+// subject.group_uuid -> permissions
+// MEMBERS.group_uuid -> user_uuid.permissions (dup)
+// DENY.subject.group_uuid -> permissions
+// NESTED.group_uuid -> nested_group_uuid.permissions (dup)
+// NO_INHERIT.entity_uuid.group_uuid -> 0
+
+use std::collections::BTreeMap;
+
+use bitcode::{Decode, Encode};
+use saferlmdb::{
+    put, ConstAccessor, ConstTransaction, LmdbResultExt, WriteAccessor, WriteTransaction,
+};
+
+use crate::permissions::Permissions;
+use crate::Core;
+
+const MEMBERS: u8 = 0;
+const DENY: u8 = 1;
+const NESTED: u8 = 2;
+const NO_INHERIT: u8 = 3;
+
+/// how deep nested groups are followed, members of groups nested any
+/// deeper don't get anything through them
+pub const MAX_GROUP_DEPTH: usize = 8;
+
+/// (uuid, permissions) of a user or group listed in a group
+pub(crate) type Listed = ([u8; 16], Permissions);
+
+/// why a user has, or hasn't, some permissions in a group
+#[derive(Clone, PartialEq, Eq, Debug, Encode, Decode)]
+pub enum PermissionReason {
+    /// allowed to the user
+    Allowed(Permissions),
+    /// allowed to a nested group (group_uuid) the user is in
+    AllowedNested([u8; 16], Permissions),
+    /// denied to the user
+    Denied(Permissions),
+    /// denied to a nested group (group_uuid) the user is in
+    DeniedNested([u8; 16], Permissions),
+}
+
+fn key(subject_uuid: &[u8; 16], group_uuid: &[u8; 16]) -> [u8; 32] {
+    let mut key = [0u8; 32];
+
+    key[0..16].copy_from_slice(subject_uuid);
+    key[16..32].copy_from_slice(group_uuid);
+
+    key
+}
+
+fn tagged_key(tag: u8, subject_uuid: &[u8; 16], group_uuid: &[u8; 16]) -> [u8; 33] {
+    let mut key = [0u8; 33];
+
+    key[0] = tag;
+    key[1..17].copy_from_slice(subject_uuid);
+    key[17..33].copy_from_slice(group_uuid);
+
+    key
+}
+
+fn list_key(tag: u8, group_uuid: &[u8; 16]) -> [u8; 17] {
+    let mut key = [0u8; 17];
+
+    key[0] = tag;
+    key[1..17].copy_from_slice(group_uuid);
+
+    key
+}
+
+fn member(subject_uuid: &[u8; 16], permissions: Permissions) -> [u8; 20] {
+    let mut member = [0u8; 20];
+
+    member[0..16].copy_from_slice(subject_uuid);
+    member[16..20].copy_from_slice(&permissions.to_be_bytes());
+
+    member
+}
+
+fn get(
+    core: &Core,
+    access: &ConstAccessor,
+    key: &[u8],
+) -> Result<Permissions, Box<dyn std::error::Error>> {
+    match access.get::<[u8], [u8]>(&core.group_db, key).to_opt()? {
+        Some(permissions) => Ok(Permissions::from_be_bytes(permissions.try_into()?)),
+        None => Ok(Permissions::NONE),
+    }
+}
+
+/// what the subject is allowed in the group, before anything denied
+pub(crate) fn allowed(
+    core: &Core,
+    access: &ConstAccessor,
+    subject_uuid: &[u8; 16],
+    group_uuid: &[u8; 16],
+) -> Result<Permissions, Box<dyn std::error::Error>> {
+    get(core, access, &key(subject_uuid, group_uuid))
+}
+
+/// what the subject is denied in the group
+pub(crate) fn denied(
+    core: &Core,
+    access: &ConstAccessor,
+    subject_uuid: &[u8; 16],
+    group_uuid: &[u8; 16],
+) -> Result<Permissions, Box<dyn std::error::Error>> {
+    get(core, access, &tagged_key(DENY, subject_uuid, group_uuid))
+}
+
+/// whether the entity inherits what its parent can do in the group,
+/// see `entity::resolve`
+pub(crate) fn inherits(
+    core: &Core,
+    access: &ConstAccessor,
+    entity_uuid: &[u8; 16],
+    group_uuid: &[u8; 16],
+) -> Result<bool, Box<dyn std::error::Error>> {
+    Ok(access
+        .get::<[u8; 33], [u8]>(
+            &core.group_db,
+            &tagged_key(NO_INHERIT, entity_uuid, group_uuid),
+        )
+        .to_opt()?
+        .is_none())
+}
+
+/// breaks inheritance at the entity for the group, so neither what its
+/// ancestors were allowed nor denied reaches the subtree, or restores it.
+pub(crate) fn set_inherits(
+    core: &Core,
+    access: &mut WriteAccessor,
+    entity_uuid: &[u8; 16],
+    group_uuid: &[u8; 16],
+    inherits: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let key = tagged_key(NO_INHERIT, entity_uuid, group_uuid);
+
+    if !self::inherits(core, access, entity_uuid, group_uuid)? {
+        access.del_key(&core.group_db, &key)?;
+    }
+
+    if !inherits {
+        access.put(&core.group_db, &key, &[0u8], put::Flags::empty())?;
+    }
+
+    Ok(())
+}
+
+/// what the subject can do in the group, `NONE` if it isn't in it.
+///
+/// only what the subject was given itself, users can also get
+/// permissions through nested groups, see `resolve`.
+pub(crate) fn permissions(
+    core: &Core,
+    access: &ConstAccessor,
+    subject_uuid: &[u8; 16],
+    group_uuid: &[u8; 16],
+) -> Result<Permissions, Box<dyn std::error::Error>> {
+    Ok(
+        allowed(core, access, subject_uuid, group_uuid)?.without(denied(
+            core,
+            access,
+            subject_uuid,
+            group_uuid,
+        )?),
+    )
+}
+
+/// fails unless the subject has every one of `required` in the group
+pub(crate) fn require(
+    core: &Core,
+    access: &ConstAccessor,
+    subject_uuid: &[u8; 16],
+    group_uuid: &[u8; 16],
+    required: Permissions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let permissions = permissions(core, access, subject_uuid, group_uuid)?;
+
+    if permissions.is_empty() || !permissions.contains(required) {
+        return Err("not permitted for this group".into());
+    }
+
+    Ok(())
+}
+
+/// what the user can do in the group, through any nested group they
+/// are in too, and why.
+pub(crate) fn resolve(
+    core: &Core,
+    txn: &ConstTransaction,
+    access: &ConstAccessor,
+    user_uuid: &[u8; 16],
+    group_uuid: &[u8; 16],
+) -> Result<(Permissions, Vec<PermissionReason>), Box<dyn std::error::Error>> {
+    resolve_in(
+        core,
+        txn,
+        access,
+        user_uuid,
+        group_uuid,
+        &mut vec![*group_uuid],
+    )
+}
+
+fn resolve_in(
+    core: &Core,
+    txn: &ConstTransaction,
+    access: &ConstAccessor,
+    user_uuid: &[u8; 16],
+    group_uuid: &[u8; 16],
+    path: &mut Vec<[u8; 16]>,
+) -> Result<(Permissions, Vec<PermissionReason>), Box<dyn std::error::Error>> {
+    let mut reasons = vec![];
+
+    let mut allowed = allowed(core, access, user_uuid, group_uuid)?;
+    let mut denied = denied(core, access, user_uuid, group_uuid)?;
+
+    if !allowed.is_empty() {
+        reasons.push(PermissionReason::Allowed(allowed));
+    }
+
+    if !denied.is_empty() {
+        reasons.push(PermissionReason::Denied(denied));
+    }
+
+    for (nested_uuid, nested_allowed) in nested(core, txn, access, group_uuid)? {
+        // `nest` refuses cycles, this only keeps a bad store from looping
+        if path.contains(&nested_uuid) || path.len() >= MAX_GROUP_DEPTH {
+            continue;
+        }
+
+        path.push(nested_uuid);
+        let (in_nested, _) = resolve_in(core, txn, access, user_uuid, &nested_uuid, path)?;
+        path.pop();
+
+        if in_nested.is_empty() {
+            continue;
+        }
+
+        allowed = allowed | nested_allowed;
+        reasons.push(PermissionReason::AllowedNested(nested_uuid, nested_allowed));
+
+        let nested_denied = self::denied(core, access, &nested_uuid, group_uuid)?;
+
+        if !nested_denied.is_empty() {
+            denied = denied | nested_denied;
+            reasons.push(PermissionReason::DeniedNested(nested_uuid, nested_denied));
+        }
+    }
+
+    Ok((allowed.without(denied), reasons))
+}
+
+/// fails unless the user has every one of `required` in the group,
+/// through nested groups too
+pub(crate) fn require_member(
+    core: &Core,
+    txn: &ConstTransaction,
+    access: &ConstAccessor,
+    user_uuid: &[u8; 16],
+    group_uuid: &[u8; 16],
+    required: Permissions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (permissions, _) = resolve(core, txn, access, user_uuid, group_uuid)?;
+
+    if permissions.is_empty() || !permissions.contains(required) {
+        return Err("not permitted for this group".into());
+    }
+
+    Ok(())
+}
+
+/// puts the user in the group with `permissions`, or takes them out
+/// with `NONE`
+pub(crate) fn set(
+    core: &Core,
+    access: &mut WriteAccessor,
+    group_uuid: &[u8; 16],
+    user_uuid: &[u8; 16],
+    permissions: Permissions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    set_listed(core, access, MEMBERS, group_uuid, user_uuid, permissions)
+}
+
+fn set_listed(
+    core: &Core,
+    access: &mut WriteAccessor,
+    tag: u8,
+    group_uuid: &[u8; 16],
+    subject_uuid: &[u8; 16],
+    permissions: Permissions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let previous = allowed(core, access, subject_uuid, group_uuid)?;
+
+    if !previous.is_empty() {
+        access.del_key(&core.group_db, &key(subject_uuid, group_uuid))?;
+        access.del_item(
+            &core.group_db,
+            &list_key(tag, group_uuid),
+            &member(subject_uuid, previous),
+        )?;
+    }
+
+    if !permissions.is_empty() {
+        access.put(
+            &core.group_db,
+            &key(subject_uuid, group_uuid),
+            &permissions.to_be_bytes(),
+            put::Flags::empty(),
+        )?;
+        access.put(
+            &core.group_db,
+            &list_key(tag, group_uuid),
+            &member(subject_uuid, permissions),
+            put::Flags::empty(),
+        )?;
+    }
+
+    Ok(())
+}
+
+/// nests a group in the group, so its members get `permissions` in
+/// it too, or takes it back out with `NONE`.
+///
+/// fails if the group is already nested in the one being nested,
+/// however deep.
+pub(crate) fn nest(
+    core: &Core,
+    txn: &ConstTransaction,
+    access: &mut WriteAccessor,
+    group_uuid: &[u8; 16],
+    nested_uuid: &[u8; 16],
+    permissions: Permissions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if !permissions.is_empty() {
+        // every group nested under the one being nested
+        let mut seen = vec![*nested_uuid];
+        let mut next = vec![*nested_uuid];
+
+        while let Some(parent_uuid) = next.pop() {
+            if parent_uuid == *group_uuid {
+                return Err("a group can't be nested in itself".into());
+            }
+
+            for (child_uuid, _) in nested(core, txn, access, &parent_uuid)? {
+                if !seen.contains(&child_uuid) {
+                    seen.push(child_uuid);
+                    next.push(child_uuid);
+                }
+            }
+        }
+    }
+
+    set_listed(core, access, NESTED, group_uuid, nested_uuid, permissions)
+}
+
+/// denies the subject `permissions` in the group, whatever it is
+/// allowed, or lifts the deny with `NONE`
+pub(crate) fn deny(
+    core: &Core,
+    access: &mut WriteAccessor,
+    subject_uuid: &[u8; 16],
+    group_uuid: &[u8; 16],
+    permissions: Permissions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let key = tagged_key(DENY, subject_uuid, group_uuid);
+
+    if !denied(core, access, subject_uuid, group_uuid)?.is_empty() {
+        access.del_key(&core.group_db, &key)?;
+    }
+
+    if !permissions.is_empty() {
+        access.put(
+            &core.group_db,
+            &key,
+            &permissions.to_be_bytes(),
+            put::Flags::empty(),
+        )?;
+    }
+
+    Ok(())
+}
+
+/// makes the entity available to the group with `permissions`, or
+/// takes it back with `NONE`
+pub(crate) fn share(
+    core: &Core,
+    access: &mut WriteAccessor,
+    entity_uuid: &[u8; 16],
+    group_uuid: &[u8; 16],
+    permissions: Permissions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let key = key(entity_uuid, group_uuid);
+
+    // would be added as a dup otherwise
+    if !allowed(core, access, entity_uuid, group_uuid)?.is_empty() {
+        access.del_key(&core.group_db, &key)?;
+    }
+
+    if !permissions.is_empty() {
+        access.put(
+            &core.group_db,
+            &key,
+            &permissions.to_be_bytes(),
+            put::Flags::empty(),
+        )?;
+    }
+
+    Ok(())
+}
+
+/// fails if the user is the only admin of the group, for when they are
+/// taken out of it, stop being an admin or are denied it.
+///
+/// members denied ADMIN aren't admins, whatever they are allowed.
+pub(crate) fn keep_admin(
+    core: &Core,
+    txn: &ConstTransaction,
+    access: &ConstAccessor,
+    group_uuid: &[u8; 16],
+    user_uuid: &[u8; 16],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut admins = vec![];
+
+    for (member_uuid, _) in members(core, txn, access, group_uuid)? {
+        if permissions(core, access, &member_uuid, group_uuid)?.contains(Permissions::ADMIN) {
+            admins.push(member_uuid);
+        }
+    }
+
+    if admins == [*user_uuid] {
+        return Err("a group has to keep at least one admin".into());
+    }
+
+    Ok(())
+}
+
+/// (user_uuid, permissions) of every member of the group
+pub(crate) fn members(
+    core: &Core,
+    txn: &ConstTransaction,
+    access: &ConstAccessor,
+    group_uuid: &[u8; 16],
+) -> Result<Vec<Listed>, Box<dyn std::error::Error>> {
+    list(core, txn, access, MEMBERS, group_uuid)
+}
+
+/// (group_uuid, permissions) of every group nested in the group
+pub(crate) fn nested(
+    core: &Core,
+    txn: &ConstTransaction,
+    access: &ConstAccessor,
+    group_uuid: &[u8; 16],
+) -> Result<Vec<Listed>, Box<dyn std::error::Error>> {
+    list(core, txn, access, NESTED, group_uuid)
+}
+
+fn list(
+    core: &Core,
+    txn: &ConstTransaction,
+    access: &ConstAccessor,
+    tag: u8,
+    group_uuid: &[u8; 16],
+) -> Result<Vec<Listed>, Box<dyn std::error::Error>> {
+    let mut listed = vec![];
+    let mut cursor = txn.cursor(core.group_db.clone())?;
+
+    let mut next = cursor
+        .seek_k_both::<[u8; 17], [u8]>(access, &list_key(tag, group_uuid))
+        .to_opt()?;
+
+    while let Some((_, member)) = next {
+        if member.len() != 20 {
+            return Err("invalid format".into());
+        }
+
+        listed.push((
+            member[0..16].try_into()?,
+            Permissions::from_be_bytes(member[16..20].try_into()?),
+        ));
+
+        next = cursor.next_dup::<[u8; 17], [u8]>(access).to_opt()?;
+    }
+
+    Ok(listed)
+}
+
+/// every group the subject has been allowed or denied anything in, or
+/// stopped inheriting in
+pub(crate) fn groups(
+    core: &Core,
+    txn: &ConstTransaction,
+    access: &ConstAccessor,
+    subject_uuid: &[u8; 16],
+) -> Result<Vec<[u8; 16]>, Box<dyn std::error::Error>> {
+    let mut groups = vec![];
+
+    for key in rows(core, txn, access, subject_uuid)? {
+        let group_uuid: [u8; 16] = key[key.len() - 16..].try_into()?;
+
+        if !groups.contains(&group_uuid) {
+            groups.push(group_uuid);
+        }
+    }
+
+    Ok(groups)
+}
+
+/// drops every row of an entity that is going away. users and groups
+/// are taken out with `set` and `nest`, so they stay listed correctly.
+pub(crate) fn forget(
+    core: &Core,
+    txn: &ConstTransaction,
+    access: &mut WriteAccessor,
+    entity_uuid: &[u8; 16],
+) -> Result<(), Box<dyn std::error::Error>> {
+    for key in rows(core, txn, access, entity_uuid)? {
+        access.del_key(&core.group_db, &key[..])?;
+    }
+
+    Ok(())
+}
+
+/// keys of the allowed, denied and inheritance rows of the subject
+fn rows(
+    core: &Core,
+    txn: &ConstTransaction,
+    access: &ConstAccessor,
+    subject_uuid: &[u8; 16],
+) -> Result<Vec<Vec<u8>>, Box<dyn std::error::Error>> {
+    let mut rows = vec![];
+    let mut cursor = txn.cursor(core.group_db.clone())?;
+
+    let mut prefixes = vec![(subject_uuid.to_vec(), 32)];
+
+    for tag in [DENY, NO_INHERIT] {
+        let mut prefix = vec![tag];
+        prefix.extend_from_slice(subject_uuid);
+
+        prefixes.push((prefix, 33));
+    }
+
+    for (prefix, len) in &prefixes {
+        let mut next = cursor
+            .seek_range_k::<[u8], [u8]>(access, &prefix[..])
+            .to_opt()?;
+
+        while let Some((key, _)) = next {
+            if !key.starts_with(prefix) {
+                break;
+            }
+
+            if key.len() == *len && !rows.iter().any(|row: &Vec<u8>| row == key) {
+                rows.push(key.to_vec());
+            }
+
+            next = cursor.next::<[u8], [u8]>(access).to_opt()?;
+        }
+    }
+
+    Ok(rows)
+}
+
+/// moves rows over from when every level a subject had in a group was
+/// its own row:
+///
+/// subject.group_uuid.level -> []
+/// group_uuid -> user_uuid.level (dup)
+///
+/// where the level was read/write (0), read (1) or admin (2). a
+/// subject with more than one level gets all of them. does nothing once
+/// there are no old rows left.
+pub(crate) fn migrate(core: &Core) -> Result<(), Box<dyn std::error::Error>> {
+    let txn = WriteTransaction::new(core.env.clone())?;
+
+    {
+        let mut access = txn.access();
+
+        let mut levels = vec![];
+        let mut members = vec![];
+
+        {
+            let mut cursor = txn.cursor(core.group_db.clone())?;
+            let mut next = cursor.first::<[u8], [u8]>(&access).to_opt()?;
+
+            while let Some((key, value)) = next {
+                match (key.len(), value.len()) {
+                    (33, 0) => levels.push(<[u8; 33]>::try_from(key)?),
+                    (16, 17) => {
+                        members.push((<[u8; 16]>::try_from(key)?, <[u8; 17]>::try_from(value)?))
+                    }
+                    _ => {}
+                }
+
+                next = cursor.next::<[u8], [u8]>(&access).to_opt()?;
+            }
+        }
+
+        if levels.is_empty() && members.is_empty() {
+            return Ok(());
+        }
+
+        for key in &levels {
+            access.del_item::<[u8; 33], [u8]>(&core.group_db, key, &[])?;
+        }
+
+        for (group_uuid, member) in &members {
+            access.del_item(&core.group_db, group_uuid, member)?;
+        }
+
+        let mut merged: BTreeMap<([u8; 16], [u8; 16]), Permissions> = BTreeMap::new();
+
+        for key in &levels {
+            let subject_uuid: [u8; 16] = key[0..16].try_into()?;
+            let group_uuid: [u8; 16] = key[16..32].try_into()?;
+
+            let permissions = match key[32] {
+                0 => Permissions::READ | Permissions::WRITE,
+                1 => Permissions::READ,
+                2 => Permissions::BUILT_IN,
+                _ => return Err("invalid group level".into()),
+            };
+
+            let merged = merged.entry((subject_uuid, group_uuid)).or_default();
+            *merged = *merged | permissions;
+        }
+
+        for ((subject_uuid, group_uuid), permissions) in merged {
+            // only users were listed as members
+            let is_member = members.iter().any(|(member_group_uuid, member)| {
+                *member_group_uuid == group_uuid && member[0..16] == subject_uuid
+            });
+
+            if is_member {
+                set(core, &mut access, &group_uuid, &subject_uuid, permissions)?;
+            } else {
+                share(core, &mut access, &subject_uuid, &group_uuid, permissions)?;
+            }
+        }
+    }
+
+    txn.commit()?;
+
+    Ok(())
+}
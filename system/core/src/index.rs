@@ -0,0 +1,242 @@
+// secondary indexes on the properties the schema says to index.
+//
+// entities are indexed under their parent, so a value only has to be
+// unique among the entities of its kind under the same parent. they are
+// kept up to date in the same write transaction as the entity, by
+// whichever core has the schema.
+//
+// unique_db: parent.kind.id.value -> entity_uuid
+// index_db: parent.kind.id.value -> entity_uuid (dup)
+
+use saferlmdb::{
+    self as lmdb, put, ConstAccessor, ConstTransaction, LmdbResultExt, WriteAccessor,
+    WriteTransaction,
+};
+
+use crate::properties::decode_properties;
+use crate::schema::{Index, Property};
+use crate::Core;
+
+/// longest value that can be indexed, so the key fits in LMDB
+pub const MAX_INDEXED_LEN: usize = 511 - 16 - 1 - 1;
+
+fn key(
+    parent_uuid: &[u8; 16],
+    kind: u8,
+    id: u8,
+    value: &[u8],
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    if value.len() > MAX_INDEXED_LEN {
+        return Err("indexed value is too long".into());
+    }
+
+    let mut key = Vec::with_capacity(16 + 1 + 1 + value.len());
+
+    key.extend_from_slice(parent_uuid);
+    key.push(kind);
+    key.push(id);
+    key.extend_from_slice(value);
+
+    Ok(key)
+}
+
+/// (name, property) of every indexed property of the kind
+fn indexed(core: &Core, kind: u8) -> Vec<(&str, Property)> {
+    match core.schema.kinds.get(&kind) {
+        Some(entity) => entity
+            .properties
+            .iter()
+            .filter(|(_, property)| property.index.is_some())
+            .map(|(name, property)| (name.as_str(), *property))
+            .collect(),
+        None => vec![],
+    }
+}
+
+/// indexes the entity. fails if a unique value is already taken, or
+/// if the kind has indexes and the entity isn't made of properties.
+pub(crate) fn add(
+    core: &Core,
+    access: &mut WriteAccessor,
+    parent_uuid: &[u8; 16],
+    kind: u8,
+    entity_uuid: &[u8; 16],
+    entity: &[u8],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let indexed = indexed(core, kind);
+
+    if indexed.is_empty() {
+        return Ok(());
+    }
+
+    let properties = decode_properties(entity)?;
+
+    for (name, property) in indexed {
+        let Some(value) = properties.get(&property.id) else {
+            continue;
+        };
+
+        let key = key(parent_uuid, kind, property.id, value)?;
+
+        match property.index {
+            Some(Index::Unique) => {
+                match access.put(
+                    &core.unique_db,
+                    &key[..],
+                    entity_uuid,
+                    put::Flags::NOOVERWRITE,
+                ) {
+                    Err(lmdb::Error::Code(lmdb::error::KEYEXIST)) => {
+                        return Err(format!("{name} has to be unique").into())
+                    }
+                    result => result?,
+                }
+            }
+            Some(Index::Multi) => {
+                access.put(&core.index_db, &key[..], entity_uuid, put::Flags::empty())?
+            }
+            None => (),
+        }
+    }
+
+    Ok(())
+}
+
+/// drops the index rows of the entity
+pub(crate) fn remove(
+    core: &Core,
+    access: &mut WriteAccessor,
+    parent_uuid: &[u8; 16],
+    kind: u8,
+    entity_uuid: &[u8; 16],
+    entity: &[u8],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let indexed = indexed(core, kind);
+
+    if indexed.is_empty() {
+        return Ok(());
+    }
+
+    // wasn't indexed either
+    let Ok(properties) = decode_properties(entity) else {
+        return Ok(());
+    };
+
+    for (_, property) in indexed {
+        let Some(value) = properties.get(&property.id) else {
+            continue;
+        };
+
+        let key = key(parent_uuid, kind, property.id, value)?;
+
+        match property.index {
+            Some(Index::Unique)
+                if access
+                    .get::<[u8], [u8]>(&core.unique_db, &key[..])
+                    .to_opt()?
+                    .is_some_and(|indexed| indexed == &entity_uuid[..]) =>
+            {
+                access.del_key(&core.unique_db, &key[..])?;
+            }
+            Some(Index::Multi) => {
+                access
+                    .del_item(&core.index_db, &key[..], entity_uuid)
+                    .to_opt()?;
+            }
+            Some(Index::Unique) | None => (),
+        }
+    }
+
+    Ok(())
+}
+
+/// uuids of the entities of the kind under the parent with the value,
+/// oldest first
+pub(crate) fn lookup(
+    core: &Core,
+    txn: &ConstTransaction,
+    access: &ConstAccessor,
+    parent_uuid: &[u8; 16],
+    kind: u8,
+    property: Property,
+    value: &[u8],
+) -> Result<Vec<[u8; 16]>, Box<dyn std::error::Error>> {
+    // can't have been indexed
+    if value.len() > MAX_INDEXED_LEN {
+        return Ok(vec![]);
+    }
+
+    let key = key(parent_uuid, kind, property.id, value)?;
+
+    match property.index {
+        Some(Index::Unique) => match access
+            .get::<[u8], [u8]>(&core.unique_db, &key[..])
+            .to_opt()?
+        {
+            Some(entity_uuid) => Ok(vec![entity_uuid.try_into()?]),
+            None => Ok(vec![]),
+        },
+        Some(Index::Multi) => {
+            let mut found = vec![];
+            let mut cursor = txn.cursor(core.index_db.clone())?;
+
+            let mut next = cursor
+                .seek_k_both::<[u8], [u8]>(access, &key[..])
+                .to_opt()?;
+
+            while let Some((_, entity_uuid)) = next {
+                found.push(entity_uuid.try_into()?);
+
+                next = cursor.next_dup::<[u8], [u8]>(access).to_opt()?;
+            }
+
+            Ok(found)
+        }
+        None => Err("property isn't indexed".into()),
+    }
+}
+
+/// drops every index, and indexes every entity again with the current
+/// schema. returns how many entities were indexed.
+pub(crate) fn rebuild(core: &Core) -> Result<u32, Box<dyn std::error::Error>> {
+    let txn = WriteTransaction::new(core.env.clone())?;
+
+    let indexed = {
+        let mut access = txn.access();
+
+        access.clear_db(&core.unique_db)?;
+        access.clear_db(&core.index_db)?;
+
+        let mut entities = vec![];
+
+        {
+            let mut cursor = txn.cursor(core.entity_db.clone())?;
+            let mut next = cursor.first::<[u8], [u8]>(&access).to_opt()?;
+
+            while let Some((key, value)) = next {
+                if key.len() == 33 && !indexed(core, key[16]).is_empty() {
+                    entities.push((<[u8; 33]>::try_from(key)?, value[33..].to_vec()));
+                }
+
+                next = cursor.next::<[u8], [u8]>(&access).to_opt()?;
+            }
+        }
+
+        for (key, entity) in &entities {
+            add(
+                core,
+                &mut access,
+                key[0..16].try_into()?,
+                key[16],
+                key[17..33].try_into()?,
+                entity,
+            )?;
+        }
+
+        entities.len() as u32
+    };
+
+    txn.commit()?;
+
+    Ok(indexed)
+}
@@ -1,16 +1,22 @@
 use std::collections::BTreeMap;
+use std::net::IpAddr;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use bytes::Bytes;
-use opaque_ke::{
-    errors::{InternalError, ProtocolError},
-    Ristretto255, ServerSetup,
-};
-use parking_lot::Mutex;
+use opaque_ke::ServerSetup;
+use parking_lot::{Mutex, RwLock};
 use rand::rngs::OsRng;
 
-use cbwaw::DefaultCipherSuite;
-use saferlmdb::{self as lmdb, Database, DatabaseOptions, EnvBuilder, Environment, Stat};
+use cbwaw::{
+    token::{self, KeyRing},
+    DefaultCipherSuite,
+};
+use saferlmdb::{
+    self as lmdb, put, Database, DatabaseOptions, EnvBuilder, Environment, LmdbResultExt,
+    ReadTransaction, Stat, WriteTransaction,
+};
+use uuid::Uuid;
 
 // ?? all objects have an expiration time that you can renew
 
@@ -21,10 +27,57 @@ use saferlmdb::{self as lmdb, Database, DatabaseOptions, EnvBuilder, Environment
 
 // ?? narrow
 
+mod api_key;
+mod certificate;
+mod delegation;
+mod entity;
+mod expiry;
+mod group;
+mod index;
+mod login_state;
+mod migration;
 pub mod ops;
+mod permissions;
+mod properties;
+mod recovery;
+mod reference;
+mod refresh;
+mod schema;
+mod seal;
+mod second_factor;
+mod secret;
+mod throttle;
+mod username;
+
+pub use api_key::{API_KEY_LEN, MAX_API_KEY_LIFETIME};
+pub use certificate::{Certificate, CertificateMatch};
+pub use entity::MAX_ENTITY_DEPTH;
+pub use expiry::{MAX_REAPED, REAP_INTERVAL};
+pub use group::{PermissionReason, MAX_GROUP_DEPTH};
+pub use index::MAX_INDEXED_LEN;
+pub use migration::CURRENT_SCHEMA_VERSION;
+pub use permissions::{custom_permissions, Permissions, MAX_CUSTOM_PERMISSIONS};
+pub use properties::{decode_properties, encode_properties, Properties};
+pub use recovery::{RECOVERY_CODES, RECOVERY_CODE_LEN};
+pub use refresh::FRESH_LOGIN;
+pub use schema::{entity_schema, EntitySchema, Index, Property, PropertyType, Schema};
+pub use seal::MASTER_KEY_PATH;
+pub use second_factor::{MAX_FAILED_SECOND_FACTOR, SECOND_FACTOR_LOCKOUT};
+pub use secret::MAX_SECRET_LEN;
+pub use throttle::{
+    Throttled, FREE_LOGIN_ATTEMPTS, FREE_SOURCE_ATTEMPTS, LOGIN_ATTEMPT_WINDOW, MAX_LOGIN_BACKOFF,
+};
+pub use username::UsernameError;
 
 const MAX_USERNAME_LEN: u8 = 255;
 
+/// rotate with `picaso rotate-token-keys`, running servers pick up
+/// the new key the next time they sign or verify a token.
+pub const TOKEN_KEYS_PATH: &str = "./store/token.keys";
+
+/// how often the key file is checked for a rotation
+pub const TOKEN_KEYS_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
 /// On-disk/transport format
 ///
 /// key([  grandparent  ],[ kind ]|[  parent  ])-value(properties)-backLink([ kind ]|[  great grandparent  ], [ grandparent_kind ])
@@ -50,21 +103,62 @@ const MAX_USERNAME_LEN: u8 = 255;
 /// encryption: e2ee | server | none
 #[derive(Clone)]
 pub struct Core {
-    opaque: ServerSetup<DefaultCipherSuite>,
+    /// setup_id -> setup
+    ///
+    /// the highest setup_id is the one new registrations are bound to,
+    /// older ones are kept around for the password files that still
+    /// reference them.
+    opaque: Arc<RwLock<BTreeMap<u32, ServerSetup<DefaultCipherSuite>>>>,
+
+    /// token signing/verification keys, loaded from `TOKEN_KEYS_PATH`
+    /// unless they are set in config
+    token_keys: Arc<RwLock<Arc<KeyRing>>>,
+
+    /// when the key file was last checked for a rotation
+    token_keys_checked: Arc<Mutex<Instant>>,
+
+    /// issuer, audience, and lifetime of each kind of token
+    token_settings: token::Settings,
+
+    /// seals what goes in `secrets_db`, loaded from `MASTER_KEY_PATH`
+    master_key: [u8; 32],
+
+    /// name -> permission, of the custom permissions groups can assign
+    custom_permissions: Arc<BTreeMap<String, Permissions>>,
+
+    /// what each kind of entity is made of
+    schema: Arc<Schema>,
+
+    /// nonce -> (user_uuid, state)
+    auth_state: Arc<Mutex<login_state::PendingLogins>>,
+
+    /// keep pending logins in `auth_state_db` instead of `auth_state`
+    shared_auth_state: bool,
+
+    /// fail `registration_start` for usernames that are taken
+    check_username_at_start: bool,
+
+    /// resolve what a group can do with an entity up the entity tree,
+    /// instead of copying it down when the entity is put
+    inherit_permissions: bool,
 
-    /// user_uuid -> state
-    auth_state: Arc<Mutex<BTreeMap<[u8; 16], Vec<u8>>>>,
+    /// what entities expire against
+    clock: expiry::Clock,
 
     /// DB env
     env: Arc<Environment>,
 
-    /// username -> user_uuid.password_file
+    /// username -> user_uuid.setup_id.password_file
     auth_db: Arc<Database<'static>>,
 
     /// user_uuid -> user()
     user_db: Arc<Database<'static>>,
 
-    /// (entity_uuid | user_uuid).group_id.action -> []
+    /// (entity_uuid | user_uuid | group_uuid).group_uuid -> permissions
+    /// MEMBERS.group_uuid -> user_uuid.permissions
+    /// DENY.(entity_uuid | user_uuid | group_uuid).group_uuid -> permissions
+    /// NESTED.group_uuid -> group_uuid.permissions
+    /// NO_INHERIT.entity_uuid.group_uuid -> 0
     /// is DUPSORT, and DUPFIXED
     group_db: Arc<Database<'static>>,
 
@@ -74,41 +168,77 @@ pub struct Core {
     /// parent relationship:
     /// key(grandparent.kind.parent) -> value(user_uuid.great_grandparent_uuid.grandparent_kind.entity)
     ///                            key([  parent  ],[ kind ]|[  child  ]) -> backLink(grandparent.parent_kind), value(properties)
+    ///
+    /// entity_uuid -> parent_uuid.kind
     entity_db: Arc<Database<'static>>,
 
     /// (entity_uuid | user_uuid).ref_type -> (entity_uuid | user_uuid)
     /// is DUPSORT, and DUPFIXED
     reference_db: Arc<Database<'static>>,
 
-    /// uuid_v4 -> encrypted
+    /// secret_uuid -> group_uuid.latest_version
+    /// secret_uuid.version -> client_sealed.user_uuid.sealed_value
+    /// user_uuid.TOTP -> confirmed.last_step.failures.locked_until.sealed_secret
     secrets_db: Arc<Database<'static>>,
+
+    /// setup_id -> serialized ServerSetup
+    setup_db: Arc<Database<'static>>,
+
+    /// user_uuid -> revoked_before_ms (log out of all sessions)
+    /// user_uuid.jti -> exp (log out of a single session)
+    revoked_db: Arc<Database<'static>>,
+
+    /// token_id -> family_id.exp
+    /// family_id -> current_token_id.exp
+    family_db: Arc<Database<'static>>,
+
+    /// nonce -> user_uuid.state
+    auth_state_db: Arc<Database<'static>>,
+
+    /// user_uuid.kind.hash(code) -> []
+    recovery_db: Arc<Database<'static>>,
+
+    /// (USERNAME.username | SOURCE.ip) -> attempts.last_attempt.locked_until
+    attempts_db: Arc<Database<'static>>,
+
+    /// key_id -> user_uuid.hash.expires.last_used.actions_len.actions.groups
+    /// user_uuid.key_id -> []
+    api_key_db: Arc<Database<'static>>,
+
+    /// (SUBJECT.hash(subject) | SPKI.spki_hash) -> user_uuid
+    certificate_db: Arc<Database<'static>>,
+
+    /// entity_uuid -> exp
+    /// EXPIRES.exp.entity_uuid -> []
+    expiry_db: Arc<Database<'static>>,
+
+    /// parent_uuid.kind.property_id.value -> entity_uuid
+    unique_db: Arc<Database<'static>>,
+
+    /// parent_uuid.kind.property_id.value -> entity_uuid
+    /// is DUPSORT, and DUPFIXED
+    index_db: Arc<Database<'static>>,
+
+    /// SCHEMA_VERSION -> version
+    meta_db: Arc<Database<'static>>,
 }
 
 impl Core {
     pub fn new() -> Result<Self, Box<dyn std::error::Error>> {
-        let mut rng = OsRng;
-
-        let keypair = match opaque_ke::keypair::KeyPair::<Ristretto255>::from_private_key_slice(
-            &[0u8; 32][..],
-        ) {
-            Ok(kp) => kp,
-            Err(err) => {
-                println!("error: {:?}", err);
-                return Err(err.to_string().into());
-            }
-        };
-
-        let opaque = ServerSetup::<DefaultCipherSuite>::new(&mut rng);
-
         let auth_state = Arc::new(Mutex::new(BTreeMap::new()));
 
         std::fs::create_dir_all("./store")?;
 
+        let token_keys = Arc::new(RwLock::new(Arc::new(KeyRing::load_or_generate(
+            TOKEN_KEYS_PATH,
+        )?)));
+        let master_key = seal::load_or_generate(MASTER_KEY_PATH)?;
+
         let env = Arc::new(unsafe {
             let mut env_builder = EnvBuilder::new().unwrap();
             env_builder.set_maxreaders(126).unwrap();
             env_builder.set_mapsize(10485760).unwrap();
-            env_builder.set_maxdbs(6).unwrap();
+            env_builder.set_maxdbs(18).unwrap();
             env_builder
                 .open("./store", saferlmdb::open::Flags::empty(), 0o600)
                 .unwrap()
@@ -154,9 +284,95 @@ impl Core {
             &DatabaseOptions::new(lmdb::db::Flags::CREATE),
         )?);
 
-        Ok(Self {
+        let setup_db = Arc::new(Database::open(
+            env.clone(),
+            Some("6"),
+            &DatabaseOptions::new(lmdb::db::Flags::CREATE),
+        )?);
+
+        let opaque = Arc::new(RwLock::new(load_server_setups(&env, &setup_db)?));
+
+        let revoked_db = Arc::new(Database::open(
+            env.clone(),
+            Some("7"),
+            &DatabaseOptions::new(lmdb::db::Flags::CREATE),
+        )?);
+
+        let family_db = Arc::new(Database::open(
+            env.clone(),
+            Some("8"),
+            &DatabaseOptions::new(lmdb::db::Flags::CREATE),
+        )?);
+
+        let auth_state_db = Arc::new(Database::open(
+            env.clone(),
+            Some("9"),
+            &DatabaseOptions::new(lmdb::db::Flags::CREATE),
+        )?);
+
+        let recovery_db = Arc::new(Database::open(
+            env.clone(),
+            Some("10"),
+            &DatabaseOptions::new(lmdb::db::Flags::CREATE),
+        )?);
+
+        let attempts_db = Arc::new(Database::open(
+            env.clone(),
+            Some("11"),
+            &DatabaseOptions::new(lmdb::db::Flags::CREATE),
+        )?);
+
+        let api_key_db = Arc::new(Database::open(
+            env.clone(),
+            Some("12"),
+            &DatabaseOptions::new(lmdb::db::Flags::CREATE),
+        )?);
+
+        let certificate_db = Arc::new(Database::open(
+            env.clone(),
+            Some("13"),
+            &DatabaseOptions::new(lmdb::db::Flags::CREATE),
+        )?);
+
+        let expiry_db = Arc::new(Database::open(
+            env.clone(),
+            Some("14"),
+            &DatabaseOptions::new(lmdb::db::Flags::CREATE),
+        )?);
+
+        let unique_db = Arc::new(Database::open(
+            env.clone(),
+            Some("15"),
+            &DatabaseOptions::new(lmdb::db::Flags::CREATE),
+        )?);
+
+        let index_db = Arc::new(Database::open(
+            env.clone(),
+            Some("16"),
+            &DatabaseOptions::new(
+                lmdb::db::Flags::DUPSORT | lmdb::db::Flags::DUPFIXED | lmdb::db::Flags::CREATE,
+            ),
+        )?);
+
+        let meta_db = Arc::new(Database::open(
+            env.clone(),
+            Some("17"),
+            &DatabaseOptions::new(lmdb::db::Flags::CREATE),
+        )?);
+
+        let core = Self {
             opaque,
+            token_keys,
+            token_keys_checked: Arc::new(Mutex::new(Instant::now())),
+            token_settings: token::Settings::default(),
+            master_key,
+            custom_permissions: Arc::new(BTreeMap::new()),
+            schema: Arc::new(Schema::default()),
             auth_state,
+            shared_auth_state: false,
+            check_username_at_start: false,
+            inherit_permissions: false,
+            clock: Arc::new(expiry::system_clock),
             env,
             auth_db,
             user_db,
@@ -164,9 +380,366 @@ impl Core {
             entity_db,
             reference_db,
             secrets_db,
+            setup_db,
+            revoked_db,
+            family_db,
+            auth_state_db,
+            recovery_db,
+            attempts_db,
+            api_key_db,
+            certificate_db,
+            expiry_db,
+            unique_db,
+            index_db,
+            meta_db,
+        };
+
+        migration::migrate(&core)?;
+
+        Ok(core)
+    }
+
+    /// generates a new OPAQUE server setup and makes it the one new
+    /// registrations are bound to.
+    ///
+    /// existing password files keep logging in against the setup they were
+    /// registered with, and only move over to the new one when the user
+    /// registers again. once nothing references the old setup it can be
+    /// dropped with `retire_server_setup`.
+    pub fn rotate_server_setup(&self) -> Result<u32, Box<dyn std::error::Error>> {
+        let mut opaque = self.opaque.write();
+
+        let setup_id = match opaque.last_key_value() {
+            Some((setup_id, _)) => setup_id.checked_add(1).ok_or("setup_id is out of range")?,
+            None => 0,
+        };
+
+        let setup = ServerSetup::<DefaultCipherSuite>::new(&mut OsRng);
+
+        let txn = WriteTransaction::new(self.env.clone())?;
+
+        {
+            let mut access = txn.access();
+
+            access.put(
+                &self.setup_db,
+                &setup_id.to_be_bytes(),
+                &setup.serialize()[..],
+                put::Flags::NOOVERWRITE,
+            )?;
+        }
+
+        txn.commit()?;
+
+        opaque.insert(setup_id, setup);
+
+        Ok(setup_id)
+    }
+
+    /// removes a rotated out OPAQUE server setup.
+    ///
+    /// fails if it is still the current setup, or if any password file in
+    /// `auth_db` was registered against it.
+    pub fn retire_server_setup(&self, setup_id: u32) -> Result<(), Box<dyn std::error::Error>> {
+        let mut opaque = self.opaque.write();
+
+        if let Some((current_id, _)) = opaque.last_key_value() {
+            if *current_id == setup_id {
+                return Err("cannot retire the current server setup".into());
+            }
+        }
+
+        let txn = WriteTransaction::new(self.env.clone())?;
+
+        {
+            let mut access = txn.access();
+            let mut cursor = txn.cursor(self.auth_db.clone())?;
+
+            let mut next = cursor.first::<[u8], [u8]>(&access).to_opt()?;
+
+            while let Some((_, user_uuid_password_file)) = next {
+                if user_uuid_password_file[16..20] == setup_id.to_be_bytes() {
+                    return Err("server setup is still referenced by a password file".into());
+                }
+
+                next = cursor.next::<[u8], [u8]>(&access).to_opt()?;
+            }
+
+            access.del_key(&self.setup_db, &setup_id.to_be_bytes())?;
+        }
+
+        txn.commit()?;
+
+        opaque.remove(&setup_id);
+
+        Ok(())
+    }
+
+    /// (setup_id, setup) new registrations are bound to
+    fn current_server_setup(
+        &self,
+    ) -> Result<(u32, ServerSetup<DefaultCipherSuite>), Box<dyn std::error::Error>> {
+        match self.opaque.read().last_key_value() {
+            Some((setup_id, setup)) => Ok((*setup_id, setup.clone())),
+            None => Err("no server setup".into()),
+        }
+    }
+
+    /// setup a password file was registered against
+    fn server_setup(
+        &self,
+        setup_id: u32,
+    ) -> Result<ServerSetup<DefaultCipherSuite>, Box<dyn std::error::Error>> {
+        if let Some(setup) = self.opaque.read().get(&setup_id) {
+            return Ok(setup.clone());
+        }
+
+        // may have been rotated in by another process sharing the store
+        let txn = ReadTransaction::new(self.env.clone())?;
+        let access = txn.access();
+
+        let setup = match access
+            .get::<[u8; 4], [u8]>(&self.setup_db, &setup_id.to_be_bytes())
+            .to_opt()?
+        {
+            Some(setup) => match ServerSetup::<DefaultCipherSuite>::deserialize(setup) {
+                Ok(setup) => setup,
+                Err(err) => return Err(err.to_string().into()),
+            },
+            None => return Err("unknown server setup".into()),
+        };
+
+        self.opaque.write().insert(setup_id, setup.clone());
+
+        Ok(setup)
+    }
+
+    pub fn with_token_settings(
+        mut self,
+        token_settings: token::Settings,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        token_settings.validate()?;
+        self.token_settings = token_settings;
+
+        Ok(self)
+    }
+
+    /// token settings from the `[tokens]` table of ordinary.toml, anything
+    /// left out keeps its default.
+    ///
+    /// `issuer` and `audience` are uuids, the lifetimes are in seconds, and
+    /// `keys` are used instead of `TOKEN_KEYS_PATH`, see `picaso print-token-keys`
+    pub fn with_token_config(mut self, config: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let config: toml::Table = config.parse()?;
+
+        let table = match config.get("tokens") {
+            Some(toml::Value::Table(table)) => table,
+            Some(_) => return Err("tokens has to be a table".into()),
+            None => return Ok(self),
+        };
+
+        let uuid = |name: &str, default: [u8; 16]| -> Result<[u8; 16], Box<dyn std::error::Error>> {
+            match table.get(name) {
+                Some(value) => {
+                    let value = value
+                        .as_str()
+                        .ok_or_else(|| format!("tokens.{name} has to be a uuid"))?;
+
+                    Ok(Uuid::parse_str(value)?.into_bytes())
+                }
+                None => Ok(default),
+            }
+        };
+
+        let lifetime = |name: &str, default: u64| -> Result<u64, Box<dyn std::error::Error>> {
+            match table.get(name) {
+                Some(value) => Ok(u64::try_from(
+                    value
+                        .as_integer()
+                        .ok_or_else(|| format!("tokens.{name} has to be a number of seconds"))?,
+                )?),
+                None => Ok(default),
+            }
+        };
+
+        let defaults = self.token_settings;
+
+        self = self.with_token_settings(token::Settings {
+            issuer: uuid("issuer", defaults.issuer)?,
+            audience: uuid("audience", defaults.audience)?,
+
+            refresh_lifetime: lifetime("refresh_lifetime", defaults.refresh_lifetime)?,
+            access_lifetime: lifetime("access_lifetime", defaults.access_lifetime)?,
+            delegated_lifetime: lifetime("delegated_lifetime", defaults.delegated_lifetime)?,
+
+            session_lifetime: lifetime("session_lifetime", defaults.session_lifetime)?,
+        })?;
+
+        if let Some(keys) = table.get("keys") {
+            let keys = keys.as_str().ok_or("tokens.keys has to be a string")?;
+
+            self.token_keys = Arc::new(RwLock::new(Arc::new(KeyRing::from_hex(keys)?)));
+        }
+
+        Ok(self)
+    }
+
+    /// custom permissions from the `[permissions]` table of ordinary.toml
+    pub fn with_custom_permissions(
+        mut self,
+        config: &str,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        self.custom_permissions = Arc::new(custom_permissions(config)?);
+
+        Ok(self)
+    }
+
+    /// a custom permission by its name in ordinary.toml
+    pub fn custom_permission(&self, name: &str) -> Option<Permissions> {
+        self.custom_permissions.get(name).copied()
+    }
+
+    /// every permission a group can assign
+    pub fn assignable_permissions(&self) -> Permissions {
+        self.custom_permissions
+            .values()
+            .fold(Permissions::BUILT_IN, |all, permission| all | *permission)
+    }
+
+    /// entity schemas from the `[entities]` table of ordinary.toml, so
+    /// queries can filter on and project properties by name, and the
+    /// indexed ones are kept indexed
+    pub fn with_entity_schema(mut self, config: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        self.schema = Arc::new(entity_schema(config)?);
+
+        Ok(self)
+    }
+
+    /// what each kind of entity is made of
+    pub fn schema(&self) -> &Schema {
+        &self.schema
+    }
+
+    /// entities inherit what their parent can do in a group, unless
+    /// inheritance was broken for the subtree, so taking access away on
+    /// a parent takes it away on everything under it.
+    ///
+    /// otherwise new entities get a copy of read access for the group
+    /// they were put with.
+    pub fn with_inherited_permissions(mut self) -> Self {
+        self.inherit_permissions = true;
+        self
+    }
+
+    /// keeps pending logins in the store instead of in memory, so that
+    /// a login started on one process can be finished on another.
+    pub fn with_shared_login_state(mut self) -> Self {
+        self.shared_auth_state = true;
+        self
+    }
+
+    /// reads the time entities expire against, in seconds since the unix
+    /// epoch, from `clock` instead of the system clock.
+    pub fn with_clock(
+        mut self,
+        clock: impl Fn() -> Result<u64, Box<dyn std::error::Error>> + Send + Sync + 'static,
+    ) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// fails `registration_start` right away for a username that is taken,
+    /// instead of only at `registration_finish`.
+    ///
+    /// saves the client the work of finishing, but tells anyone asking
+    /// whether a username is registered.
+    pub fn with_username_check_at_start(mut self) -> Self {
+        self.check_username_at_start = true;
+        self
+    }
+
+    /// retires the current token signing key and starts signing with a
+    /// new one, dropping any keys retired longer than the max token lifetime.
+    pub fn rotate_token_keys(&self) -> Result<u32, Box<dyn std::error::Error>> {
+        let mut token_keys = self.token_keys.write();
+
+        let path = token_keys
+            .path()
+            .ok_or("token keys are set in config, rotate them there")?
+            .to_path_buf();
+
+        let mut rotated = KeyRing::load(&path)?;
+
+        rotated.prune()?;
+
+        let key_id = rotated.rotate()?;
+
+        rotated.save(&path)?;
+
+        *token_keys = Arc::new(KeyRing::load(&path)?);
+
+        Ok(key_id)
+    }
+
+    /// drops the refresh token family records of expired tokens
+    pub fn prune_token_families(&self) -> Result<(), Box<dyn std::error::Error>> {
+        refresh::prune(self)
+    }
+
+    /// drops the login attempt records of usernames and sources
+    /// that have been quiet for `LOGIN_ATTEMPT_WINDOW`
+    pub fn prune_login_attempts(&self) -> Result<(), Box<dyn std::error::Error>> {
+        throttle::prune(self)
+    }
+
+    /// removes expired entities along with everything under them, and
+    /// returns how many were removed
+    pub fn reap_expired(&self) -> Result<u32, Box<dyn std::error::Error>> {
+        expiry::reap(self)
+    }
+
+    /// indexes every entity again, for when the indexes in the schema
+    /// have changed. returns how many entities were indexed.
+    pub fn rebuild_indexes(&self) -> Result<u32, Box<dyn std::error::Error>> {
+        index::rebuild(self)
+    }
+
+    /// reaps expired entities `every` so often on a thread of its own,
+    /// for as long as the process runs
+    pub fn spawn_reaper(&self, every: Duration) -> std::thread::JoinHandle<()> {
+        let core = self.clone();
+
+        std::thread::spawn(move || loop {
+            std::thread::sleep(every);
+
+            if let Err(err) = core.reap_expired() {
+                log::error!("failed to reap expired entities: {err}");
+            }
         })
     }
 
+    /// keys to sign/verify tokens with, picking up any rotation done to
+    /// the key file within `TOKEN_KEYS_CHECK_INTERVAL`.
+    fn token_keys(&self) -> Result<Arc<KeyRing>, Box<dyn std::error::Error>> {
+        {
+            let mut checked = self.token_keys_checked.lock();
+
+            if checked.elapsed() >= TOKEN_KEYS_CHECK_INTERVAL {
+                *checked = Instant::now();
+
+                let token_keys = self.token_keys.read().clone();
+
+                if token_keys.is_stale()? {
+                    let path = token_keys.path().ok_or("token keys have no key file")?;
+
+                    *self.token_keys.write() = Arc::new(KeyRing::load(path)?);
+                }
+            }
+        }
+
+        Ok(self.token_keys.read().clone())
+    }
+
     pub fn stat(&self) -> Result<Stat, Box<dyn std::error::Error>> {
         let stat = self.env.stat()?;
         return Ok(stat);
@@ -176,24 +749,125 @@ impl Core {
         ops::access_get::handle(self, payload)
     }
 
+    /// `certificate` is the verified client certificate of the connection
+    pub fn access_get_certificate(
+        &self,
+        certificate: &Certificate,
+        payload: Bytes,
+    ) -> Result<Bytes, Box<dyn std::error::Error>> {
+        ops::access_get_certificate::handle(self, certificate, payload)
+    }
+
+    pub fn api_key_create(&self, payload: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
+        ops::api_key_create::handle(self, payload)
+    }
+    pub fn api_key_exchange(&self, payload: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
+        ops::api_key_exchange::handle(self, payload)
+    }
+    pub fn api_key_list(&self, payload: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
+        ops::api_key_list::handle(self, payload)
+    }
+    pub fn api_key_revoke(&self, payload: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
+        ops::api_key_revoke::handle(self, payload)
+    }
+
+    /// `certificate` is the verified client certificate of the connection
+    pub fn certificate_bind(
+        &self,
+        certificate: &Certificate,
+        payload: Bytes,
+    ) -> Result<Bytes, Box<dyn std::error::Error>> {
+        ops::certificate_bind::handle(self, certificate, payload)
+    }
+
+    pub fn certificate_unbind(&self, payload: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
+        ops::certificate_unbind::handle(self, payload)
+    }
+
+    pub fn delegation_create(&self, payload: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
+        ops::delegation_create::handle(self, payload)
+    }
+
     pub fn group_assign(&self, payload: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
-        Ok(Bytes::new())
+        ops::group_assign::handle(self, payload)
     }
     pub fn group_create(&self, payload: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
         ops::group_create::handle(self, payload)
     }
+    pub fn group_deny(&self, payload: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
+        ops::group_deny::handle(self, payload)
+    }
     pub fn group_drop(&self, payload: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
-        Ok(Bytes::new())
+        ops::group_drop::handle(self, payload)
+    }
+    pub fn group_inherit(&self, payload: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
+        ops::group_inherit::handle(self, payload)
+    }
+
+    pub fn group_members(&self, payload: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
+        ops::group_members::handle(self, payload)
+    }
+    pub fn group_nest(&self, payload: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
+        ops::group_nest::handle(self, payload)
+    }
+    pub fn group_share(&self, payload: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
+        ops::group_share::handle(self, payload)
+    }
+
+    pub fn link_drop(&self, payload: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
+        ops::link_drop::handle(self, payload)
+    }
+    pub fn link_put(&self, payload: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
+        ops::link_put::handle(self, payload)
     }
 
     pub fn login_finish(&self, payload: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
         ops::login_finish::handle(self, payload)
     }
+    pub fn login_second_factor(&self, payload: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
+        ops::login_second_factor::handle(self, payload)
+    }
     pub fn login_start(&self, payload: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
-        ops::login_finish::handle(self, payload)
+        ops::login_start::handle(self, None, payload)
+    }
+    /// `login_start`, also throttling the address the request came from
+    pub fn login_start_from(
+        &self,
+        source: IpAddr,
+        payload: Bytes,
+    ) -> Result<Bytes, Box<dyn std::error::Error>> {
+        ops::login_start::handle(self, Some(&source), payload)
+    }
+
+    pub fn logout(&self, payload: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
+        ops::logout::handle(self, payload)
+    }
+
+    pub fn password_change_finish(
+        &self,
+        payload: Bytes,
+    ) -> Result<Bytes, Box<dyn std::error::Error>> {
+        ops::password_change_finish::handle(self, payload)
+    }
+    pub fn password_change_start(
+        &self,
+        payload: Bytes,
+    ) -> Result<Bytes, Box<dyn std::error::Error>> {
+        ops::password_change_start::handle(self, payload)
+    }
+
+    pub fn permission_explain(&self, payload: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
+        ops::permission_explain::handle(self, payload)
     }
 
-    pub fn registration_finish(&self, payload: Bytes) -> Result<(), Box<dyn std::error::Error>> {
+    pub fn recovery_finish(&self, payload: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
+        ops::recovery_finish::handle(self, payload)
+    }
+    pub fn recovery_start(&self, payload: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
+        ops::recovery_start::handle(self, payload)
+    }
+
+    pub fn registration_finish(&self, payload: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
         ops::registration_finish::handle(self, payload)
     }
     pub fn registration_start(&self, payload: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
@@ -201,17 +875,99 @@ impl Core {
     }
 
     pub fn secret_get(&self, payload: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
-        Ok(Bytes::new())
+        ops::secret_get::handle(self, payload)
     }
     pub fn secret_put(&self, payload: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
-        Ok(Bytes::new())
+        ops::secret_put::handle(self, payload)
+    }
+
+    pub fn storage_delete(&self, payload: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
+        ops::storage_delete::handle(self, payload)
     }
 
     pub fn storage_put(&self, payload: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
-        ops::storage_put::handle(self, payload)
+        ops::storage_put::handle(self, false, payload)
+    }
+
+    /// `storage_put` of an entity that expires, see `req_with_ttl`
+    pub fn storage_put_with_ttl(
+        &self,
+        payload: Bytes,
+    ) -> Result<Bytes, Box<dyn std::error::Error>> {
+        ops::storage_put::handle(self, true, payload)
     }
 
     pub fn storage_query(&self, payload: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
         ops::storage_query::handle(self, payload)
     }
+
+    pub fn storage_renew(&self, payload: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
+        ops::storage_renew::handle(self, payload)
+    }
+
+    pub fn storage_traverse(&self, payload: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
+        ops::storage_traverse::handle(self, payload)
+    }
+
+    pub fn storage_update(&self, payload: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
+        ops::storage_update::handle(self, payload)
+    }
+
+    pub fn totp_enroll_finish(&self, payload: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
+        ops::totp_enroll_finish::handle(self, payload)
+    }
+    pub fn totp_enroll_start(&self, payload: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
+        ops::totp_enroll_start::handle(self, payload)
+    }
+}
+
+/// reads every stored OPAQUE server setup, generating and storing
+/// the first one if the store is new.
+///
+/// everything happens in a single write transaction so that two
+/// processes booting against a fresh store agree on the same setup.
+fn load_server_setups(
+    env: &Arc<Environment>,
+    setup_db: &Arc<Database<'static>>,
+) -> Result<BTreeMap<u32, ServerSetup<DefaultCipherSuite>>, Box<dyn std::error::Error>> {
+    let mut setups = BTreeMap::new();
+
+    let txn = WriteTransaction::new(env.clone())?;
+
+    {
+        let mut access = txn.access();
+        let mut cursor = txn.cursor(setup_db.clone())?;
+
+        let mut next = cursor.first::<[u8], [u8]>(&access).to_opt()?;
+
+        while let Some((setup_id, setup)) = next {
+            let setup_id = u32::from_be_bytes(setup_id.try_into()?);
+
+            let setup = match ServerSetup::<DefaultCipherSuite>::deserialize(setup) {
+                Ok(setup) => setup,
+                Err(err) => return Err(err.to_string().into()),
+            };
+
+            setups.insert(setup_id, setup);
+
+            next = cursor.next::<[u8], [u8]>(&access).to_opt()?;
+        }
+
+        if setups.is_empty() {
+            let setup = ServerSetup::<DefaultCipherSuite>::new(&mut OsRng);
+
+            access.put(
+                setup_db,
+                &0u32.to_be_bytes(),
+                &setup.serialize()[..],
+                put::Flags::NOOVERWRITE,
+            )?;
+
+            setups.insert(0, setup);
+        }
+    }
+
+    txn.commit()?;
+
+    Ok(setups)
 }
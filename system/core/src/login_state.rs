@@ -0,0 +1,191 @@
+use std::collections::BTreeMap;
+use std::time::SystemTime;
+
+use saferlmdb::{put, LmdbResultExt, WriteTransaction};
+use uuid::Uuid;
+
+use crate::Core;
+
+// pending OPAQUE logins
+//
+// every `login_start` gets a server issued nonce that `login_finish`
+// has to send back, so concurrent logins for the same user don't
+// clobber each other. nonces are UUIDv7, which makes them ordered by
+// issue time, so expiring and evicting is always from the front.
+//
+// nonce -> user_uuid.stage.state
+//
+// a login with a second factor goes through two stages, each with its own
+// nonce, and a nonce only works for the stage it was issued for.
+//
+// kept in memory unless the core was built `with_shared_login_state`,
+// in which case it goes in `auth_state_db` so any process sharing the
+// store can finish the login.
+
+/// how long a client has between `login_start` and `login_finish`
+pub const LOGIN_STATE_TTL: u64 = 60;
+
+/// oldest pending logins are evicted past this
+pub const MAX_PENDING_LOGINS: usize = 65_536;
+
+/// (user_uuid, stage.state)
+pub(crate) type PendingLogin = ([u8; 16], Vec<u8>);
+
+/// nonce -> pending login, when kept in memory
+pub(crate) type PendingLogins = BTreeMap<[u8; 16], PendingLogin>;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Stage {
+    /// between `login_start` and `login_finish`, state is the OPAQUE server state
+    Password = 0,
+    /// between `login_finish` and `login_second_factor`, state is the session key
+    SecondFactor = 1,
+}
+
+/// nonce
+pub(crate) fn insert(
+    core: &Core,
+    stage: Stage,
+    user_uuid: &[u8; 16],
+    state: &[u8],
+) -> Result<[u8; 16], Box<dyn std::error::Error>> {
+    let nonce = *Uuid::now_v7().as_bytes();
+
+    let mut stage_state = Vec::with_capacity(1 + state.len());
+
+    stage_state.push(stage as u8);
+    stage_state.extend_from_slice(state);
+
+    let state = &stage_state[..];
+    let expired_before = now_ms()?.saturating_sub(LOGIN_STATE_TTL * 1000);
+
+    if !core.shared_auth_state {
+        let mut auth_state = core.auth_state.lock();
+
+        while let Some((oldest, _)) = auth_state.first_key_value() {
+            if issued_ms(oldest) < expired_before || auth_state.len() >= MAX_PENDING_LOGINS {
+                auth_state.pop_first();
+            } else {
+                break;
+            }
+        }
+
+        auth_state.insert(nonce, (*user_uuid, state.to_vec()));
+
+        return Ok(nonce);
+    }
+
+    let txn = WriteTransaction::new(core.env.clone())?;
+
+    {
+        let mut access = txn.access();
+        let mut len = txn.db_stat(&core.auth_state_db)?.entries;
+
+        let mut evicted = vec![];
+
+        {
+            let mut cursor = txn.cursor(core.auth_state_db.clone())?;
+
+            let mut next = cursor.first::<[u8], [u8]>(&access).to_opt()?;
+
+            while let Some((key, _)) = next {
+                if issued_ms(key) < expired_before || len >= MAX_PENDING_LOGINS {
+                    evicted.push(key.to_vec());
+                    len -= 1;
+                } else {
+                    break;
+                }
+
+                next = cursor.next::<[u8], [u8]>(&access).to_opt()?;
+            }
+        }
+
+        for key in evicted {
+            access.del_key(&core.auth_state_db, &key[..])?;
+        }
+
+        let mut value = Vec::with_capacity(16 + state.len());
+
+        value.extend_from_slice(&user_uuid[..]);
+        value.extend_from_slice(state);
+
+        access.put(&core.auth_state_db, &nonce, &value, put::Flags::NOOVERWRITE)?;
+    }
+
+    txn.commit()?;
+
+    Ok(nonce)
+}
+
+/// (user_uuid, state)
+///
+/// a nonce can only be taken once, whether or not the login finishes,
+/// or it was sent to the wrong stage.
+pub(crate) fn take(
+    core: &Core,
+    stage: Stage,
+    nonce: &[u8; 16],
+) -> Result<Option<PendingLogin>, Box<dyn std::error::Error>> {
+    Ok(match take_any(core, nonce)? {
+        Some((user_uuid, state)) if state.first() == Some(&(stage as u8)) => {
+            Some((user_uuid, state[1..].to_vec()))
+        }
+        _ => None,
+    })
+}
+
+fn take_any(
+    core: &Core,
+    nonce: &[u8; 16],
+) -> Result<Option<PendingLogin>, Box<dyn std::error::Error>> {
+    let expired_before = now_ms()?.saturating_sub(LOGIN_STATE_TTL * 1000);
+
+    if issued_ms(nonce) < expired_before {
+        return Ok(None);
+    }
+
+    if !core.shared_auth_state {
+        return Ok(core.auth_state.lock().remove(nonce));
+    }
+
+    let txn = WriteTransaction::new(core.env.clone())?;
+
+    let user_uuid_state = {
+        let mut access = txn.access();
+
+        let user_uuid_state = match access
+            .get::<[u8; 16], [u8]>(&core.auth_state_db, nonce)
+            .to_opt()?
+        {
+            Some(value) => {
+                let user_uuid: [u8; 16] = value[0..16].try_into()?;
+                Some((user_uuid, value[16..].to_vec()))
+            }
+            None => None,
+        };
+
+        if user_uuid_state.is_some() {
+            access.del_key(&core.auth_state_db, nonce)?;
+        }
+
+        user_uuid_state
+    };
+
+    txn.commit()?;
+
+    Ok(user_uuid_state)
+}
+
+/// the UUIDv7 timestamp is the first 48 bits
+fn issued_ms(nonce: &[u8]) -> u64 {
+    let mut ms = [0u8; 8];
+    ms[2..8].copy_from_slice(&nonce[0..6]);
+
+    u64::from_be_bytes(ms)
+}
+
+fn now_ms() -> Result<u64, Box<dyn std::error::Error>> {
+    Ok(SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_millis() as u64)
+}
@@ -0,0 +1,64 @@
+// which version of the on-disk layout the store is at, so migrations
+// run once instead of scanning their databases on every start.
+//
+// a store without a version predates it, and gets every migration.
+// each one is safe to run again, so a store that stopped between a
+// migration and its version being written just redoes it.
+//
+// SCHEMA_VERSION -> version
+
+use saferlmdb::{put, LmdbResultExt, ReadTransaction, WriteTransaction};
+
+use crate::Core;
+
+const SCHEMA_VERSION: u8 = 0;
+
+/// the layout this version writes, usernames normalized, group
+/// permissions as bitsets and entities indexed by uuid
+pub const CURRENT_SCHEMA_VERSION: u32 = 1;
+
+fn version(core: &Core) -> Result<u32, Box<dyn std::error::Error>> {
+    let txn = ReadTransaction::new(core.env.clone())?;
+    let access = txn.access();
+
+    match access
+        .get::<[u8; 1], [u8]>(&core.meta_db, &[SCHEMA_VERSION])
+        .to_opt()?
+    {
+        Some(version) => Ok(u32::from_be_bytes(version.try_into()?)),
+        None => Ok(0),
+    }
+}
+
+/// brings the store up to `CURRENT_SCHEMA_VERSION`, fails if it was
+/// written by a newer version
+pub(crate) fn migrate(core: &Core) -> Result<(), Box<dyn std::error::Error>> {
+    let version = version(core)?;
+
+    if version > CURRENT_SCHEMA_VERSION {
+        return Err("store was written by a newer version".into());
+    }
+
+    if version == CURRENT_SCHEMA_VERSION {
+        return Ok(());
+    }
+
+    if version < 1 {
+        crate::username::migrate(core)?;
+        crate::group::migrate(core)?;
+        crate::entity::migrate(core)?;
+    }
+
+    let txn = WriteTransaction::new(core.env.clone())?;
+
+    txn.access().put(
+        &core.meta_db,
+        &[SCHEMA_VERSION],
+        &CURRENT_SCHEMA_VERSION.to_be_bytes(),
+        put::Flags::empty(),
+    )?;
+
+    txn.commit()?;
+
+    Ok(())
+}
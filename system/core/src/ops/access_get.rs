@@ -1,21 +1,28 @@
+use crate::refresh::{self, Status};
 use crate::Core;
 use bytes::{BufMut, Bytes, BytesMut};
-use saferlmdb::ReadTransaction;
+use cbwaw::token::{self, Kind, LEN_WITHOUT_GROUP};
+use saferlmdb::{ConstAccessor, ConstTransaction, ReadTransaction, WriteTransaction};
 
-/// refresh_token.action?group
+/// refresh_token.action.rotate?group
+///
+/// with `rotate` a new refresh token is handed out along with the
+/// access token, and the one sent can't be used again.
 pub fn req(
     refresh_token: &[u8],
     action: u8,
     group_uuid: Option<&[u8; 16]>,
+    rotate: bool,
 ) -> Result<Bytes, Box<dyn std::error::Error>> {
     let mut buf = BytesMut::with_capacity(if group_uuid.is_some() {
-        57 + 16 + 1
+        LEN_WITHOUT_GROUP + 2 + 16
     } else {
-        57 + 1
+        LEN_WITHOUT_GROUP + 2
     });
 
     buf.put(refresh_token);
     buf.put_u8(action);
+    buf.put_u8(rotate as u8);
 
     if let Some(group_uuid) = group_uuid {
         buf.put(&group_uuid[..]);
@@ -24,35 +31,194 @@ pub fn req(
     Ok(buf.into())
 }
 
-/// access token
+/// access_token
+/// refresh_token.access_token (rotate)
 pub fn handle(core: &Core, bytes: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
     let with_group = match bytes.len() {
-        74 => true,
-        58 => false,
+        len if len == LEN_WITHOUT_GROUP + 2 + 16 => true,
+        len if len == LEN_WITHOUT_GROUP + 2 => false,
         _ => return Err("invalid format".into()),
     };
 
-    let user_uuid = cbwaw::token::verify_without_group(0, &bytes[..57])?;
-    let action = bytes[57];
+    let token_keys = core.token_keys()?;
 
-    // todo: convert this to all be fixed 33 byte array creation/assignment
-    let mut check = Vec::with_capacity(32);
+    let refresh_token = &bytes[..LEN_WITHOUT_GROUP];
 
-    check.extend_from_slice(&user_uuid[..]);
-    check.push(action);
+    let user_uuid = token::verify(
+        &token_keys,
+        &core.token_settings,
+        Kind::Refresh,
+        0,
+        refresh_token,
+    )?
+    .user_uuid;
+    let action = bytes[LEN_WITHOUT_GROUP];
+    let rotate = bytes[LEN_WITHOUT_GROUP + 1] == 1;
 
-    if with_group {
-        let group_uuid: [u8; 16] = bytes[57 + 1..57 + 1 + 16].try_into()?;
+    let group_uuid: Option<[u8; 16]> = if with_group {
+        Some(bytes[LEN_WITHOUT_GROUP + 2..LEN_WITHOUT_GROUP + 2 + 16].try_into()?)
+    } else {
+        None
+    };
+
+    let next_refresh_token = if rotate {
+        let txn = WriteTransaction::new(core.env.clone())?;
+
+        let next_refresh_token = {
+            let mut access = txn.access();
 
-        check.extend_from_slice(&group_uuid[..]);
+            match check(
+                core,
+                &txn,
+                &access,
+                &user_uuid,
+                refresh_token,
+                action,
+                &group_uuid,
+            )? {
+                Status::Current(family_id) => Some(refresh::rotate(
+                    core,
+                    &token_keys,
+                    &mut access,
+                    &family_id,
+                    &user_uuid,
+                )?),
+                // issued without a family, so this starts one
+                Status::Unknown => {
+                    let next_refresh_token = token::gen(
+                        &token_keys,
+                        &core.token_settings,
+                        Kind::Refresh,
+                        0,
+                        &user_uuid,
+                        None,
+                    )?;
+                    refresh::start(core, &mut access, &next_refresh_token)?;
 
-        let txn = ReadTransaction::new(core.env.clone())?;
-        let access = txn.access();
+                    Some(next_refresh_token)
+                }
+                Status::Reused(family_id) => {
+                    refresh::revoke(core, &mut access, &family_id)?;
+                    None
+                }
+                Status::Revoked => None,
+            }
+        };
 
-        access.get::<[u8], [u8]>(&core.group_db.clone(), &check)?;
+        txn.commit()?;
 
-        cbwaw::token::gen_with_group(action, &user_uuid, &group_uuid)
+        match next_refresh_token {
+            Some(next_refresh_token) => Some(next_refresh_token),
+            None => return Err("refresh token has been revoked".into()),
+        }
     } else {
-        cbwaw::token::gen_without_group(action, &user_uuid)
+        let status = {
+            let txn = ReadTransaction::new(core.env.clone())?;
+            let access = txn.access();
+
+            check(
+                core,
+                &txn,
+                &access,
+                &user_uuid,
+                refresh_token,
+                action,
+                &group_uuid,
+            )?
+        };
+
+        match status {
+            Status::Current(_) | Status::Unknown => None,
+            Status::Reused(family_id) => {
+                let txn = WriteTransaction::new(core.env.clone())?;
+                refresh::revoke(core, &mut txn.access(), &family_id)?;
+                txn.commit()?;
+
+                return Err("refresh token has been revoked".into());
+            }
+            Status::Revoked => return Err("refresh token has been revoked".into()),
+        }
+    };
+
+    let access_token = token::gen(
+        &token_keys,
+        &core.token_settings,
+        Kind::Access,
+        action,
+        &user_uuid,
+        group_uuid.as_ref(),
+    )?;
+
+    match next_refresh_token {
+        Some(next_refresh_token) => {
+            let mut buf = BytesMut::with_capacity(next_refresh_token.len() + access_token.len());
+
+            buf.put(&next_refresh_token[..]);
+            buf.put(&access_token[..]);
+
+            Ok(buf.into())
+        }
+        None => Ok(access_token),
     }
 }
+
+/// (refresh_token, access_token)
+pub fn res(res: Bytes) -> Result<(Bytes, Bytes), Box<dyn std::error::Error>> {
+    if res.len() < LEN_WITHOUT_GROUP {
+        return Err("response does not include refresh token".into());
+    }
+
+    Ok((
+        res.slice(..LEN_WITHOUT_GROUP),
+        res.slice(LEN_WITHOUT_GROUP..),
+    ))
+}
+
+/// makes sure the refresh token can still be used, and that the
+/// user has been given `action` on the group.
+fn check(
+    core: &Core,
+    txn: &ConstTransaction,
+    access: &ConstAccessor,
+    user_uuid: &[u8; 16],
+    refresh_token: &[u8],
+    action: u8,
+    group_uuid: &Option<[u8; 16]>,
+) -> Result<Status, Box<dyn std::error::Error>> {
+    if super::logout::is_revoked(core, access, user_uuid, refresh_token)? {
+        return Err("refresh token has been revoked".into());
+    }
+
+    let status = refresh::status(core, access, refresh_token)?;
+
+    // revoking the family is up to the caller
+    if let Status::Reused(_) | Status::Revoked = status {
+        return Ok(status);
+    }
+
+    if let Some(group_uuid) = group_uuid {
+        permitted(core, txn, access, user_uuid, action, group_uuid)?;
+    }
+
+    Ok(status)
+}
+
+/// fails unless the user has what the action needs in the group,
+/// through nested groups too, see `permissions::required`
+pub(crate) fn permitted(
+    core: &Core,
+    txn: &ConstTransaction,
+    access: &ConstAccessor,
+    user_uuid: &[u8; 16],
+    action: u8,
+    group_uuid: &[u8; 16],
+) -> Result<(), Box<dyn std::error::Error>> {
+    crate::group::require_member(
+        core,
+        txn,
+        access,
+        user_uuid,
+        group_uuid,
+        crate::permissions::required(action),
+    )
+}
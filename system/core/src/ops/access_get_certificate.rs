@@ -0,0 +1,62 @@
+use crate::certificate::Certificate;
+use crate::Core;
+use bytes::{BufMut, Bytes, BytesMut};
+use cbwaw::token::{self, Kind};
+use saferlmdb::ReadTransaction;
+
+/// action?group
+///
+/// sent over a TLS connection with a client certificate bound to the
+/// user with `certificate_bind`, in place of a refresh token.
+pub fn req(action: u8, group_uuid: Option<&[u8; 16]>) -> Result<Bytes, Box<dyn std::error::Error>> {
+    let mut buf = BytesMut::with_capacity(1 + 16);
+
+    buf.put_u8(action);
+
+    if let Some(group_uuid) = group_uuid {
+        buf.put(&group_uuid[..]);
+    }
+
+    Ok(buf.into())
+}
+
+/// access_token
+///
+/// `certificate` is the one the client presented, after it was verified
+/// against the trusted CAs.
+pub fn handle(
+    core: &Core,
+    certificate: &Certificate,
+    bytes: Bytes,
+) -> Result<Bytes, Box<dyn std::error::Error>> {
+    let group_uuid: Option<[u8; 16]> = match bytes.len() {
+        17 => Some(bytes[1..].try_into()?),
+        1 => None,
+        _ => return Err("invalid format".into()),
+    };
+
+    let action = bytes[0];
+
+    let txn = ReadTransaction::new(core.env.clone())?;
+    let access = txn.access();
+
+    let user_uuid = match crate::certificate::user(core, &access, certificate)? {
+        Some(user_uuid) => user_uuid,
+        None => return Err("certificate is not bound to a user".into()),
+    };
+
+    if let Some(group_uuid) = &group_uuid {
+        super::access_get::permitted(core, &txn, &access, &user_uuid, action, group_uuid)?;
+    }
+
+    let token_keys = core.token_keys()?;
+
+    Ok(token::gen(
+        &token_keys,
+        &core.token_settings,
+        Kind::Access,
+        action,
+        &user_uuid,
+        group_uuid.as_ref(),
+    )?)
+}
@@ -0,0 +1,113 @@
+use std::time::SystemTime;
+
+use crate::api_key::{self, Record, API_KEY_LEN, MAX_API_KEY_LIFETIME};
+use crate::Core;
+use bytes::{BufMut, Bytes, BytesMut};
+use cbwaw::token::LEN_WITHOUT_GROUP;
+use rand::{rngs::OsRng, RngCore};
+use saferlmdb::WriteTransaction;
+use uuid::Uuid;
+
+/// refresh_token.lifetime.actions_len.actions.groups
+///
+/// the key can only be exchanged for access tokens for `actions`, and
+/// only for the `groups` when asking for one with a group.
+pub fn req(
+    refresh_token: &[u8],
+    lifetime: u64,
+    actions: &[u8],
+    groups: &[[u8; 16]],
+) -> Result<Bytes, Box<dyn std::error::Error>> {
+    if actions.len() > 255 {
+        return Err("cannot have more than 255 actions".into());
+    }
+
+    let mut buf =
+        BytesMut::with_capacity(LEN_WITHOUT_GROUP + 8 + 1 + actions.len() + groups.len() * 16);
+
+    buf.put(refresh_token);
+    buf.put_u64(lifetime);
+    buf.put_u8(actions.len() as u8);
+    buf.put(actions);
+
+    for group in groups {
+        buf.put(&group[..]);
+    }
+
+    Ok(buf.into())
+}
+
+/// api_key
+///
+/// handed out once, only a hash of it is kept.
+pub fn handle(core: &Core, bytes: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
+    if bytes.len() < LEN_WITHOUT_GROUP + 9 {
+        return Err("invalid format".into());
+    }
+
+    let refresh_token = &bytes[..LEN_WITHOUT_GROUP];
+    let lifetime = u64::from_be_bytes(bytes[LEN_WITHOUT_GROUP..LEN_WITHOUT_GROUP + 8].try_into()?);
+
+    let actions_start = LEN_WITHOUT_GROUP + 9;
+    let groups_start = actions_start + bytes[LEN_WITHOUT_GROUP + 8] as usize;
+
+    if bytes.len() < groups_start || !(bytes.len() - groups_start).is_multiple_of(16) {
+        return Err("invalid format".into());
+    }
+
+    let actions = bytes[actions_start..groups_start].to_vec();
+
+    if actions.is_empty() {
+        return Err("api key needs at least one action".into());
+    }
+
+    if lifetime == 0 || lifetime > MAX_API_KEY_LIFETIME {
+        return Err("api key lifetime is out of range".into());
+    }
+
+    let groups = bytes[groups_start..]
+        .chunks(16)
+        .map(|group| group.try_into())
+        .collect::<Result<Vec<[u8; 16]>, _>>()?;
+
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_secs();
+
+    let key_id = *Uuid::now_v7().as_bytes();
+
+    let mut secret = [0u8; 32];
+    OsRng.fill_bytes(&mut secret);
+
+    let txn = WriteTransaction::new(core.env.clone())?;
+
+    {
+        let mut access = txn.access();
+
+        // long lived, so only right after entering the password
+        let user_uuid = crate::refresh::fresh(core, &access, refresh_token)?;
+
+        api_key::store(
+            core,
+            &mut access,
+            &key_id,
+            &Record {
+                user_uuid,
+                hash: api_key::hash(&secret),
+                expires: now + lifetime,
+                last_used: 0,
+                actions,
+                groups,
+            },
+        )?;
+    }
+
+    txn.commit()?;
+
+    let mut buf = BytesMut::with_capacity(API_KEY_LEN);
+
+    buf.put(&key_id[..]);
+    buf.put(&secret[..]);
+
+    Ok(buf.into())
+}
@@ -0,0 +1,102 @@
+use std::time::SystemTime;
+
+use crate::api_key::API_KEY_LEN;
+use crate::Core;
+use bytes::{BufMut, Bytes, BytesMut};
+use cbwaw::token::{self, Kind};
+use saferlmdb::WriteTransaction;
+use subtle::ConstantTimeEq;
+
+/// api_key.action?group
+pub fn req(
+    api_key: &[u8],
+    action: u8,
+    group_uuid: Option<&[u8; 16]>,
+) -> Result<Bytes, Box<dyn std::error::Error>> {
+    if api_key.len() != API_KEY_LEN {
+        return Err("invalid api key".into());
+    }
+
+    let mut buf = BytesMut::with_capacity(API_KEY_LEN + 1 + 16);
+
+    buf.put(api_key);
+    buf.put_u8(action);
+
+    if let Some(group_uuid) = group_uuid {
+        buf.put(&group_uuid[..]);
+    }
+
+    Ok(buf.into())
+}
+
+/// access_token
+///
+/// the key has to have been created with the action (and group), and
+/// the user it belongs to still has to be allowed to do it.
+pub fn handle(core: &Core, bytes: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
+    let group_uuid: Option<[u8; 16]> = match bytes.len() {
+        len if len == API_KEY_LEN + 1 + 16 => Some(bytes[API_KEY_LEN + 1..].try_into()?),
+        len if len == API_KEY_LEN + 1 => None,
+        _ => return Err("invalid format".into()),
+    };
+
+    let key_id: [u8; 16] = bytes[0..16].try_into()?;
+    let secret = &bytes[16..API_KEY_LEN];
+    let action = bytes[API_KEY_LEN];
+
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_secs();
+
+    let txn = WriteTransaction::new(core.env.clone())?;
+
+    let user_uuid = {
+        let mut access = txn.access();
+
+        let mut record = match crate::api_key::load(core, &access, &key_id)? {
+            Some(record) if bool::from(record.hash.ct_eq(&crate::api_key::hash(secret))) => record,
+            _ => return Err("invalid api key".into()),
+        };
+
+        if record.expires < now {
+            return Err("api key has expired".into());
+        }
+
+        if !record.actions.contains(&action) {
+            return Err("api key can't be used for this action".into());
+        }
+
+        if let Some(group_uuid) = &group_uuid {
+            if !record.groups.contains(group_uuid) {
+                return Err("api key can't be used for this group".into());
+            }
+
+            super::access_get::permitted(
+                core,
+                &txn,
+                &access,
+                &record.user_uuid,
+                action,
+                group_uuid,
+            )?;
+        }
+
+        record.last_used = now;
+        crate::api_key::store(core, &mut access, &key_id, &record)?;
+
+        record.user_uuid
+    };
+
+    txn.commit()?;
+
+    let token_keys = core.token_keys()?;
+
+    Ok(token::gen(
+        &token_keys,
+        &core.token_settings,
+        Kind::Access,
+        action,
+        &user_uuid,
+        group_uuid.as_ref(),
+    )?)
+}
@@ -0,0 +1,75 @@
+use crate::Core;
+use bitcode::{Decode, Encode};
+use bytes::Bytes;
+use cbwaw::token::LEN_WITHOUT_GROUP;
+use saferlmdb::{LmdbResultExt, ReadTransaction};
+
+#[derive(Encode, Decode, PartialEq, Debug)]
+pub struct ApiKey {
+    pub key_id: [u8; 16],
+    pub expires: u64,
+    /// 0 if it has never been exchanged
+    pub last_used: u64,
+    pub actions: Vec<u8>,
+    pub groups: Vec<[u8; 16]>,
+}
+
+/// refresh_token
+pub fn req(refresh_token: &[u8]) -> Result<Bytes, Box<dyn std::error::Error>> {
+    Ok(Bytes::copy_from_slice(refresh_token))
+}
+
+/// every api key of the user, oldest first
+pub fn handle(core: &Core, bytes: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
+    if bytes.len() != LEN_WITHOUT_GROUP {
+        return Err("invalid format".into());
+    }
+
+    let txn = ReadTransaction::new(core.env.clone())?;
+    let access = txn.access();
+
+    let (user_uuid, _) = crate::refresh::usable(core, &access, &bytes)?;
+
+    let mut key_ids = vec![];
+
+    {
+        let mut cursor = txn.cursor(core.api_key_db.clone())?;
+
+        let mut next = cursor
+            .seek_range_k::<[u8], [u8]>(&access, &user_uuid[..])
+            .to_opt()?;
+
+        while let Some((key, _)) = next {
+            if key.len() != 32 || key[0..16] != user_uuid[..] {
+                break;
+            }
+
+            let key_id: [u8; 16] = key[16..32].try_into()?;
+            key_ids.push(key_id);
+
+            next = cursor.next::<[u8], [u8]>(&access).to_opt()?;
+        }
+    }
+
+    let mut api_keys = Vec::with_capacity(key_ids.len());
+
+    for key_id in key_ids {
+        if let Some(record) = crate::api_key::load(core, &access, &key_id)? {
+            api_keys.push(ApiKey {
+                key_id,
+                expires: record.expires,
+                last_used: record.last_used,
+                actions: record.actions,
+                groups: record.groups,
+            });
+        }
+    }
+
+    let encoded: Vec<u8> = bitcode::encode(&api_keys);
+
+    Ok(encoded.into())
+}
+
+pub fn res(res: Bytes) -> Result<Vec<ApiKey>, Box<dyn std::error::Error>> {
+    Ok(bitcode::decode(&res)?)
+}
@@ -0,0 +1,42 @@
+use crate::Core;
+use bytes::{BufMut, Bytes, BytesMut};
+use cbwaw::token::LEN_WITHOUT_GROUP;
+use saferlmdb::WriteTransaction;
+
+/// refresh_token.key_id
+pub fn req(refresh_token: &[u8], key_id: &[u8; 16]) -> Result<Bytes, Box<dyn std::error::Error>> {
+    let mut buf = BytesMut::with_capacity(LEN_WITHOUT_GROUP + 16);
+
+    buf.put(refresh_token);
+    buf.put(&key_id[..]);
+
+    Ok(buf.into())
+}
+
+pub fn handle(core: &Core, bytes: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
+    if bytes.len() != LEN_WITHOUT_GROUP + 16 {
+        return Err("invalid format".into());
+    }
+
+    let refresh_token = &bytes[..LEN_WITHOUT_GROUP];
+    let key_id: [u8; 16] = bytes[LEN_WITHOUT_GROUP..].try_into()?;
+
+    let txn = WriteTransaction::new(core.env.clone())?;
+
+    {
+        let mut access = txn.access();
+
+        let (user_uuid, _) = crate::refresh::usable(core, &access, refresh_token)?;
+
+        match crate::api_key::load(core, &access, &key_id)? {
+            Some(record) if record.user_uuid == user_uuid => {
+                crate::api_key::delete(core, &mut access, &key_id, &user_uuid)?
+            }
+            _ => return Err("unknown api key".into()),
+        }
+    }
+
+    txn.commit()?;
+
+    Ok(Bytes::new())
+}
@@ -0,0 +1,58 @@
+use crate::certificate::{self, Certificate, CertificateMatch};
+use crate::Core;
+use bytes::{BufMut, Bytes, BytesMut};
+use cbwaw::token::LEN_WITHOUT_GROUP;
+use saferlmdb::WriteTransaction;
+
+/// refresh_token.match
+///
+/// binds the client certificate of the connection the request is sent
+/// over, by its subject or by the SHA-256 of its SubjectPublicKeyInfo,
+/// depending on `certificate_match`.
+pub fn req(
+    refresh_token: &[u8],
+    certificate_match: CertificateMatch,
+) -> Result<Bytes, Box<dyn std::error::Error>> {
+    let mut buf = BytesMut::with_capacity(LEN_WITHOUT_GROUP + 1);
+
+    buf.put(refresh_token);
+    buf.put_u8(certificate_match as u8);
+
+    Ok(buf.into())
+}
+
+/// a client presenting a matching certificate can get access tokens
+/// for the user with `access_get_certificate` until it is unbound.
+///
+/// `certificate` is the verified client certificate of the connection,
+/// so only a certificate the client holds the key for can be bound.
+pub fn handle(
+    core: &Core,
+    certificate: &Certificate,
+    bytes: Bytes,
+) -> Result<Bytes, Box<dyn std::error::Error>> {
+    if bytes.len() != LEN_WITHOUT_GROUP + 1 {
+        return Err("invalid format".into());
+    }
+
+    let refresh_token = &bytes[..LEN_WITHOUT_GROUP];
+    let key = certificate::key_of(
+        certificate,
+        CertificateMatch::try_from(bytes[LEN_WITHOUT_GROUP])?,
+    )?;
+
+    let txn = WriteTransaction::new(core.env.clone())?;
+
+    {
+        let mut access = txn.access();
+
+        // stands in for the password from then on
+        let user_uuid = crate::refresh::fresh(core, &access, refresh_token)?;
+
+        certificate::bind(core, &mut access, &user_uuid, &key)?;
+    }
+
+    txn.commit()?;
+
+    Ok(Bytes::new())
+}
@@ -0,0 +1,46 @@
+use crate::certificate::{self, CertificateMatch};
+use crate::Core;
+use bytes::{BufMut, Bytes, BytesMut};
+use cbwaw::token::LEN_WITHOUT_GROUP;
+use saferlmdb::WriteTransaction;
+
+/// refresh_token.match.value
+pub fn req(
+    refresh_token: &[u8],
+    certificate_match: CertificateMatch,
+    value: &[u8],
+) -> Result<Bytes, Box<dyn std::error::Error>> {
+    let mut buf = BytesMut::with_capacity(LEN_WITHOUT_GROUP + 1 + value.len());
+
+    buf.put(refresh_token);
+    buf.put_u8(certificate_match as u8);
+    buf.put(value);
+
+    Ok(buf.into())
+}
+
+pub fn handle(core: &Core, bytes: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
+    if bytes.len() <= LEN_WITHOUT_GROUP + 1 {
+        return Err("invalid format".into());
+    }
+
+    let refresh_token = &bytes[..LEN_WITHOUT_GROUP];
+    let key = certificate::key(
+        CertificateMatch::try_from(bytes[LEN_WITHOUT_GROUP])?,
+        &bytes[LEN_WITHOUT_GROUP + 1..],
+    )?;
+
+    let txn = WriteTransaction::new(core.env.clone())?;
+
+    {
+        let mut access = txn.access();
+
+        let (user_uuid, _) = crate::refresh::usable(core, &access, refresh_token)?;
+
+        certificate::unbind(core, &mut access, &user_uuid, &key)?;
+    }
+
+    txn.commit()?;
+
+    Ok(Bytes::new())
+}
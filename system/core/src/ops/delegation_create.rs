@@ -0,0 +1,65 @@
+use crate::permissions::Permissions;
+use crate::Core;
+use bytes::Bytes;
+use cbwaw::delegated;
+use cbwaw::token::{self, Kind, TokenError, LEN_WITH_GROUP};
+use saferlmdb::ReadTransaction;
+
+/// access_token
+///
+/// the access token has to be for a storage op, and
+/// for a group the user can share in.
+pub fn req(access_token: &[u8]) -> Result<Bytes, Box<dyn std::error::Error>> {
+    Ok(Bytes::copy_from_slice(access_token))
+}
+
+/// delegated_token
+///
+/// for the same action and group as the access token, without any
+/// caveats. the holder narrows it with `cbwaw::delegated::attenuate`
+/// before handing it out.
+pub fn handle(core: &Core, bytes: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
+    if bytes.len() != LEN_WITH_GROUP {
+        return Err("invalid format".into());
+    }
+
+    let action = token::parse(&bytes)?.action;
+
+    if !(12..=18).contains(&action) {
+        return Err(TokenError::Action.into());
+    }
+
+    let token_keys = core.token_keys()?;
+
+    let token = token::verify(
+        &token_keys,
+        &core.token_settings,
+        Kind::Access,
+        action,
+        &bytes,
+    )?;
+
+    let group_uuid = token.group_uuid.ok_or(TokenError::Group)?;
+
+    {
+        let txn = ReadTransaction::new(core.env.clone())?;
+        let access = txn.access();
+
+        crate::group::require_member(
+            core,
+            &txn,
+            &access,
+            &token.user_uuid,
+            &group_uuid,
+            Permissions::SHARE,
+        )?;
+    }
+
+    Ok(delegated::gen(
+        &token_keys,
+        &core.token_settings,
+        action,
+        &token.user_uuid,
+        &group_uuid,
+    )?)
+}
@@ -1 +1,86 @@
+use crate::group;
+use crate::permissions::Permissions;
+use crate::Core;
+use bytes::{BufMut, Bytes, BytesMut};
+use cbwaw::token::LEN_WITHOUT_GROUP;
+use saferlmdb::{LmdbResultExt, WriteTransaction};
 
+/// refresh_token.group_uuid.user_uuid.permissions
+///
+/// adds the user to the group, or replaces their permissions if they
+/// are already in it.
+pub fn req(
+    refresh_token: &[u8],
+    group_uuid: &[u8; 16],
+    user_uuid: &[u8; 16],
+    permissions: Permissions,
+) -> Result<Bytes, Box<dyn std::error::Error>> {
+    let mut buf = BytesMut::with_capacity(LEN_WITHOUT_GROUP + 16 + 16 + 4);
+
+    buf.put(refresh_token);
+    buf.put(&group_uuid[..]);
+    buf.put(&user_uuid[..]);
+    buf.put(&permissions.to_be_bytes()[..]);
+
+    Ok(buf.into())
+}
+
+/// only admins of the group can assign members, and only permissions
+/// that are built in or defined in ordinary.toml
+pub fn handle(core: &Core, bytes: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
+    if bytes.len() != LEN_WITHOUT_GROUP + 16 + 16 + 4 {
+        return Err("invalid format".into());
+    }
+
+    let refresh_token = &bytes[..LEN_WITHOUT_GROUP];
+    let group_uuid: [u8; 16] = bytes[LEN_WITHOUT_GROUP..LEN_WITHOUT_GROUP + 16].try_into()?;
+    let user_uuid: [u8; 16] = bytes[LEN_WITHOUT_GROUP + 16..LEN_WITHOUT_GROUP + 32].try_into()?;
+    let permissions = Permissions::from_be_bytes(bytes[LEN_WITHOUT_GROUP + 32..].try_into()?);
+
+    if permissions.is_empty() {
+        return Err("members need at least one permission".into());
+    }
+
+    if !core.assignable_permissions().contains(permissions) {
+        return Err("unknown permission".into());
+    }
+
+    let txn = WriteTransaction::new(core.env.clone())?;
+
+    {
+        let mut access = txn.access();
+
+        let (admin_uuid, _) = crate::refresh::usable(core, &access, refresh_token)?;
+
+        if group::require_member(
+            core,
+            &txn,
+            &access,
+            &admin_uuid,
+            &group_uuid,
+            Permissions::ADMIN,
+        )
+        .is_err()
+        {
+            return Err("only group admins can assign members".into());
+        }
+
+        if access
+            .get::<[u8; 16], [u8]>(&core.user_db, &user_uuid)
+            .to_opt()?
+            .is_none()
+        {
+            return Err("unknown user".into());
+        }
+
+        if !permissions.contains(Permissions::ADMIN) {
+            group::keep_admin(core, &txn, &access, &group_uuid, &user_uuid)?;
+        }
+
+        group::set(core, &mut access, &group_uuid, &user_uuid, permissions)?;
+    }
+
+    txn.commit()?;
+
+    Ok(Bytes::new())
+}
@@ -1,39 +1,52 @@
+use crate::group;
 use crate::Core;
 use bytes::Bytes;
-use cbwaw::token;
-use saferlmdb::{put, WriteTransaction};
+use cbwaw::token::{self, Kind, LEN_WITHOUT_GROUP};
+use saferlmdb::WriteTransaction;
 use uuid::Uuid;
 
 pub fn req(access_token: &[u8]) -> Result<Bytes, Box<dyn std::error::Error>> {
     Ok(Bytes::copy_from_slice(access_token))
 }
 
+/// the user creating the group is its first admin, with every
+/// permission the group can assign
 pub fn handle(core: &Core, bytes: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
     let len = bytes.len();
 
-    if len < 57 {
+    if len < LEN_WITHOUT_GROUP {
         return Err("req does not include access token".into());
     }
 
-    let user_uuid = token::verify_without_group(3, &bytes[0..57])?;
+    let token_keys = core.token_keys()?;
+
+    let user_uuid = token::verify(
+        &token_keys,
+        &core.token_settings,
+        Kind::Access,
+        3,
+        &bytes[0..LEN_WITHOUT_GROUP],
+    )?
+    .user_uuid;
 
     let uuid = Uuid::new_v4();
     let group_uuid = uuid.as_bytes();
 
-    let mut rule = Vec::with_capacity(33);
-
-    rule.extend_from_slice(&user_uuid[..]);
-    rule.extend_from_slice(&group_uuid[..]);
-    // read/write
-    rule.push(0);
-
     let txn = WriteTransaction::new(core.env.clone())?;
 
     {
         let mut access = txn.access();
-        access.put::<[u8], [u8]>(&core.group_db, &rule, &[], put::Flags::empty())?;
+        group::set(
+            core,
+            &mut access,
+            group_uuid,
+            &user_uuid,
+            core.assignable_permissions(),
+        )?;
     }
 
+    txn.commit()?;
+
     Ok(Bytes::copy_from_slice(group_uuid))
 }
 
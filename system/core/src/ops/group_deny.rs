@@ -0,0 +1,75 @@
+use crate::group;
+use crate::permissions::Permissions;
+use crate::Core;
+use bytes::{BufMut, Bytes, BytesMut};
+use cbwaw::token::LEN_WITHOUT_GROUP;
+use saferlmdb::WriteTransaction;
+
+/// refresh_token.group_uuid.subject_uuid.permissions
+///
+/// denies the subject (a user, a nested group, or an entity) the
+/// permissions in the group whatever it is allowed, or lifts the deny
+/// with `Permissions::NONE`.
+pub fn req(
+    refresh_token: &[u8],
+    group_uuid: &[u8; 16],
+    subject_uuid: &[u8; 16],
+    permissions: Permissions,
+) -> Result<Bytes, Box<dyn std::error::Error>> {
+    let mut buf = BytesMut::with_capacity(LEN_WITHOUT_GROUP + 16 + 16 + 4);
+
+    buf.put(refresh_token);
+    buf.put(&group_uuid[..]);
+    buf.put(&subject_uuid[..]);
+    buf.put(&permissions.to_be_bytes()[..]);
+
+    Ok(buf.into())
+}
+
+/// only admins of the group can deny
+pub fn handle(core: &Core, bytes: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
+    if bytes.len() != LEN_WITHOUT_GROUP + 16 + 16 + 4 {
+        return Err("invalid format".into());
+    }
+
+    let refresh_token = &bytes[..LEN_WITHOUT_GROUP];
+    let group_uuid: [u8; 16] = bytes[LEN_WITHOUT_GROUP..LEN_WITHOUT_GROUP + 16].try_into()?;
+    let subject_uuid: [u8; 16] =
+        bytes[LEN_WITHOUT_GROUP + 16..LEN_WITHOUT_GROUP + 32].try_into()?;
+    let permissions = Permissions::from_be_bytes(bytes[LEN_WITHOUT_GROUP + 32..].try_into()?);
+
+    if !core.assignable_permissions().contains(permissions) {
+        return Err("unknown permission".into());
+    }
+
+    let txn = WriteTransaction::new(core.env.clone())?;
+
+    {
+        let mut access = txn.access();
+
+        let (admin_uuid, _) = crate::refresh::usable(core, &access, refresh_token)?;
+
+        if group::require_member(
+            core,
+            &txn,
+            &access,
+            &admin_uuid,
+            &group_uuid,
+            Permissions::ADMIN,
+        )
+        .is_err()
+        {
+            return Err("only group admins can deny".into());
+        }
+
+        if permissions.contains(Permissions::ADMIN) {
+            group::keep_admin(core, &txn, &access, &group_uuid, &subject_uuid)?;
+        }
+
+        group::deny(core, &mut access, &subject_uuid, &group_uuid, permissions)?;
+    }
+
+    txn.commit()?;
+
+    Ok(Bytes::new())
+}
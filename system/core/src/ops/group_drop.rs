@@ -1,20 +1,71 @@
-use bytes::Bytes;
+use crate::group;
+use crate::permissions::Permissions;
+use crate::Core;
+use bytes::{BufMut, Bytes, BytesMut};
+use cbwaw::token::LEN_WITHOUT_GROUP;
+use saferlmdb::WriteTransaction;
 
-pub fn req(refresh_token: [u8; 73], action: u8) -> Result<Bytes, Box<dyn std::error::Error>> {
-    // TODO: validate refresh token
-    // TODO: check that user has
-    // let token = token::generate_access(action, &user, &group)?;
-    Ok(Bytes::new())
+/// refresh_token.group_uuid.user_uuid
+pub fn req(
+    refresh_token: &[u8],
+    group_uuid: &[u8; 16],
+    user_uuid: &[u8; 16],
+) -> Result<Bytes, Box<dyn std::error::Error>> {
+    let mut buf = BytesMut::with_capacity(LEN_WITHOUT_GROUP + 16 + 16);
+
+    buf.put(refresh_token);
+    buf.put(&group_uuid[..]);
+    buf.put(&user_uuid[..]);
+
+    Ok(buf.into())
 }
 
-pub fn handle(bytes: Bytes) {}
+/// admins can drop anyone, everyone else only themselves
+pub fn handle(core: &Core, bytes: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
+    if bytes.len() != LEN_WITHOUT_GROUP + 16 + 16 {
+        return Err("invalid format".into());
+    }
+
+    let refresh_token = &bytes[..LEN_WITHOUT_GROUP];
+    let group_uuid: [u8; 16] = bytes[LEN_WITHOUT_GROUP..LEN_WITHOUT_GROUP + 16].try_into()?;
+    let user_uuid: [u8; 16] = bytes[LEN_WITHOUT_GROUP + 16..].try_into()?;
+
+    let txn = WriteTransaction::new(core.env.clone())?;
 
-#[cfg(test)]
-mod test {
-    use super::*;
+    {
+        let mut access = txn.access();
 
-    #[test]
-    fn test() -> Result<(), Box<dyn std::error::Error>> {
-        Ok(())
+        let (caller_uuid, _) = crate::refresh::usable(core, &access, refresh_token)?;
+
+        if caller_uuid != user_uuid
+            && group::require_member(
+                core,
+                &txn,
+                &access,
+                &caller_uuid,
+                &group_uuid,
+                Permissions::ADMIN,
+            )
+            .is_err()
+        {
+            return Err("only group admins can drop other members".into());
+        }
+
+        if group::allowed(core, &access, &user_uuid, &group_uuid)?.is_empty() {
+            return Err("user is not in the group".into());
+        }
+
+        group::keep_admin(core, &txn, &access, &group_uuid, &user_uuid)?;
+        group::set(
+            core,
+            &mut access,
+            &group_uuid,
+            &user_uuid,
+            Permissions::NONE,
+        )?;
     }
+
+    txn.commit()?;
+
+    Ok(Bytes::new())
 }
@@ -0,0 +1,66 @@
+use crate::group;
+use crate::permissions::Permissions;
+use crate::Core;
+use bytes::{BufMut, Bytes, BytesMut};
+use cbwaw::token::LEN_WITHOUT_GROUP;
+use saferlmdb::WriteTransaction;
+
+/// refresh_token.group_uuid.entity_uuid.inherits
+///
+/// breaks inheritance for the group at the entity, so the subtree under
+/// it only gets what was shared with, or denied to, it and its
+/// descendants. or restores it.
+pub fn req(
+    refresh_token: &[u8],
+    group_uuid: &[u8; 16],
+    entity_uuid: &[u8; 16],
+    inherits: bool,
+) -> Result<Bytes, Box<dyn std::error::Error>> {
+    let mut buf = BytesMut::with_capacity(LEN_WITHOUT_GROUP + 16 + 16 + 1);
+
+    buf.put(refresh_token);
+    buf.put(&group_uuid[..]);
+    buf.put(&entity_uuid[..]);
+    buf.put_u8(inherits as u8);
+
+    Ok(buf.into())
+}
+
+/// only admins of the group can break inheritance
+pub fn handle(core: &Core, bytes: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
+    if bytes.len() != LEN_WITHOUT_GROUP + 16 + 16 + 1 {
+        return Err("invalid format".into());
+    }
+
+    let refresh_token = &bytes[..LEN_WITHOUT_GROUP];
+    let group_uuid: [u8; 16] = bytes[LEN_WITHOUT_GROUP..LEN_WITHOUT_GROUP + 16].try_into()?;
+    let entity_uuid: [u8; 16] = bytes[LEN_WITHOUT_GROUP + 16..LEN_WITHOUT_GROUP + 32].try_into()?;
+    let inherits = bytes[LEN_WITHOUT_GROUP + 32] == 1;
+
+    let txn = WriteTransaction::new(core.env.clone())?;
+
+    {
+        let mut access = txn.access();
+
+        let (admin_uuid, _) = crate::refresh::usable(core, &access, refresh_token)?;
+
+        if group::require_member(
+            core,
+            &txn,
+            &access,
+            &admin_uuid,
+            &group_uuid,
+            Permissions::ADMIN,
+        )
+        .is_err()
+        {
+            return Err("only group admins can break inheritance".into());
+        }
+
+        group::set_inherits(core, &mut access, &entity_uuid, &group_uuid, inherits)?;
+    }
+
+    txn.commit()?;
+
+    Ok(Bytes::new())
+}
@@ -0,0 +1,63 @@
+use crate::permissions::Permissions;
+use crate::Core;
+use bitcode::{Decode, Encode};
+use bytes::{BufMut, Bytes, BytesMut};
+use cbwaw::token::LEN_WITHOUT_GROUP;
+use saferlmdb::ReadTransaction;
+
+#[derive(Encode, Decode, PartialEq, Debug)]
+pub struct Member {
+    pub user_uuid: [u8; 16],
+    pub permissions: Permissions,
+}
+
+/// refresh_token.group_uuid
+pub fn req(
+    refresh_token: &[u8],
+    group_uuid: &[u8; 16],
+) -> Result<Bytes, Box<dyn std::error::Error>> {
+    let mut buf = BytesMut::with_capacity(LEN_WITHOUT_GROUP + 16);
+
+    buf.put(refresh_token);
+    buf.put(&group_uuid[..]);
+
+    Ok(buf.into())
+}
+
+/// every member of the group, for anyone in it. members of nested
+/// groups aren't listed.
+pub fn handle(core: &Core, bytes: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
+    if bytes.len() != LEN_WITHOUT_GROUP + 16 {
+        return Err("invalid format".into());
+    }
+
+    let refresh_token = &bytes[..LEN_WITHOUT_GROUP];
+    let group_uuid: [u8; 16] = bytes[LEN_WITHOUT_GROUP..].try_into()?;
+
+    let txn = ReadTransaction::new(core.env.clone())?;
+    let access = txn.access();
+
+    let (user_uuid, _) = crate::refresh::usable(core, &access, refresh_token)?;
+
+    let (permissions, _) = crate::group::resolve(core, &txn, &access, &user_uuid, &group_uuid)?;
+
+    if permissions.is_empty() {
+        return Err("user is not in the group".into());
+    }
+
+    let members: Vec<Member> = crate::group::members(core, &txn, &access, &group_uuid)?
+        .into_iter()
+        .map(|(user_uuid, permissions)| Member {
+            user_uuid,
+            permissions,
+        })
+        .collect();
+
+    let encoded: Vec<u8> = bitcode::encode(&members);
+
+    Ok(encoded.into())
+}
+
+pub fn res(res: Bytes) -> Result<Vec<Member>, Box<dyn std::error::Error>> {
+    Ok(bitcode::decode(&res)?)
+}
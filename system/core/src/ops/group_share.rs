@@ -0,0 +1,86 @@
+use crate::group;
+use crate::permissions::Permissions;
+use crate::Core;
+use bytes::{BufMut, Bytes, BytesMut};
+use cbwaw::token::LEN_WITHOUT_GROUP;
+use saferlmdb::{LmdbResultExt, WriteTransaction};
+
+/// refresh_token.group_uuid.entity_uuid.permissions
+///
+/// makes the entity, and with inherited permissions everything under
+/// it, available to the group, or takes it back with `Permissions::NONE`.
+pub fn req(
+    refresh_token: &[u8],
+    group_uuid: &[u8; 16],
+    entity_uuid: &[u8; 16],
+    permissions: Permissions,
+) -> Result<Bytes, Box<dyn std::error::Error>> {
+    let mut buf = BytesMut::with_capacity(LEN_WITHOUT_GROUP + 16 + 16 + 4);
+
+    buf.put(refresh_token);
+    buf.put(&group_uuid[..]);
+    buf.put(&entity_uuid[..]);
+    buf.put(&permissions.to_be_bytes()[..]);
+
+    Ok(buf.into())
+}
+
+/// only admins of the group can share, and no more than they can share
+/// the entity with, see `entity::shareable`
+pub fn handle(core: &Core, bytes: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
+    if bytes.len() != LEN_WITHOUT_GROUP + 16 + 16 + 4 {
+        return Err("invalid format".into());
+    }
+
+    let refresh_token = &bytes[..LEN_WITHOUT_GROUP];
+    let group_uuid: [u8; 16] = bytes[LEN_WITHOUT_GROUP..LEN_WITHOUT_GROUP + 16].try_into()?;
+    let entity_uuid: [u8; 16] = bytes[LEN_WITHOUT_GROUP + 16..LEN_WITHOUT_GROUP + 32].try_into()?;
+    let permissions = Permissions::from_be_bytes(bytes[LEN_WITHOUT_GROUP + 32..].try_into()?);
+
+    if !core.assignable_permissions().contains(permissions) {
+        return Err("unknown permission".into());
+    }
+
+    let txn = WriteTransaction::new(core.env.clone())?;
+
+    {
+        let mut access = txn.access();
+
+        let (admin_uuid, _) = crate::refresh::usable(core, &access, refresh_token)?;
+
+        if group::require_member(
+            core,
+            &txn,
+            &access,
+            &admin_uuid,
+            &group_uuid,
+            Permissions::ADMIN,
+        )
+        .is_err()
+        {
+            return Err("only group admins can share".into());
+        }
+
+        // users and groups have their own ops, and are listed
+        let is_user = access
+            .get::<[u8; 16], [u8]>(&core.user_db, &entity_uuid)
+            .to_opt()?
+            .is_some();
+
+        if is_user || !group::members(core, &txn, &access, &entity_uuid)?.is_empty() {
+            return Err("only entities can be shared".into());
+        }
+
+        match crate::entity::shareable(core, &txn, &access, &admin_uuid, &entity_uuid)? {
+            Some(shareable) if shareable.contains(permissions) => {}
+            Some(_) => return Err("can't share more than is held".into()),
+            None => return Err("not permitted to share this entity".into()),
+        }
+
+        group::share(core, &mut access, &entity_uuid, &group_uuid, permissions)?;
+    }
+
+    txn.commit()?;
+
+    Ok(Bytes::new())
+}
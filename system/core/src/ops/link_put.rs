@@ -0,0 +1,72 @@
+use crate::Core;
+use bytes::{BufMut, Bytes, BytesMut};
+use saferlmdb::WriteTransaction;
+
+/// token.subject_uuid.target_uuid.name
+/// - token: 135 bytes, see `cbwaw::token::gen`, or a delegated token
+///   for link_put, see `cbwaw::delegated::gen`
+/// - name: of the link in the schema of the subject, or the `on` name
+///   of a link to it
+///
+/// links the subject to the target, and the target back to the subject.
+pub fn req(
+    token: &[u8],
+
+    subject_uuid: &[u8; 16],
+    target_uuid: &[u8; 16],
+
+    name: &str,
+) -> Result<Bytes, Box<dyn std::error::Error>> {
+    let mut buf = BytesMut::with_capacity(token.len() + 16 + 16 + name.len());
+
+    buf.put(token);
+
+    buf.put(&subject_uuid[..]);
+    buf.put(&target_uuid[..]);
+
+    buf.put(name.as_bytes());
+
+    Ok(buf.into())
+}
+
+pub fn handle(core: &Core, bytes: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
+    let (grant, token_len) = crate::delegation::authorize(core, 17, &bytes)?;
+
+    let bytes = bytes.slice(token_len..);
+
+    if bytes.len() <= 32 {
+        return Err("invalid format".into());
+    }
+
+    let subject_uuid: [u8; 16] = bytes[0..16].try_into()?;
+    let target_uuid: [u8; 16] = bytes[16..32].try_into()?;
+
+    let name = std::str::from_utf8(&bytes[32..])?;
+
+    let txn = WriteTransaction::new(core.env.clone())?;
+
+    {
+        let mut access = txn.access();
+
+        let link = crate::reference::require_link(
+            core,
+            &access,
+            &grant,
+            &subject_uuid,
+            &target_uuid,
+            name,
+        )?;
+
+        crate::reference::link(
+            core,
+            &mut access,
+            &subject_uuid,
+            link.ref_type,
+            &target_uuid,
+        )?;
+    }
+
+    txn.commit()?;
+
+    Ok(Bytes::new())
+}
@@ -1,8 +1,16 @@
-use crate::{Core, MAX_USERNAME_LEN};
+use super::login_start::UNKNOWN_USER;
+use crate::login_state::Stage;
+use crate::Core;
 use bytes::{BufMut, Bytes, BytesMut};
-use saferlmdb::ReadTransaction;
+use saferlmdb::{LmdbResultExt, ReadTransaction, WriteTransaction};
 
-/// username_len.username.client_finish
+/// the same for unknown usernames and wrong passwords
+const LOGIN_FAILED: &str = "invalid username or password";
+
+const TOKEN: u8 = 0;
+const SECOND_FACTOR: u8 = 1;
+
+/// username_len.username.nonce.client_finish
 /// payload
 pub fn req(
     username: &[u8],
@@ -10,54 +18,134 @@ pub fn req(
     client_state: &[u8],
     server_message: &[u8],
 ) -> Result<(Bytes, Vec<u8>), Box<dyn std::error::Error>> {
+    if server_message.len() < 16 {
+        return Err("invalid format".into());
+    }
+
+    let nonce = &server_message[0..16];
+
     let (client_finish, session_key) =
-        cbwaw::login::client_finish(password, client_state, server_message)?;
+        cbwaw::login::client_finish(password, client_state, &server_message[16..])?;
 
+    let username = crate::username::normalize(username)?;
     let username_len = username.len();
-    if username_len > MAX_USERNAME_LEN as usize {
-        return Err("username is too long".into());
-    }
 
-    let mut buf = BytesMut::with_capacity(1 + username_len + client_finish.len());
+    let mut buf = BytesMut::with_capacity(1 + username_len + 16 + client_finish.len());
 
     buf.put_u8(username_len as u8);
-    buf.put(username);
+    buf.put(&username[..]);
+    buf.put(nonce);
     buf.put(&client_finish[..]);
 
     Ok((buf.into(), session_key))
 }
 
-/// (username, client_finish)
+/// 0.encrypted_refresh_token
+/// 1.nonce (second factor required)
 pub fn handle(core: &Core, bytes: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
+    if bytes.is_empty() || bytes[0] as usize + 1 > bytes.len() {
+        return Err("invalid format".into());
+    }
+
     let username_len = bytes[0];
-    if username_len > bytes.len() as u8 - 2 {
+    let username = crate::username::normalize(&bytes[1..(username_len as usize) + 1])?;
+
+    let nonce_start = username_len as usize + 1;
+    if bytes.len() < nonce_start + 16 {
         return Err("invalid format".into());
     }
 
-    let username = bytes[1..(username_len as usize) + 1].to_vec();
-    let client_finish = bytes[username_len as usize + 1..].to_vec();
+    let nonce: [u8; 16] = bytes[nonce_start..nonce_start + 16].try_into()?;
+    let client_finish = bytes[nonce_start + 16..].to_vec();
 
-    let txn = ReadTransaction::new(core.env.clone())?;
-    let access = txn.access();
+    let (user_uuid, server_start) = match crate::login_state::take(core, Stage::Password, &nonce)? {
+        Some(user_uuid_state) => user_uuid_state,
+        None => return Err("login has expired".into()),
+    };
 
-    let user_uuid_password_file: &[u8] = access.get(&core.auth_db.clone(), &username)?;
+    let second_factor = {
+        let txn = ReadTransaction::new(core.env.clone())?;
+        let access = txn.access();
 
-    let user_uuid: [u8; 16] = user_uuid_password_file[0..16].try_into()?;
-    drop(access);
+        match access
+            .get::<[u8], [u8]>(&core.auth_db, &username)
+            .to_opt()?
+        {
+            // the login was started for this user
+            Some(user_uuid_password_file)
+                if user_uuid != UNKNOWN_USER && user_uuid_password_file[0..16] == user_uuid =>
+            {
+                crate::second_factor::enrolled(core, &access, &user_uuid)?
+            }
+            _ => return Err(LOGIN_FAILED.into()),
+        }
+    };
 
-    let mut auth_state = core.auth_state.lock();
+    let session_key = cbwaw::login::server_session_key(&client_finish, &server_start)
+        .map_err(|_| LOGIN_FAILED)?;
 
-    if let Some(server_start) = auth_state.get(&user_uuid) {
-        let refresh_token = cbwaw::login::server_finish(&user_uuid, &client_finish, &server_start)?;
-        auth_state.remove(&user_uuid);
-        drop(auth_state);
+    // the password was right
+    crate::throttle::succeeded(core, &username)?;
 
-        return Ok(refresh_token);
+    let mut buf = BytesMut::new();
+
+    if second_factor {
+        let nonce =
+            crate::login_state::insert(core, Stage::SecondFactor, &user_uuid, &session_key)?;
+
+        buf.put_u8(SECOND_FACTOR);
+        buf.put(&nonce[..]);
+    } else {
+        buf.put_u8(TOKEN);
+        buf.put(issue(core, &user_uuid, &session_key)?);
     }
 
-    Ok(Bytes::new())
+    Ok(buf.into())
 }
 
-pub fn res(payload: Bytes, session_key: &[u8]) -> Result<Bytes, Box<dyn std::error::Error>> {
-    cbwaw::login::decrypt_token(&payload, session_key)
+/// encrypted refresh token, starting a new family
+pub(crate) fn issue(
+    core: &Core,
+    user_uuid: &[u8; 16],
+    session_key: &[u8],
+) -> Result<Bytes, Box<dyn std::error::Error>> {
+    let token_keys = core.token_keys()?;
+
+    let refresh_token = cbwaw::token::gen(
+        &token_keys,
+        &core.token_settings,
+        cbwaw::token::Kind::Refresh,
+        0,
+        user_uuid,
+        None,
+    )?;
+
+    let encrypted_token = cbwaw::login::encrypt_token(&refresh_token, session_key)?;
+
+    // every login starts a new refresh token family
+    let txn = WriteTransaction::new(core.env.clone())?;
+    crate::refresh::start(core, &mut txn.access(), &refresh_token)?;
+    txn.commit()?;
+
+    Ok(encrypted_token)
+}
+
+#[derive(Debug)]
+pub enum Login {
+    RefreshToken(Bytes),
+    /// nonce to send to `login_second_factor` along with a code
+    SecondFactor([u8; 16]),
+}
+
+pub fn res(payload: Bytes, session_key: &[u8]) -> Result<Login, Box<dyn std::error::Error>> {
+    match payload.first() {
+        Some(&TOKEN) => Ok(Login::RefreshToken(cbwaw::login::decrypt_token(
+            &payload[1..],
+            session_key,
+        )?)),
+        Some(&SECOND_FACTOR) if payload.len() == 17 => {
+            Ok(Login::SecondFactor(payload[1..17].try_into()?))
+        }
+        _ => Err("invalid format".into()),
+    }
 }
@@ -0,0 +1,104 @@
+use std::time::SystemTime;
+
+use crate::login_state::Stage;
+use crate::recovery::Kind;
+use crate::second_factor::{self, MAX_FAILED_SECOND_FACTOR, SECOND_FACTOR_LOCKOUT};
+use crate::{Core, RECOVERY_CODE_LEN};
+use bytes::{BufMut, Bytes, BytesMut};
+use saferlmdb::WriteTransaction;
+
+const TOTP_CODE_LEN: usize = cbwaw::totp::DIGITS as usize;
+
+/// nonce.code
+///
+/// `code` is either the 6 digits from the authenticator app, or
+/// one of the backup codes handed out by `totp_enroll_finish`.
+pub fn req(nonce: &[u8; 16], code: &[u8]) -> Result<Bytes, Box<dyn std::error::Error>> {
+    let mut buf = BytesMut::with_capacity(16 + code.len());
+
+    buf.put(&nonce[..]);
+    buf.put(code);
+
+    Ok(buf.into())
+}
+
+/// encrypted_refresh_token
+///
+/// a failed code uses up the nonce, so every attempt needs the password
+/// again, and too many in a row lock the second factor for a while.
+pub fn handle(core: &Core, bytes: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
+    if bytes.len() <= 16 {
+        return Err("invalid format".into());
+    }
+
+    let nonce: [u8; 16] = bytes[0..16].try_into()?;
+    let code = &bytes[16..];
+
+    let (user_uuid, session_key) =
+        crate::login_state::take(core, Stage::SecondFactor, &nonce)?.ok_or("login has expired")?;
+
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_secs();
+
+    let txn = WriteTransaction::new(core.env.clone())?;
+
+    let valid = {
+        let mut access = txn.access();
+
+        let mut record = second_factor::load(core, &access, &user_uuid)?
+            .ok_or("second factor isn't enrolled")?;
+
+        if record.locked_until > now {
+            return Err("too many failed attempts, try again later".into());
+        }
+
+        let valid = match code.len() {
+            TOTP_CODE_LEN => match std::str::from_utf8(code)?.parse::<u32>() {
+                Ok(code) => {
+                    match cbwaw::totp::verify(&record.secret, code, now, record.last_step) {
+                        Some(step) => {
+                            record.last_step = step;
+                            true
+                        }
+                        None => false,
+                    }
+                }
+                Err(_) => false,
+            },
+            RECOVERY_CODE_LEN => {
+                crate::recovery::consume(core, &mut access, &user_uuid, Kind::Backup, code).is_ok()
+            }
+            _ => false,
+        };
+
+        if valid {
+            record.failures = 0;
+        } else {
+            record.failures += 1;
+
+            if record.failures >= MAX_FAILED_SECOND_FACTOR {
+                record.failures = 0;
+                record.locked_until = now + SECOND_FACTOR_LOCKOUT;
+            }
+        }
+
+        second_factor::store(core, &mut access, &user_uuid, &record)?;
+
+        valid
+    };
+
+    // failures have to be counted even though the login fails
+    txn.commit()?;
+
+    if !valid {
+        return Err("invalid code".into());
+    }
+
+    super::login_finish::issue(core, &user_uuid, &session_key)
+}
+
+/// refresh_token
+pub fn res(payload: Bytes, session_key: &[u8]) -> Result<Bytes, Box<dyn std::error::Error>> {
+    cbwaw::login::decrypt_token(&payload, session_key)
+}
@@ -1,6 +1,12 @@
-use crate::{Core, MAX_USERNAME_LEN};
+use std::net::IpAddr;
+
+use crate::login_state::Stage;
+use crate::Core;
 use bytes::{BufMut, Bytes, BytesMut};
-use saferlmdb::ReadTransaction;
+use saferlmdb::{LmdbResultExt, ReadTransaction};
+
+/// pending logins for usernames that aren't registered belong to nobody
+pub(crate) const UNKNOWN_USER: [u8; 16] = [0u8; 16];
 
 /// username_len.username.client_start
 /// (client_state, payload)
@@ -10,10 +16,8 @@ pub fn req(
 ) -> Result<(Vec<u8>, Bytes), Box<dyn std::error::Error>> {
     let (client_state, client_start) = cbwaw::login::client_start(password)?;
 
+    let username = crate::username::normalize(username)?;
     let username_len = username.len();
-    if username_len > MAX_USERNAME_LEN as usize {
-        return Err("username is too long".into());
-    }
 
     let mut buf = BytesMut::with_capacity(1 + username_len + client_start.len());
 
@@ -24,34 +28,66 @@ pub fn req(
     Ok((client_state, buf.into()))
 }
 
-/// (username, client_start)
-pub fn handle(core: &Core, payload: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
-    let username_len = payload[0];
-    if username_len > payload.len() as u8 - 2 {
+/// nonce.message
+///
+/// `source` is the address the request came from, if known, to
+/// throttle attempts from it across usernames.
+pub fn handle(
+    core: &Core,
+    source: Option<&IpAddr>,
+    payload: Bytes,
+) -> Result<Bytes, Box<dyn std::error::Error>> {
+    if payload.is_empty() || payload[0] as usize + 1 > payload.len() {
         return Err("invalid format".into());
     }
 
-    let username = payload[1..(username_len as usize) + 1].to_vec();
+    let username_len = payload[0];
+    let username = crate::username::normalize(&payload[1..(username_len as usize) + 1])?;
     let client_start = payload[username_len as usize + 1..].to_vec();
 
-    let txn = ReadTransaction::new(core.env.clone())?;
-    let access = txn.access();
+    crate::throttle::attempt(core, &username, source)?;
+
+    let (user_uuid, state, message) = {
+        let txn = ReadTransaction::new(core.env.clone())?;
+        let access = txn.access();
+
+        match access
+            .get::<[u8], [u8]>(&core.auth_db, &username)
+            .to_opt()?
+        {
+            Some(user_uuid_password_file) => {
+                let setup_id: [u8; 4] = user_uuid_password_file[16..20].try_into()?;
+                let setup = core.server_setup(u32::from_be_bytes(setup_id))?;
+
+                let (state, message) = cbwaw::login::server_start(
+                    &setup,
+                    &username,
+                    Some(&user_uuid_password_file[20..]),
+                    &client_start,
+                )?;
+
+                let user_uuid: [u8; 16] = user_uuid_password_file[0..16].try_into()?;
+
+                (user_uuid, state, message)
+            }
+            // answered like any other username, the login just can't finish
+            None => {
+                let (_, setup) = core.current_server_setup()?;
+
+                let (state, message) =
+                    cbwaw::login::server_start(&setup, &username, None, &client_start)?;
 
-    let user_uuid_password_file: &[u8] = access.get(&core.auth_db.clone(), &username)?;
+                (UNKNOWN_USER, state, message)
+            }
+        }
+    };
 
-    let (state, message) = cbwaw::login::server_start(
-        &core.opaque,
-        &username,
-        &user_uuid_password_file[16..],
-        &client_start,
-    )?;
+    let nonce = crate::login_state::insert(core, Stage::Password, &user_uuid, &state)?;
 
-    let user_uuid: [u8; 16] = user_uuid_password_file[0..16].try_into()?;
-    drop(access);
+    let mut buf = BytesMut::with_capacity(16 + message.len());
 
-    let mut auth_state = core.auth_state.lock();
-    auth_state.insert(user_uuid, state);
-    drop(auth_state);
+    buf.put(&nonce[..]);
+    buf.put(&message[..]);
 
-    Ok(message)
+    Ok(buf.into())
 }
@@ -0,0 +1,155 @@
+use std::time::SystemTime;
+
+use crate::refresh::Status;
+use crate::Core;
+use bytes::{BufMut, Bytes, BytesMut};
+use cbwaw::token::{self, Kind, LEN_WITHOUT_GROUP, MAX_TOKEN_LIFETIME};
+use saferlmdb::{put, ConstAccessor, LmdbResultExt, WriteTransaction};
+
+/// refresh_token.all
+///
+/// with `all` every session of the user is logged out, not just
+/// the one the refresh token belongs to.
+pub fn req(refresh_token: &[u8], all: bool) -> Result<Bytes, Box<dyn std::error::Error>> {
+    let mut buf = BytesMut::with_capacity(LEN_WITHOUT_GROUP + 1);
+
+    buf.put(refresh_token);
+    buf.put_u8(all as u8);
+
+    Ok(buf.into())
+}
+
+pub fn handle(core: &Core, bytes: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
+    if bytes.len() != LEN_WITHOUT_GROUP + 1 {
+        return Err("invalid format".into());
+    }
+
+    let refresh_token = &bytes[..LEN_WITHOUT_GROUP];
+    let all = bytes[LEN_WITHOUT_GROUP] == 1;
+
+    let token_keys = core.token_keys()?;
+
+    let token::Token {
+        user_uuid, jti, exp, ..
+    } = token::verify(
+        &token_keys,
+        &core.token_settings,
+        Kind::Refresh,
+        0,
+        refresh_token,
+    )?;
+
+    let now_ms = u64::try_from(
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_millis(),
+    )?;
+    let now = now_ms / 1000;
+
+    let txn = WriteTransaction::new(core.env.clone())?;
+
+    {
+        let mut access = txn.access();
+
+        if all {
+            // everything issued up to now is revoked
+            access.put(
+                &core.revoked_db,
+                &user_uuid,
+                &now_ms.to_be_bytes(),
+                put::Flags::empty(),
+            )?;
+        }
+
+        let mut key = [0u8; 32];
+
+        key[0..16].copy_from_slice(&user_uuid[..]);
+        key[16..32].copy_from_slice(&jti[..]);
+
+        access.put(
+            &core.revoked_db,
+            &key,
+            &exp.to_be_bytes(),
+            put::Flags::empty(),
+        )?;
+
+        // along with every token rotated from it
+        match crate::refresh::status(core, &access, refresh_token)? {
+            Status::Current(family_id) | Status::Reused(family_id) => {
+                crate::refresh::revoke(core, &mut access, &family_id)?
+            }
+            Status::Revoked | Status::Unknown => {}
+        }
+
+        // clean up revocations of this user's tokens that have expired anyway
+        let mut expired = vec![];
+
+        {
+            let mut cursor = txn.cursor(core.revoked_db.clone())?;
+
+            let mut next = cursor
+                .seek_range_k::<[u8], [u8]>(&access, &user_uuid[..])
+                .to_opt()?;
+
+            while let Some((key, value)) = next {
+                if key[0..16] != user_uuid[..] {
+                    break;
+                }
+
+                let value = u64::from_be_bytes(value.try_into()?);
+
+                let exp = match key.len() {
+                    // all sessions cutoff, covers tokens issued before then
+                    16 => value / 1000 + MAX_TOKEN_LIFETIME,
+                    _ => value,
+                };
+
+                if exp < now {
+                    expired.push(key.to_vec());
+                }
+
+                next = cursor.next::<[u8], [u8]>(&access).to_opt()?;
+            }
+        }
+
+        for key in expired {
+            access.del_key(&core.revoked_db, &key[..])?;
+        }
+    }
+
+    txn.commit()?;
+
+    Ok(Bytes::new())
+}
+
+/// true if the refresh token was logged out, either on its
+/// own or as part of logging out all of the user's sessions.
+pub(crate) fn is_revoked(
+    core: &Core,
+    access: &ConstAccessor,
+    user_uuid: &[u8; 16],
+    refresh_token: &[u8],
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let token::Token { issued, jti, .. } = token::parse(refresh_token)?;
+
+    if let Some(cutoff) = access
+        .get::<[u8], [u8]>(&core.revoked_db, &user_uuid[..])
+        .to_opt()?
+    {
+        // the cutoff's millisecond is included, a login right after
+        // logging out all sessions takes longer than that
+        if issued <= u64::from_be_bytes(cutoff.try_into()?) {
+            return Ok(true);
+        }
+    }
+
+    let mut key = [0u8; 32];
+
+    key[0..16].copy_from_slice(&user_uuid[..]);
+    key[16..32].copy_from_slice(&jti[..]);
+
+    Ok(access
+        .get::<[u8; 32], [u8]>(&core.revoked_db, &key)
+        .to_opt()?
+        .is_some())
+}
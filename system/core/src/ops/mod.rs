@@ -1,8 +1,40 @@
 pub mod access_get;
+pub mod access_get_certificate;
+pub mod api_key_create;
+pub mod api_key_exchange;
+pub mod api_key_list;
+pub mod api_key_revoke;
+pub mod certificate_bind;
+pub mod certificate_unbind;
+pub mod delegation_create;
+pub mod group_assign;
 pub mod group_create;
+pub mod group_deny;
+pub mod group_drop;
+pub mod group_inherit;
+pub mod group_members;
+pub mod group_nest;
+pub mod group_share;
+pub mod link_drop;
+pub mod link_put;
 pub mod login_finish;
+pub mod login_second_factor;
 pub mod login_start;
+pub mod logout;
+pub mod password_change_finish;
+pub mod password_change_start;
+pub mod permission_explain;
+pub mod recovery_finish;
+pub mod recovery_start;
 pub mod registration_finish;
 pub mod registration_start;
+pub mod secret_get;
+pub mod secret_put;
+pub mod storage_delete;
 pub mod storage_put;
 pub mod storage_query;
+pub mod storage_renew;
+pub mod storage_traverse;
+pub mod storage_update;
+pub mod totp_enroll_finish;
+pub mod totp_enroll_start;
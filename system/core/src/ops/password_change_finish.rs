@@ -0,0 +1,113 @@
+use std::time::SystemTime;
+
+use crate::Core;
+use bytes::{BufMut, Bytes, BytesMut};
+use cbwaw::token::LEN_WITHOUT_GROUP;
+use saferlmdb::{put, WriteAccessor, WriteTransaction};
+
+/// refresh_token.setup_id.client_finish
+/// payload
+pub fn req(
+    refresh_token: &[u8],
+    password: &[u8],
+    client_state: &[u8],
+    server_message: &[u8],
+) -> Result<Bytes, Box<dyn std::error::Error>> {
+    if server_message.len() < 4 {
+        return Err("invalid format".into());
+    }
+
+    let setup_id = &server_message[0..4];
+
+    let client_finish =
+        cbwaw::registration::client_finish(password, client_state, &server_message[4..])?;
+
+    let mut buf = BytesMut::with_capacity(LEN_WITHOUT_GROUP + 4 + client_finish.len());
+
+    buf.put(refresh_token);
+    buf.put(setup_id);
+    buf.put(&client_finish[..]);
+
+    Ok(buf.into())
+}
+
+/// changing the password logs out every session, including the
+/// one the refresh token belongs to.
+pub fn handle(core: &Core, bytes: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
+    if bytes.len() <= LEN_WITHOUT_GROUP + 4 {
+        return Err("invalid format".into());
+    }
+
+    let refresh_token = &bytes[..LEN_WITHOUT_GROUP];
+    let setup_id: [u8; 4] = bytes[LEN_WITHOUT_GROUP..LEN_WITHOUT_GROUP + 4].try_into()?;
+    let client_finish = &bytes[LEN_WITHOUT_GROUP + 4..];
+
+    // make sure the setup wasn't retired between start and finish
+    core.server_setup(u32::from_be_bytes(setup_id))?;
+
+    let password_file = cbwaw::registration::server_finish(client_finish)?;
+
+    let txn = WriteTransaction::new(core.env.clone())?;
+
+    {
+        let mut access = txn.access();
+
+        let user_uuid = crate::refresh::fresh(core, &access, refresh_token)?;
+
+        let username = access
+            .get::<[u8], [u8]>(&core.user_db, &user_uuid[..])?
+            .to_vec();
+
+        replace(
+            core,
+            &mut access,
+            &username,
+            &user_uuid,
+            &setup_id,
+            &password_file,
+        )?;
+    }
+
+    txn.commit()?;
+
+    Ok(Bytes::new())
+}
+
+/// swaps in the new password file and logs out all of the user's sessions
+pub(crate) fn replace(
+    core: &Core,
+    access: &mut WriteAccessor,
+    username: &[u8],
+    user_uuid: &[u8; 16],
+    setup_id: &[u8; 4],
+    password_file: &[u8],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut user_uuid_password_file = Vec::with_capacity(16 + 4 + password_file.len());
+
+    user_uuid_password_file.extend_from_slice(user_uuid);
+    user_uuid_password_file.extend_from_slice(setup_id);
+    user_uuid_password_file.extend_from_slice(password_file);
+
+    access.put(
+        &core.auth_db,
+        username,
+        &user_uuid_password_file,
+        put::Flags::empty(),
+    )?;
+
+    let now_ms = u64::try_from(
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_millis(),
+    )?;
+
+    // everything issued up to now, with the old password, is revoked
+    access.put(
+        &core.revoked_db,
+        &user_uuid[..],
+        &now_ms.to_be_bytes(),
+        put::Flags::empty(),
+    )?;
+
+    Ok(())
+}
@@ -0,0 +1,52 @@
+use crate::Core;
+use bytes::{BufMut, Bytes, BytesMut};
+use cbwaw::token::LEN_WITHOUT_GROUP;
+use saferlmdb::ReadTransaction;
+
+/// refresh_token.client_start
+/// (client_state, payload)
+pub fn req(
+    refresh_token: &[u8],
+    password: &[u8],
+) -> Result<(Vec<u8>, Bytes), Box<dyn std::error::Error>> {
+    let (client_state, client_start) = cbwaw::registration::client_start(password)?;
+
+    let mut buf = BytesMut::with_capacity(LEN_WITHOUT_GROUP + client_start.len());
+
+    buf.put(refresh_token);
+    buf.put(&client_start[..]);
+
+    Ok((client_state, buf.into()))
+}
+
+/// setup_id.message
+pub fn handle(core: &Core, bytes: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
+    if bytes.len() <= LEN_WITHOUT_GROUP {
+        return Err("invalid format".into());
+    }
+
+    let refresh_token = &bytes[..LEN_WITHOUT_GROUP];
+    let client_start = &bytes[LEN_WITHOUT_GROUP..];
+
+    let username = {
+        let txn = ReadTransaction::new(core.env.clone())?;
+        let access = txn.access();
+
+        let user_uuid = crate::refresh::fresh(core, &access, refresh_token)?;
+
+        access
+            .get::<[u8], [u8]>(&core.user_db, &user_uuid[..])?
+            .to_vec()
+    };
+
+    let (setup_id, setup) = core.current_server_setup()?;
+
+    let message = cbwaw::registration::server_start(&setup, &username, client_start)?;
+
+    let mut buf = BytesMut::with_capacity(4 + message.len());
+
+    buf.put_u32(setup_id);
+    buf.put(&message[..]);
+
+    Ok(buf.into())
+}
@@ -0,0 +1,122 @@
+use crate::group::{self, PermissionReason};
+use crate::permissions::Permissions;
+use crate::Core;
+use bitcode::{Decode, Encode};
+use bytes::{BufMut, Bytes, BytesMut};
+use cbwaw::token::LEN_WITHOUT_GROUP;
+use saferlmdb::ReadTransaction;
+
+/// what a user can do with an entity through one group it was shared
+/// with, and why
+#[derive(Encode, Decode, PartialEq, Debug)]
+pub struct Explanation {
+    pub group_uuid: [u8; 16],
+    /// allowed to the entity in the group
+    pub entity_allowed: Permissions,
+    /// denied to the entity in the group
+    pub entity_denied: Permissions,
+    /// what the group can do with the entity, see `Core::with_inherited_permissions`
+    pub entity: Permissions,
+    /// the entity, or the ancestor, `entity` was allowed on
+    pub inherited_from: Option<[u8; 16]>,
+    /// what the user can do in the group
+    pub user: Permissions,
+    pub reasons: Vec<PermissionReason>,
+    /// what the user can do with the entity, through this group
+    pub permissions: Permissions,
+}
+
+/// refresh_token.user_uuid.entity_uuid
+pub fn req(
+    refresh_token: &[u8],
+    user_uuid: &[u8; 16],
+    entity_uuid: &[u8; 16],
+) -> Result<Bytes, Box<dyn std::error::Error>> {
+    let mut buf = BytesMut::with_capacity(LEN_WITHOUT_GROUP + 16 + 16);
+
+    buf.put(refresh_token);
+    buf.put(&user_uuid[..]);
+    buf.put(&entity_uuid[..]);
+
+    Ok(buf.into())
+}
+
+/// an explanation for every group the entity, or with inherited
+/// permissions any of its ancestors, was shared with or denied in.
+/// users can explain themselves, and admins anyone in their groups,
+/// other groups are left out.
+pub fn handle(core: &Core, bytes: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
+    if bytes.len() != LEN_WITHOUT_GROUP + 16 + 16 {
+        return Err("invalid format".into());
+    }
+
+    let refresh_token = &bytes[..LEN_WITHOUT_GROUP];
+    let user_uuid: [u8; 16] = bytes[LEN_WITHOUT_GROUP..LEN_WITHOUT_GROUP + 16].try_into()?;
+    let entity_uuid: [u8; 16] = bytes[LEN_WITHOUT_GROUP + 16..].try_into()?;
+
+    let txn = ReadTransaction::new(core.env.clone())?;
+    let access = txn.access();
+
+    let (caller_uuid, _) = crate::refresh::usable(core, &access, refresh_token)?;
+
+    let subjects = if core.inherit_permissions {
+        crate::entity::ancestors(core, &access, &entity_uuid)?
+    } else {
+        vec![entity_uuid]
+    };
+
+    let mut groups = vec![];
+
+    for subject_uuid in &subjects {
+        for group_uuid in group::groups(core, &txn, &access, subject_uuid)? {
+            if !groups.contains(&group_uuid) {
+                groups.push(group_uuid);
+            }
+        }
+    }
+
+    let mut explanations = vec![];
+
+    for group_uuid in groups {
+        if caller_uuid != user_uuid
+            && group::require_member(
+                core,
+                &txn,
+                &access,
+                &caller_uuid,
+                &group_uuid,
+                Permissions::ADMIN,
+            )
+            .is_err()
+        {
+            continue;
+        }
+
+        let entity_allowed = group::allowed(core, &access, &entity_uuid, &group_uuid)?;
+        let entity_denied = group::denied(core, &access, &entity_uuid, &group_uuid)?;
+
+        let (entity, inherited_from) =
+            crate::entity::resolve(core, &access, &entity_uuid, &group_uuid)?;
+
+        let (user, reasons) = group::resolve(core, &txn, &access, &user_uuid, &group_uuid)?;
+
+        explanations.push(Explanation {
+            group_uuid,
+            entity_allowed,
+            entity_denied,
+            user,
+            entity,
+            inherited_from,
+            reasons,
+            permissions: user & entity,
+        });
+    }
+
+    let encoded: Vec<u8> = bitcode::encode(&explanations);
+
+    Ok(encoded.into())
+}
+
+pub fn res(res: Bytes) -> Result<Vec<Explanation>, Box<dyn std::error::Error>> {
+    Ok(bitcode::decode(&res)?)
+}
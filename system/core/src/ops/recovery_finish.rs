@@ -0,0 +1,93 @@
+use crate::recovery::Kind;
+use crate::{Core, RECOVERY_CODE_LEN};
+use bytes::{BufMut, Bytes, BytesMut};
+use saferlmdb::{LmdbResultExt, WriteTransaction};
+
+/// username_len.username.recovery_code.setup_id.client_finish
+/// payload
+pub fn req(
+    username: &[u8],
+    recovery_code: &[u8],
+    password: &[u8],
+    client_state: &[u8],
+    server_message: &[u8],
+) -> Result<Bytes, Box<dyn std::error::Error>> {
+    if recovery_code.len() != RECOVERY_CODE_LEN {
+        return Err("invalid recovery code".into());
+    }
+
+    if server_message.len() < 4 {
+        return Err("invalid format".into());
+    }
+
+    let setup_id = &server_message[0..4];
+
+    let client_finish =
+        cbwaw::registration::client_finish(password, client_state, &server_message[4..])?;
+
+    let username = crate::username::normalize(username)?;
+    let username_len = username.len();
+
+    let mut buf =
+        BytesMut::with_capacity(1 + username_len + RECOVERY_CODE_LEN + 4 + client_finish.len());
+
+    buf.put_u8(username_len as u8);
+    buf.put(&username[..]);
+    buf.put(recovery_code);
+    buf.put(setup_id);
+    buf.put(&client_finish[..]);
+
+    Ok(buf.into())
+}
+
+/// uses up the recovery code, replaces the password file, and logs
+/// out every session of the user.
+pub fn handle(core: &Core, payload: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
+    if payload.is_empty() || payload[0] as usize + 1 + RECOVERY_CODE_LEN + 4 > payload.len() {
+        return Err("invalid format".into());
+    }
+
+    let username_len = payload[0];
+    let username = crate::username::normalize(&payload[1..(username_len as usize) + 1])?;
+
+    let code_start = username_len as usize + 1;
+    let recovery_code = &payload[code_start..code_start + RECOVERY_CODE_LEN];
+
+    let setup_start = code_start + RECOVERY_CODE_LEN;
+    let setup_id: [u8; 4] = payload[setup_start..setup_start + 4].try_into()?;
+    let client_finish = &payload[setup_start + 4..];
+
+    // make sure the setup wasn't retired between start and finish
+    core.server_setup(u32::from_be_bytes(setup_id))?;
+
+    let password_file = cbwaw::registration::server_finish(client_finish)?;
+
+    let txn = WriteTransaction::new(core.env.clone())?;
+
+    {
+        let mut access = txn.access();
+
+        let user_uuid: [u8; 16] = match access
+            .get::<[u8], [u8]>(&core.auth_db, &username)
+            .to_opt()?
+        {
+            Some(user_uuid_password_file) => user_uuid_password_file[0..16].try_into()?,
+            None => return Err(crate::recovery::RECOVERY_FAILED.into()),
+        };
+
+        crate::recovery::consume(core, &mut access, &user_uuid, Kind::Recovery, recovery_code)?;
+
+        super::password_change_finish::replace(
+            core,
+            &mut access,
+            &username,
+            &user_uuid,
+            &setup_id,
+            &password_file,
+        )?;
+    }
+
+    txn.commit()?;
+
+    Ok(Bytes::new())
+}
@@ -0,0 +1,56 @@
+use crate::{Core, RECOVERY_CODE_LEN};
+use bytes::{BufMut, Bytes, BytesMut};
+
+/// username_len.username.recovery_code.client_start
+/// (client_state, payload)
+pub fn req(
+    username: &[u8],
+    recovery_code: &[u8],
+    password: &[u8],
+) -> Result<(Vec<u8>, Bytes), Box<dyn std::error::Error>> {
+    if recovery_code.len() != RECOVERY_CODE_LEN {
+        return Err("invalid recovery code".into());
+    }
+
+    let (client_state, client_start) = cbwaw::registration::client_start(password)?;
+
+    let username = crate::username::normalize(username)?;
+    let username_len = username.len();
+
+    let mut buf =
+        BytesMut::with_capacity(1 + username_len + RECOVERY_CODE_LEN + client_start.len());
+
+    buf.put_u8(username_len as u8);
+    buf.put(&username[..]);
+    buf.put(recovery_code);
+    buf.put(&client_start[..]);
+
+    Ok((client_state, buf.into()))
+}
+
+/// setup_id.message
+pub fn handle(core: &Core, payload: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
+    if payload.is_empty() || payload[0] as usize + 1 + RECOVERY_CODE_LEN > payload.len() {
+        return Err("invalid format".into());
+    }
+
+    let username_len = payload[0];
+    let username = crate::username::normalize(&payload[1..(username_len as usize) + 1])?;
+
+    let code_start = username_len as usize + 1;
+    let client_start = &payload[code_start + RECOVERY_CODE_LEN..];
+
+    // the username and code are only checked by recovery_finish, so an
+    // unknown username or a wrong code is answered like any other, the
+    // same way login_start does
+    let (setup_id, setup) = core.current_server_setup()?;
+
+    let message = cbwaw::registration::server_start(&setup, &username, client_start)?;
+
+    let mut buf = BytesMut::with_capacity(4 + message.len());
+
+    buf.put_u32(setup_id);
+    buf.put(&message[..]);
+
+    Ok(buf.into())
+}
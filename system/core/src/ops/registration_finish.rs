@@ -1,9 +1,10 @@
-use crate::{Core, MAX_USERNAME_LEN};
+use crate::recovery::Kind;
+use crate::Core;
 use bytes::{BufMut, Bytes, BytesMut};
-use saferlmdb::{put, WriteTransaction};
+use saferlmdb::{self as lmdb, put, WriteTransaction};
 use uuid::Uuid;
 
-/// username_len.username.client_finish
+/// username_len.username.setup_id.client_finish
 /// payload
 pub fn req(
     username: &[u8],
@@ -11,50 +12,79 @@ pub fn req(
     client_state: &[u8],
     server_message: &[u8],
 ) -> Result<Bytes, Box<dyn std::error::Error>> {
-    let client_finish = cbwaw::registration::client_finish(password, client_state, server_message)?;
+    if server_message.len() < 4 {
+        return Err("invalid format".into());
+    }
+
+    let setup_id = &server_message[0..4];
 
+    let client_finish =
+        cbwaw::registration::client_finish(password, client_state, &server_message[4..])?;
+
+    let username = crate::username::normalize(username)?;
     let username_len = username.len();
-    if username_len > MAX_USERNAME_LEN as usize {
-        return Err("username is too long".into());
-    }
 
-    let mut buf = BytesMut::with_capacity(1 + username_len + client_finish.len());
+    let mut buf = BytesMut::with_capacity(1 + username_len + 4 + client_finish.len());
 
     buf.put_u8(username_len as u8);
     buf.put(&username[..]);
+    buf.put(setup_id);
     buf.put(&client_finish[..]);
 
     Ok(buf.into())
 }
 
-/// (username, client_finish)
-pub fn handle(core: &Core, payload: Bytes) -> Result<(), Box<dyn std::error::Error>> {
+/// recovery_codes
+///
+/// `RECOVERY_CODES` codes of `RECOVERY_CODE_LEN` each, they are only
+/// ever handed out here.
+pub fn handle(core: &Core, payload: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
+    if payload.is_empty() || payload[0] as usize + 1 > payload.len() {
+        return Err("invalid format".into());
+    }
+
     let username_len = payload[0];
-    if username_len > payload.len() as u8 - 2 {
+    let username = crate::username::normalize(&payload[1..(username_len as usize) + 1])?;
+
+    let setup_start = username_len as usize + 1;
+    if payload.len() < setup_start + 4 {
         return Err("invalid format".into());
     }
 
-    let username = payload[1..(username_len as usize) + 1].to_vec();
-    let client_finish = payload[username_len as usize + 1..].to_vec();
+    let setup_id: [u8; 4] = payload[setup_start..setup_start + 4].try_into()?;
+
+    // make sure the setup wasn't retired between start and finish
+    core.server_setup(u32::from_be_bytes(setup_id))?;
 
-    let mut password_file = cbwaw::registration::server_finish(&client_finish)?;
+    let client_finish = payload[setup_start + 4..].to_vec();
+
+    let password_file = cbwaw::registration::server_finish(&client_finish)?;
 
     let uuid = Uuid::new_v4();
     let user_uuid = uuid.as_bytes();
 
-    password_file.extend_from_slice(user_uuid);
+    let mut user_uuid_password_file = Vec::with_capacity(16 + 4 + password_file.len());
+
+    user_uuid_password_file.extend_from_slice(user_uuid);
+    user_uuid_password_file.extend_from_slice(&setup_id);
+    user_uuid_password_file.extend_from_slice(&password_file);
 
     let txn = WriteTransaction::new(core.env.clone())?;
 
-    {
+    let recovery_codes = {
         let mut access = txn.access();
 
-        access.put(
+        match access.put(
             &core.auth_db,
             &username,
-            &password_file,
-            put::Flags::empty(),
-        )?;
+            &user_uuid_password_file,
+            put::Flags::NOOVERWRITE,
+        ) {
+            Err(lmdb::Error::Code(lmdb::error::KEYEXIST)) => {
+                return Err(crate::UsernameError::Taken.into())
+            }
+            res => res?,
+        }
 
         access.put(
             &core.user_db,
@@ -62,9 +92,16 @@ pub fn handle(core: &Core, payload: Bytes) -> Result<(), Box<dyn std::error::Err
             &username, // TODO: figure out what the user type should have
             put::Flags::empty(),
         )?;
-    }
+
+        crate::recovery::generate(core, &txn, &mut access, user_uuid, Kind::Recovery)?
+    };
 
     txn.commit()?;
 
-    Ok(())
+    Ok(recovery_codes.into())
+}
+
+/// recovery_codes
+pub fn res(res: Bytes) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    crate::recovery::split(&res)
 }
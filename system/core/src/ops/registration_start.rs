@@ -1,5 +1,6 @@
-use crate::{Core, MAX_USERNAME_LEN};
+use crate::Core;
 use bytes::{BufMut, Bytes, BytesMut};
+use saferlmdb::{LmdbResultExt, ReadTransaction};
 
 /// username_len.username.client_start
 /// (client_state, payload)
@@ -9,10 +10,8 @@ pub fn req(
 ) -> Result<(Vec<u8>, Bytes), Box<dyn std::error::Error>> {
     let (client_state, client_start) = cbwaw::registration::client_start(password)?;
 
+    let username = crate::username::normalize(username)?;
     let username_len = username.len();
-    if username_len > MAX_USERNAME_LEN as usize {
-        return Err("username is too long".into());
-    }
 
     let mut buf = BytesMut::with_capacity(1 + username_len + client_start.len());
 
@@ -23,15 +22,40 @@ pub fn req(
     Ok((client_state, buf.into()))
 }
 
-/// (username, client_start)
+/// setup_id.message
 pub fn handle(core: &Core, payload: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
-    let username_len = payload[0];
-    if username_len > payload.len() as u8 - 2 {
+    if payload.is_empty() || payload[0] as usize + 1 > payload.len() {
         return Err("invalid format".into());
     }
 
-    let username = payload[1..(username_len as usize) + 1].to_vec();
+    let username_len = payload[0];
+    let username = crate::username::normalize(&payload[1..(username_len as usize) + 1])?;
     let client_start = payload[username_len as usize + 1..].to_vec();
 
-    cbwaw::registration::server_start(&core.opaque, &username, &client_start)
+    // registration_finish enforces this on its own, see
+    // `Core::with_username_check_at_start`
+    if core.check_username_at_start {
+        let txn = ReadTransaction::new(core.env.clone())?;
+        let access = txn.access();
+
+        if access
+            .get::<[u8], [u8]>(&core.auth_db, &username)
+            .to_opt()?
+            .is_some()
+        {
+            return Err(crate::UsernameError::Taken.into());
+        }
+    }
+
+    let (setup_id, setup) = core.current_server_setup()?;
+
+    let message = cbwaw::registration::server_start(&setup, &username, &client_start)?;
+
+    let mut buf = BytesMut::with_capacity(4 + message.len());
+
+    // the finish needs to know which setup this registration is bound to
+    buf.put_u32(setup_id);
+    buf.put(&message[..]);
+
+    Ok(buf.into())
 }
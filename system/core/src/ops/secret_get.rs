@@ -1,20 +1,106 @@
-use bytes::Bytes;
+use crate::permissions::Permissions;
+use crate::Core;
+use bytes::{BufMut, Bytes, BytesMut};
+use cbwaw::token::{self, Kind, TokenError, LEN_WITH_GROUP};
+use saferlmdb::ReadTransaction;
 
-pub fn req(refresh_token: [u8; 73], action: u8) -> Result<Bytes, Box<dyn std::error::Error>> {
-    // TODO: validate refresh token
-    // TODO: check that user has
-    // let token = token::generate_access(action, &user, &group)?;
-    Ok(Bytes::new())
+#[derive(PartialEq, Debug)]
+pub struct Secret {
+    pub version: u32,
+    /// `value` has to be opened with `open`
+    pub client_sealed: bool,
+    /// who put this version
+    pub user_uuid: [u8; 16],
+    pub value: Vec<u8>,
 }
 
-pub fn handle(bytes: Bytes) {}
+/// access_token.secret_uuid?version
+///
+/// without a `version` the latest one is returned.
+pub fn req(
+    access_token: &[u8],
+    secret_uuid: &[u8; 16],
+    version: Option<u32>,
+) -> Result<Bytes, Box<dyn std::error::Error>> {
+    let mut buf = BytesMut::with_capacity(LEN_WITH_GROUP + 16 + 4);
 
-#[cfg(test)]
-mod test {
-    use super::*;
+    buf.put(access_token);
+    buf.put(&secret_uuid[..]);
 
-    #[test]
-    fn test() -> Result<(), Box<dyn std::error::Error>> {
-        Ok(())
+    if let Some(version) = version {
+        buf.put_u32(version);
     }
+
+    Ok(buf.into())
+}
+
+/// version.client_sealed.user_uuid.value
+pub fn handle(core: &Core, bytes: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
+    let version = match bytes.len() {
+        len if len == LEN_WITH_GROUP + 16 + 4 => {
+            Some(u32::from_be_bytes(bytes[LEN_WITH_GROUP + 16..].try_into()?))
+        }
+        len if len == LEN_WITH_GROUP + 16 => None,
+        _ => return Err("invalid format".into()),
+    };
+
+    let token_keys = core.token_keys()?;
+
+    let group_uuid = token::verify(
+        &token_keys,
+        &core.token_settings,
+        Kind::Access,
+        8,
+        &bytes[..LEN_WITH_GROUP],
+    )?
+    .group_uuid
+    .ok_or(TokenError::Group)?;
+
+    let secret_uuid: [u8; 16] = bytes[LEN_WITH_GROUP..LEN_WITH_GROUP + 16].try_into()?;
+
+    let txn = ReadTransaction::new(core.env.clone())?;
+    let access = txn.access();
+
+    let latest = match crate::secret::head(core, &access, &secret_uuid)? {
+        Some((secret_group_uuid, latest)) if secret_group_uuid == group_uuid => latest,
+        _ => return Err("unknown secret".into()),
+    };
+
+    if crate::group::require(core, &access, &secret_uuid, &group_uuid, Permissions::READ).is_err() {
+        return Err("secret is not readable by this group".into());
+    }
+
+    let version = version.unwrap_or(latest);
+
+    let secret = match crate::secret::load(core, &access, &secret_uuid, version)? {
+        Some(secret) => secret,
+        None => return Err("unknown secret version".into()),
+    };
+
+    let mut buf = BytesMut::with_capacity(4 + 1 + 16 + secret.value.len());
+
+    buf.put_u32(version);
+    buf.put_u8(secret.client_sealed as u8);
+    buf.put(&secret.user_uuid[..]);
+    buf.put(&secret.value[..]);
+
+    Ok(buf.into())
+}
+
+pub fn res(res: Bytes) -> Result<Secret, Box<dyn std::error::Error>> {
+    if res.len() < 21 {
+        return Err("invalid format".into());
+    }
+
+    Ok(Secret {
+        version: u32::from_be_bytes(res[0..4].try_into()?),
+        client_sealed: res[4] == 1,
+        user_uuid: res[5..21].try_into()?,
+        value: res[21..].to_vec(),
+    })
+}
+
+/// opens a value sealed on the client with `secret_put::seal`
+pub fn open(key: &[u8; 32], value: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    crate::seal::open_with(key, &[], value)
 }
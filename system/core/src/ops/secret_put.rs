@@ -1,20 +1,147 @@
-use bytes::Bytes;
+use crate::group;
+use crate::permissions::Permissions;
+use crate::secret::{self, Version, MAX_SECRET_LEN};
+use crate::Core;
+use bytes::{BufMut, Bytes, BytesMut};
+use cbwaw::token::{self, Kind, TokenError, LEN_WITH_GROUP};
+use saferlmdb::WriteTransaction;
+use uuid::Uuid;
 
-pub fn req(refresh_token: [u8; 73], action: u8) -> Result<Bytes, Box<dyn std::error::Error>> {
-    // TODO: validate refresh token
-    // TODO: check that user has
-    // let token = token::generate_access(action, &user, &group)?;
-    Ok(Bytes::new())
+/// access_token.client_sealed.has_uuid?secret_uuid.value
+///
+/// without a `secret_uuid` a new secret is created in the group of the
+/// access token, otherwise a new version is added to it.
+///
+/// with `client_sealed` the value has already been sealed with `seal`,
+/// under a key the server never sees.
+pub fn req(
+    access_token: &[u8],
+    secret_uuid: Option<&[u8; 16]>,
+    client_sealed: bool,
+    value: &[u8],
+) -> Result<Bytes, Box<dyn std::error::Error>> {
+    if value.len() > MAX_SECRET_LEN {
+        return Err("secret is too long".into());
+    }
+
+    let mut buf = BytesMut::with_capacity(LEN_WITH_GROUP + 2 + 16 + value.len());
+
+    buf.put(access_token);
+    buf.put_u8(client_sealed as u8);
+
+    match secret_uuid {
+        Some(secret_uuid) => {
+            buf.put_u8(1);
+            buf.put(&secret_uuid[..]);
+        }
+        None => buf.put_u8(0),
+    }
+
+    buf.put(value);
+
+    Ok(buf.into())
 }
 
-pub fn handle(bytes: Bytes) {}
+/// secret_uuid.version
+pub fn handle(core: &Core, bytes: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
+    if bytes.len() < LEN_WITH_GROUP + 2 {
+        return Err("invalid format".into());
+    }
+
+    let token_keys = core.token_keys()?;
+
+    let token = token::verify(
+        &token_keys,
+        &core.token_settings,
+        Kind::Access,
+        9,
+        &bytes[..LEN_WITH_GROUP],
+    )?;
+
+    let group_uuid = token.group_uuid.ok_or(TokenError::Group)?;
 
-#[cfg(test)]
-mod test {
-    use super::*;
+    let client_sealed = bytes[LEN_WITH_GROUP] == 1;
 
-    #[test]
-    fn test() -> Result<(), Box<dyn std::error::Error>> {
-        Ok(())
+    let (secret_uuid, value, new) = match bytes[LEN_WITH_GROUP + 1] {
+        0 => (
+            *Uuid::now_v7().as_bytes(),
+            &bytes[LEN_WITH_GROUP + 2..],
+            true,
+        ),
+        1 if bytes.len() >= LEN_WITH_GROUP + 18 => (
+            bytes[LEN_WITH_GROUP + 2..LEN_WITH_GROUP + 18].try_into()?,
+            &bytes[LEN_WITH_GROUP + 18..],
+            false,
+        ),
+        _ => return Err("invalid format".into()),
+    };
+
+    if value.is_empty() || value.len() > MAX_SECRET_LEN {
+        return Err("secret is empty or too long".into());
     }
+
+    let txn = WriteTransaction::new(core.env.clone())?;
+
+    let version = {
+        let mut access = txn.access();
+
+        if new {
+            // make available to anyone in this group
+            group::share(
+                core,
+                &mut access,
+                &secret_uuid,
+                &group_uuid,
+                Permissions::READ | Permissions::WRITE,
+            )?;
+        } else {
+            match secret::head(core, &access, &secret_uuid)? {
+                Some((secret_group_uuid, _)) if secret_group_uuid == group_uuid => {}
+                _ => return Err("unknown secret".into()),
+            }
+
+            if group::require(core, &access, &secret_uuid, &group_uuid, Permissions::WRITE).is_err()
+            {
+                return Err("secret is not writable by this group".into());
+            }
+        }
+
+        secret::store(
+            core,
+            &mut access,
+            &secret_uuid,
+            &group_uuid,
+            &Version {
+                client_sealed,
+                user_uuid: token.user_uuid,
+                value: value.to_vec(),
+            },
+        )?
+    };
+
+    txn.commit()?;
+
+    let mut buf = BytesMut::with_capacity(20);
+
+    buf.put(&secret_uuid[..]);
+    buf.put_u32(version);
+
+    Ok(buf.into())
+}
+
+/// (secret_uuid, version)
+pub fn res(res: Bytes) -> Result<([u8; 16], u32), Box<dyn std::error::Error>> {
+    if res.len() != 20 {
+        return Err("invalid format".into());
+    }
+
+    Ok((
+        res[0..16].try_into()?,
+        u32::from_be_bytes(res[16..20].try_into()?),
+    ))
+}
+
+/// seals a value on the client, for putting with `client_sealed`
+pub fn seal(key: &[u8; 32], value: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    crate::seal::seal_with(key, &[], value)
 }
@@ -0,0 +1,105 @@
+use crate::permissions::Permissions;
+use crate::Core;
+use bytes::{BufMut, Bytes, BytesMut};
+use saferlmdb::{LmdbResultExt, WriteTransaction};
+
+/// token.parent.kind.entity_uuid.cascade
+/// - token: 135 bytes, see `cbwaw::token::gen`, or a delegated token
+///   for storage_delete, see `cbwaw::delegated::gen`
+///
+/// without `cascade` an entity with children can't be deleted, and with
+/// it the group has to be able to delete every one of them too.
+pub fn req(
+    token: &[u8],
+
+    parent_uuid: &[u8; 16],
+    kind: u8,
+    entity_uuid: &[u8; 16],
+
+    cascade: bool,
+) -> Result<Bytes, Box<dyn std::error::Error>> {
+    let mut buf = BytesMut::with_capacity(token.len() + 16 + 1 + 16 + 1);
+
+    buf.put(token);
+
+    buf.put(&parent_uuid[..]);
+    buf.put_u8(kind);
+    buf.put(&entity_uuid[..]);
+
+    buf.put_u8(cascade as u8);
+
+    Ok(buf.into())
+}
+
+/// deleted
+///
+/// how many entities were deleted, counting the children of a cascade
+pub fn handle(core: &Core, bytes: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
+    let (grant, token_len) = crate::delegation::authorize(core, 15, &bytes)?;
+
+    let group_uuid = grant.group_uuid;
+
+    let bytes = bytes.slice(token_len..);
+
+    if bytes.len() != 34 {
+        return Err("invalid format".into());
+    }
+
+    let parent_uuid: [u8; 16] = bytes[0..16].try_into()?;
+    let kind = bytes[16];
+    let entity_uuid: [u8; 16] = bytes[17..33].try_into()?;
+
+    let cascade = bytes[33] == 1;
+
+    if !grant.allows(&parent_uuid, kind) {
+        return Err("token can't be used for this entity".into());
+    }
+
+    let mut key = [0u8; 33];
+
+    key[0..16].copy_from_slice(&parent_uuid[..]);
+    key[16] = kind;
+    key[17..33].copy_from_slice(&entity_uuid[..]);
+
+    let txn = WriteTransaction::new(core.env.clone())?;
+
+    let deleted = {
+        let mut access = txn.access();
+
+        crate::entity::require_change(
+            core,
+            &access,
+            &entity_uuid,
+            &parent_uuid,
+            &group_uuid,
+            Permissions::DELETE,
+        )?;
+
+        if access
+            .get::<[u8; 33], [u8]>(&core.entity_db, &key)
+            .to_opt()?
+            .is_none()
+        {
+            return Err("unknown entity".into());
+        }
+
+        crate::entity::remove(
+            core,
+            &txn,
+            &mut access,
+            &parent_uuid,
+            kind,
+            &entity_uuid,
+            cascade,
+            Some(&group_uuid),
+        )?
+    };
+
+    txn.commit()?;
+
+    Ok(Bytes::copy_from_slice(&deleted.to_be_bytes()))
+}
+
+pub fn res(res: Bytes) -> Result<u32, Box<dyn std::error::Error>> {
+    Ok(u32::from_be_bytes(res[..].try_into()?))
+}
@@ -1,17 +1,13 @@
+use crate::permissions::Permissions;
 use crate::Core;
 use bytes::{BufMut, Bytes, BytesMut};
-use cbwaw::token;
 use saferlmdb::{put, WriteTransaction};
 use uuid::Uuid;
 
 /// reversed <-
-/// put[token[action.hmac.exp.user_uuid.group_uuid]parent.kind.grandparent.parent_kind.entity]
-/// - token
-///     - action: 1 byte
-///     - hmac: 32 bytes
-///     - exp: 8 bytes
-///     - user_uuid: 16 bytes
-///     - group_uuid: 16 bytes
+/// put[token[version.kind.action.key_id.exp.issuer.audience.user_uuid.group_uuid.hmac]parent.kind.grandparent.parent_kind.entity]
+/// - token: 135 bytes, see `cbwaw::token::gen`, or a delegated token
+///   for storage_put, see `cbwaw::delegated::gen`
 /// - h
 ///     - parent: 16 bytes
 ///     - kind: 1 byte (max 255 entities)
@@ -20,7 +16,7 @@ use uuid::Uuid;
 /// - entity:
 ///     - entity: ..
 pub fn req(
-    access_token: &[u8],
+    token: &[u8],
 
     parent_uuid: &[u8; 16],
     kind: u8,
@@ -30,9 +26,57 @@ pub fn req(
 
     entity: &[u8],
 ) -> Result<Bytes, Box<dyn std::error::Error>> {
-    let mut buf = BytesMut::with_capacity(73 + 1 + 16 + entity.len());
+    put_req(
+        token,
+        parent_uuid,
+        kind,
+        grandparent_uuid,
+        parent_kind,
+        None,
+        entity,
+    )
+}
+
+/// for `storage_put_with_ttl`
+///
+/// token.parent.kind.grandparent.parent_kind.ttl.entity
+/// - ttl: 8 bytes, seconds until it expires, 0 to never expire
+pub fn req_with_ttl(
+    token: &[u8],
+
+    parent_uuid: &[u8; 16],
+    kind: u8,
+
+    grandparent_uuid: &[u8; 16],
+    parent_kind: u8,
+
+    ttl: u64,
+
+    entity: &[u8],
+) -> Result<Bytes, Box<dyn std::error::Error>> {
+    put_req(
+        token,
+        parent_uuid,
+        kind,
+        grandparent_uuid,
+        parent_kind,
+        Some(ttl),
+        entity,
+    )
+}
+
+fn put_req(
+    token: &[u8],
+    parent_uuid: &[u8; 16],
+    kind: u8,
+    grandparent_uuid: &[u8; 16],
+    parent_kind: u8,
+    ttl: Option<u64>,
+    entity: &[u8],
+) -> Result<Bytes, Box<dyn std::error::Error>> {
+    let mut buf = BytesMut::with_capacity(token.len() + 16 + 1 + 16 + 1 + 8 + entity.len());
 
-    buf.put(&access_token[..]);
+    buf.put(token);
 
     buf.put(&parent_uuid[..]);
     buf.put_u8(kind);
@@ -40,6 +84,10 @@ pub fn req(
     buf.put(&grandparent_uuid[..]);
     buf.put_u8(parent_kind);
 
+    if let Some(ttl) = ttl {
+        buf.put_u64(ttl);
+    }
+
     buf.put(entity);
 
     Ok(buf.into())
@@ -58,21 +106,45 @@ pub fn req(
 ///
 /// !! "an upstream provider has made a change to a data model you depend on; see the diff ..."
 /// !! "see if you're impacted and resolve any discrepancies ..."
-pub fn handle(core: &Core, bytes: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
-    let len = bytes.len();
+///
+/// with `with_ttl` the request is from `req_with_ttl`, otherwise the
+/// entity never expires.
+pub fn handle(
+    core: &Core,
+    with_ttl: bool,
+    bytes: Bytes,
+) -> Result<Bytes, Box<dyn std::error::Error>> {
+    let (grant, token_len) = crate::delegation::authorize(core, 12, &bytes)?;
+
+    let user_uuid = grant.user_uuid;
+    let group_uuid = grant.group_uuid;
+
+    let bytes = bytes.slice(token_len..);
 
-    if len < 73 {
-        return Err("req does not include access token".into());
+    let entity_start = if with_ttl { 42 } else { 34 };
+
+    if bytes.len() < entity_start {
+        return Err("invalid format".into());
     }
 
-    let (user_uuid, group_uuid) = token::verify_with_group(12, &bytes[0..73])?;
+    let parent_uuid: [u8; 16] = bytes[0..16].try_into()?;
+
+    let kind = bytes[16];
 
-    let parent_uuid: [u8; 16] = bytes[73..89].try_into()?;
+    let grandparent_uuid: [u8; 16] = bytes[17..33].try_into()?;
+    let parent_kind = bytes[33];
 
-    let kind = bytes[89];
+    let ttl = if with_ttl {
+        u64::from_be_bytes(bytes[34..42].try_into()?)
+    } else {
+        0
+    };
 
-    let grandparent_uuid: [u8; 16] = bytes[90..106].try_into()?;
-    let parent_kind = bytes[106];
+    let entity_bytes = bytes.slice(entity_start..);
+
+    if !grant.allows(&parent_uuid, kind) {
+        return Err("token can't be used for this entity".into());
+    }
 
     let uuid = Uuid::now_v7();
     let entity_uuid = *uuid.as_bytes();
@@ -83,43 +155,61 @@ pub fn handle(core: &Core, bytes: Bytes) -> Result<Bytes, Box<dyn std::error::Er
     key[16] = kind;
     key[17..33].copy_from_slice(&entity_uuid[..]);
 
-    let entity_len = len - 107;
-
-    let mut entity = BytesMut::with_capacity(16 + 16 + 1 + entity_len);
+    let mut entity = BytesMut::with_capacity(16 + 16 + 1 + entity_bytes.len());
 
     entity.put(&grandparent_uuid[..]);
     entity.put_u8(parent_kind);
     entity.put(&user_uuid[..]);
 
-    entity.put(&bytes[107..]);
+    entity.put(&entity_bytes[..]);
 
     let txn = WriteTransaction::new(core.env.clone())?;
 
     {
         let mut access = txn.access();
 
-        // check the parent for this group association
-        let mut rule = [0u8; 33];
-
-        rule[0..16].copy_from_slice(&parent_uuid[..]);
-        rule[16..32].copy_from_slice(&group_uuid[..]);
-        // read/write
-        rule[32] = 0;
+        // the back link has to be where the parent really is
+        if let Some(parent) = crate::entity::parent(core, &access, &parent_uuid)? {
+            if parent != (grandparent_uuid, parent_kind) {
+                return Err("grandparent does not match the parent".into());
+            }
+        }
 
-        // check the parent group
-        access.get::<[u8; 33], [u8]>(&core.group_db.clone(), &rule)?;
+        if crate::expiry::is_expired(core, &access, &parent_uuid, crate::expiry::now(core)?)? {
+            return Err("parent has expired".into());
+        }
 
-        // define read only rule
-        rule[0..16].copy_from_slice(&entity_uuid[..]);
-        rule[16..32].copy_from_slice(&group_uuid[..]);
-        // read
-        rule[32] = 1;
-
-        // make available to read for anyone in this group
-        access.put::<[u8; 33], [u8]>(&core.group_db, &rule, &[], put::Flags::empty())?;
+        // check the parent for this group association
+        if crate::entity::require(core, &access, &parent_uuid, &group_uuid, Permissions::WRITE)
+            .is_err()
+        {
+            return Err("parent is not writable by this group".into());
+        }
+
+        // make available to read for anyone in this group, unless
+        // it is inherited from the parent
+        if !core.inherit_permissions {
+            crate::group::share(
+                core,
+                &mut access,
+                &entity_uuid,
+                &group_uuid,
+                Permissions::READ,
+            )?;
+        }
 
         // insert
         access.put(&core.entity_db, &key, &*entity, put::Flags::empty())?;
+        crate::entity::index(core, &mut access, &entity_uuid, &parent_uuid, kind)?;
+        crate::expiry::set(core, &mut access, &entity_uuid, ttl)?;
+        crate::index::add(
+            core,
+            &mut access,
+            &parent_uuid,
+            kind,
+            &entity_uuid,
+            &entity_bytes,
+        )?;
     }
 
     txn.commit()?;
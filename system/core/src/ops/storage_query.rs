@@ -1,39 +1,164 @@
+use crate::permissions::Permissions;
+use crate::properties::{decode_properties, encode_properties, Properties};
+use crate::schema::{Property, PropertyType};
 use crate::Core;
 use bitcode::{Decode, Encode};
 use bytes::{BufMut, Bytes, BytesMut};
-use cbwaw::token;
-use saferlmdb::ReadTransaction;
+use saferlmdb::{ConstAccessor, ConstTransaction, LmdbResultExt, ReadTransaction};
+use std::cmp::Ordering;
 use std::collections::BTreeMap;
 
+/// most entities returned for each kind or link of each uuid in a query
+pub const MAX_QUERY_LIMIT: u16 = 1000;
+
 #[derive(Encode, Decode, PartialEq, Debug)]
 pub struct Entity {
-    uuid: [u8; 16],
-    user: [u8; 16],
-    value: Vec<u8>,
+    pub uuid: [u8; 16],
+    pub user: [u8; 16],
+    pub value: Vec<u8>,
+}
+
+/// where to pick up each kind or link of each uuid that has more entities
+#[derive(Encode, Decode, Clone, PartialEq, Debug, Default)]
+pub struct Continuation {
+    /// uuid -> kind -> uuid of the last entity returned
+    pub after: BTreeMap<[u8; 16], BTreeMap<u8, [u8; 16]>>,
+    /// uuid -> link name -> uuid of the last entity returned
+    pub links_after: BTreeMap<[u8; 16], BTreeMap<String, [u8; 16]>>,
 }
 
 #[derive(Encode, Decode, PartialEq, Debug)]
 pub struct QueryResult {
     /// uuid -> kind -> entity[]
     pub entities: BTreeMap<[u8; 16], BTreeMap<u8, Vec<Entity>>>,
+    /// uuid -> link name -> entity[]
+    pub links: BTreeMap<[u8; 16], BTreeMap<String, Vec<Entity>>>,
+    /// `None` once every kind and link of every uuid has been returned
+    pub continuation: Option<Continuation>,
+}
+
+/// how much of a query to return, and in what order
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct Page {
+    /// for each kind or link of each uuid, 0 for `MAX_QUERY_LIMIT`
+    pub limit: u16,
+    /// newest first, instead of oldest first
+    pub reverse: bool,
+    /// from a previous `QueryResult`, or made up to start after an
+    /// entity. only the kinds and links of the uuids in it are returned.
+    pub continuation: Option<Continuation>,
+}
+
+/// a condition on a property, named as in the schema of the kind, with
+/// values encoded as its type. see `PropertyType`
+#[derive(Encode, Decode, Clone, PartialEq, Debug)]
+pub enum Predicate {
+    Eq(String, Vec<u8>),
+    /// from inclusive, to exclusive, either end can be left open
+    Range(String, Option<Vec<u8>>, Option<Vec<u8>>),
+    Prefix(String, Vec<u8>),
+    In(String, Vec<Vec<u8>>),
+}
+
+impl Predicate {
+    fn name(&self) -> &str {
+        match self {
+            Predicate::Eq(name, _)
+            | Predicate::Range(name, _, _)
+            | Predicate::Prefix(name, _)
+            | Predicate::In(name, _) => name,
+        }
+    }
+
+    fn holds(&self, property_type: PropertyType, value: &[u8]) -> bool {
+        let equal =
+            |expected: &Vec<u8>| property_type.compare(value, expected) == Some(Ordering::Equal);
+
+        match self {
+            Predicate::Eq(_, expected) => equal(expected),
+            Predicate::Range(_, from, to) => {
+                from.as_ref().is_none_or(|from| {
+                    matches!(
+                        property_type.compare(value, from),
+                        Some(Ordering::Greater | Ordering::Equal)
+                    )
+                }) && to
+                    .as_ref()
+                    .is_none_or(|to| property_type.compare(value, to) == Some(Ordering::Less))
+            }
+            Predicate::Prefix(_, prefix) => value.starts_with(prefix),
+            Predicate::In(_, values) => values.iter().any(equal),
+        }
+    }
+}
+
+/// a uuid to query, under its parent, for its children and links
+#[derive(Clone, PartialEq, Debug)]
+pub struct Query<'a> {
+    pub parent_uuid: &'a [u8; 16],
+    pub uuid: &'a [u8; 16],
+    /// kinds of the children to return
+    pub kinds: Vec<u8>,
+    /// names of the links to follow
+    pub links: Vec<&'a str>,
 }
 
-/// [parent][uuid][kind count][kinds][parent][uuid][kind count][kinds]
+/// which entities to return, and which of their properties
+#[derive(Encode, Decode, Clone, PartialEq, Debug, Default)]
+pub struct Filter {
+    /// every one of them has to hold
+    pub predicates: Vec<Predicate>,
+    /// names of the properties to return, every one of them if empty
+    pub projection: Vec<String>,
+}
+
+/// [token][limit][reverse][continuation len][continuation][filter len][filter][parent][uuid][kind count][kinds][link count]([name len][name])..
+///
+/// the token is an access token for storage_query, or a delegated token
+/// for storage_query or storage_put.
+///
+/// filtering on or projecting properties needs the queried kinds to have
+/// a schema, see `Core::with_entity_schema`. links are followed by their
+/// name in the schema of the uuid, including the `on` name of links to it.
 pub fn req(
     token: &[u8],
-    query: Vec<(&[u8; 16], &[u8; 16], Vec<u8>)>,
+    page: &Page,
+    filter: &Filter,
+    query: Vec<Query>,
 ) -> Result<Bytes, Box<dyn std::error::Error>> {
     if query.len() > 255 {
         return Err("query cannot contain more than 255 entities".into());
     }
 
+    let continuation = match &page.continuation {
+        Some(continuation) => bitcode::encode(continuation),
+        None => vec![],
+    };
+
     let mut buf = BytesMut::new();
 
-    buf.put(&token[..]);
+    buf.put(token);
+
+    buf.put_u16(page.limit);
+    buf.put_u8(page.reverse as u8);
 
-    for (parent_uuid, entity_uuid, kinds) in query {
+    buf.put_u32(u32::try_from(continuation.len())?);
+    buf.put(&continuation[..]);
+
+    let filter = bitcode::encode(filter);
+
+    buf.put_u32(u32::try_from(filter.len())?);
+    buf.put(&filter[..]);
+
+    for Query {
+        parent_uuid,
+        uuid,
+        kinds,
+        links,
+    } in query
+    {
         buf.put(&parent_uuid[..]);
-        buf.put(&entity_uuid[..]);
+        buf.put(&uuid[..]);
 
         if kinds.len() > 255 {
             return Err("cannot have more than 255 kinds".into());
@@ -44,112 +169,539 @@ pub fn req(
         for kind in kinds {
             buf.put_u8(kind);
         }
+
+        if links.len() > 255 {
+            return Err("cannot have more than 255 links".into());
+        }
+
+        buf.put_u8(links.len() as u8);
+
+        for name in links {
+            buf.put_u8(u8::try_from(name.len()).map_err(|_| "link name is too long")?);
+            buf.put(name.as_bytes());
+        }
     }
 
     Ok(buf.into())
 }
 
+/// entities the group can't read, or that have expired, are left out,
+/// including the ones on the other end of a link
 pub fn handle(core: &Core, bytes: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
-    let len = bytes.len();
-    let mut query_cursor = 73;
+    let (grant, token_len) = crate::delegation::authorize(core, 13, &bytes)?;
 
-    if len < query_cursor {
-        return Err("does not include token".into());
+    let bytes = bytes.slice(token_len..);
+
+    if bytes.len() < 7 {
+        return Err("invalid query".into());
     }
 
-    let (_, group_uuid) = token::verify_with_group(13, &bytes[0..query_cursor])?;
+    let limit = match u16::from_be_bytes(bytes[0..2].try_into()?) {
+        0 => MAX_QUERY_LIMIT,
+        limit if limit > MAX_QUERY_LIMIT => return Err("limit is too high".into()),
+        limit => limit,
+    } as usize;
+
+    let reverse = bytes[2] == 1;
+
+    let continuation_len = u32::from_be_bytes(bytes[3..7].try_into()?) as usize;
+    let filter_start = 7 + continuation_len;
+
+    if bytes.len() < filter_start + 4 {
+        return Err("invalid query".into());
+    }
+
+    let continuation: Option<Continuation> = match continuation_len {
+        0 => None,
+        _ => Some(bitcode::decode(&bytes[7..filter_start]).map_err(|_| "invalid continuation")?),
+    };
+
+    let filter_len = u32::from_be_bytes(bytes[filter_start..filter_start + 4].try_into()?) as usize;
+    let mut query_cursor = filter_start + 4 + filter_len;
+
+    if bytes.len() < query_cursor {
+        return Err("invalid query".into());
+    }
+
+    let filter: Filter =
+        bitcode::decode(&bytes[filter_start + 4..query_cursor]).map_err(|_| "invalid filter")?;
+
+    let now = crate::expiry::now(core)?;
 
     let txn = ReadTransaction::new(core.env.clone())?;
     let access = txn.access();
 
-    let mut entity_cursor = txn
-        .cursor(core.entity_db.clone())
-        .expect("failed to create entity cursor");
-
     let mut query_result = QueryResult {
         entities: BTreeMap::new(),
+        links: BTreeMap::new(),
+        continuation: None,
     };
 
-    'outer: loop {
-        if len == query_cursor {
-            break 'outer;
-        } else if len > query_cursor + 34 {
-            return Err("invalid query".into());
-        }
+    let mut next_continuation = Continuation::default();
 
-        let parent_uuid = &bytes[query_cursor..query_cursor + 16];
-        query_cursor += 16;
+    let reader = Reader {
+        core,
+        access: &access,
+        group_uuid: grant.group_uuid,
+        now,
+    };
 
-        let entity_uuid = &bytes[query_cursor..query_cursor + 16];
-        query_cursor += 16;
+    while query_cursor < bytes.len() {
+        if bytes.len() < query_cursor + 33 {
+            return Err("invalid query".into());
+        }
 
-        let kind_count = bytes[query_cursor] as usize;
-        query_cursor += 1;
+        let parent_uuid: [u8; 16] = bytes[query_cursor..query_cursor + 16].try_into()?;
+        let entity_uuid: [u8; 16] = bytes[query_cursor + 16..query_cursor + 32].try_into()?;
+        let kind_count = bytes[query_cursor + 32] as usize;
 
-        let end = query_cursor + kind_count;
+        let kinds = query_cursor + 33;
+        let kinds_end = kinds + kind_count;
+        query_cursor = kinds_end;
 
-        if len > end {
+        if bytes.len() < query_cursor + 1 {
             return Err("invalid query".into());
         }
 
-        'inner: loop {
-            let mut root_key = [0u8; 33];
+        let link_count = bytes[query_cursor] as usize;
+        query_cursor += 1;
 
-            root_key[0..16].copy_from_slice(&parent_uuid[..]);
-            root_key[17] = bytes[query_cursor];
-            root_key[17..33].copy_from_slice(&entity_uuid[..]);
+        let mut links = vec![];
 
-            loop {
-                if query_cursor > end {
-                    break 'inner;
-                }
+        for _ in 0..link_count {
+            let Some(&name_len) = bytes.get(query_cursor) else {
+                return Err("invalid query".into());
+            };
 
-                let mut start_key = [0u8; 17];
+            let name_end = query_cursor + 1 + name_len as usize;
 
-                start_key[0..16].copy_from_slice(&entity_uuid[..]);
-                start_key[16] = bytes[query_cursor];
-                query_cursor += 1;
+            if bytes.len() < name_end {
+                return Err("invalid query".into());
+            }
 
-                let (mut entity_key, mut entity_value) =
-                    entity_cursor.seek_k_both::<[u8], [u8]>(&access, &start_key)?;
+            links.push(std::str::from_utf8(&bytes[query_cursor + 1..name_end])?);
+            query_cursor = name_end;
+        }
+
+        let entity_kind = match crate::entity::parent(core, &access, &entity_uuid)? {
+            Some((parent, _)) if parent != parent_uuid => {
+                return Err("parent does not match the entity".into());
+            }
+            Some((_, entity_kind)) => Some(entity_kind),
+            None => None,
+        };
+
+        if crate::expiry::is_expired(core, &access, &entity_uuid, now)? {
+            continue;
+        }
 
-                loop {
-                    if entity_key[17] != start_key[16] {
-                        break;
-                    } else {
-                        let mut rule = [0u8; 33];
+        for &kind in &bytes[kinds..kinds_end] {
+            if !grant.allows(&entity_uuid, kind) {
+                return Err("token can't be used for this entity".into());
+            }
 
-                        rule[0..16].copy_from_slice(&entity_key);
-                        rule[16..32].copy_from_slice(&group_uuid[..]);
+            let after = match &continuation {
+                Some(continuation) => match continuation
+                    .after
+                    .get(&entity_uuid)
+                    .and_then(|after| after.get(&kind))
+                {
+                    Some(after) => Some(*after),
+                    // already returned in full
+                    None => continue,
+                },
+                None => None,
+            };
+
+            let (predicates, projection) = compile(core, &filter, kind)?;
+
+            let window = Window {
+                after,
+                reverse,
+                limit,
+            };
+
+            let (entities, more) = children(
+                &reader,
+                &txn,
+                &entity_uuid,
+                kind,
+                &predicates,
+                &projection,
+                window,
+            )?;
+
+            if more {
+                next_continuation
+                    .after
+                    .entry(entity_uuid)
+                    .or_default()
+                    .insert(kind, entities[limit - 1].uuid);
+            }
 
-                        // todo: this should probably just be a list
-                        // todo: of perms on the group, instead of each
-                        // todo: group perm getting its own entry.
-                        rule[32] = 1;
+            if !entities.is_empty() {
+                query_result
+                    .entities
+                    .entry(entity_uuid)
+                    .or_default()
+                    .insert(kind, entities);
+            }
+        }
 
-                        access.get::<[u8; 33], [u8]>(&core.group_db.clone(), &rule)?;
+        // what an entity links to is part of it
+        if !links.is_empty() {
+            crate::entity::require(
+                core,
+                &access,
+                &entity_uuid,
+                &grant.group_uuid,
+                Permissions::READ,
+            )?;
+        }
 
-                        let entities = query_result
-                            .entities
-                            .entry(parent_uuid.try_into().expect("failed to convert"))
-                            .or_insert(BTreeMap::new());
+        for name in links {
+            let entity_kind = entity_kind.ok_or("entity has no links")?;
+            let link = core.schema.link(entity_kind, name)?;
 
-                        let entities = entities.entry(start_key[16]).or_insert(vec![]);
+            if !grant.allows(&parent_uuid, entity_kind) {
+                return Err("token can't be used for this entity".into());
+            }
 
-                        entities.push(Entity {
-                            uuid: entity_key[17..33].try_into().expect("failed to convert"),
-                            user: entity_value[17..33].try_into().expect("failed to convert"),
-                            value: entity_value[33..].to_vec(),
-                        });
-                    }
+            let after = match &continuation {
+                Some(continuation) => match continuation
+                    .links_after
+                    .get(&entity_uuid)
+                    .and_then(|after| after.get(name))
+                {
+                    Some(after) => Some(*after),
+                    // already returned in full
+                    None => continue,
+                },
+                None => None,
+            };
+
+            let (predicates, projection) = compile(core, &filter, link.kind)?;
+
+            let candidates =
+                crate::reference::linked(core, &txn, &access, &entity_uuid, link.ref_type)?;
+
+            let window = Window {
+                after,
+                reverse,
+                limit,
+            };
+
+            let (entities, more) = page(candidates, window, |linked_uuid| {
+                reader.linked(&predicates, &projection, link.kind, &linked_uuid)
+            })?;
+
+            if more {
+                next_continuation
+                    .links_after
+                    .entry(entity_uuid)
+                    .or_default()
+                    .insert(name.to_string(), entities[limit - 1].uuid);
+            }
 
-                    (entity_key, entity_value) = entity_cursor.next::<[u8; 33], [u8]>(&access)?;
-                }
+            if !entities.is_empty() {
+                query_result
+                    .links
+                    .entry(entity_uuid)
+                    .or_default()
+                    .insert(name.to_string(), entities);
             }
         }
     }
 
+    if !next_continuation.after.is_empty() || !next_continuation.links_after.is_empty() {
+        query_result.continuation = Some(next_continuation);
+    }
+
     let encoded: Vec<u8> = bitcode::encode(&query_result);
 
     Ok(encoded.into())
 }
+
+/// the predicates of the filter, and the ids of the projected properties,
+/// as properties of the kind
+pub(crate) type Compiled<'a> = (Vec<(Property, &'a Predicate)>, Vec<u8>);
+
+pub(crate) fn compile<'a>(
+    core: &Core,
+    filter: &'a Filter,
+    kind: u8,
+) -> Result<Compiled<'a>, Box<dyn std::error::Error>> {
+    let predicates = filter
+        .predicates
+        .iter()
+        .map(|predicate| Ok((core.schema.property(kind, predicate.name())?, predicate)))
+        .collect::<Result<Vec<_>, Box<dyn std::error::Error>>>()?;
+
+    let projection = filter
+        .projection
+        .iter()
+        .map(|name| Ok(core.schema.property(kind, name)?.id))
+        .collect::<Result<Vec<_>, Box<dyn std::error::Error>>>()?;
+
+    Ok((predicates, projection))
+}
+
+/// where a page of entities starts, and how long it is
+#[derive(Clone, Copy)]
+pub(crate) struct Window {
+    /// uuid of the last entity of the previous page
+    pub after: Option<[u8; 16]>,
+    /// newest first, instead of oldest first
+    pub reverse: bool,
+    pub limit: usize,
+}
+
+/// what a group is returned of the entities it queries
+pub(crate) struct Reader<'a> {
+    pub core: &'a Core,
+    pub access: &'a ConstAccessor<'a>,
+    pub group_uuid: [u8; 16],
+    pub now: u64,
+}
+
+impl Reader<'_> {
+    /// the entity, if the group can read it, it hasn't expired and the
+    /// predicates hold for it
+    pub fn found(
+        &self,
+        predicates: &[(Property, &Predicate)],
+        projection: &[u8],
+        entity_uuid: [u8; 16],
+        value: &[u8],
+    ) -> Result<Option<Entity>, Box<dyn std::error::Error>> {
+        let readable = crate::entity::require(
+            self.core,
+            self.access,
+            &entity_uuid,
+            &self.group_uuid,
+            Permissions::READ,
+        )
+        .is_ok();
+
+        if !readable || crate::expiry::is_expired(self.core, self.access, &entity_uuid, self.now)? {
+            return Ok(None);
+        }
+
+        match select(predicates, projection, &value[33..])? {
+            Some(selected) => Ok(Some(Entity {
+                uuid: entity_uuid,
+                user: value[17..33].try_into()?,
+                value: selected,
+            })),
+            None => Ok(None),
+        }
+    }
+
+    /// `found` for an entity on the other end of a link, if it is of the
+    /// kind the link is to
+    pub fn linked(
+        &self,
+        predicates: &[(Property, &Predicate)],
+        projection: &[u8],
+        kind: u8,
+        linked_uuid: &[u8; 16],
+    ) -> Result<Option<Entity>, Box<dyn std::error::Error>> {
+        match crate::entity::get(self.core, self.access, linked_uuid)? {
+            Some((_, linked_kind, value)) if linked_kind == kind => {
+                self.found(predicates, projection, *linked_uuid, value)
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+/// up to `limit` of what `found` returns for the candidates, which are
+/// oldest first, starting after `after`, and whether there are more.
+pub(crate) fn page(
+    mut candidates: Vec<[u8; 16]>,
+    window: Window,
+    mut found: impl FnMut([u8; 16]) -> Result<Option<Entity>, Box<dyn std::error::Error>>,
+) -> Result<(Vec<Entity>, bool), Box<dyn std::error::Error>> {
+    if window.reverse {
+        candidates.reverse();
+    }
+
+    let mut entities = vec![];
+
+    for uuid in candidates {
+        let seen = window.after.is_some_and(|after| match window.reverse {
+            true => uuid >= after,
+            false => uuid <= after,
+        });
+
+        if seen {
+            continue;
+        }
+
+        if entities.len() == window.limit {
+            return Ok((entities, true));
+        }
+
+        entities.extend(found(uuid)?);
+    }
+
+    Ok((entities, false))
+}
+
+/// a page of the children of the kind under the parent, looked up by an
+/// index if a predicate can use one, and whether there are more.
+pub(crate) fn children(
+    reader: &Reader,
+    txn: &ConstTransaction,
+    parent_uuid: &[u8; 16],
+    kind: u8,
+    predicates: &[(Property, &Predicate)],
+    projection: &[u8],
+    window: Window,
+) -> Result<(Vec<Entity>, bool), Box<dyn std::error::Error>> {
+    let (core, access) = (reader.core, reader.access);
+
+    let mut prefix = [0u8; 17];
+
+    prefix[0..16].copy_from_slice(&parent_uuid[..]);
+    prefix[16] = kind;
+
+    if let Some(candidates) = indexed(core, txn, access, parent_uuid, kind, predicates)? {
+        return page(candidates, window, |child_uuid| {
+            let mut key = [0u8; 33];
+
+            key[0..17].copy_from_slice(&prefix[..]);
+            key[17..33].copy_from_slice(&child_uuid[..]);
+
+            match access
+                .get::<[u8; 33], [u8]>(&core.entity_db, &key)
+                .to_opt()?
+            {
+                Some(value) => reader.found(predicates, projection, child_uuid, value),
+                None => Ok(None),
+            }
+        });
+    }
+
+    let mut entities = vec![];
+    let mut cursor = txn.cursor(core.entity_db.clone())?;
+
+    let mut bound = [0u8; 33];
+
+    bound[0..17].copy_from_slice(&prefix[..]);
+
+    // no uuid v7 is all 0xff, so newest first starts below all of them
+    let start = if window.reverse { [0xff; 16] } else { [0; 16] };
+    bound[17..33].copy_from_slice(&window.after.unwrap_or(start));
+
+    let mut next = cursor
+        .seek_range_k::<[u8], [u8]>(access, &bound[..])
+        .to_opt()?;
+
+    if window.reverse {
+        next = match next {
+            Some(_) => cursor.prev::<[u8], [u8]>(access).to_opt()?,
+            None => cursor.last::<[u8], [u8]>(access).to_opt()?,
+        };
+    } else if window.after.is_some() && next.is_some_and(|(key, _)| key == &bound[..]) {
+        next = cursor.next::<[u8], [u8]>(access).to_opt()?;
+    }
+
+    while let Some((key, value)) = next {
+        if key.len() != 33 || !key.starts_with(&prefix) {
+            break;
+        }
+
+        if entities.len() == window.limit {
+            return Ok((entities, true));
+        }
+
+        entities.extend(reader.found(predicates, projection, key[17..33].try_into()?, value)?);
+
+        next = match window.reverse {
+            true => cursor.prev::<[u8], [u8]>(access).to_opt()?,
+            false => cursor.next::<[u8], [u8]>(access).to_opt()?,
+        };
+    }
+
+    Ok((entities, false))
+}
+
+/// uuids of the entities an equality on an indexed property picks out,
+/// oldest first. `None` if no predicate can use an index.
+fn indexed(
+    core: &Core,
+    txn: &ConstTransaction,
+    access: &ConstAccessor,
+    parent_uuid: &[u8; 16],
+    kind: u8,
+    predicates: &[(Property, &Predicate)],
+) -> Result<Option<Vec<[u8; 16]>>, Box<dyn std::error::Error>> {
+    for (property, predicate) in predicates {
+        if property.index.is_none() {
+            continue;
+        }
+
+        let values = match predicate {
+            Predicate::Eq(_, value) => vec![value],
+            Predicate::In(_, values) => values.iter().collect(),
+            _ => continue,
+        };
+
+        let mut candidates = vec![];
+
+        for value in values {
+            candidates.extend(crate::index::lookup(
+                core,
+                txn,
+                access,
+                parent_uuid,
+                kind,
+                *property,
+                value,
+            )?);
+        }
+
+        candidates.sort();
+        candidates.dedup();
+
+        return Ok(Some(candidates));
+    }
+
+    Ok(None)
+}
+
+/// the entity, with only the projected properties, if every predicate
+/// holds for it. entities that aren't made of properties only hold
+/// when there is nothing to filter on or project.
+fn select(
+    predicates: &[(Property, &Predicate)],
+    projection: &[u8],
+    entity: &[u8],
+) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+    if predicates.is_empty() && projection.is_empty() {
+        return Ok(Some(entity.to_vec()));
+    }
+
+    let Ok(properties) = decode_properties(entity) else {
+        return Ok(None);
+    };
+
+    for (property, predicate) in predicates {
+        match properties.get(&property.id) {
+            Some(value) if predicate.holds(property.property_type, value) => (),
+            _ => return Ok(None),
+        }
+    }
+
+    if projection.is_empty() {
+        return Ok(Some(entity.to_vec()));
+    }
+
+    let projected: Properties = properties
+        .into_iter()
+        .filter(|(id, _)| projection.contains(id))
+        .collect();
+
+    Ok(Some(encode_properties(&projected)?))
+}
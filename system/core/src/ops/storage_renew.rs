@@ -0,0 +1,96 @@
+use crate::permissions::Permissions;
+use crate::Core;
+use bytes::{BufMut, Bytes, BytesMut};
+use saferlmdb::{LmdbResultExt, WriteTransaction};
+
+/// token.parent.kind.entity_uuid.ttl
+/// - token: 135 bytes, see `cbwaw::token::gen`, or a delegated token
+///   for storage_renew, see `cbwaw::delegated::gen`
+/// - ttl: seconds from now until it expires, 0 to never expire
+pub fn req(
+    token: &[u8],
+
+    parent_uuid: &[u8; 16],
+    kind: u8,
+    entity_uuid: &[u8; 16],
+
+    ttl: u64,
+) -> Result<Bytes, Box<dyn std::error::Error>> {
+    let mut buf = BytesMut::with_capacity(token.len() + 16 + 1 + 16 + 8);
+
+    buf.put(token);
+
+    buf.put(&parent_uuid[..]);
+    buf.put_u8(kind);
+    buf.put(&entity_uuid[..]);
+
+    buf.put_u64(ttl);
+
+    Ok(buf.into())
+}
+
+/// exp
+///
+/// when the entity now expires, 0 for never. an entity that has
+/// already expired can't be renewed.
+pub fn handle(core: &Core, bytes: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
+    let (grant, token_len) = crate::delegation::authorize(core, 16, &bytes)?;
+
+    let group_uuid = grant.group_uuid;
+
+    let bytes = bytes.slice(token_len..);
+
+    if bytes.len() != 41 {
+        return Err("invalid format".into());
+    }
+
+    let parent_uuid: [u8; 16] = bytes[0..16].try_into()?;
+    let kind = bytes[16];
+    let entity_uuid: [u8; 16] = bytes[17..33].try_into()?;
+
+    let ttl = u64::from_be_bytes(bytes[33..41].try_into()?);
+
+    if !grant.allows(&parent_uuid, kind) {
+        return Err("token can't be used for this entity".into());
+    }
+
+    let mut key = [0u8; 33];
+
+    key[0..16].copy_from_slice(&parent_uuid[..]);
+    key[16] = kind;
+    key[17..33].copy_from_slice(&entity_uuid[..]);
+
+    let txn = WriteTransaction::new(core.env.clone())?;
+
+    let exp = {
+        let mut access = txn.access();
+
+        crate::entity::require_change(
+            core,
+            &access,
+            &entity_uuid,
+            &parent_uuid,
+            &group_uuid,
+            Permissions::WRITE,
+        )?;
+
+        if access
+            .get::<[u8; 33], [u8]>(&core.entity_db, &key)
+            .to_opt()?
+            .is_none()
+            || crate::expiry::is_expired(core, &access, &entity_uuid, crate::expiry::now(core)?)?
+        {
+            return Err("unknown entity".into());
+        }
+
+        crate::expiry::set(core, &mut access, &entity_uuid, ttl)?
+    };
+
+    txn.commit()?;
+
+    Ok(Bytes::copy_from_slice(&exp.to_be_bytes()))
+}
+
+pub fn res(res: Bytes) -> Result<u64, Box<dyn std::error::Error>> {
+    Ok(u64::from_be_bytes(res[..].try_into()?))
+}
@@ -0,0 +1,447 @@
+use crate::delegation::Grant;
+use crate::ops::storage_query::{
+    children, compile, page, Entity, Filter, Reader, Window, MAX_QUERY_LIMIT,
+};
+use crate::permissions::Permissions;
+use crate::Core;
+use bitcode::{Decode, Encode};
+use bytes::{BufMut, Bytes, BytesMut};
+use saferlmdb::{ConstTransaction, LmdbResultExt, ReadTransaction};
+
+/// most hops a traversal can take from where it starts
+pub const MAX_TRAVERSAL_DEPTH: usize = 8;
+
+/// most hops a traversal can have, across every depth
+pub const MAX_HOPS: usize = 255;
+
+/// most entities a traversal can return, across every hop
+pub const MAX_TRAVERSED: usize = 10_000;
+
+/// where a hop goes from each entity it is taken from
+#[derive(Encode, Decode, Clone, PartialEq, Debug)]
+pub enum Step {
+    /// to its children of the kinds of the hop
+    Children,
+    /// to its parent, through the grandparent it was put with. roots of
+    /// trees aren't entities, so there is none under them.
+    Parent,
+    /// to the entities on the other end of the link, named as in the
+    /// schema of its kind
+    Link(String),
+}
+
+/// a hop of a traversal, and the hops to take from where it goes
+#[derive(Clone, PartialEq, Debug)]
+pub struct Hop {
+    pub step: Step,
+    /// kinds of entities to go to, every kind if empty. children need
+    /// at least one.
+    pub kinds: Vec<u8>,
+    /// for each kind, from each entity. 0 for `MAX_QUERY_LIMIT`
+    pub limit: u16,
+    /// newest first, instead of oldest first
+    pub reverse: bool,
+    pub filter: Filter,
+    pub hops: Vec<Hop>,
+}
+
+/// an entity a hop went to, and what each of the hops from it found
+#[derive(PartialEq, Debug)]
+pub struct Node {
+    pub kind: u8,
+    pub entity: Entity,
+    /// in the order of the hops of the query
+    pub hops: Vec<Vec<Node>>,
+}
+
+// bitcode can't encode recursive types, so hops and nodes are sent in
+// pre-order, each pointing back at the one it is under.
+
+#[derive(Encode, Decode)]
+struct FlatHop {
+    /// the hop this one is taken after, `None` for the start
+    under: Option<u16>,
+    step: Step,
+    kinds: Vec<u8>,
+    limit: u16,
+    reverse: bool,
+    filter: Filter,
+}
+
+#[derive(Encode, Decode)]
+struct FlatNode {
+    /// the node this one was found from, `None` for the start
+    under: Option<u32>,
+    /// which hop of the one it is under found it
+    hop: u16,
+    /// how many hops were taken from it
+    hops: u16,
+    kind: u8,
+    entity: Entity,
+}
+
+#[derive(Encode, Decode)]
+struct FlatTraversal {
+    hops: u16,
+    nodes: Vec<FlatNode>,
+}
+
+fn flatten_hops(
+    hops: &[Hop],
+    under: Option<u16>,
+    flat: &mut Vec<FlatHop>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    for hop in hops {
+        if flat.len() == MAX_HOPS {
+            return Err("traversal has too many hops".into());
+        }
+
+        let index = flat.len() as u16;
+
+        flat.push(FlatHop {
+            under,
+            step: hop.step.clone(),
+            kinds: hop.kinds.clone(),
+            limit: hop.limit,
+            reverse: hop.reverse,
+            filter: hop.filter.clone(),
+        });
+
+        flatten_hops(&hop.hops, Some(index), flat)?;
+    }
+
+    Ok(())
+}
+
+/// the hops under `under`, failing past `MAX_TRAVERSAL_DEPTH`
+fn nest_hops(
+    flat: &[FlatHop],
+    under: Option<u16>,
+    depth: usize,
+) -> Result<Vec<Hop>, Box<dyn std::error::Error>> {
+    let mut hops = vec![];
+
+    for (index, hop) in flat.iter().enumerate() {
+        if hop.under != under {
+            continue;
+        }
+
+        if depth == MAX_TRAVERSAL_DEPTH {
+            return Err("traversal is too deep".into());
+        }
+
+        hops.push(Hop {
+            step: hop.step.clone(),
+            kinds: hop.kinds.clone(),
+            limit: hop.limit,
+            reverse: hop.reverse,
+            filter: hop.filter.clone(),
+            hops: nest_hops(flat, Some(u16::try_from(index)?), depth + 1)?,
+        });
+    }
+
+    Ok(hops)
+}
+
+fn flatten_nodes(
+    found: Vec<Vec<Node>>,
+    under: Option<u32>,
+    flat: &mut Vec<FlatNode>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    for (hop, nodes) in found.into_iter().enumerate() {
+        for node in nodes {
+            let index = u32::try_from(flat.len())?;
+
+            flat.push(FlatNode {
+                under,
+                hop: u16::try_from(hop)?,
+                hops: u16::try_from(node.hops.len())?,
+                kind: node.kind,
+                entity: node.entity,
+            });
+
+            flatten_nodes(node.hops, Some(index), flat)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// token.entity_uuid.hops
+/// - token: an access token for storage_query, or a delegated token
+///   for storage_query or storage_put
+/// - hops: taken from the entity, each from every entity the one before
+///   it went to
+///
+/// filtering on or projecting properties needs the kinds gone to to
+/// have a schema, see `Core::with_entity_schema`.
+pub fn req(
+    token: &[u8],
+    entity_uuid: &[u8; 16],
+    hops: &[Hop],
+) -> Result<Bytes, Box<dyn std::error::Error>> {
+    let mut flat = vec![];
+
+    flatten_hops(hops, None, &mut flat)?;
+
+    let flat = bitcode::encode(&flat);
+
+    let mut buf = BytesMut::with_capacity(token.len() + 16 + flat.len());
+
+    buf.put(token);
+
+    buf.put(&entity_uuid[..]);
+    buf.put(&flat[..]);
+
+    Ok(buf.into())
+}
+
+/// entities the group can't read, that have expired, or that a delegated
+/// token can't be used for are left out along with everything found from
+/// them. there is no continuation, a hop that needs more pages can be
+/// picked up with storage_query.
+pub fn handle(core: &Core, bytes: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
+    let (grant, token_len) = crate::delegation::authorize(core, 13, &bytes)?;
+
+    let bytes = bytes.slice(token_len..);
+
+    if bytes.len() < 16 {
+        return Err("invalid format".into());
+    }
+
+    let entity_uuid: [u8; 16] = bytes[0..16].try_into()?;
+
+    let flat: Vec<FlatHop> = bitcode::decode(&bytes[16..]).map_err(|_| "invalid traversal")?;
+
+    if flat.len() > MAX_HOPS {
+        return Err("traversal has too many hops".into());
+    }
+
+    let hops = nest_hops(&flat, None, 0)?;
+
+    let txn = ReadTransaction::new(core.env.clone())?;
+    let access = txn.access();
+
+    let now = crate::expiry::now(core)?;
+
+    let found = if crate::expiry::is_expired(core, &access, &entity_uuid, now)? {
+        hops.iter().map(|_| vec![]).collect()
+    } else {
+        let reader = Reader {
+            core,
+            access: &access,
+            group_uuid: grant.group_uuid,
+            now,
+        };
+
+        let mut traversed = 0;
+
+        walk(&reader, &txn, &grant, &entity_uuid, &hops, &mut traversed)?
+    };
+
+    let mut nodes = vec![];
+
+    flatten_nodes(found, None, &mut nodes)?;
+
+    let encoded = bitcode::encode(&FlatTraversal {
+        hops: u16::try_from(hops.len())?,
+        nodes,
+    });
+
+    Ok(encoded.into())
+}
+
+/// what each of the hops found from the entity
+fn walk(
+    reader: &Reader,
+    txn: &ConstTransaction,
+    grant: &Grant,
+    entity_uuid: &[u8; 16],
+    hops: &[Hop],
+    traversed: &mut usize,
+) -> Result<Vec<Vec<Node>>, Box<dyn std::error::Error>> {
+    let (core, access) = (reader.core, reader.access);
+
+    let mut found = vec![];
+
+    for hop in hops {
+        let window = Window {
+            after: None,
+            reverse: hop.reverse,
+            limit: match hop.limit {
+                0 => MAX_QUERY_LIMIT,
+                limit if limit > MAX_QUERY_LIMIT => return Err("limit is too high".into()),
+                limit => limit,
+            } as usize,
+        };
+
+        let wanted = |kind: u8| hop.kinds.is_empty() || hop.kinds.contains(&kind);
+
+        let mut reached = vec![];
+
+        match &hop.step {
+            Step::Children => {
+                if hop.kinds.is_empty() {
+                    return Err("children need a kind".into());
+                }
+
+                for &kind in &hop.kinds {
+                    if !grant.allows(entity_uuid, kind) {
+                        continue;
+                    }
+
+                    let (predicates, projection) = compile(core, &hop.filter, kind)?;
+
+                    let (entities, _) = children(
+                        reader,
+                        txn,
+                        entity_uuid,
+                        kind,
+                        &predicates,
+                        &projection,
+                        window,
+                    )?;
+
+                    reached.extend(entities.into_iter().map(|entity| (kind, entity)));
+                }
+            }
+            Step::Parent => {
+                if let Some((parent_uuid, _, value)) =
+                    crate::entity::get(core, access, entity_uuid)?
+                {
+                    // grandparent.parent_kind is where the parent is
+                    let mut key = [0u8; 33];
+
+                    key[0..17].copy_from_slice(&value[0..17]);
+                    key[17..33].copy_from_slice(&parent_uuid[..]);
+
+                    let grandparent_uuid: [u8; 16] = value[0..16].try_into()?;
+                    let kind = value[16];
+
+                    if let Some(parent) = access
+                        .get::<[u8; 33], [u8]>(&core.entity_db, &key)
+                        .to_opt()?
+                    {
+                        if wanted(kind) && grant.allows(&grandparent_uuid, kind) {
+                            let (predicates, projection) = compile(core, &hop.filter, kind)?;
+
+                            reached.extend(
+                                reader
+                                    .found(&predicates, &projection, parent_uuid, parent)?
+                                    .map(|entity| (kind, entity)),
+                            );
+                        }
+                    }
+                }
+            }
+            Step::Link(name) => {
+                if let Some((_, kind, _)) = crate::entity::get(core, access, entity_uuid)? {
+                    let link = core.schema.link(kind, name)?;
+
+                    // what an entity links to is part of it
+                    let readable = crate::entity::require(
+                        core,
+                        access,
+                        entity_uuid,
+                        &grant.group_uuid,
+                        Permissions::READ,
+                    )
+                    .is_ok();
+
+                    if readable && wanted(link.kind) {
+                        let (predicates, projection) = compile(core, &hop.filter, link.kind)?;
+
+                        let candidates = crate::reference::linked(
+                            core,
+                            txn,
+                            access,
+                            entity_uuid,
+                            link.ref_type,
+                        )?;
+
+                        let (entities, _) = page(candidates, window, |linked_uuid| {
+                            let linked = crate::entity::get(core, access, &linked_uuid)?;
+
+                            match linked {
+                                Some((linked_parent, linked_kind, value))
+                                    if linked_kind == link.kind
+                                        && grant.allows(&linked_parent, linked_kind) =>
+                                {
+                                    reader.found(&predicates, &projection, linked_uuid, value)
+                                }
+                                _ => Ok(None),
+                            }
+                        })?;
+
+                        reached.extend(entities.into_iter().map(|entity| (link.kind, entity)));
+                    }
+                }
+            }
+        }
+
+        *traversed += reached.len();
+
+        if *traversed > MAX_TRAVERSED {
+            return Err("traversal returns too many entities".into());
+        }
+
+        let mut nodes = vec![];
+
+        for (kind, entity) in reached {
+            nodes.push(Node {
+                kind,
+                hops: walk(reader, txn, grant, &entity.uuid, &hop.hops, traversed)?,
+                entity,
+            });
+        }
+
+        found.push(nodes);
+    }
+
+    Ok(found)
+}
+
+/// what each hop of the query found from the entity, nested the same way
+pub fn res(res: Bytes) -> Result<Vec<Vec<Node>>, Box<dyn std::error::Error>> {
+    let flat: FlatTraversal = bitcode::decode(&res[..]).map_err(|_| "invalid traversal")?;
+
+    let mut nodes: Vec<Option<Node>> = flat.nodes.iter().map(|_| None).collect();
+    let mut found: Vec<Vec<Node>> = (0..flat.hops).map(|_| vec![]).collect();
+
+    let mut places = vec![];
+
+    for (index, node) in flat.nodes.into_iter().enumerate() {
+        places.push((node.under, node.hop));
+
+        if node.under.is_some_and(|under| under as usize >= index) {
+            return Err("invalid traversal".into());
+        }
+
+        nodes[index] = Some(Node {
+            kind: node.kind,
+            entity: node.entity,
+            hops: (0..node.hops).map(|_| vec![]).collect(),
+        });
+    }
+
+    // children come after what they are under, so they are all in place
+    // by the time it is
+    for index in (0..nodes.len()).rev() {
+        let node = nodes[index].take().ok_or("invalid traversal")?;
+        let (under, hop) = places[index];
+
+        let siblings = match under {
+            Some(under) => nodes[under as usize]
+                .as_mut()
+                .ok_or("invalid traversal")?
+                .hops
+                .get_mut(hop as usize),
+            None => found.get_mut(hop as usize),
+        }
+        .ok_or("invalid traversal")?;
+
+        siblings.insert(0, node);
+    }
+
+    Ok(found)
+}
@@ -0,0 +1,149 @@
+use crate::permissions::Permissions;
+use crate::properties::{self, decode_properties, encode_properties};
+use crate::Core;
+use bytes::{BufMut, Bytes, BytesMut};
+use saferlmdb::{put, LmdbResultExt, WriteTransaction};
+
+/// how the entity is updated
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Update {
+    /// with a whole new entity
+    Replace = 0,
+    /// with encoded properties to set on it, where an empty value
+    /// removes the property. see `encode_properties`
+    Patch = 1,
+}
+
+impl TryFrom<u8> for Update {
+    type Error = Box<dyn std::error::Error>;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Update::Replace),
+            1 => Ok(Update::Patch),
+            _ => Err("invalid update".into()),
+        }
+    }
+}
+
+/// token.parent.kind.entity_uuid.update.entity
+/// - token: 135 bytes, see `cbwaw::token::gen`, or a delegated token
+///   for storage_update, see `cbwaw::delegated::gen`
+pub fn req(
+    token: &[u8],
+
+    parent_uuid: &[u8; 16],
+    kind: u8,
+    entity_uuid: &[u8; 16],
+
+    update: Update,
+    entity: &[u8],
+) -> Result<Bytes, Box<dyn std::error::Error>> {
+    let mut buf = BytesMut::with_capacity(token.len() + 16 + 1 + 16 + 1 + entity.len());
+
+    buf.put(token);
+
+    buf.put(&parent_uuid[..]);
+    buf.put_u8(kind);
+    buf.put(&entity_uuid[..]);
+
+    buf.put_u8(update as u8);
+    buf.put(entity);
+
+    Ok(buf.into())
+}
+
+/// keeps where the entity is, and who put it
+pub fn handle(core: &Core, bytes: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
+    let (grant, token_len) = crate::delegation::authorize(core, 14, &bytes)?;
+
+    let group_uuid = grant.group_uuid;
+
+    let bytes = bytes.slice(token_len..);
+
+    if bytes.len() < 34 {
+        return Err("invalid format".into());
+    }
+
+    let parent_uuid: [u8; 16] = bytes[0..16].try_into()?;
+    let kind = bytes[16];
+    let entity_uuid: [u8; 16] = bytes[17..33].try_into()?;
+
+    let update = Update::try_from(bytes[33])?;
+
+    if !grant.allows(&parent_uuid, kind) {
+        return Err("token can't be used for this entity".into());
+    }
+
+    let mut key = [0u8; 33];
+
+    key[0..16].copy_from_slice(&parent_uuid[..]);
+    key[16] = kind;
+    key[17..33].copy_from_slice(&entity_uuid[..]);
+
+    let txn = WriteTransaction::new(core.env.clone())?;
+
+    {
+        let mut access = txn.access();
+
+        crate::entity::require_change(
+            core,
+            &access,
+            &entity_uuid,
+            &parent_uuid,
+            &group_uuid,
+            Permissions::WRITE,
+        )?;
+
+        if crate::expiry::is_expired(core, &access, &entity_uuid, crate::expiry::now(core)?)? {
+            return Err("unknown entity".into());
+        }
+
+        let previous = match access
+            .get::<[u8; 33], [u8]>(&core.entity_db, &key)
+            .to_opt()?
+        {
+            Some(previous) if previous.len() >= 33 => previous.to_vec(),
+            Some(_) => return Err("invalid format".into()),
+            None => return Err("unknown entity".into()),
+        };
+
+        let mut entity = BytesMut::with_capacity(previous.len().max(33 + bytes.len() - 34));
+
+        // grandparent.parent_kind.user_uuid
+        entity.put(&previous[0..33]);
+
+        match update {
+            Update::Replace => entity.put(&bytes[34..]),
+            Update::Patch => {
+                let mut properties = decode_properties(&previous[33..])?;
+                properties::patch(&mut properties, decode_properties(&bytes[34..])?);
+
+                entity.put(&encode_properties(&properties)?[..]);
+            }
+        }
+
+        crate::index::remove(
+            core,
+            &mut access,
+            &parent_uuid,
+            kind,
+            &entity_uuid,
+            &previous[33..],
+        )?;
+        crate::index::add(
+            core,
+            &mut access,
+            &parent_uuid,
+            kind,
+            &entity_uuid,
+            &entity[33..],
+        )?;
+
+        access.put(&core.entity_db, &key, &*entity, put::Flags::empty())?;
+    }
+
+    txn.commit()?;
+
+    Ok(Bytes::new())
+}
@@ -0,0 +1,66 @@
+use std::time::SystemTime;
+
+use crate::recovery::Kind;
+use crate::second_factor;
+use crate::Core;
+use bytes::{BufMut, Bytes, BytesMut};
+use cbwaw::token::LEN_WITHOUT_GROUP;
+use saferlmdb::WriteTransaction;
+
+/// refresh_token.code
+pub fn req(refresh_token: &[u8], code: u32) -> Result<Bytes, Box<dyn std::error::Error>> {
+    let mut buf = BytesMut::with_capacity(LEN_WITHOUT_GROUP + 4);
+
+    buf.put(refresh_token);
+    buf.put_u32(code);
+
+    Ok(buf.into())
+}
+
+/// backup_codes
+///
+/// `RECOVERY_CODES` codes of `RECOVERY_CODE_LEN` each, any of them can
+/// be sent to `login_second_factor` once in place of a TOTP code.
+pub fn handle(core: &Core, bytes: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
+    if bytes.len() != LEN_WITHOUT_GROUP + 4 {
+        return Err("invalid format".into());
+    }
+
+    let refresh_token = &bytes[..LEN_WITHOUT_GROUP];
+    let code = u32::from_be_bytes(bytes[LEN_WITHOUT_GROUP..].try_into()?);
+
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_secs();
+
+    let txn = WriteTransaction::new(core.env.clone())?;
+
+    let backup_codes = {
+        let mut access = txn.access();
+
+        let user_uuid = crate::refresh::fresh(core, &access, refresh_token)?;
+
+        let mut record = match second_factor::load(core, &access, &user_uuid)? {
+            Some(record) if !record.confirmed => record,
+            Some(_) => return Err("second factor is already enrolled".into()),
+            None => return Err("second factor enrollment wasn't started".into()),
+        };
+
+        record.last_step = cbwaw::totp::verify(&record.secret, code, now, record.last_step)
+            .ok_or("invalid code")?;
+        record.confirmed = true;
+
+        second_factor::store(core, &mut access, &user_uuid, &record)?;
+
+        crate::recovery::generate(core, &txn, &mut access, &user_uuid, Kind::Backup)?
+    };
+
+    txn.commit()?;
+
+    Ok(backup_codes.into())
+}
+
+/// backup_codes
+pub fn res(res: Bytes) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    crate::recovery::split(&res)
+}
@@ -0,0 +1,62 @@
+use crate::second_factor::{self, Record};
+use crate::Core;
+use bytes::Bytes;
+use cbwaw::token::LEN_WITHOUT_GROUP;
+use saferlmdb::WriteTransaction;
+
+/// refresh_token
+pub fn req(refresh_token: &[u8]) -> Result<Bytes, Box<dyn std::error::Error>> {
+    Ok(Bytes::copy_from_slice(refresh_token))
+}
+
+/// secret
+///
+/// the second factor isn't required to log in until a code for
+/// the secret is sent to `totp_enroll_finish`.
+pub fn handle(core: &Core, bytes: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
+    if bytes.len() != LEN_WITHOUT_GROUP {
+        return Err("invalid format".into());
+    }
+
+    let secret = cbwaw::totp::generate_secret();
+
+    let txn = WriteTransaction::new(core.env.clone())?;
+
+    {
+        let mut access = txn.access();
+
+        let user_uuid = crate::refresh::fresh(core, &access, &bytes)?;
+
+        if second_factor::enrolled(core, &access, &user_uuid)? {
+            return Err("second factor is already enrolled".into());
+        }
+
+        second_factor::store(
+            core,
+            &mut access,
+            &user_uuid,
+            &Record {
+                confirmed: false,
+                last_step: 0,
+                failures: 0,
+                locked_until: 0,
+                secret: secret.to_vec(),
+            },
+        )?;
+    }
+
+    txn.commit()?;
+
+    Ok(Bytes::copy_from_slice(&secret))
+}
+
+/// secret
+///
+/// shown to the user with `cbwaw::totp::base32`.
+pub fn res(res: Bytes) -> Result<Bytes, Box<dyn std::error::Error>> {
+    if res.len() != cbwaw::totp::SECRET_LEN {
+        return Err("invalid format".into());
+    }
+
+    Ok(res)
+}
@@ -0,0 +1,124 @@
+// what a subject can do in a group, as a set of bits.
+//
+// the low byte is for the permissions every deployment has (the bits
+// above admin are reserved), the rest are custom ones named in the
+// `[permissions]` table of ordinary.toml:
+//
+// [permissions]
+// publish = 0
+// moderate = 1
+
+use std::collections::BTreeMap;
+use std::ops::{BitAnd, BitOr};
+
+use bitcode::{Decode, Encode};
+
+/// the most custom permissions a deployment can define
+pub const MAX_CUSTOM_PERMISSIONS: u8 = 24;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Encode, Decode)]
+pub struct Permissions(pub u32);
+
+impl Permissions {
+    pub const NONE: Permissions = Permissions(0);
+
+    pub const READ: Permissions = Permissions(1 << 0);
+    pub const WRITE: Permissions = Permissions(1 << 1);
+    pub const DELETE: Permissions = Permissions(1 << 2);
+    /// hand out delegated tokens
+    pub const SHARE: Permissions = Permissions(1 << 3);
+    /// assign and drop members
+    pub const ADMIN: Permissions = Permissions(1 << 4);
+
+    /// every permission that isn't custom
+    pub const BUILT_IN: Permissions = Permissions(0x1f);
+
+    /// the `bit`th custom permission
+    pub fn custom(bit: u8) -> Result<Self, Box<dyn std::error::Error>> {
+        if bit >= MAX_CUSTOM_PERMISSIONS {
+            return Err("custom permission is out of range".into());
+        }
+
+        Ok(Permissions(1 << (8 + bit as u32)))
+    }
+
+    /// has every permission in `other`
+    pub fn contains(self, other: Permissions) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// these permissions, minus any in `other`
+    pub fn without(self, other: Permissions) -> Permissions {
+        Permissions(self.0 & !other.0)
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn to_be_bytes(self) -> [u8; 4] {
+        self.0.to_be_bytes()
+    }
+
+    pub fn from_be_bytes(bytes: [u8; 4]) -> Self {
+        Permissions(u32::from_be_bytes(bytes))
+    }
+}
+
+impl BitOr for Permissions {
+    type Output = Permissions;
+
+    fn bitor(self, other: Permissions) -> Permissions {
+        Permissions(self.0 | other.0)
+    }
+}
+
+impl BitAnd for Permissions {
+    type Output = Permissions;
+
+    fn bitand(self, other: Permissions) -> Permissions {
+        Permissions(self.0 & other.0)
+    }
+}
+
+/// what an access token for `action` needs in its group: secret_get
+/// and storage_query only read, everything else writes.
+pub(crate) fn required(action: u8) -> Permissions {
+    match action {
+        8 | 13 => Permissions::READ,
+        15 => Permissions::DELETE,
+        _ => Permissions::WRITE,
+    }
+}
+
+/// name -> permission, from the `[permissions]` table of ordinary.toml
+pub fn custom_permissions(
+    config: &str,
+) -> Result<BTreeMap<String, Permissions>, Box<dyn std::error::Error>> {
+    let config: toml::Table = config.parse()?;
+
+    let mut custom = BTreeMap::new();
+
+    let table = match config.get("permissions") {
+        Some(toml::Value::Table(table)) => table,
+        Some(_) => return Err("permissions has to be a table".into()),
+        None => return Ok(custom),
+    };
+
+    for (name, bit) in table {
+        let bit = bit
+            .as_integer()
+            .and_then(|bit| u8::try_from(bit).ok())
+            .ok_or("custom permissions have to be a bit number")?;
+
+        let permission = Permissions::custom(bit)?;
+
+        if custom.values().any(|defined| *defined == permission) {
+            return Err(format!("custom permission bit {bit} is defined twice").into());
+        }
+
+        custom.insert(name.clone(), permission);
+    }
+
+    Ok(custom)
+}
@@ -0,0 +1,84 @@
+// entity values as numbered properties, so they can be patched without
+// sending the whole entity again.
+//
+// count.(index.len.value)*
+//
+// properties are in index order, and each index is only used once.
+
+use std::collections::BTreeMap;
+
+/// index -> value
+pub type Properties = BTreeMap<u8, Vec<u8>>;
+
+pub fn encode_properties(properties: &Properties) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    if properties.len() > 255 {
+        return Err("an entity can only have up to 255 properties".into());
+    }
+
+    let mut encoded = Vec::with_capacity(
+        1 + properties
+            .values()
+            .map(|value| 1 + 4 + value.len())
+            .sum::<usize>(),
+    );
+
+    encoded.push(properties.len() as u8);
+
+    for (index, value) in properties {
+        encoded.push(*index);
+        encoded.extend_from_slice(&u32::try_from(value.len())?.to_be_bytes());
+        encoded.extend_from_slice(value);
+    }
+
+    Ok(encoded)
+}
+
+pub fn decode_properties(encoded: &[u8]) -> Result<Properties, Box<dyn std::error::Error>> {
+    let mut properties = Properties::new();
+
+    let count = *encoded.first().ok_or("entity is not made of properties")?;
+    let mut cursor = 1;
+
+    for _ in 0..count {
+        if encoded.len() < cursor + 5 {
+            return Err("entity is not made of properties".into());
+        }
+
+        let index = encoded[cursor];
+        let len = u32::from_be_bytes(encoded[cursor + 1..cursor + 5].try_into()?) as usize;
+        cursor += 5;
+
+        let end = cursor
+            .checked_add(len)
+            .filter(|end| *end <= encoded.len())
+            .ok_or("entity is not made of properties")?;
+
+        if properties
+            .last_key_value()
+            .is_some_and(|(last, _)| *last >= index)
+        {
+            return Err("entity is not made of properties".into());
+        }
+
+        properties.insert(index, encoded[cursor..end].to_vec());
+        cursor = end;
+    }
+
+    if cursor != encoded.len() {
+        return Err("entity is not made of properties".into());
+    }
+
+    Ok(properties)
+}
+
+/// sets every property in `patch` on `properties`, and removes the ones
+/// patched with an empty value
+pub(crate) fn patch(properties: &mut Properties, patch: Properties) {
+    for (index, value) in patch {
+        if value.is_empty() {
+            properties.remove(&index);
+        } else {
+            properties.insert(index, value);
+        }
+    }
+}
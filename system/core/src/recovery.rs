@@ -0,0 +1,149 @@
+// one-time codes
+//
+// recovery codes are handed out once at registration so a user who lost
+// their password can register a new one without losing their user_uuid.
+// backup codes are handed out when enrolling a second factor, and stand
+// in for it when the authenticator is lost.
+//
+// only a hash of each code is kept:
+//
+// user_uuid.kind.hash -> []
+
+use blake2::{Blake2s256, Digest};
+use rand::{rngs::OsRng, RngCore};
+use saferlmdb::{put, ConstAccessor, LmdbResultExt, WriteAccessor, WriteTransaction};
+
+use crate::Core;
+
+pub const RECOVERY_CODES: usize = 10;
+
+/// hex encoded 10 random bytes
+pub const RECOVERY_CODE_LEN: usize = 20;
+
+/// the same for unknown usernames and wrong codes
+pub(crate) const RECOVERY_FAILED: &str = "invalid username or recovery code";
+
+#[derive(Clone, Copy)]
+pub(crate) enum Kind {
+    Recovery = 0,
+    Backup = 1,
+}
+
+fn key(user_uuid: &[u8; 16], kind: Kind, code: &[u8]) -> [u8; 49] {
+    let mut key = [0u8; 49];
+
+    key[0..16].copy_from_slice(&user_uuid[..]);
+    key[16] = kind as u8;
+    key[17..49].copy_from_slice(&Blake2s256::digest(code.to_ascii_lowercase())[..]);
+
+    key
+}
+
+/// stores a fresh set of codes for the user, replacing any of
+/// the same kind that are left, and returns them
+pub(crate) fn generate(
+    core: &Core,
+    txn: &WriteTransaction,
+    access: &mut WriteAccessor,
+    user_uuid: &[u8; 16],
+    kind: Kind,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut prefix = [0u8; 17];
+
+    prefix[0..16].copy_from_slice(&user_uuid[..]);
+    prefix[16] = kind as u8;
+
+    let mut unused = vec![];
+
+    {
+        let mut cursor = txn.cursor(core.recovery_db.clone())?;
+
+        let mut next = cursor
+            .seek_range_k::<[u8], [u8]>(access, &prefix[..])
+            .to_opt()?;
+
+        while let Some((key, _)) = next {
+            if !key.starts_with(&prefix) {
+                break;
+            }
+
+            unused.push(key.to_vec());
+
+            next = cursor.next::<[u8], [u8]>(access).to_opt()?;
+        }
+    }
+
+    for key in unused {
+        access.del_key(&core.recovery_db, &key[..])?;
+    }
+
+    let mut codes = Vec::with_capacity(RECOVERY_CODES * RECOVERY_CODE_LEN);
+
+    for _ in 0..RECOVERY_CODES {
+        let code = new_code();
+
+        access.put::<[u8], [u8]>(
+            &core.recovery_db,
+            &key(user_uuid, kind, code.as_bytes())[..],
+            &[],
+            put::Flags::empty(),
+        )?;
+
+        codes.extend_from_slice(code.as_bytes());
+    }
+
+    Ok(codes)
+}
+
+fn new_code() -> String {
+    let mut bytes = [0u8; RECOVERY_CODE_LEN / 2];
+    OsRng.fill_bytes(&mut bytes);
+
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// true if the code was handed out to the user and hasn't been used
+pub(crate) fn exists(
+    core: &Core,
+    access: &ConstAccessor,
+    user_uuid: &[u8; 16],
+    kind: Kind,
+    code: &[u8],
+) -> Result<bool, Box<dyn std::error::Error>> {
+    Ok(access
+        .get::<[u8], [u8]>(&core.recovery_db, &key(user_uuid, kind, code)[..])
+        .to_opt()?
+        .is_some())
+}
+
+/// uses up the code, failing if it was never handed out or already used
+pub(crate) fn consume(
+    core: &Core,
+    access: &mut WriteAccessor,
+    user_uuid: &[u8; 16],
+    kind: Kind,
+    code: &[u8],
+) -> Result<(), Box<dyn std::error::Error>> {
+    if !exists(core, access, user_uuid, kind, code)? {
+        return Err(match kind {
+            Kind::Recovery => RECOVERY_FAILED.into(),
+            Kind::Backup => "invalid backup code".into(),
+        });
+    }
+
+    access.del_key(&core.recovery_db, &key(user_uuid, kind, code)[..])?;
+
+    Ok(())
+}
+
+/// splits codes as returned by `generate`
+pub(crate) fn split(codes: &[u8]) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    if codes.len() != RECOVERY_CODES * RECOVERY_CODE_LEN {
+        return Err("invalid format".into());
+    }
+
+    Ok(codes
+        .chunks(RECOVERY_CODE_LEN)
+        .map(|code| String::from_utf8(code.to_vec()))
+        .collect::<Result<_, _>>()?)
+}
@@ -0,0 +1,220 @@
+// references between entities and users.
+//
+// a reference is kept on both ends, so whichever end goes away can
+// take the other side of it along.
+//
+// the inverse of a ref_type is it with the high bit flipped, see `schema`.
+//
+// (entity_uuid | user_uuid).ref_type -> (entity_uuid | user_uuid) (dup)
+
+use saferlmdb::{put, ConstAccessor, ConstTransaction, LmdbResultExt, WriteAccessor};
+
+use crate::delegation::Grant;
+use crate::permissions::Permissions;
+use crate::schema::{Link, MAX_LINKS};
+use crate::Core;
+
+fn key(subject_uuid: &[u8; 16], ref_type: u8) -> [u8; 17] {
+    let mut key = [0u8; 17];
+
+    key[0..16].copy_from_slice(subject_uuid);
+    key[16] = ref_type;
+
+    key
+}
+
+/// the link named in the schema of the subject, if the grant can change
+/// it. that takes what it takes to change the subject, and to read the
+/// target, and neither can have expired.
+pub(crate) fn require_link(
+    core: &Core,
+    access: &ConstAccessor,
+    grant: &Grant,
+    subject_uuid: &[u8; 16],
+    target_uuid: &[u8; 16],
+    name: &str,
+) -> Result<Link, Box<dyn std::error::Error>> {
+    let now = crate::expiry::now(core)?;
+
+    let (Some((subject_parent, subject_kind)), Some((_, target_kind))) = (
+        crate::entity::parent(core, access, subject_uuid)?,
+        crate::entity::parent(core, access, target_uuid)?,
+    ) else {
+        return Err("unknown entity".into());
+    };
+
+    if crate::expiry::is_expired(core, access, subject_uuid, now)?
+        || crate::expiry::is_expired(core, access, target_uuid, now)?
+    {
+        return Err("unknown entity".into());
+    }
+
+    let link = core.schema.link(subject_kind, name)?;
+
+    if link.kind != target_kind {
+        return Err(format!("{name} can't link to this entity").into());
+    }
+
+    if !grant.allows(&subject_parent, subject_kind) {
+        return Err("token can't be used for this entity".into());
+    }
+
+    crate::entity::require_change(
+        core,
+        access,
+        subject_uuid,
+        &subject_parent,
+        &grant.group_uuid,
+        Permissions::WRITE,
+    )?;
+    crate::entity::require(
+        core,
+        access,
+        target_uuid,
+        &grant.group_uuid,
+        Permissions::READ,
+    )?;
+
+    Ok(link)
+}
+
+/// references the target from the subject, and the subject from the target
+pub(crate) fn link(
+    core: &Core,
+    access: &mut WriteAccessor,
+    subject_uuid: &[u8; 16],
+    ref_type: u8,
+    target_uuid: &[u8; 16],
+) -> Result<(), Box<dyn std::error::Error>> {
+    access.put(
+        &core.reference_db,
+        &key(subject_uuid, ref_type),
+        target_uuid,
+        put::Flags::empty(),
+    )?;
+    access.put(
+        &core.reference_db,
+        &key(target_uuid, ref_type ^ MAX_LINKS),
+        subject_uuid,
+        put::Flags::empty(),
+    )?;
+
+    Ok(())
+}
+
+/// drops the reference both ways, if there was one
+pub(crate) fn unlink(
+    core: &Core,
+    access: &mut WriteAccessor,
+    subject_uuid: &[u8; 16],
+    ref_type: u8,
+    target_uuid: &[u8; 16],
+) -> Result<(), Box<dyn std::error::Error>> {
+    access
+        .del_item(
+            &core.reference_db,
+            &key(subject_uuid, ref_type),
+            target_uuid,
+        )
+        .to_opt()?;
+    access
+        .del_item(
+            &core.reference_db,
+            &key(target_uuid, ref_type ^ MAX_LINKS),
+            subject_uuid,
+        )
+        .to_opt()?;
+
+    Ok(())
+}
+
+/// what the subject references as `ref_type`, oldest first
+pub(crate) fn linked(
+    core: &Core,
+    txn: &ConstTransaction,
+    access: &ConstAccessor,
+    subject_uuid: &[u8; 16],
+    ref_type: u8,
+) -> Result<Vec<[u8; 16]>, Box<dyn std::error::Error>> {
+    let mut linked = vec![];
+    let mut cursor = txn.cursor(core.reference_db.clone())?;
+
+    let mut next = cursor
+        .seek_k_both::<[u8; 17], [u8]>(access, &key(subject_uuid, ref_type))
+        .to_opt()?;
+
+    while let Some((_, target_uuid)) = next {
+        linked.push(target_uuid.try_into()?);
+
+        next = cursor.next_dup::<[u8; 17], [u8]>(access).to_opt()?;
+    }
+
+    Ok(linked)
+}
+
+/// drops every reference from the subject, and the other side of each
+pub(crate) fn forget(
+    core: &Core,
+    txn: &ConstTransaction,
+    access: &mut WriteAccessor,
+    subject_uuid: &[u8; 16],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut references = vec![];
+
+    {
+        let mut cursor = txn.cursor(core.reference_db.clone())?;
+
+        let mut next = cursor
+            .seek_range_k::<[u8], [u8]>(access, &subject_uuid[..])
+            .to_opt()?;
+
+        while let Some((key, target)) = next {
+            if key.len() != 17 || key[0..16] != subject_uuid[..] {
+                break;
+            }
+
+            references.push((<[u8; 17]>::try_from(key)?, <[u8; 16]>::try_from(target)?));
+
+            next = cursor.next::<[u8], [u8]>(access).to_opt()?;
+        }
+    }
+
+    // a link from the subject to itself is listed from both sides, and
+    // the second side is gone by the time it comes up
+    for (key, target_uuid) in &references {
+        access
+            .del_item(&core.reference_db, key, target_uuid)
+            .to_opt()?;
+
+        // whatever the type is called from the other side
+        let mut inverse = vec![];
+
+        {
+            let mut cursor = txn.cursor(core.reference_db.clone())?;
+
+            let mut next = cursor
+                .seek_range_k::<[u8], [u8]>(access, &target_uuid[..])
+                .to_opt()?;
+
+            while let Some((key, target)) = next {
+                if key.len() != 17 || key[0..16] != target_uuid[..] {
+                    break;
+                }
+
+                if target == &subject_uuid[..] {
+                    inverse.push(<[u8; 17]>::try_from(key)?);
+                }
+
+                next = cursor.next::<[u8], [u8]>(access).to_opt()?;
+            }
+        }
+
+        for key in &inverse {
+            access
+                .del_item(&core.reference_db, key, subject_uuid)
+                .to_opt()?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,270 @@
+use std::time::SystemTime;
+
+use bytes::Bytes;
+use cbwaw::token::{self, KeyRing, Kind};
+use saferlmdb::{put, ConstAccessor, LmdbResultExt, WriteAccessor, WriteTransaction};
+use uuid::{NoContext, Timestamp, Uuid};
+
+use crate::Core;
+
+/// refresh token families
+///
+/// every login starts a family, and every rotation hands out the next
+/// token in it. only the newest token of a family can be used, so if an
+/// older one shows up again it has been copied and the whole family is
+/// revoked.
+///
+/// token_id (the hmac of the token) -> family_id.exp
+/// family_id -> current_token_id.exp
+///
+/// a revoked family's current_token_id is `REVOKED`.
+const REVOKED: [u8; 32] = [0u8; 32];
+
+/// how long after logging in sensitive changes (password, second
+/// factor) can be made with the session, in seconds
+pub const FRESH_LOGIN: u64 = 5 * 60;
+
+/// (user_uuid, family_id), without a family for tokens issued before them
+pub(crate) type Usable = ([u8; 16], Option<[u8; 16]>);
+
+pub(crate) enum Status {
+    /// newest token of its family
+    Current([u8; 16]),
+    /// an older token of its family
+    Reused([u8; 16]),
+    /// the family has been revoked
+    Revoked,
+    /// issued without a family
+    Unknown,
+}
+
+pub(crate) fn status(
+    core: &Core,
+    access: &ConstAccessor,
+    refresh_token: &[u8],
+) -> Result<Status, Box<dyn std::error::Error>> {
+    let token_id = token::parse(refresh_token)?.hmac;
+
+    let family_id: [u8; 16] = match access
+        .get::<[u8; 32], [u8]>(&core.family_db, &token_id)
+        .to_opt()?
+    {
+        Some(family_id_exp) => family_id_exp[0..16].try_into()?,
+        None => return Ok(Status::Unknown),
+    };
+
+    let current_token_id_exp = access.get::<[u8; 16], [u8]>(&core.family_db, &family_id)?;
+
+    if current_token_id_exp[0..32] == REVOKED {
+        Ok(Status::Revoked)
+    } else if current_token_id_exp[0..32] == token_id {
+        Ok(Status::Current(family_id))
+    } else {
+        Ok(Status::Reused(family_id))
+    }
+}
+
+/// starts a new family with `refresh_token` as its first token
+///
+/// family ids are v7 from when the first token was issued, so they
+/// record when the login that started the family happened no matter
+/// how often it's been rotated since.
+pub(crate) fn start(
+    core: &Core,
+    access: &mut WriteAccessor,
+    refresh_token: &[u8],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let issued = token::parse(refresh_token)?.issued;
+
+    let family_id = *Uuid::new_v7(Timestamp::from_unix(
+        NoContext,
+        issued / 1000,
+        (issued % 1000) as u32 * 1_000_000,
+    ))
+    .as_bytes();
+
+    advance(core, access, &family_id, refresh_token)
+}
+
+/// seconds since the epoch of the login that started the family,
+/// `None` for families started before their ids were v7
+pub(crate) fn started(family_id: &[u8; 16]) -> Option<u64> {
+    let (secs, _) = Uuid::from_bytes(*family_id).get_timestamp()?.to_unix();
+    Some(secs)
+}
+
+/// hands out the next token of the family, which expires no later
+/// than `session_lifetime` after the login that started the family
+pub(crate) fn rotate(
+    core: &Core,
+    token_keys: &KeyRing,
+    access: &mut WriteAccessor,
+    family_id: &[u8; 16],
+    user_uuid: &[u8; 16],
+) -> Result<Bytes, Box<dyn std::error::Error>> {
+    let started = started(family_id).ok_or("login again to do this")?;
+
+    let next_refresh_token = token::gen_until(
+        token_keys,
+        &core.token_settings,
+        Kind::Refresh,
+        0,
+        user_uuid,
+        None,
+        started.saturating_add(core.token_settings.session_lifetime),
+    )?;
+
+    advance(core, access, family_id, &next_refresh_token)?;
+
+    Ok(next_refresh_token)
+}
+
+/// makes `refresh_token` the newest token of the family
+pub(crate) fn advance(
+    core: &Core,
+    access: &mut WriteAccessor,
+    family_id: &[u8; 16],
+    refresh_token: &[u8],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let token::Token {
+        hmac: token_id,
+        exp,
+        ..
+    } = token::parse(refresh_token)?;
+
+    let mut family_id_exp = [0u8; 24];
+
+    family_id_exp[0..16].copy_from_slice(&family_id[..]);
+    family_id_exp[16..24].copy_from_slice(&exp.to_be_bytes());
+
+    access.put(
+        &core.family_db,
+        &token_id,
+        &family_id_exp,
+        put::Flags::empty(),
+    )?;
+
+    let mut current_token_id_exp = [0u8; 40];
+
+    current_token_id_exp[0..32].copy_from_slice(&token_id[..]);
+    current_token_id_exp[32..40].copy_from_slice(&exp.to_be_bytes());
+
+    access.put(
+        &core.family_db,
+        family_id,
+        &current_token_id_exp[..],
+        put::Flags::empty(),
+    )?;
+
+    Ok(())
+}
+
+/// no token of the family can be used again
+pub(crate) fn revoke(
+    core: &Core,
+    access: &mut WriteAccessor,
+    family_id: &[u8; 16],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut current_token_id_exp = [0u8; 40];
+
+    // keep the exp around so the record can be pruned
+    current_token_id_exp[32..40]
+        .copy_from_slice(&access.get::<[u8; 16], [u8]>(&core.family_db, family_id)?[32..40]);
+
+    access.put(
+        &core.family_db,
+        family_id,
+        &current_token_id_exp[..],
+        put::Flags::empty(),
+    )?;
+
+    Ok(())
+}
+
+/// drops the records of tokens and families that have expired
+pub(crate) fn prune(core: &Core) -> Result<(), Box<dyn std::error::Error>> {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_secs();
+
+    let txn = WriteTransaction::new(core.env.clone())?;
+
+    {
+        let mut access = txn.access();
+        let mut expired = vec![];
+
+        {
+            let mut cursor = txn.cursor(core.family_db.clone())?;
+
+            let mut next = cursor.first::<[u8], [u8]>(&access).to_opt()?;
+
+            while let Some((key, value)) = next {
+                let exp = match key.len() {
+                    16 => &value[32..40],
+                    _ => &value[16..24],
+                };
+
+                if u64::from_be_bytes(exp.try_into()?) < now {
+                    expired.push(key.to_vec());
+                }
+
+                next = cursor.next::<[u8], [u8]>(&access).to_opt()?;
+            }
+        }
+
+        for key in expired {
+            access.del_key(&core.family_db, &key[..])?;
+        }
+    }
+
+    txn.commit()?;
+
+    Ok(())
+}
+
+/// (user_uuid, family_id) of a refresh token that hasn't been
+/// logged out, rotated, or revoked
+pub(crate) fn usable(
+    core: &Core,
+    access: &ConstAccessor,
+    refresh_token: &[u8],
+) -> Result<Usable, Box<dyn std::error::Error>> {
+    let token_keys = core.token_keys()?;
+
+    let user_uuid = token::verify(
+        &token_keys,
+        &core.token_settings,
+        Kind::Refresh,
+        0,
+        refresh_token,
+    )?
+    .user_uuid;
+
+    if crate::ops::logout::is_revoked(core, access, &user_uuid, refresh_token)? {
+        return Err("refresh token has been revoked".into());
+    }
+
+    match status(core, access, refresh_token)? {
+        Status::Current(family_id) => Ok((user_uuid, Some(family_id))),
+        Status::Unknown => Ok((user_uuid, None)),
+        Status::Reused(_) | Status::Revoked => Err("refresh token has been revoked".into()),
+    }
+}
+
+/// user_uuid of a refresh token from a login within `FRESH_LOGIN`
+pub(crate) fn fresh(
+    core: &Core,
+    access: &ConstAccessor,
+    refresh_token: &[u8],
+) -> Result<[u8; 16], Box<dyn std::error::Error>> {
+    let (user_uuid, family_id) = usable(core, access, refresh_token)?;
+
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_secs();
+
+    match family_id.as_ref().and_then(started) {
+        Some(started) if now.saturating_sub(started) <= FRESH_LOGIN => Ok(user_uuid),
+        _ => Err("login again to do this".into()),
+    }
+}
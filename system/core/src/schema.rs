@@ -0,0 +1,307 @@
+// what entities are made of, from the `[entities]` table of ordinary.toml:
+//
+// [entities.post]
+// kind = 1
+// title = { type = "str", id = 0 }
+// views = { type = "uint", id = 1 }
+// slug = { type = "str", id = 2, index = "unique" }
+// liked_by = { link = "user_profile", on = "liked_posts", id = 0 }
+//
+// the id of a property is its index in the entity, see `properties`.
+// indexed properties can be looked up without going through every
+// entity of their kind, see `index`.
+//
+// links aren't properties, they are kept in reference_db both ways,
+// see `reference`. their ids are unique across every entity, since they
+// are the ref_type of the link, and the inverse of a link (named by
+// `on` on the other end) is its id with the high bit set.
+
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+
+/// how a property value is encoded, and so how it is ordered
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PropertyType {
+    /// utf-8
+    Str,
+    Bytes,
+    /// 8 bytes, big endian
+    Int,
+    /// 8 bytes, big endian
+    Uint,
+    /// 8 bytes, big endian
+    Float,
+    /// 1 byte
+    Bool,
+    /// 16 bytes
+    Uuid,
+}
+
+impl TryFrom<&str> for PropertyType {
+    type Error = Box<dyn std::error::Error>;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "str" => Ok(PropertyType::Str),
+            "bytes" => Ok(PropertyType::Bytes),
+            "int" => Ok(PropertyType::Int),
+            "uint" => Ok(PropertyType::Uint),
+            "float" => Ok(PropertyType::Float),
+            "bool" => Ok(PropertyType::Bool),
+            "uuid" => Ok(PropertyType::Uuid),
+            _ => Err(format!("unknown property type {value}").into()),
+        }
+    }
+}
+
+impl PropertyType {
+    /// `None` if either value isn't encoded as this type
+    pub fn compare(self, a: &[u8], b: &[u8]) -> Option<Ordering> {
+        match self {
+            PropertyType::Str | PropertyType::Bytes => Some(a.cmp(b)),
+            PropertyType::Int => Some(
+                i64::from_be_bytes(a.try_into().ok()?).cmp(&i64::from_be_bytes(b.try_into().ok()?)),
+            ),
+            PropertyType::Uint => Some(
+                u64::from_be_bytes(a.try_into().ok()?).cmp(&u64::from_be_bytes(b.try_into().ok()?)),
+            ),
+            PropertyType::Float => f64::from_be_bytes(a.try_into().ok()?)
+                .partial_cmp(&f64::from_be_bytes(b.try_into().ok()?)),
+            PropertyType::Bool => match (a, b) {
+                ([a], [b]) => Some(a.cmp(b)),
+                _ => None,
+            },
+            PropertyType::Uuid => match (a.len(), b.len()) {
+                (16, 16) => Some(a.cmp(b)),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// how a property is indexed, under the parent of the entity
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Index {
+    /// no two entities of the kind can have the same value
+    Unique,
+    /// any number of entities of the kind can have the same value
+    Multi,
+}
+
+impl TryFrom<&str> for Index {
+    type Error = Box<dyn std::error::Error>;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "unique" => Ok(Index::Unique),
+            "multi" => Ok(Index::Multi),
+            _ => Err(format!("unknown index {value}").into()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Property {
+    pub id: u8,
+    pub property_type: PropertyType,
+    pub index: Option<Index>,
+}
+
+/// the most links a schema can define, the high bit of a ref_type is
+/// for their inverse
+pub const MAX_LINKS: u8 = 128;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Link {
+    /// what the link is kept as in reference_db
+    pub ref_type: u8,
+    /// of the entities on the other end
+    pub kind: u8,
+}
+
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct EntitySchema {
+    pub name: String,
+    /// name -> property
+    pub properties: BTreeMap<String, Property>,
+    /// name -> link, including the inverse of links to this kind
+    pub links: BTreeMap<String, Link>,
+}
+
+/// kind -> entity schema
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct Schema {
+    pub kinds: BTreeMap<u8, EntitySchema>,
+}
+
+impl Schema {
+    /// a property of a kind of entity by its name
+    pub fn property(&self, kind: u8, name: &str) -> Result<Property, Box<dyn std::error::Error>> {
+        let entity = self.kinds.get(&kind).ok_or("kind has no schema")?;
+
+        match entity.properties.get(name) {
+            Some(property) => Ok(*property),
+            None => Err(format!("{} has no property {name}", entity.name).into()),
+        }
+    }
+
+    /// a link from a kind of entity by its name
+    pub fn link(&self, kind: u8, name: &str) -> Result<Link, Box<dyn std::error::Error>> {
+        let entity = self.kinds.get(&kind).ok_or("kind has no schema")?;
+
+        match entity.links.get(name) {
+            Some(link) => Ok(*link),
+            None => Err(format!("{} has no link {name}", entity.name).into()),
+        }
+    }
+}
+
+/// the `[entities]` table of ordinary.toml
+pub fn entity_schema(config: &str) -> Result<Schema, Box<dyn std::error::Error>> {
+    let config: toml::Table = config.parse()?;
+
+    let mut schema = Schema::default();
+
+    let table = match config.get("entities") {
+        Some(toml::Value::Table(table)) => table,
+        Some(_) => return Err("entities has to be a table".into()),
+        None => return Ok(schema),
+    };
+
+    // (kind, name, id, linked entity, inverse name)
+    let mut links = vec![];
+
+    for (name, entity) in table {
+        let entity = entity.as_table().ok_or("entities have to be tables")?;
+
+        let kind = entity
+            .get("kind")
+            .and_then(|kind| kind.as_integer())
+            .and_then(|kind| u8::try_from(kind).ok())
+            .ok_or(format!("{name} needs a kind from 0 to 255"))?;
+
+        let mut properties = BTreeMap::new();
+
+        for (property_name, property) in entity {
+            let Some(property) = property.as_table() else {
+                continue;
+            };
+
+            let id = property
+                .get("id")
+                .and_then(|id| id.as_integer())
+                .and_then(|id| u8::try_from(id).ok())
+                .ok_or(format!("{name}.{property_name} needs an id from 0 to 255"))?;
+
+            if let Some(linked) = property.get("link") {
+                let linked = linked
+                    .as_str()
+                    .ok_or(format!("{name}.{property_name} links to an invalid entity"))?;
+
+                let inverse = match property.get("on") {
+                    Some(inverse) => Some(
+                        inverse
+                            .as_str()
+                            .ok_or(format!("{name}.{property_name} has an invalid on"))?,
+                    ),
+                    None => None,
+                };
+
+                links.push((kind, property_name, id, linked, inverse));
+
+                continue;
+            }
+
+            let property_type = property
+                .get("type")
+                .and_then(|property_type| property_type.as_str())
+                .ok_or(format!("{name}.{property_name} needs a type"))?;
+
+            let index = match property.get("index") {
+                Some(index) => {
+                    Some(Index::try_from(index.as_str().ok_or(format!(
+                        "{name}.{property_name} has an invalid index"
+                    ))?)?)
+                }
+                None => None,
+            };
+
+            let property = Property {
+                id,
+                property_type: PropertyType::try_from(property_type)?,
+                index,
+            };
+
+            if properties
+                .values()
+                .any(|defined: &Property| defined.id == id)
+            {
+                return Err(format!("{name} uses property id {id} twice").into());
+            }
+
+            properties.insert(property_name.clone(), property);
+        }
+
+        let entity = EntitySchema {
+            name: name.clone(),
+            properties,
+            links: BTreeMap::new(),
+        };
+
+        if schema.kinds.insert(kind, entity).is_some() {
+            return Err(format!("kind {kind} is defined twice").into());
+        }
+    }
+
+    let mut ids = vec![];
+
+    for (kind, name, id, linked, inverse) in links {
+        if id >= MAX_LINKS {
+            return Err(format!("{name} needs an id below {MAX_LINKS}").into());
+        }
+
+        if ids.contains(&id) {
+            return Err(format!("link id {id} is defined twice").into());
+        }
+
+        ids.push(id);
+
+        let linked_kind = schema
+            .kinds
+            .iter()
+            .find(|(_, entity)| entity.name == linked)
+            .map(|(linked_kind, _)| *linked_kind)
+            .ok_or(format!("{name} links to an unknown entity {linked}"))?;
+
+        let mut add = |kind: u8, name: &str, link: Link| {
+            let entity = schema.kinds.get_mut(&kind).ok_or("kind has no schema")?;
+
+            match entity.links.insert(name.to_string(), link) {
+                Some(_) => Err(format!("{} has two links named {name}", entity.name)),
+                None => Ok(()),
+            }
+        };
+
+        add(
+            kind,
+            name,
+            Link {
+                ref_type: id,
+                kind: linked_kind,
+            },
+        )?;
+
+        if let Some(inverse) = inverse {
+            add(
+                linked_kind,
+                inverse,
+                Link {
+                    ref_type: id | MAX_LINKS,
+                    kind,
+                },
+            )?;
+        }
+    }
+
+    Ok(schema)
+}
@@ -0,0 +1,110 @@
+// server side encryption of what goes in `secrets_db`, under a master key
+// that lives next to the store rather than in it.
+
+use std::path::Path;
+
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+use rand::{rngs::OsRng, RngCore};
+
+use crate::Core;
+
+/// generated the first time the deployment is started
+pub const MASTER_KEY_PATH: &str = "./store/master.key";
+
+pub(crate) fn load_or_generate(
+    path: impl AsRef<Path>,
+) -> Result<[u8; 32], Box<dyn std::error::Error>> {
+    let path = path.as_ref();
+
+    if !path.exists() {
+        let mut key = [0u8; 32];
+        OsRng.fill_bytes(&mut key);
+
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+        let mut file = options.open(path)?;
+
+        std::io::Write::write_all(&mut file, &key)?;
+        file.sync_all()?;
+    }
+
+    Ok(std::fs::read(path)?
+        .try_into()
+        .map_err(|_| "invalid master key")?)
+}
+
+/// nonce.ciphertext
+///
+/// `aad` should be the key the value is stored under, so a sealed
+/// value can't be moved to another record.
+pub(crate) fn seal(
+    core: &Core,
+    aad: &[u8],
+    plaintext: &[u8],
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    seal_with(&core.master_key, aad, plaintext)
+}
+
+pub(crate) fn open(
+    core: &Core,
+    aad: &[u8],
+    sealed: &[u8],
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    open_with(&core.master_key, aad, sealed)
+}
+
+/// `seal` under a key other than the master key
+pub(crate) fn seal_with(
+    key: &[u8; 32],
+    aad: &[u8],
+    plaintext: &[u8],
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let cipher = XChaCha20Poly1305::new(key.into());
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+
+    let ciphertext = cipher
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: plaintext,
+                aad,
+            },
+        )
+        .map_err(|_| "failed to seal")?;
+
+    let mut sealed = Vec::with_capacity(24 + ciphertext.len());
+
+    sealed.extend_from_slice(&nonce);
+    sealed.extend_from_slice(&ciphertext);
+
+    Ok(sealed)
+}
+
+pub(crate) fn open_with(
+    key: &[u8; 32],
+    aad: &[u8],
+    sealed: &[u8],
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    if sealed.len() < 24 {
+        return Err("invalid format".into());
+    }
+
+    let cipher = XChaCha20Poly1305::new(key.into());
+
+    Ok(cipher
+        .decrypt(
+            XNonce::from_slice(&sealed[0..24]),
+            Payload {
+                msg: &sealed[24..],
+                aad,
+            },
+        )
+        .map_err(|_| "failed to open")?)
+}
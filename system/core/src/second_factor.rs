@@ -0,0 +1,97 @@
+// TOTP second factor
+//
+// the shared secret is sealed under the master key, the rest of the
+// record is kept in the clear so failed attempts can be counted without
+// touching the secret.
+//
+// user_uuid.TOTP -> confirmed.last_step.failures.locked_until.sealed_secret
+
+use saferlmdb::{put, ConstAccessor, LmdbResultExt, WriteAccessor};
+
+use crate::Core;
+
+const TOTP: u8 = 0;
+
+/// failed codes in a row before the second factor is locked
+pub const MAX_FAILED_SECOND_FACTOR: u32 = 5;
+
+/// how long it stays locked, in seconds
+pub const SECOND_FACTOR_LOCKOUT: u64 = 15 * 60;
+
+pub(crate) struct Record {
+    /// a code has been entered since the secret was generated
+    pub confirmed: bool,
+    /// codes for this step or earlier can't be used again
+    pub last_step: u64,
+    pub failures: u32,
+    pub locked_until: u64,
+    pub secret: Vec<u8>,
+}
+
+fn key(user_uuid: &[u8; 16]) -> [u8; 17] {
+    let mut key = [0u8; 17];
+
+    key[0..16].copy_from_slice(&user_uuid[..]);
+    key[16] = TOTP;
+
+    key
+}
+
+pub(crate) fn load(
+    core: &Core,
+    access: &ConstAccessor,
+    user_uuid: &[u8; 16],
+) -> Result<Option<Record>, Box<dyn std::error::Error>> {
+    let key = key(user_uuid);
+
+    let value = match access
+        .get::<[u8; 17], [u8]>(&core.secrets_db, &key)
+        .to_opt()?
+    {
+        Some(value) if value.len() > 21 => value,
+        Some(_) => return Err("invalid format".into()),
+        None => return Ok(None),
+    };
+
+    Ok(Some(Record {
+        confirmed: value[0] == 1,
+        last_step: u64::from_be_bytes(value[1..9].try_into()?),
+        failures: u32::from_be_bytes(value[9..13].try_into()?),
+        locked_until: u64::from_be_bytes(value[13..21].try_into()?),
+        secret: crate::seal::open(core, &key, &value[21..])?,
+    }))
+}
+
+pub(crate) fn store(
+    core: &Core,
+    access: &mut WriteAccessor,
+    user_uuid: &[u8; 16],
+    record: &Record,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let key = key(user_uuid);
+    let sealed = crate::seal::seal(core, &key, &record.secret)?;
+
+    let mut value = Vec::with_capacity(21 + sealed.len());
+
+    value.push(record.confirmed as u8);
+    value.extend_from_slice(&record.last_step.to_be_bytes());
+    value.extend_from_slice(&record.failures.to_be_bytes());
+    value.extend_from_slice(&record.locked_until.to_be_bytes());
+    value.extend_from_slice(&sealed);
+
+    access.put(&core.secrets_db, &key, &value, put::Flags::empty())?;
+
+    Ok(())
+}
+
+/// true if logging in takes a code as well as the password
+pub(crate) fn enrolled(
+    core: &Core,
+    access: &ConstAccessor,
+    user_uuid: &[u8; 16],
+) -> Result<bool, Box<dyn std::error::Error>> {
+    Ok(access
+        .get::<[u8; 17], [u8]>(&core.secrets_db, &key(user_uuid))
+        .to_opt()?
+        .is_some_and(|value| value.first() == Some(&1)))
+}
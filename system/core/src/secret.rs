@@ -0,0 +1,112 @@
+// versioned secrets, sealed under the master key.
+//
+// a secret belongs to the group it was created in, and every put adds a
+// version instead of replacing the last one. values the client sealed
+// itself are sealed again, the flag only tells the client it has to
+// open what it gets back.
+//
+// secret_uuid -> group_uuid.latest_version
+// secret_uuid.version -> client_sealed.user_uuid.sealed_value
+
+use saferlmdb::{put, ConstAccessor, LmdbResultExt, WriteAccessor};
+
+use crate::Core;
+
+/// the largest value a single version can hold, in bytes
+pub const MAX_SECRET_LEN: usize = 64 * 1024;
+
+/// (group_uuid, latest_version)
+pub(crate) type Head = ([u8; 16], u32);
+
+pub(crate) struct Version {
+    /// sealed by the client before it was sent
+    pub client_sealed: bool,
+    /// who put this version
+    pub user_uuid: [u8; 16],
+    pub value: Vec<u8>,
+}
+
+pub(crate) fn head(
+    core: &Core,
+    access: &ConstAccessor,
+    secret_uuid: &[u8; 16],
+) -> Result<Option<Head>, Box<dyn std::error::Error>> {
+    match access
+        .get::<[u8; 16], [u8]>(&core.secrets_db, secret_uuid)
+        .to_opt()?
+    {
+        Some(value) if value.len() == 20 => Ok(Some((
+            value[0..16].try_into()?,
+            u32::from_be_bytes(value[16..20].try_into()?),
+        ))),
+        Some(_) => Err("invalid format".into()),
+        None => Ok(None),
+    }
+}
+
+pub(crate) fn load(
+    core: &Core,
+    access: &ConstAccessor,
+    secret_uuid: &[u8; 16],
+    version: u32,
+) -> Result<Option<Version>, Box<dyn std::error::Error>> {
+    let key = key(secret_uuid, version);
+
+    let value = match access
+        .get::<[u8; 20], [u8]>(&core.secrets_db, &key)
+        .to_opt()?
+    {
+        Some(value) if value.len() > 17 => value,
+        Some(_) => return Err("invalid format".into()),
+        None => return Ok(None),
+    };
+
+    Ok(Some(Version {
+        client_sealed: value[0] == 1,
+        user_uuid: value[1..17].try_into()?,
+        value: crate::seal::open(core, &key, &value[17..])?,
+    }))
+}
+
+/// stores `version` as the one after the latest, and returns its number
+pub(crate) fn store(
+    core: &Core,
+    access: &mut WriteAccessor,
+    secret_uuid: &[u8; 16],
+    group_uuid: &[u8; 16],
+    version: &Version,
+) -> Result<u32, Box<dyn std::error::Error>> {
+    let number = match head(core, access, secret_uuid)? {
+        Some((_, latest)) => latest.checked_add(1).ok_or("too many versions")?,
+        None => 0,
+    };
+
+    let key = key(secret_uuid, number);
+    let sealed = crate::seal::seal(core, &key, &version.value)?;
+
+    let mut value = Vec::with_capacity(17 + sealed.len());
+
+    value.push(version.client_sealed as u8);
+    value.extend_from_slice(&version.user_uuid);
+    value.extend_from_slice(&sealed);
+
+    access.put(&core.secrets_db, &key, &value, put::Flags::empty())?;
+
+    let mut head = [0u8; 20];
+
+    head[0..16].copy_from_slice(group_uuid);
+    head[16..20].copy_from_slice(&number.to_be_bytes());
+
+    access.put(&core.secrets_db, secret_uuid, &head, put::Flags::empty())?;
+
+    Ok(number)
+}
+
+fn key(secret_uuid: &[u8; 16], version: u32) -> [u8; 20] {
+    let mut key = [0u8; 20];
+
+    key[0..16].copy_from_slice(secret_uuid);
+    key[16..20].copy_from_slice(&version.to_be_bytes());
+
+    key
+}
@@ -0,0 +1,208 @@
+// login attempts
+//
+// with OPAQUE a wrong password is caught by the client, which never
+// sends `login_finish`, so every `login_start` counts as an attempt and
+// a successful `login_finish` clears the username's count. past the free
+// attempts each one locks out the next for twice as long as the last.
+//
+// counted per (normalized) username whether or not it's registered, so
+// being locked out says nothing about the account existing, and per
+// source address to slow down stuffing many usernames from one place.
+// a source's count is never cleared by logging in, or logging into an
+// account of its own between guesses would start it over, it only
+// wears off after `LOGIN_ATTEMPT_WINDOW`.
+//
+// USERNAME.username -> attempts.last_attempt.locked_until
+// SOURCE.ip -> attempts.last_attempt.locked_until
+
+use std::net::IpAddr;
+use std::time::SystemTime;
+
+use saferlmdb::{put, LmdbResultExt, WriteTransaction};
+
+use crate::Core;
+
+const USERNAME: u8 = 0;
+const SOURCE: u8 = 1;
+
+/// attempts per username before backing off
+pub const FREE_LOGIN_ATTEMPTS: u32 = 5;
+
+/// attempts per source before backing off, higher since many
+/// users can share an address
+pub const FREE_SOURCE_ATTEMPTS: u32 = 50;
+
+/// the longest a single lockout lasts, in seconds
+pub const MAX_LOGIN_BACKOFF: u64 = 15 * 60;
+
+/// counts start over after this long without an attempt, in seconds
+pub const LOGIN_ATTEMPT_WINDOW: u64 = 60 * 60;
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Throttled {
+    /// seconds until the next attempt is allowed
+    pub retry_after: u64,
+}
+
+impl std::fmt::Display for Throttled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "too many login attempts, try again in {} seconds",
+            self.retry_after
+        )
+    }
+}
+
+impl std::error::Error for Throttled {}
+
+fn key(kind: u8, subject: &[u8]) -> Vec<u8> {
+    let mut key = Vec::with_capacity(1 + subject.len());
+
+    key.push(kind);
+    key.extend_from_slice(subject);
+
+    key
+}
+
+fn source_bytes(source: &IpAddr) -> Vec<u8> {
+    match source {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    }
+}
+
+/// records an attempt for the username and source, failing
+/// without recording anything if either is locked out.
+pub(crate) fn attempt(
+    core: &Core,
+    username: &[u8],
+    source: Option<&IpAddr>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let now = now()?;
+
+    let mut subjects = vec![(key(USERNAME, username), FREE_LOGIN_ATTEMPTS)];
+
+    if let Some(source) = source {
+        subjects.push((key(SOURCE, &source_bytes(source)), FREE_SOURCE_ATTEMPTS));
+    }
+
+    let txn = WriteTransaction::new(core.env.clone())?;
+
+    {
+        let mut access = txn.access();
+        let mut records = Vec::with_capacity(subjects.len());
+
+        for (key, free) in subjects {
+            let (mut attempts, last_attempt, locked_until) =
+                match access.get::<[u8], [u8]>(&core.attempts_db, &key).to_opt()? {
+                    Some(value) if value.len() == 20 => (
+                        u32::from_be_bytes(value[0..4].try_into()?),
+                        u64::from_be_bytes(value[4..12].try_into()?),
+                        u64::from_be_bytes(value[12..20].try_into()?),
+                    ),
+                    Some(_) => return Err("invalid format".into()),
+                    None => (0, 0, 0),
+                };
+
+            if locked_until > now {
+                return Err(Throttled {
+                    retry_after: locked_until - now,
+                }
+                .into());
+            }
+
+            if now.saturating_sub(last_attempt) > LOGIN_ATTEMPT_WINDOW {
+                attempts = 0;
+            }
+
+            attempts = attempts.saturating_add(1);
+
+            let locked_until = match attempts.checked_sub(free + 1) {
+                // 2, 4, 8, ... seconds
+                Some(over) => {
+                    now + 2u64
+                        .saturating_pow(over.saturating_add(1))
+                        .min(MAX_LOGIN_BACKOFF)
+                }
+                None => 0,
+            };
+
+            let mut value = [0u8; 20];
+
+            value[0..4].copy_from_slice(&attempts.to_be_bytes());
+            value[4..12].copy_from_slice(&now.to_be_bytes());
+            value[12..20].copy_from_slice(&locked_until.to_be_bytes());
+
+            records.push((key, value));
+        }
+
+        for (key, value) in records {
+            access.put(&core.attempts_db, &key[..], &value, put::Flags::empty())?;
+        }
+    }
+
+    txn.commit()?;
+
+    Ok(())
+}
+
+/// the password was right, so the username starts over
+pub(crate) fn succeeded(core: &Core, username: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+    let txn = WriteTransaction::new(core.env.clone())?;
+
+    {
+        let mut access = txn.access();
+        access
+            .del_key(&core.attempts_db, &key(USERNAME, username)[..])
+            .to_opt()?;
+    }
+
+    txn.commit()?;
+
+    Ok(())
+}
+
+/// drops the records of usernames and sources that haven't
+/// attempted a login within `LOGIN_ATTEMPT_WINDOW` and aren't locked
+pub(crate) fn prune(core: &Core) -> Result<(), Box<dyn std::error::Error>> {
+    let now = now()?;
+
+    let txn = WriteTransaction::new(core.env.clone())?;
+
+    {
+        let mut access = txn.access();
+        let mut stale = vec![];
+
+        {
+            let mut cursor = txn.cursor(core.attempts_db.clone())?;
+
+            let mut next = cursor.first::<[u8], [u8]>(&access).to_opt()?;
+
+            while let Some((key, value)) = next {
+                let last_attempt = u64::from_be_bytes(value[4..12].try_into()?);
+                let locked_until = u64::from_be_bytes(value[12..20].try_into()?);
+
+                if now.saturating_sub(last_attempt) > LOGIN_ATTEMPT_WINDOW && locked_until < now {
+                    stale.push(key.to_vec());
+                }
+
+                next = cursor.next::<[u8], [u8]>(&access).to_opt()?;
+            }
+        }
+
+        for key in stale {
+            access.del_key(&core.attempts_db, &key[..])?;
+        }
+    }
+
+    txn.commit()?;
+
+    Ok(())
+}
+
+fn now() -> Result<u64, Box<dyn std::error::Error>> {
+    Ok(SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_secs())
+}
@@ -0,0 +1,120 @@
+// usernames are compared after normalization, so "Alice", "alice",
+// and "ａｌｉｃｅ" (fullwidth) all map to the same auth_db key.
+
+use saferlmdb::{self as lmdb, put, LmdbResultExt, WriteTransaction};
+use unicode_normalization::UnicodeNormalization;
+
+use crate::{Core, MAX_USERNAME_LEN};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum UsernameError {
+    Empty,
+    TooLong,
+    Utf8,
+    Char(char),
+    Taken,
+}
+
+impl std::fmt::Display for UsernameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Empty => write!(f, "username is empty"),
+            Self::TooLong => write!(f, "username is too long"),
+            Self::Utf8 => write!(f, "username is not valid utf-8"),
+            Self::Char(c) => write!(f, "username contains invalid character {c:?}"),
+            Self::Taken => write!(f, "username taken"),
+        }
+    }
+}
+
+impl std::error::Error for UsernameError {}
+
+/// letters and digits from any script, plus `_`, `-`, and `.`
+fn allowed(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '_' | '-' | '.')
+}
+
+/// NFKC, lowercase, NFKC again (lowercasing can produce
+/// sequences that are no longer normalized), then check the
+/// character set and length.
+pub(crate) fn normalize(username: &[u8]) -> Result<Vec<u8>, UsernameError> {
+    let username = std::str::from_utf8(username).map_err(|_| UsernameError::Utf8)?;
+
+    let folded: String = username.nfkc().collect::<String>().to_lowercase();
+    let normalized: String = folded.nfkc().collect();
+
+    if normalized.is_empty() {
+        return Err(UsernameError::Empty);
+    }
+
+    if normalized.len() > MAX_USERNAME_LEN as usize {
+        return Err(UsernameError::TooLong);
+    }
+
+    if let Some(c) = normalized.chars().find(|c| !allowed(*c)) {
+        return Err(UsernameError::Char(c));
+    }
+
+    Ok(normalized.into_bytes())
+}
+
+/// moves usernames registered before they were normalized to their
+/// normalized key, so they keep working now that logins normalize.
+///
+/// one that doesn't normalize anymore, or that normalizes to a
+/// username registered on its own, is left where it was.
+pub(crate) fn migrate(core: &Core) -> Result<(), Box<dyn std::error::Error>> {
+    let txn = WriteTransaction::new(core.env.clone())?;
+
+    {
+        let mut access = txn.access();
+
+        let mut moved = vec![];
+
+        {
+            let mut cursor = txn.cursor(core.auth_db.clone())?;
+            let mut next = cursor.first::<[u8], [u8]>(&access).to_opt()?;
+
+            while let Some((username, value)) = next {
+                if let Ok(normalized) = normalize(username) {
+                    if normalized != username {
+                        moved.push((username.to_vec(), normalized, value.to_vec()));
+                    }
+                }
+
+                next = cursor.next::<[u8], [u8]>(&access).to_opt()?;
+            }
+        }
+
+        for (username, normalized, user_uuid_password_file) in moved {
+            match access.put(
+                &core.auth_db,
+                &normalized[..],
+                &user_uuid_password_file[..],
+                put::Flags::NOOVERWRITE,
+            ) {
+                Err(lmdb::Error::Code(lmdb::error::KEYEXIST)) => {
+                    log::warn!(
+                        "username {:?} normalizes to one that is taken, leaving it",
+                        String::from_utf8_lossy(&username)
+                    );
+                    continue;
+                }
+                result => result?,
+            }
+
+            access.del_key(&core.auth_db, &username[..])?;
+
+            access.put(
+                &core.user_db,
+                &user_uuid_password_file[0..16],
+                &normalized[..],
+                put::Flags::empty(),
+            )?;
+        }
+    }
+
+    txn.commit()?;
+
+    Ok(())
+}
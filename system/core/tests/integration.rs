@@ -1,34 +1,115 @@
 use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
 
+use cbwaw::delegated::{self, Caveat};
 use stewball::ops;
-use stewball::ops::storage_query::QueryResult;
-use stewball::Core;
+use stewball::ops::group_members::Member;
+use stewball::ops::login_finish::Login;
+use stewball::ops::storage_query::{Filter, Page, Predicate, Query, QueryResult};
+use stewball::ops::storage_traverse::{Hop, Step};
+use stewball::{
+    Certificate, CertificateMatch, Core, PermissionReason, Permissions, Throttled, UsernameError,
+    FREE_LOGIN_ATTEMPTS, MAX_REAPED, RECOVERY_CODES,
+};
+use uuid::Uuid;
+
+const ORDINARY_TOML: &str = include_str!("../../../ordinary.toml");
 
 #[test]
 fn all() -> Result<(), Box<dyn std::error::Error>> {
-    let core = Core::new()?;
+    let core = Core::new()?
+        .with_custom_permissions(ORDINARY_TOML)?
+        .with_entity_schema(ORDINARY_TOML)?
+        .with_token_config(ORDINARY_TOML)?;
+
+    // the store outlives the test, so each run needs its own usernames
+    let username = format!("User-{}", Uuid::new_v4().simple());
+    let username = username.as_bytes();
 
     // registration start
-    let (state, req) = ops::registration_start::req(b"username", b"password")?;
+    let (state, req) = ops::registration_start::req(username, b"password")?;
     let res = core.registration_start(req)?;
 
     // registration finish
-    let req = ops::registration_finish::req(b"username", b"password", &state, &res)?;
+    let req = ops::registration_finish::req(username, b"password", &state, &res)?;
+    let recovery_codes = ops::registration_finish::res(core.registration_finish(req)?)?;
+    assert_eq!(recovery_codes.len(), RECOVERY_CODES);
+
+    // usernames are case folded, so the same name in a different case is
+    // taken. starting doesn't tell, only finishing does
+    let lowercase = String::from_utf8(username.to_vec())?.to_lowercase();
+    let (state, req) = ops::registration_start::req(lowercase.as_bytes(), b"password")?;
+    let res = core.registration_start(req)?;
+    let req = ops::registration_finish::req(lowercase.as_bytes(), b"password", &state, &res)?;
+    let err = core.registration_finish(req).unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<UsernameError>(),
+        Some(&UsernameError::Taken)
+    );
+
+    // two registrations racing for the same name, only the first one wins
+    let racing = format!("racing-{}", Uuid::new_v4().simple());
+    let (first_state, req) = ops::registration_start::req(racing.as_bytes(), b"password")?;
+    let first_res = core.registration_start(req)?;
+    let (second_state, req) = ops::registration_start::req(racing.as_bytes(), b"password")?;
+    let second_res = core.registration_start(req)?;
+
+    let req =
+        ops::registration_finish::req(racing.as_bytes(), b"password", &first_state, &first_res)?;
     core.registration_finish(req)?;
+    let req =
+        ops::registration_finish::req(racing.as_bytes(), b"password", &second_state, &second_res)?;
+    let err = core.registration_finish(req).unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<UsernameError>(),
+        Some(&UsernameError::Taken)
+    );
+
+    // characters outside the allowed set are rejected
+    assert!(ops::registration_start::req(b"user name", b"password").is_err());
+
+    // restart, the password file must still be usable. sessions last
+    // no longer than a single refresh token, so rotating can't extend them
+    drop(core);
+    let core = Core::new()?
+        .with_custom_permissions(ORDINARY_TOML)?
+        .with_entity_schema(ORDINARY_TOML)?
+        .with_token_config(ORDINARY_TOML)?
+        .with_username_check_at_start();
+
+    // with the check at start enabled, starting tells too
+    let (_, req) = ops::registration_start::req(lowercase.as_bytes(), b"password")?;
+    let err = core.registration_start(req).unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<UsernameError>(),
+        Some(&UsernameError::Taken)
+    );
+
+    // rotating must not invalidate existing registrations
+    core.rotate_server_setup()?;
 
     // login start
-    let (state, req) = ops::login_start::req(b"username", b"password")?;
+    let (state, req) = ops::login_start::req(lowercase.as_bytes(), b"password")?;
     let res = core.login_start(req)?;
 
     // login finish
-    let (req, session_key) = ops::login_finish::req(b"username", b"password", &state, &res)?;
-    let res = core.login_finish(req)?;
+    let (req, session_key) =
+        ops::login_finish::req(lowercase.as_bytes(), b"password", &state, &res)?;
+    let res = core.login_finish(req.clone())?;
+
+    // pending login state can only be used once
+    assert!(core.login_finish(req).is_err());
 
-    let refresh_token = ops::login_finish::res(res, &session_key)?;
-    let user_uuid: [u8; 16] = refresh_token[41..57].try_into()?;
+    let Login::RefreshToken(refresh_token) = ops::login_finish::res(res, &session_key)? else {
+        panic!("no second factor was enrolled");
+    };
+    let user_uuid = cbwaw::token::parse(&refresh_token)?.user_uuid;
 
     // get GROUP_CREATE access token
-    let req = ops::access_get::req(&refresh_token, 3, None)?;
+    let req = ops::access_get::req(&refresh_token, 3, None, false)?;
     let access_token = core.access_get(req)?;
 
     // create a group
@@ -36,8 +117,159 @@ fn all() -> Result<(), Box<dyn std::error::Error>> {
     let res = core.group_create(req)?;
     let group_uuid = ops::group_create::res(res)?;
 
+    // add the user who won the race to the group, read only, and
+    // the custom publish permission
+    let (state, req) = ops::login_start::req(racing.as_bytes(), b"password")?;
+    let res = core.login_start(req)?;
+    let (req, racing_session_key) =
+        ops::login_finish::req(racing.as_bytes(), b"password", &state, &res)?;
+    let Login::RefreshToken(racing_refresh_token) =
+        ops::login_finish::res(core.login_finish(req)?, &racing_session_key)?
+    else {
+        panic!("no second factor was enrolled");
+    };
+    let racing_uuid = cbwaw::token::parse(&racing_refresh_token)?.user_uuid;
+
+    let req = ops::group_assign::req(
+        &refresh_token,
+        &group_uuid,
+        &racing_uuid,
+        Permissions::custom(1)?,
+    )?;
+    assert!(core.group_assign(req).is_err());
+
+    let publish = core
+        .custom_permission("publish")
+        .ok_or("publish is defined")?;
+
+    let req = ops::group_assign::req(
+        &refresh_token,
+        &group_uuid,
+        &racing_uuid,
+        Permissions::READ | publish,
+    )?;
+    core.group_assign(req)?;
+
+    let req = ops::access_get::req(&racing_refresh_token, 13, Some(&group_uuid), false)?;
+    core.access_get(req)?;
+
+    let req = ops::access_get::req(&racing_refresh_token, 12, Some(&group_uuid), false)?;
+    assert!(core.access_get(req).is_err());
+
+    // only admins can assign
+    let req = ops::group_assign::req(
+        &racing_refresh_token,
+        &group_uuid,
+        &racing_uuid,
+        Permissions::ADMIN,
+    )?;
+    assert!(core.group_assign(req).is_err());
+
+    // denies override what was allowed
+    let req = ops::group_deny::req(&refresh_token, &group_uuid, &racing_uuid, Permissions::READ)?;
+    core.group_deny(req)?;
+
+    let req = ops::access_get::req(&racing_refresh_token, 13, Some(&group_uuid), false)?;
+    assert!(core.access_get(req).is_err());
+
+    let req = ops::group_deny::req(&refresh_token, &group_uuid, &racing_uuid, Permissions::NONE)?;
+    core.group_deny(req)?;
+
+    // everyone in a nested group gets what it was allowed
+    let req = ops::group_create::req(&access_token)?;
+    let outer_uuid = ops::group_create::res(core.group_create(req)?)?;
+
+    let req = ops::access_get::req(&racing_refresh_token, 12, Some(&outer_uuid), false)?;
+    assert!(core.access_get(req).is_err());
+
+    let req = ops::group_nest::req(
+        &refresh_token,
+        &outer_uuid,
+        &group_uuid,
+        Permissions::READ | Permissions::WRITE,
+    )?;
+    core.group_nest(req)?;
+
+    let req = ops::access_get::req(&racing_refresh_token, 12, Some(&outer_uuid), false)?;
+    core.access_get(req)?;
+
+    // but not what the nested group was denied
+    let req = ops::group_deny::req(&refresh_token, &outer_uuid, &group_uuid, Permissions::WRITE)?;
+    core.group_deny(req)?;
+
+    let req = ops::access_get::req(&racing_refresh_token, 12, Some(&outer_uuid), false)?;
+    assert!(core.access_get(req).is_err());
+
+    let req = ops::access_get::req(&racing_refresh_token, 13, Some(&outer_uuid), false)?;
+    core.access_get(req)?;
+
+    // groups can't end up nested in themselves
+    let req = ops::group_nest::req(&refresh_token, &group_uuid, &outer_uuid, Permissions::READ)?;
+    assert!(core.group_nest(req).is_err());
+
+    let req = ops::group_nest::req(&refresh_token, &outer_uuid, &outer_uuid, Permissions::READ)?;
+    assert!(core.group_nest(req).is_err());
+
+    let req = ops::group_members::req(&refresh_token, &group_uuid)?;
+    let mut members = ops::group_members::res(core.group_members(req)?)?;
+    members.sort_by_key(|member| member.user_uuid != user_uuid);
+
+    assert_eq!(
+        members,
+        vec![
+            Member {
+                user_uuid,
+                permissions: Permissions::BUILT_IN | publish
+            },
+            Member {
+                user_uuid: racing_uuid,
+                permissions: Permissions::READ | publish
+            },
+        ]
+    );
+
+    // the last admin can't leave, or stop being an admin
+    let req = ops::group_drop::req(&refresh_token, &group_uuid, &user_uuid)?;
+    assert!(core.group_drop(req).is_err());
+
+    let req = ops::group_assign::req(&refresh_token, &group_uuid, &user_uuid, Permissions::READ)?;
+    assert!(core.group_assign(req).is_err());
+
+    // nor can they leave to someone denied being an admin
+    let req = ops::group_assign::req(
+        &refresh_token,
+        &group_uuid,
+        &racing_uuid,
+        Permissions::READ | Permissions::ADMIN,
+    )?;
+    core.group_assign(req)?;
+
+    let req = ops::group_deny::req(
+        &refresh_token,
+        &group_uuid,
+        &racing_uuid,
+        Permissions::ADMIN,
+    )?;
+    core.group_deny(req)?;
+
+    let req = ops::group_drop::req(&refresh_token, &group_uuid, &user_uuid)?;
+    assert!(core.group_drop(req).is_err());
+
+    let req = ops::group_deny::req(&refresh_token, &group_uuid, &user_uuid, Permissions::ADMIN)?;
+    assert!(core.group_deny(req).is_err());
+
+    let req = ops::group_deny::req(&refresh_token, &group_uuid, &racing_uuid, Permissions::NONE)?;
+    core.group_deny(req)?;
+
+    // but anyone else can leave
+    let req = ops::group_drop::req(&racing_refresh_token, &group_uuid, &racing_uuid)?;
+    core.group_drop(req)?;
+
+    let req = ops::group_members::req(&refresh_token, &group_uuid)?;
+    assert_eq!(ops::group_members::res(core.group_members(req)?)?.len(), 1);
+
     // get STORAGE_PUT access token for new group
-    let req = ops::access_get::req(&refresh_token, 12, Some(&group_uuid))?;
+    let req = ops::access_get::req(&refresh_token, 12, Some(&group_uuid), false)?;
     let access_token = core.access_get(req)?;
 
     // create an entity relationship with your user
@@ -54,11 +286,21 @@ fn all() -> Result<(), Box<dyn std::error::Error>> {
         .expect("failed to convert");
 
     // get STORAGE_QUERY access token
-    let req = ops::access_get::req(&refresh_token, 13, Some(&group_uuid))?;
+    let req = ops::access_get::req(&refresh_token, 13, Some(&group_uuid), false)?;
     let access_token = core.access_get(req)?;
 
     // query your user
-    let req = ops::storage_query::req(&access_token, vec![(&user_uuid, &entity_uuid, vec![1])])?;
+    let req = ops::storage_query::req(
+        &access_token,
+        &Page::default(),
+        &Filter::default(),
+        vec![Query {
+            parent_uuid: &user_uuid,
+            uuid: &entity_uuid,
+            kinds: vec![1],
+            links: vec![],
+        }],
+    )?;
     let query_result = core.storage_query(req)?;
 
     let decoded: QueryResult = bitcode::decode(&query_result).unwrap();
@@ -66,9 +308,900 @@ fn all() -> Result<(), Box<dyn std::error::Error>> {
     assert_eq!(
         decoded,
         QueryResult {
-            entities: BTreeMap::new()
+            entities: BTreeMap::new(),
+            links: BTreeMap::new(),
+            continuation: None
         }
     );
 
+    // page through the children of the user, oldest first
+    let req = ops::access_get::req(&refresh_token, 12, Some(&group_uuid), false)?;
+    let put_token = core.access_get(req)?;
+
+    let mut children = vec![];
+
+    for _ in 0..3 {
+        let req = ops::storage_put::req(&put_token, &user_uuid, 2, &user_uuid, 0, &[0])?;
+        children.push(<[u8; 16]>::try_from(&core.storage_put(req)?[..])?);
+    }
+
+    let query = vec![Query {
+        parent_uuid: &user_uuid,
+        uuid: &user_uuid,
+        kinds: vec![2],
+        links: vec![],
+    }];
+    let mut page = Page {
+        limit: 2,
+        ..Page::default()
+    };
+
+    let req = ops::storage_query::req(&access_token, &page, &Filter::default(), query.clone())?;
+    let decoded: QueryResult = bitcode::decode(&core.storage_query(req)?)?;
+
+    assert_eq!(decoded.entities[&user_uuid][&2].len(), 2);
+    assert!(decoded.continuation.is_some());
+
+    page.continuation = decoded.continuation;
+
+    let req = ops::storage_query::req(&access_token, &page, &Filter::default(), query.clone())?;
+    let decoded: QueryResult = bitcode::decode(&core.storage_query(req)?)?;
+
+    assert_eq!(decoded.entities[&user_uuid][&2].len(), 1);
+    assert_eq!(decoded.continuation, None);
+
+    // or newest first
+    let page = Page {
+        limit: 1,
+        reverse: true,
+        continuation: None,
+    };
+
+    let req = ops::storage_query::req(&access_token, &page, &Filter::default(), query)?;
+    let decoded: QueryResult = bitcode::decode(&core.storage_query(req)?)?;
+
+    assert_eq!(
+        decoded.continuation.map(|continuation| continuation.after),
+        Some(BTreeMap::from([(
+            user_uuid,
+            BTreeMap::from([(2, children[2])])
+        )]))
+    );
+
+    // only entities with properties that match, and only some of them
+    let named = |name: &[u8]| {
+        stewball::encode_properties(&BTreeMap::from([(0, name.to_vec()), (1, vec![1])]))
+    };
+
+    for name in [&b"ada"[..], b"alan", b"grace"] {
+        let req = ops::storage_put::req(&put_token, &user_uuid, 1, &user_uuid, 0, &named(name)?)?;
+        core.storage_put(req)?;
+    }
+
+    let query = vec![Query {
+        parent_uuid: &user_uuid,
+        uuid: &user_uuid,
+        kinds: vec![1],
+        links: vec![],
+    }];
+
+    let filter = Filter {
+        predicates: vec![Predicate::Prefix("name".into(), b"a".to_vec())],
+        projection: vec!["name".into()],
+    };
+
+    let req = ops::storage_query::req(&access_token, &Page::default(), &filter, query.clone())?;
+    let decoded: QueryResult = bitcode::decode(&core.storage_query(req)?)?;
+
+    let names = decoded.entities[&user_uuid][&1]
+        .iter()
+        .map(|entity| stewball::decode_properties(&entity.value))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    assert_eq!(
+        names,
+        vec![
+            BTreeMap::from([(0, b"ada".to_vec())]),
+            BTreeMap::from([(0, b"alan".to_vec())])
+        ]
+    );
+
+    let filter = Filter {
+        predicates: vec![
+            Predicate::Range("name".into(), Some(b"alan".to_vec()), None),
+            Predicate::In("name".into(), vec![b"ada".to_vec(), b"grace".to_vec()]),
+        ],
+        projection: vec![],
+    };
+
+    let req = ops::storage_query::req(&access_token, &Page::default(), &filter, query.clone())?;
+    let decoded: QueryResult = bitcode::decode(&core.storage_query(req)?)?;
+
+    assert_eq!(decoded.entities[&user_uuid][&1].len(), 1);
+    assert_eq!(decoded.entities[&user_uuid][&1][0].value, named(b"grace")?);
+
+    // by properties the kind has
+    let filter = Filter {
+        predicates: vec![Predicate::Eq("title".into(), b"ada".to_vec())],
+        projection: vec![],
+    };
+
+    let req = ops::storage_query::req(&access_token, &Page::default(), &filter, query)?;
+    assert!(core.storage_query(req).is_err());
+
+    // indexed properties are looked up, and unique ones can't be taken twice
+    let slugged = stewball::encode_properties(&BTreeMap::from([(2, b"hello".to_vec())]))?;
+
+    let req = ops::storage_put::req(&put_token, &user_uuid, 1, &user_uuid, 0, &slugged)?;
+    let slugged_uuid: [u8; 16] = core.storage_put(req.clone())?[..].try_into()?;
+    assert!(core.storage_put(req).is_err());
+
+    let filter = Filter {
+        predicates: vec![Predicate::Eq("slug".into(), b"hello".to_vec())],
+        projection: vec![],
+    };
+
+    let req = ops::storage_query::req(
+        &access_token,
+        &Page::default(),
+        &filter,
+        vec![Query {
+            parent_uuid: &user_uuid,
+            uuid: &user_uuid,
+            kinds: vec![1],
+            links: vec![],
+        }],
+    )?;
+    let decoded: QueryResult = bitcode::decode(&core.storage_query(req)?)?;
+
+    assert_eq!(decoded.entities[&user_uuid][&1].len(), 1);
+    assert_eq!(decoded.entities[&user_uuid][&1][0].uuid, slugged_uuid);
+
+    // link entities both ways, and follow the link from either end
+    let titled = stewball::encode_properties(&BTreeMap::from([(0, b"hello".to_vec())]))?;
+
+    let req = ops::storage_put::req(&put_token, &user_uuid, 2, &user_uuid, 0, &titled)?;
+    let post_uuid: [u8; 16] = core.storage_put(req)?[..].try_into()?;
+
+    let req = ops::access_get::req(&refresh_token, 17, Some(&group_uuid), false)?;
+    let link_token = core.access_get(req)?;
+
+    let req = ops::link_put::req(&link_token, &slugged_uuid, &post_uuid, "liked_posts")?;
+    core.link_put(req)?;
+
+    // only to the kind of entity in the schema
+    let req = ops::link_put::req(&link_token, &slugged_uuid, &slugged_uuid, "liked_posts")?;
+    assert!(core.link_put(req).is_err());
+
+    let liked = vec![Query {
+        parent_uuid: &user_uuid,
+        uuid: &slugged_uuid,
+        kinds: vec![],
+        links: vec!["liked_posts"],
+    }];
+
+    let req = ops::storage_query::req(
+        &access_token,
+        &Page::default(),
+        &Filter::default(),
+        liked.clone(),
+    )?;
+    let decoded: QueryResult = bitcode::decode(&core.storage_query(req)?)?;
+
+    assert_eq!(
+        decoded.links[&slugged_uuid]["liked_posts"][0].uuid,
+        post_uuid
+    );
+
+    // only the links of entities the group can read, even to ones it can
+    let req = ops::group_share::req(&refresh_token, &outer_uuid, &post_uuid, Permissions::READ)?;
+    core.group_share(req)?;
+
+    let req = ops::access_get::req(&refresh_token, 13, Some(&outer_uuid), false)?;
+    let outer_query_token = core.access_get(req)?;
+
+    let req = ops::storage_query::req(
+        &outer_query_token,
+        &Page::default(),
+        &Filter::default(),
+        liked.clone(),
+    )?;
+    assert!(core.storage_query(req).is_err());
+
+    // entities linked to themselves can still be deleted
+    let req = ops::storage_put::req(&put_token, &user_uuid, 1, &user_uuid, 0, &[0])?;
+    let following_uuid: [u8; 16] = core.storage_put(req)?[..].try_into()?;
+
+    let req = ops::link_put::req(&link_token, &following_uuid, &following_uuid, "follows")?;
+    core.link_put(req)?;
+
+    let req = ops::access_get::req(&refresh_token, 15, Some(&group_uuid), false)?;
+    let req = ops::storage_delete::req(
+        &core.access_get(req)?,
+        &user_uuid,
+        1,
+        &following_uuid,
+        false,
+    )?;
+    assert_eq!(ops::storage_delete::res(core.storage_delete(req)?)?, 1);
+
+    let req = ops::storage_query::req(
+        &access_token,
+        &Page::default(),
+        &Filter::default(),
+        vec![Query {
+            parent_uuid: &user_uuid,
+            uuid: &post_uuid,
+            kinds: vec![],
+            links: vec!["liked_by"],
+        }],
+    )?;
+    let decoded: QueryResult = bitcode::decode(&core.storage_query(req)?)?;
+
+    assert_eq!(decoded.links[&post_uuid]["liked_by"][0].uuid, slugged_uuid);
+
+    // or walk several hops from an entity at once
+    let hop = |step: Step, kinds: Vec<u8>, filter: Filter, hops: Vec<Hop>| Hop {
+        step,
+        kinds,
+        limit: 0,
+        reverse: false,
+        filter,
+        hops,
+    };
+
+    let slug = Filter {
+        predicates: vec![Predicate::Eq("slug".into(), b"hello".to_vec())],
+        projection: vec![],
+    };
+
+    let hops = vec![hop(
+        Step::Children,
+        vec![1],
+        slug,
+        vec![hop(
+            Step::Link("liked_posts".into()),
+            vec![],
+            Filter::default(),
+            vec![
+                hop(
+                    Step::Link("liked_by".into()),
+                    vec![],
+                    Filter::default(),
+                    vec![],
+                ),
+                hop(Step::Parent, vec![], Filter::default(), vec![]),
+            ],
+        )],
+    )];
+
+    let req = ops::storage_traverse::req(&access_token, &user_uuid, &hops)?;
+    let found = ops::storage_traverse::res(core.storage_traverse(req)?)?;
+
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].len(), 1);
+    assert_eq!(found[0][0].entity.uuid, slugged_uuid);
+
+    let post = &found[0][0].hops[0][0];
+
+    assert_eq!((post.kind, post.entity.uuid), (2, post_uuid));
+    assert_eq!(post.hops[0][0].entity.uuid, slugged_uuid);
+    // put straight under the user, which isn't an entity
+    assert!(post.hops[1].is_empty());
+
+    let req = ops::access_get::req(&refresh_token, 18, Some(&group_uuid), false)?;
+    let unlink_token = core.access_get(req)?;
+
+    let req = ops::link_drop::req(&unlink_token, &post_uuid, &slugged_uuid, "liked_by")?;
+    core.link_drop(req)?;
+
+    let req = ops::storage_query::req(&access_token, &Page::default(), &Filter::default(), liked)?;
+    let decoded: QueryResult = bitcode::decode(&core.storage_query(req)?)?;
+
+    assert!(decoded.links.is_empty());
+
+    // why the user can read the entity
+    let req = ops::permission_explain::req(&refresh_token, &user_uuid, &entity_uuid)?;
+    let explanations = ops::permission_explain::res(core.permission_explain(req)?)?;
+
+    assert_eq!(explanations.len(), 1);
+    assert_eq!(explanations[0].group_uuid, group_uuid);
+    assert_eq!(explanations[0].permissions, Permissions::READ);
+    assert_eq!(
+        explanations[0].reasons,
+        vec![PermissionReason::Allowed(Permissions::BUILT_IN | publish)]
+    );
+
+    // entities can only be shared on with what the group they were shared
+    // with can do with them
+    let req = ops::group_share::req(
+        &refresh_token,
+        &outer_uuid,
+        &entity_uuid,
+        Permissions::READ | Permissions::WRITE,
+    )?;
+    assert!(core.group_share(req).is_err());
+
+    let req = ops::group_share::req(&refresh_token, &outer_uuid, &entity_uuid, Permissions::READ)?;
+    core.group_share(req)?;
+
+    // and not by someone the entity was never shared with, into a group of
+    // their own
+    let req = ops::access_get::req(&racing_refresh_token, 3, None, false)?;
+    let req = ops::group_create::req(&core.access_get(req)?)?;
+    let own_uuid = ops::group_create::res(core.group_create(req)?)?;
+
+    let req = ops::group_share::req(
+        &racing_refresh_token,
+        &own_uuid,
+        &entity_uuid,
+        Permissions::READ,
+    )?;
+    assert!(core.group_share(req).is_err());
+
+    // a clock entities can be expired on without waiting
+    let clock = Arc::new(AtomicU64::new(
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_secs(),
+    ));
+
+    // with inherited permissions, entities get what their parent can do
+    let inheriting = core.clone().with_inherited_permissions().with_clock({
+        let clock = clock.clone();
+        move || Ok(clock.load(Ordering::SeqCst))
+    });
+
+    let req = ops::access_get::req(&refresh_token, 12, Some(&group_uuid), false)?;
+    let put_token = inheriting.access_get(req)?;
+
+    let req = ops::storage_put::req(&put_token, &user_uuid, 1, &user_uuid, 0, &[0])?;
+    let parent_uuid: [u8; 16] = inheriting.storage_put(req)?[..].try_into()?;
+
+    let req = ops::storage_put::req(&put_token, &parent_uuid, 2, &user_uuid, 1, &[0])?;
+    let child_uuid: [u8; 16] = inheriting.storage_put(req)?[..].try_into()?;
+
+    let req = ops::permission_explain::req(&refresh_token, &user_uuid, &child_uuid)?;
+    let explanations = ops::permission_explain::res(inheriting.permission_explain(req)?)?;
+
+    let explanation = explanations
+        .iter()
+        .find(|explanation| explanation.group_uuid == group_uuid)
+        .ok_or("shared with the group through the user")?;
+
+    assert_eq!(explanation.inherited_from, Some(user_uuid));
+
+    // the back link has to match where the parent is
+    let req = ops::storage_put::req(&put_token, &child_uuid, 3, &user_uuid, 2, &[0])?;
+    assert!(inheriting.storage_put(req).is_err());
+
+    // taking access away on a parent takes it away on the subtree
+    let req = ops::group_deny::req(
+        &refresh_token,
+        &group_uuid,
+        &parent_uuid,
+        Permissions::WRITE,
+    )?;
+    inheriting.group_deny(req)?;
+
+    let put_req = ops::storage_put::req(&put_token, &child_uuid, 3, &parent_uuid, 2, &[0])?;
+    assert!(inheriting.storage_put(put_req.clone()).is_err());
+
+    // unless inheritance is broken for it
+    let req = ops::group_inherit::req(&refresh_token, &group_uuid, &child_uuid, false)?;
+    inheriting.group_inherit(req)?;
+    assert!(inheriting.storage_put(put_req.clone()).is_err());
+
+    let req = ops::group_share::req(
+        &refresh_token,
+        &group_uuid,
+        &child_uuid,
+        Permissions::READ | Permissions::WRITE,
+    )?;
+    inheriting.group_share(req)?;
+    inheriting.storage_put(put_req)?;
+
+    // patch the properties of an entity, or replace it
+    let req = ops::access_get::req(&refresh_token, 14, Some(&group_uuid), false)?;
+    let update_token = inheriting.access_get(req)?;
+
+    let patch = stewball::encode_properties(&BTreeMap::from([(1, b"name".to_vec())]))?;
+
+    let req = ops::storage_update::req(
+        &update_token,
+        &parent_uuid,
+        2,
+        &child_uuid,
+        ops::storage_update::Update::Patch,
+        &patch,
+    )?;
+    inheriting.storage_update(req)?;
+
+    let req = ops::storage_update::req(
+        &update_token,
+        &parent_uuid,
+        2,
+        &child_uuid,
+        ops::storage_update::Update::Replace,
+        &[0],
+    )?;
+    inheriting.storage_update(req)?;
+
+    // only with what was denied on the way taken out
+    let req = ops::storage_update::req(
+        &update_token,
+        &user_uuid,
+        1,
+        &parent_uuid,
+        ops::storage_update::Update::Replace,
+        &[0],
+    )?;
+    assert!(inheriting.storage_update(req).is_err());
+
+    // entities with children are only deleted along with them
+    let req = ops::access_get::req(&refresh_token, 15, Some(&group_uuid), false)?;
+    let delete_token = inheriting.access_get(req)?;
+
+    let req = ops::storage_delete::req(&delete_token, &user_uuid, 1, &parent_uuid, false)?;
+    assert!(inheriting.storage_delete(req).is_err());
+
+    // and only if the group can delete every one of them, which it can't
+    // with the child shared without DELETE
+    let req = ops::storage_delete::req(&delete_token, &user_uuid, 1, &parent_uuid, true)?;
+    assert!(inheriting.storage_delete(req.clone()).is_err());
+
+    let share_req = ops::group_share::req(
+        &refresh_token,
+        &group_uuid,
+        &child_uuid,
+        Permissions::READ | Permissions::WRITE | Permissions::DELETE,
+    )?;
+    inheriting.group_share(share_req)?;
+
+    assert_eq!(
+        ops::storage_delete::res(inheriting.storage_delete(req.clone())?)?,
+        3
+    );
+    assert!(inheriting.storage_delete(req).is_err());
+
+    // along with what the group was allowed on them
+    let req = ops::permission_explain::req(&refresh_token, &user_uuid, &child_uuid)?;
+    let explanations = ops::permission_explain::res(inheriting.permission_explain(req)?)?;
+
+    assert!(explanations
+        .iter()
+        .all(|explanation| explanation.group_uuid != group_uuid
+            || explanation.inherited_from != Some(child_uuid)));
+
+    // entities put with a ttl expire, unless they are renewed
+    let req = ops::access_get::req(&refresh_token, 16, Some(&group_uuid), false)?;
+    let renew_token = inheriting.access_get(req)?;
+
+    let req = ops::storage_put::req_with_ttl(&put_token, &user_uuid, 1, &user_uuid, 0, 1, &[0])?;
+    let renewed_uuid: [u8; 16] = inheriting.storage_put_with_ttl(req)?[..].try_into()?;
+
+    let req = ops::storage_renew::req(&renew_token, &user_uuid, 1, &renewed_uuid, 0)?;
+    assert_eq!(ops::storage_renew::res(inheriting.storage_renew(req)?)?, 0);
+
+    let req = ops::storage_put::req_with_ttl(&put_token, &user_uuid, 1, &user_uuid, 0, 1, &[0])?;
+    let expiring_uuid: [u8; 16] = inheriting.storage_put_with_ttl(req)?[..].try_into()?;
+
+    let req = ops::storage_put::req(&put_token, &expiring_uuid, 2, &user_uuid, 1, &[0])?;
+    inheriting.storage_put(req)?;
+
+    clock.fetch_add(2, Ordering::SeqCst);
+
+    let req = ops::storage_renew::req(&renew_token, &user_uuid, 1, &expiring_uuid, 60)?;
+    assert!(inheriting.storage_renew(req).is_err());
+
+    let req = ops::storage_put::req(&put_token, &expiring_uuid, 2, &user_uuid, 1, &[0])?;
+    assert!(inheriting.storage_put(req).is_err());
+
+    // and are reaped along with everything under them
+    assert!(inheriting.reap_expired()? >= 2);
+
+    let req = ops::storage_delete::req(&delete_token, &user_uuid, 1, &expiring_uuid, true)?;
+    assert!(inheriting.storage_delete(req).is_err());
+
+    let req = ops::storage_delete::req(&delete_token, &user_uuid, 1, &renewed_uuid, false)?;
+    assert_eq!(
+        ops::storage_delete::res(inheriting.storage_delete(req)?)?,
+        1
+    );
+
+    // a subtree too large for one write transaction is reaped over several
+    let req = ops::storage_put::req_with_ttl(&put_token, &user_uuid, 1, &user_uuid, 0, 1, &[0])?;
+    let large_uuid: [u8; 16] = inheriting.storage_put_with_ttl(req)?[..].try_into()?;
+
+    for _ in 0..MAX_REAPED {
+        let req = ops::storage_put::req(&put_token, &large_uuid, 2, &user_uuid, 1, &[0])?;
+        inheriting.storage_put(req)?;
+    }
+
+    clock.fetch_add(2, Ordering::SeqCst);
+
+    assert_eq!(inheriting.reap_expired()? as usize, MAX_REAPED + 1);
+
+    // only admins can explain others
+    let req = ops::permission_explain::req(&racing_refresh_token, &user_uuid, &entity_uuid)?;
+    assert!(ops::permission_explain::res(core.permission_explain(req)?)?.is_empty());
+
+    // keep a secret for the group, sealed by the server
+    let req = ops::access_get::req(&refresh_token, 9, Some(&group_uuid), false)?;
+    let secret_put_token = core.access_get(req)?;
+
+    let req = ops::access_get::req(&refresh_token, 8, Some(&group_uuid), false)?;
+    let secret_get_token = core.access_get(req)?;
+
+    let req = ops::secret_put::req(&secret_put_token, None, false, b"first")?;
+    let (secret_uuid, version) = ops::secret_put::res(core.secret_put(req)?)?;
+    assert_eq!(version, 0);
+
+    // and a second version of it, sealed by the client
+    let client_key = [7u8; 32];
+
+    let sealed = ops::secret_put::seal(&client_key, b"second")?;
+
+    let req = ops::secret_put::req(&secret_put_token, Some(&secret_uuid), true, &sealed)?;
+    assert_eq!(
+        ops::secret_put::res(core.secret_put(req)?)?,
+        (secret_uuid, 1)
+    );
+
+    let req = ops::secret_get::req(&secret_get_token, &secret_uuid, None)?;
+    let secret = ops::secret_get::res(core.secret_get(req)?)?;
+
+    assert_eq!(secret.version, 1);
+    assert!(secret.client_sealed);
+    assert_eq!(secret.user_uuid, user_uuid);
+    assert_eq!(
+        ops::secret_get::open(&client_key, &secret.value)?,
+        b"second"
+    );
+
+    let req = ops::secret_get::req(&secret_get_token, &secret_uuid, Some(0))?;
+    let secret = ops::secret_get::res(core.secret_get(req)?)?;
+
+    assert!(!secret.client_sealed);
+    assert_eq!(secret.value, b"first");
+
+    let req = ops::secret_get::req(&secret_get_token, &secret_uuid, Some(2))?;
+    assert!(core.secret_get(req).is_err());
+
+    // only with a token for getting secrets
+    let req = ops::secret_get::req(&secret_put_token, &secret_uuid, None)?;
+    assert!(core.secret_get(req).is_err());
+
+    // share the entity with a token narrowed offline to a single query
+    let req = ops::delegation_create::req(&access_token)?;
+    let delegated_token = core.delegation_create(req)?;
+
+    let shared = delegated::attenuate(&delegated_token, Caveat::Entity(entity_uuid))?;
+    let shared = delegated::attenuate(&shared, Caveat::SingleUse)?;
+
+    let query = vec![Query {
+        parent_uuid: &user_uuid,
+        uuid: &entity_uuid,
+        kinds: vec![1],
+        links: vec![],
+    }];
+
+    let req =
+        ops::storage_query::req(&shared, &Page::default(), &Filter::default(), query.clone())?;
+    assert_eq!(core.storage_query(req)?, query_result);
+
+    let req =
+        ops::storage_query::req(&shared, &Page::default(), &Filter::default(), query.clone())?;
+    assert!(core.storage_query(req).is_err());
+
+    // narrowing further doesn't get another use
+    let req = ops::storage_query::req(
+        &delegated::attenuate(&shared, Caveat::Kind(1))?,
+        &Page::default(),
+        &Filter::default(),
+        query,
+    )?;
+    assert!(core.storage_query(req).is_err());
+
+    // nor can it be used for other entities, or to write
+    let other = delegated::attenuate(&delegated_token, Caveat::Entity(user_uuid))?;
+
+    let req = ops::storage_query::req(
+        &other,
+        &Page::default(),
+        &Filter::default(),
+        vec![Query {
+            parent_uuid: &user_uuid,
+            uuid: &entity_uuid,
+            kinds: vec![1],
+            links: vec![],
+        }],
+    )?;
+    assert!(core.storage_query(req).is_err());
+
+    let req = ops::storage_put::req(&delegated_token, &user_uuid, 1, &user_uuid, 0, &[0])?;
+    assert!(core.storage_put(req).is_err());
+
+    // rotate the refresh token
+    let req = ops::access_get::req(&refresh_token, 3, None, true)?;
+    let (next_refresh_token, _) = ops::access_get::res(core.access_get(req)?)?;
+
+    let req = ops::access_get::req(&next_refresh_token, 3, None, false)?;
+    core.access_get(req)?;
+
+    assert_ne!(refresh_token, next_refresh_token);
+    assert!(
+        cbwaw::token::parse(&next_refresh_token)?.exp <= cbwaw::token::parse(&refresh_token)?.exp
+    );
+
+    // replaying the rotated out token revokes the whole family
+    let req = ops::access_get::req(&refresh_token, 3, None, false)?;
+    assert!(core.access_get(req).is_err());
+
+    let req = ops::access_get::req(&next_refresh_token, 3, None, false)?;
+    assert!(core.access_get(req).is_err());
+
+    // logout
+    let req = ops::logout::req(&refresh_token, false)?;
+    core.logout(req)?;
+
+    // the refresh token can no longer be used
+    let req = ops::access_get::req(&refresh_token, 3, None, false)?;
+    assert!(core.access_get(req).is_err());
+
+    // a logged out session can't change the password
+    let (_, req) = ops::password_change_start::req(&refresh_token, b"new password")?;
+    assert!(core.password_change_start(req).is_err());
+
+    // log out of every session
+    let (state, req) = ops::login_start::req(username, b"password")?;
+    let res = core.login_start(req)?;
+    let (req, session_key) = ops::login_finish::req(username, b"password", &state, &res)?;
+    let Login::RefreshToken(refresh_token) =
+        ops::login_finish::res(core.login_finish(req)?, &session_key)?
+    else {
+        panic!("no second factor was enrolled");
+    };
+
+    let req = ops::logout::req(&refresh_token, true)?;
+    core.logout(req)?;
+
+    let req = ops::access_get::req(&refresh_token, 3, None, false)?;
+    assert!(core.access_get(req).is_err());
+
+    // log in again right away, which is a new session and fresh
+    // enough to change the password
+    let (state, req) = ops::login_start::req(username, b"password")?;
+    let res = core.login_start(req)?;
+    let (req, session_key) = ops::login_finish::req(username, b"password", &state, &res)?;
+    let Login::RefreshToken(refresh_token) =
+        ops::login_finish::res(core.login_finish(req)?, &session_key)?
+    else {
+        panic!("no second factor was enrolled");
+    };
+
+    let req = ops::access_get::req(&refresh_token, 3, None, false)?;
+    core.access_get(req)?;
+
+    let (state, req) = ops::password_change_start::req(&refresh_token, b"new password")?;
+    let res = core.password_change_start(req)?;
+    let req = ops::password_change_finish::req(&refresh_token, b"new password", &state, &res)?;
+    core.password_change_finish(req)?;
+
+    // every session is logged out, and the old password no longer works
+    let req = ops::access_get::req(&refresh_token, 3, None, false)?;
+    assert!(core.access_get(req).is_err());
+
+    let (state, req) = ops::login_start::req(username, b"password")?;
+    let res = core.login_start(req)?;
+    assert!(ops::login_finish::req(username, b"password", &state, &res).is_err());
+
+    // recover the account with one of the codes from registration
+    let recovery_code = recovery_codes[0].as_bytes();
+
+    let (state, req) = ops::recovery_start::req(username, recovery_code, b"recovered")?;
+    let res = core.recovery_start(req)?;
+    let req = ops::recovery_finish::req(username, recovery_code, b"recovered", &state, &res)?;
+    core.recovery_finish(req)?;
+
+    // each code can only be used once, which only finishing tells
+    let (state, req) = ops::recovery_start::req(username, recovery_code, b"recovered")?;
+    let res = core.recovery_start(req)?;
+    let req = ops::recovery_finish::req(username, recovery_code, b"recovered", &state, &res)?;
+    let used_err = core.recovery_finish(req).unwrap_err().to_string();
+
+    // an unknown username fails the same way
+    let unknown = format!("unknown-{}", Uuid::new_v4().simple());
+    let (state, req) = ops::recovery_start::req(unknown.as_bytes(), recovery_code, b"recovered")?;
+    let res = core.recovery_start(req)?;
+    let req =
+        ops::recovery_finish::req(unknown.as_bytes(), recovery_code, b"recovered", &state, &res)?;
+    assert_eq!(core.recovery_finish(req).unwrap_err().to_string(), used_err);
+
+    // the recovered account is still the same user
+    let (state, req) = ops::login_start::req(username, b"recovered")?;
+    let res = core.login_start(req)?;
+    let (req, session_key) = ops::login_finish::req(username, b"recovered", &state, &res)?;
+    let Login::RefreshToken(refresh_token) =
+        ops::login_finish::res(core.login_finish(req)?, &session_key)?
+    else {
+        panic!("no second factor was enrolled");
+    };
+
+    assert_eq!(cbwaw::token::parse(&refresh_token)?.user_uuid, user_uuid);
+
+    let req = ops::access_get::req(&refresh_token, 3, None, false)?;
+    core.access_get(req)?;
+
+    // enroll a second factor with the fresh login
+    let req = ops::totp_enroll_start::req(&refresh_token)?;
+    let secret = ops::totp_enroll_start::res(core.totp_enroll_start(req)?)?;
+
+    let step = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_secs()
+        / cbwaw::totp::STEP;
+
+    let req = ops::totp_enroll_finish::req(&refresh_token, cbwaw::totp::code(&secret, step))?;
+    let backup_codes = ops::totp_enroll_finish::res(core.totp_enroll_finish(req)?)?;
+    assert_eq!(backup_codes.len(), RECOVERY_CODES);
+
+    // the password alone is no longer enough
+    let (state, req) = ops::login_start::req(username, b"recovered")?;
+    let res = core.login_start(req)?;
+    let (req, session_key) = ops::login_finish::req(username, b"recovered", &state, &res)?;
+    let Login::SecondFactor(nonce) = ops::login_finish::res(core.login_finish(req)?, &session_key)?
+    else {
+        panic!("second factor was enrolled");
+    };
+
+    // a wrong code uses up the nonce
+    let code = cbwaw::totp::code(&secret, step + 1);
+
+    let req = ops::login_second_factor::req(&nonce, b"wrong!")?;
+    assert!(core.login_second_factor(req).is_err());
+
+    let req = ops::login_second_factor::req(&nonce, format!("{code:06}").as_bytes())?;
+    assert!(core.login_second_factor(req).is_err());
+
+    // the next code from the authenticator
+    let (state, req) = ops::login_start::req(username, b"recovered")?;
+    let res = core.login_start(req)?;
+    let (req, session_key) = ops::login_finish::req(username, b"recovered", &state, &res)?;
+    let Login::SecondFactor(nonce) = ops::login_finish::res(core.login_finish(req)?, &session_key)?
+    else {
+        panic!("second factor was enrolled");
+    };
+
+    let req = ops::login_second_factor::req(&nonce, format!("{code:06}").as_bytes())?;
+    let refresh_token =
+        ops::login_second_factor::res(core.login_second_factor(req)?, &session_key)?;
+
+    let req = ops::access_get::req(&refresh_token, 3, None, false)?;
+    core.access_get(req)?;
+
+    // an api key for a service, limited to creating groups
+    let req = ops::api_key_create::req(&refresh_token, 60 * 60, &[3], &[])?;
+    let api_key = core.api_key_create(req)?;
+
+    let req = ops::api_key_exchange::req(&api_key, 3, None)?;
+    let access_token = core.api_key_exchange(req)?;
+
+    let req = ops::group_create::req(&access_token)?;
+    core.group_create(req)?;
+
+    let req = ops::api_key_exchange::req(&api_key, 12, None)?;
+    assert!(core.api_key_exchange(req).is_err());
+
+    let req = ops::api_key_list::req(&refresh_token)?;
+    let api_keys = ops::api_key_list::res(core.api_key_list(req)?)?;
+
+    assert_eq!(api_keys.len(), 1);
+    assert_eq!(api_keys[0].key_id[..], api_key[0..16]);
+    assert!(api_keys[0].last_used > 0);
+
+    // revoked keys can't be exchanged anymore
+    let req = ops::api_key_revoke::req(&refresh_token, &api_keys[0].key_id)?;
+    core.api_key_revoke(req)?;
+
+    let req = ops::api_key_exchange::req(&api_key, 3, None)?;
+    assert!(core.api_key_exchange(req).is_err());
+
+    // a machine client with a client certificate bound to the user
+    let certificate = Certificate {
+        subject: Uuid::new_v4().as_bytes().to_vec(),
+        spki_hash: ring::digest::digest(&ring::digest::SHA256, Uuid::new_v4().as_bytes())
+            .as_ref()
+            .try_into()?,
+    };
+
+    let req = ops::access_get_certificate::req(3, None)?;
+    assert!(core.access_get_certificate(&certificate, req).is_err());
+
+    let req = ops::certificate_bind::req(&refresh_token, CertificateMatch::Spki)?;
+    core.certificate_bind(&certificate, req)?;
+
+    let req = ops::access_get_certificate::req(3, None)?;
+    let access_token = core.access_get_certificate(&certificate, req)?;
+
+    let req = ops::group_create::req(&access_token)?;
+    core.group_create(req)?;
+
+    let req = ops::certificate_unbind::req(
+        &refresh_token,
+        CertificateMatch::Spki,
+        &certificate.spki_hash,
+    )?;
+    core.certificate_unbind(req)?;
+
+    let req = ops::access_get_certificate::req(3, None)?;
+    assert!(core.access_get_certificate(&certificate, req).is_err());
+
+    // or a backup code in place of one, but only once
+    let backup_code = backup_codes[0].as_bytes();
+
+    for expected in [true, false] {
+        let (state, req) = ops::login_start::req(username, b"recovered")?;
+        let res = core.login_start(req)?;
+        let (req, session_key) = ops::login_finish::req(username, b"recovered", &state, &res)?;
+        let Login::SecondFactor(nonce) =
+            ops::login_finish::res(core.login_finish(req)?, &session_key)?
+        else {
+            panic!("second factor was enrolled");
+        };
+
+        let req = ops::login_second_factor::req(&nonce, backup_code)?;
+        assert_eq!(core.login_second_factor(req).is_ok(), expected);
+    }
+
+    // unknown usernames get an answer too, but the login can't finish
+    let unknown = format!("unknown-{}", Uuid::new_v4().simple());
+
+    let (state, req) = ops::login_start::req(unknown.as_bytes(), b"password")?;
+    let res = core.login_start(req)?;
+    assert!(ops::login_finish::req(unknown.as_bytes(), b"password", &state, &res).is_err());
+
+    // and are throttled the same as any other once the free attempts are used up
+    for _ in 1..=FREE_LOGIN_ATTEMPTS {
+        let (_, req) = ops::login_start::req(unknown.as_bytes(), b"password")?;
+        core.login_start(req)?;
+    }
+
+    let (_, req) = ops::login_start::req(unknown.as_bytes(), b"password")?;
+    let err = core.login_start(req).unwrap_err();
+    assert!(err.is::<Throttled>());
+
+    // per source as well
+    let source = IpAddr::V6(Ipv6Addr::from(*Uuid::new_v4().as_bytes()));
+
+    for _ in 0..stewball::FREE_SOURCE_ATTEMPTS + 1 {
+        let other = format!("other-{}", Uuid::new_v4().simple());
+        let (_, req) = ops::login_start::req(other.as_bytes(), b"password")?;
+        core.login_start_from(source, req)?;
+    }
+
+    let (_, req) = ops::login_start::req(username, b"recovered")?;
+    let err = core.login_start_from(source, req).unwrap_err();
+    assert!(err.is::<Throttled>());
+
+    // logging in doesn't clear the source, or an account of its own
+    // would let it guess on
+    let source = IpAddr::V6(Ipv6Addr::from(*Uuid::new_v4().as_bytes()));
+
+    for _ in 0..stewball::FREE_SOURCE_ATTEMPTS {
+        let other = format!("other-{}", Uuid::new_v4().simple());
+        let (_, req) = ops::login_start::req(other.as_bytes(), b"password")?;
+        core.login_start_from(source, req)?;
+    }
+
+    let (state, req) = ops::login_start::req(username, b"recovered")?;
+    let res = core.login_start_from(source, req)?;
+    let (req, _) = ops::login_finish::req(username, b"recovered", &state, &res)?;
+    core.login_finish(req)?;
+
+    let (_, req) = ops::login_start::req(username, b"recovered")?;
+    let err = core.login_start_from(source, req).unwrap_err();
+    assert!(err.is::<Throttled>());
+
     Ok(())
 }
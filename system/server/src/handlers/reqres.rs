@@ -1,29 +1,92 @@
+use std::net::SocketAddr;
+
 use axum::body::Bytes;
+use axum::extract::connect_info::ConnectInfo;
 use axum::extract::State;
 use axum::http::StatusCode;
 use axum::response::IntoResponse;
+use axum::Extension;
+
+use crate::tls::ClientCertificate;
 
 #[axum::debug_handler]
-pub async fn handler(State(state): State<crate::State>, body: Bytes) -> impl IntoResponse {
-    match match body[0] {
+pub async fn handler(
+    State(state): State<crate::State>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    client_certificate: Option<Extension<ClientCertificate>>,
+    body: Bytes,
+) -> impl IntoResponse {
+    let Some(&action) = body.first() else {
+        return (StatusCode::BAD_REQUEST, Bytes::new());
+    };
+
+    // everything after the action byte is the op's payload
+    let body = body.slice(1..);
+
+    match match action {
         0 => state.core.access_get(body),
         1 => state.core.group_assign(body),
         2 => state.core.group_create(body),
         3 => state.core.group_drop(body),
         4 => state.core.login_finish(body),
-        5 => state.core.login_start(body),
-        6 => match state.core.registration_finish(body) {
-            Ok(_) => Ok(Bytes::new()),
-            Err(err) => Err(err),
-        },
+        5 => state.core.login_start_from(addr.ip(), body),
+        6 => state.core.registration_finish(body),
         7 => state.core.registration_start(body),
         8 => state.core.secret_get(body),
         9 => state.core.secret_put(body),
         10 => state.core.storage_put(body),
         11 => state.core.storage_query(body),
+        12 => state.core.logout(body),
+        13 => state.core.password_change_finish(body),
+        14 => state.core.password_change_start(body),
+        15 => state.core.recovery_finish(body),
+        16 => state.core.recovery_start(body),
+        17 => state.core.login_second_factor(body),
+        18 => state.core.totp_enroll_finish(body),
+        19 => state.core.totp_enroll_start(body),
+        20 => state.core.api_key_create(body),
+        21 => state.core.api_key_exchange(body),
+        22 => state.core.api_key_list(body),
+        23 => state.core.api_key_revoke(body),
+        24 => state.core.delegation_create(body),
+        25 => match &client_certificate {
+            Some(Extension(ClientCertificate(Some(certificate)))) => {
+                state.core.access_get_certificate(certificate, body)
+            }
+            _ => Err("no client certificate".into()),
+        },
+        26 => match &client_certificate {
+            Some(Extension(ClientCertificate(Some(certificate)))) => {
+                state.core.certificate_bind(certificate, body)
+            }
+            _ => Err("no client certificate".into()),
+        },
+        27 => state.core.certificate_unbind(body),
+        28 => state.core.group_members(body),
+        29 => state.core.group_deny(body),
+        30 => state.core.group_nest(body),
+        31 => state.core.permission_explain(body),
+        32 => state.core.group_share(body),
+        33 => state.core.group_inherit(body),
+        34 => state.core.storage_update(body),
+        35 => state.core.storage_delete(body),
+        36 => state.core.storage_renew(body),
+        37 => state.core.link_put(body),
+        38 => state.core.link_drop(body),
+        39 => state.core.storage_traverse(body),
+        40 => state.core.storage_put_with_ttl(body),
         _ => Err("unknown action".into()),
     } {
         Ok(val) => (StatusCode::OK, val),
+        Err(err)
+            if err.downcast_ref::<stewball::UsernameError>()
+                == Some(&stewball::UsernameError::Taken) =>
+        {
+            (StatusCode::CONFLICT, Bytes::new())
+        }
+        Err(err) if err.is::<stewball::Throttled>() => {
+            (StatusCode::TOO_MANY_REQUESTS, Bytes::new())
+        }
         Err(err) => {
             log::error!("{err}");
             (StatusCode::INTERNAL_SERVER_ERROR, Bytes::new())
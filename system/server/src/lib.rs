@@ -1,6 +1,9 @@
 mod handlers;
+mod tls;
 use handlers::{reqres, stream};
 
+pub use tls::Tls;
+
 use axum::routing::{any, delete, get, put};
 use axum::Router;
 use tokio::net::TcpListener;
@@ -47,10 +50,15 @@ pub async fn start(
     router: BTreeMap<Vec<u8>, Vec<u8>>,
     core: Core,
 ) -> Result<(), Box<dyn std::error::Error>> {
+    core.spawn_reaper(stewball::REAP_INTERVAL);
+
     let state = State { router, core };
 
     let (reqres, stream) = tokio::join!(
-        axum::serve(reqres_listener, reqres(state.clone(), assets_dir)),
+        axum::serve(
+            reqres_listener,
+            reqres(state.clone(), assets_dir).into_make_service_with_connect_info::<SocketAddr>()
+        ),
         axum::serve(
             stream_listener,
             stream(state).into_make_service_with_connect_info::<SocketAddr>()
@@ -62,3 +70,36 @@ pub async fn start(
 
     Ok(())
 }
+
+/// `start`, but terminating TLS on both listeners.
+///
+/// with `tls.client_ca` set, clients can present a certificate bound to
+/// a user to get access tokens without logging in.
+pub async fn start_tls(
+    reqres_listener: TcpListener,
+    stream_listener: TcpListener,
+    assets_dir: &str,
+    router: BTreeMap<Vec<u8>, Vec<u8>>,
+    core: Core,
+    tls: Tls,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let acceptor = tls.acceptor()?;
+
+    core.spawn_reaper(stewball::REAP_INTERVAL);
+
+    let state = State { router, core };
+
+    let (reqres, stream) = tokio::join!(
+        tls::serve(
+            reqres_listener,
+            acceptor.clone(),
+            reqres(state.clone(), assets_dir)
+        ),
+        tls::serve(stream_listener, acceptor, stream(state))
+    );
+
+    reqres?;
+    stream?;
+
+    Ok(())
+}
@@ -0,0 +1,143 @@
+// terminating TLS in hostess itself, instead of behind a proxy, so
+// client certificates can be verified and handed to the ops.
+
+use std::fs::File;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use axum::extract::connect_info::ConnectInfo;
+use axum::{Extension, Router};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use hyper_util::service::TowerToHyperService;
+use tokio::net::TcpListener;
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+use stewball::Certificate;
+
+/// certificate and key to serve with, and whether to ask clients for theirs
+pub struct Tls {
+    /// PEM encoded certificate chain, leaf first
+    pub cert_chain: PathBuf,
+    /// PEM encoded private key for the leaf certificate
+    pub private_key: PathBuf,
+    /// PEM encoded CAs client certificates have to chain to.
+    ///
+    /// without it clients aren't asked for a certificate.
+    pub client_ca: Option<PathBuf>,
+    /// refuse connections that don't present a client certificate
+    pub require_client_cert: bool,
+}
+
+impl Tls {
+    pub(crate) fn acceptor(&self) -> Result<TlsAcceptor, Box<dyn std::error::Error>> {
+        let cert_chain = rustls_pemfile::certs(&mut BufReader::new(File::open(&self.cert_chain)?))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let private_key =
+            rustls_pemfile::private_key(&mut BufReader::new(File::open(&self.private_key)?))?
+                .ok_or("no private key")?;
+
+        let builder = ServerConfig::builder();
+
+        let builder = match &self.client_ca {
+            Some(client_ca) => {
+                let mut roots = RootCertStore::empty();
+
+                for cert in rustls_pemfile::certs(&mut BufReader::new(File::open(client_ca)?)) {
+                    roots.add(cert?)?;
+                }
+
+                let verifier = WebPkiClientVerifier::builder(Arc::new(roots));
+
+                builder.with_client_cert_verifier(if self.require_client_cert {
+                    verifier.build()?
+                } else {
+                    verifier.allow_unauthenticated().build()?
+                })
+            }
+            None if self.require_client_cert => {
+                return Err("client certificates can't be required without a client CA".into())
+            }
+            None => builder.with_no_client_auth(),
+        };
+
+        let mut config = builder.with_single_cert(cert_chain, private_key)?;
+
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+}
+
+/// the verified client certificate of the connection, if it sent one
+#[derive(Clone)]
+pub(crate) struct ClientCertificate(pub Option<Certificate>);
+
+fn certificate(der: &[u8]) -> Result<Certificate, Box<dyn std::error::Error>> {
+    let (_, cert) = x509_parser::parse_x509_certificate(der)?;
+
+    let spki_hash = ring::digest::digest(&ring::digest::SHA256, cert.public_key().raw);
+
+    Ok(Certificate {
+        subject: cert.subject().as_raw().to_vec(),
+        spki_hash: spki_hash.as_ref().try_into()?,
+    })
+}
+
+/// `axum::serve`, with a TLS handshake before each connection
+pub(crate) async fn serve(
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+    router: Router,
+) -> std::io::Result<()> {
+    loop {
+        let (stream, addr) = listener.accept().await?;
+
+        let acceptor = acceptor.clone();
+        let router = router.clone();
+
+        tokio::spawn(async move {
+            let stream = match acceptor.accept(stream).await {
+                Ok(stream) => stream,
+                Err(err) => {
+                    log::debug!("tls handshake with {addr} failed: {err}");
+                    return;
+                }
+            };
+
+            let client_certificate = match stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|certs| certs.first())
+            {
+                Some(der) => match certificate(der) {
+                    Ok(certificate) => Some(certificate),
+                    Err(err) => {
+                        log::debug!("client certificate from {addr} is invalid: {err}");
+                        return;
+                    }
+                },
+                None => None,
+            };
+
+            let service = TowerToHyperService::new(
+                router
+                    .layer(Extension(ConnectInfo::<SocketAddr>(addr)))
+                    .layer(Extension(ClientCertificate(client_certificate))),
+            );
+
+            if let Err(err) = auto::Builder::new(TokioExecutor::new())
+                .serve_connection_with_upgrades(TokioIo::new(stream), service)
+                .await
+            {
+                log::debug!("connection with {addr} failed: {err}");
+            }
+        });
+    }
+}
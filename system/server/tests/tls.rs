@@ -0,0 +1,195 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+use uuid::Uuid;
+
+use stewball::ops;
+use stewball::ops::login_finish::Login;
+use stewball::{CertificateMatch, Core};
+
+const ACCESS_GET_CERTIFICATE: u8 = 25;
+const CERTIFICATE_BIND: u8 = 26;
+
+/// certificates made with openssl for these tests, ca.pem signed
+/// server.pem and client.pem, other.pem is self-signed
+fn fixture(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/tls")
+        .join(name)
+}
+
+async fn server(core: Core) -> Result<SocketAddr, Box<dyn std::error::Error>> {
+    let reqres_listener = TcpListener::bind("127.0.0.1:0").await?;
+    let reqres_addr = reqres_listener.local_addr()?;
+
+    let stream_listener = TcpListener::bind("127.0.0.1:0").await?;
+
+    let tls = hostess::Tls {
+        cert_chain: fixture("server.pem"),
+        private_key: fixture("server.key"),
+        client_ca: Some(fixture("ca.pem")),
+        require_client_cert: false,
+    };
+
+    tokio::spawn(async move {
+        hostess::start_tls(
+            reqres_listener,
+            stream_listener,
+            "./",
+            BTreeMap::new(),
+            core,
+            tls,
+        )
+        .await
+        .unwrap();
+    });
+
+    Ok(reqres_addr)
+}
+
+/// trusts ca.pem, and presents `client`.pem if there is one
+fn connector(client: Option<&str>) -> Result<TlsConnector, Box<dyn std::error::Error>> {
+    let mut roots = RootCertStore::empty();
+
+    for cert in rustls_pemfile::certs(&mut BufReader::new(File::open(fixture("ca.pem"))?)) {
+        roots.add(cert?)?;
+    }
+
+    let builder = ClientConfig::builder().with_root_certificates(roots);
+
+    let config = match client {
+        Some(client) => {
+            let cert_chain = rustls_pemfile::certs(&mut BufReader::new(File::open(fixture(
+                &format!("{client}.pem"),
+            ))?))
+            .collect::<Result<Vec<_>, _>>()?;
+
+            let private_key = rustls_pemfile::private_key(&mut BufReader::new(File::open(
+                fixture(&format!("{client}.key")),
+            )?))?
+            .ok_or("no private key")?;
+
+            builder.with_client_auth_cert(cert_chain, private_key)?
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+/// (status, body) of posting `body` to reqres over a new connection
+async fn post(
+    addr: SocketAddr,
+    connector: &TlsConnector,
+    body: &[u8],
+) -> Result<(u16, Vec<u8>), Box<dyn std::error::Error>> {
+    let stream = TcpStream::connect(addr).await?;
+    let mut stream = connector
+        .connect(ServerName::try_from("localhost")?, stream)
+        .await?;
+
+    let head = format!(
+        "POST / HTTP/1.1\r\nhost: localhost\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+        body.len()
+    );
+
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body).await?;
+
+    let mut res = vec![];
+
+    match stream.read_to_end(&mut res).await {
+        Ok(_) => {}
+        // closed without a close_notify, everything was read anyway
+        Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof && !res.is_empty() => {}
+        Err(err) => return Err(err.into()),
+    }
+
+    let head_end = res
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .ok_or("no response")?;
+
+    let status = std::str::from_utf8(&res[..head_end])?
+        .split(' ')
+        .nth(1)
+        .ok_or("no status")?
+        .parse()?;
+
+    Ok((status, res[head_end + 4..].to_vec()))
+}
+
+fn action(action: u8, payload: &[u8]) -> Vec<u8> {
+    [&[action], payload].concat()
+}
+
+#[tokio::test]
+async fn client_certificates() -> Result<(), Box<dyn std::error::Error>> {
+    let core = Core::new()?;
+
+    let username = format!("tls-{}", Uuid::new_v4().simple());
+    let username = username.as_bytes();
+
+    let (state, req) = ops::registration_start::req(username, b"password")?;
+    let res = core.registration_start(req)?;
+    let req = ops::registration_finish::req(username, b"password", &state, &res)?;
+    core.registration_finish(req)?;
+
+    let (state, req) = ops::login_start::req(username, b"password")?;
+    let res = core.login_start(req)?;
+    let (req, session_key) = ops::login_finish::req(username, b"password", &state, &res)?;
+    let Login::RefreshToken(refresh_token) =
+        ops::login_finish::res(core.login_finish(req)?, &session_key)?
+    else {
+        panic!("no second factor was enrolled");
+    };
+
+    let addr = server(core.clone()).await?;
+
+    let anonymous = connector(None)?;
+    let client = connector(Some("client"))?;
+
+    // a body without an action is a bad request, not a panic
+    assert_eq!(post(addr, &anonymous, &[]).await?.0, 400);
+
+    // nothing to bind without a certificate on the connection
+    let req = ops::certificate_bind::req(&refresh_token, CertificateMatch::Spki)?;
+    let (status, _) = post(addr, &anonymous, &action(CERTIFICATE_BIND, &req)).await?;
+    assert_eq!(status, 500);
+
+    // not bound yet
+    let req = ops::access_get_certificate::req(3, None)?;
+    let (status, _) = post(addr, &client, &action(ACCESS_GET_CERTIFICATE, &req)).await?;
+    assert_eq!(status, 500);
+
+    // binds the certificate the connection was made with
+    let req = ops::certificate_bind::req(&refresh_token, CertificateMatch::Spki)?;
+    let (status, _) = post(addr, &client, &action(CERTIFICATE_BIND, &req)).await?;
+    assert_eq!(status, 200);
+
+    let req = ops::access_get_certificate::req(3, None)?;
+    let (status, access_token) =
+        post(addr, &client, &action(ACCESS_GET_CERTIFICATE, &req)).await?;
+    assert_eq!(status, 200);
+
+    // the access token is for the user the certificate was bound to
+    core.group_create(ops::group_create::req(&access_token)?)?;
+
+    // a certificate that doesn't chain to the client CA doesn't get a connection
+    let other = connector(Some("other"))?;
+    let req = ops::access_get_certificate::req(3, None)?;
+    assert!(post(addr, &other, &action(ACCESS_GET_CERTIFICATE, &req))
+        .await
+        .is_err());
+
+    Ok(())
+}
@@ -3,6 +3,12 @@ use clap::{Parser, ValueEnum};
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
 enum Command {
     Command,
+    /// retire the current token signing key and generate a new one
+    RotateTokenKeys,
+    /// print the token keys as hex, for the `[tokens]` table of ordinary.toml
+    PrintTokenKeys,
+    /// index every entity again with the indexes in ordinary.toml
+    RebuildIndexes,
 }
 
 #[derive(Parser, Debug)]
@@ -10,6 +16,14 @@ enum Command {
 struct Args {
     #[arg(value_enum)]
     command: Command,
+
+    /// token key file shared by the servers of a deployment
+    #[arg(long, default_value = "./store/token.keys")]
+    token_keys: String,
+
+    /// where the entity schema and its indexes are declared
+    #[arg(long, default_value = "./ordinary.toml")]
+    config: String,
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -19,6 +33,33 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     match args.command {
         Command::Command => (),
+        Command::RotateTokenKeys => {
+            let mut keys = cbwaw::token::KeyRing::load_or_generate(&args.token_keys)?;
+
+            // keys retired more than the max token lifetime ago can't
+            // have any valid tokens left, so they go now
+            keys.prune()?;
+
+            let key_id = keys.rotate()?;
+            keys.save(&args.token_keys)?;
+
+            log::info!("signing with key {key_id}");
+        }
+        Command::PrintTokenKeys => {
+            println!(
+                "{}",
+                cbwaw::token::KeyRing::load(&args.token_keys)?.to_hex()
+            );
+        }
+        Command::RebuildIndexes => {
+            let config = std::fs::read_to_string(&args.config)?;
+
+            let indexed = stewball::Core::new()?
+                .with_entity_schema(&config)?
+                .rebuild_indexes()?;
+
+            log::info!("indexed {indexed} entities");
+        }
     }
 
     Ok(())